@@ -0,0 +1,133 @@
+// Benchmarks for the per-frame paths of the device: command decoding, response encoding,
+// scatter-gather translation and the 2D transfer copy.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rutabaga_gfx::{
+    ResourceCreate3D, RutabagaBuilder, RutabagaComponentType, Transfer3D,
+    RUTABAGA_PIPE_BIND_RENDER_TARGET, RUTABAGA_PIPE_TEXTURE_2D,
+};
+use vhost_gpu_backend::protocol::*;
+use vhost_gpu_backend::virtio_gpu::sglist_to_rutabaga_iovecs;
+use vhost_gpu_backend::{VirtioGpuCommand, VirtioGpuResponse};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap, Le32};
+
+const GUEST_MEM_SIZE: usize = 64 << 20;
+const PAGE_SIZE: usize = 4096;
+
+fn guest_memory() -> GuestMemoryMmap {
+    GuestMemoryMmap::from_ranges(&[(GuestAddress(0), GUEST_MEM_SIZE)]).unwrap()
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mem = guest_memory();
+    let mut group = c.benchmark_group("decode");
+
+    let transfer = virtio_gpu_transfer_to_host_2d {
+        hdr: virtio_gpu_ctrl_hdr {
+            type_: Le32::from(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+            ..Default::default()
+        },
+        resource_id: Le32::from(1),
+        ..Default::default()
+    };
+    mem.write_obj(transfer, GuestAddress(0)).unwrap();
+    group.bench_function("transfer_to_host_2d", |b| {
+        b.iter(|| VirtioGpuCommand::decode(black_box(&mem), GuestAddress(0)).unwrap())
+    });
+
+    let cursor = virtio_gpu_update_cursor {
+        hdr: virtio_gpu_ctrl_hdr {
+            type_: Le32::from(VIRTIO_GPU_CMD_MOVE_CURSOR),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    mem.write_obj(cursor, GuestAddress(PAGE_SIZE as u64)).unwrap();
+    group.bench_function("move_cursor", |b| {
+        b.iter(|| VirtioGpuCommand::decode(black_box(&mem), GuestAddress(PAGE_SIZE as u64)).unwrap())
+    });
+
+    group.finish();
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+
+    let no_data = VirtioGpuResponse::OkNoData;
+    group.bench_function("ok_no_data", |b| b.iter(|| black_box(&no_data).encode(1, 1, 0).unwrap()));
+
+    let display_info = VirtioGpuResponse::OkDisplayInfo(vec![(1920, 1080), (3840, 2160)]);
+    group.bench_function("ok_display_info", |b| {
+        b.iter(|| black_box(&display_info).encode(0, 0, 0).unwrap())
+    });
+
+    for size in [1024usize, 64 * 1024].iter() {
+        let capset = VirtioGpuResponse::OkCapset(vec![0xa5; *size]);
+        group.throughput(Throughput::Bytes(*size as u64));
+        group.bench_with_input(BenchmarkId::new("ok_capset", size), size, |b, _| {
+            b.iter(|| black_box(&capset).encode(0, 0, 0).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_sglist(c: &mut Criterion) {
+    let mem = guest_memory();
+    let mut group = c.benchmark_group("sglist_to_rutabaga_iovecs");
+
+    // a 1080p and a 4K framebuffer backed by scattered 4K pages
+    for &(width, height) in [(1920usize, 1080usize), (3840, 2160)].iter() {
+        let pages = width * height * 4 / PAGE_SIZE;
+        let sglist: Vec<(GuestAddress, usize)> = (0..pages)
+            .map(|i| (GuestAddress(((i * 2) % (GUEST_MEM_SIZE / PAGE_SIZE) * PAGE_SIZE) as u64), PAGE_SIZE))
+            .collect();
+        group.throughput(Throughput::Elements(pages as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(format!("{}x{}", width, height)), &sglist, |b, sglist| {
+            b.iter(|| sglist_to_rutabaga_iovecs(black_box(sglist), &mem).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_transfer_2d(c: &mut Criterion) {
+    let mem = guest_memory();
+    let mut rutabaga = RutabagaBuilder::new(RutabagaComponentType::Rutabaga2D)
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("transfer_to_host_2d");
+
+    for (resource_id, &(width, height)) in (1u32..).zip([(1920u32, 1080u32), (3840, 2160)].iter()) {
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width,
+            height,
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+        rutabaga.resource_create_3d(resource_id, resource_create_3d).unwrap();
+        let size = (width * height * 4) as usize;
+        let iovecs = sglist_to_rutabaga_iovecs(&[(GuestAddress(0), size)], &mem).unwrap();
+        rutabaga.attach_backing(resource_id, iovecs).unwrap();
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(format!("{}x{}", width, height)), |b| {
+            b.iter(|| {
+                rutabaga
+                    .transfer_write(0, resource_id, Transfer3D::new_2d(0, 0, width, height))
+                    .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode, bench_encode, bench_sglist, bench_transfer_2d);
+criterion_main!(benches);
@@ -0,0 +1,66 @@
+// Creates a headless device and prints what a guest driver learns about it while probing: the
+// renderer and capsets, the config space, and the display info and EDID responses as they go out
+// on the control queue.
+//
+//     cargo run --example device_info
+
+use gpu_display::GpuDisplay;
+use vhost_gpu_backend::probe::probe_with_display;
+use vhost_gpu_backend::protocol::*;
+use vhost_gpu_backend::virtio_gpu::{GpuMode, GpuParameter};
+use vhost_gpu_backend::{VirtioGpu, VirtioGpuResponseResult};
+use vm_memory::Le32;
+
+fn gpu_parameter() -> GpuParameter {
+    GpuParameter {
+        mode: GpuMode::Mode2D,
+        use_edid: true,
+        deterministic: true,
+        ..Default::default()
+    }
+}
+
+/// Prints the response the control queue would write back for a command of type `type_`.
+fn print_response(name: &str, type_: u32, result: VirtioGpuResponseResult) {
+    let response = match result {
+        Ok(response) | Err(response) => response,
+    };
+    match response.encode(0, 0, 0) {
+        Ok(bytes) => {
+            println!("{} (0x{:x}): {} response bytes", name, type_, bytes.len());
+            for row in bytes.chunks(16).take(8) {
+                let hex: Vec<String> = row.iter().map(|byte| format!("{:02x}", byte)).collect();
+                println!("    {}", hex.join(" "));
+            }
+        }
+        Err(e) => println!("{} (0x{:x}): failed to encode {:?}", name, type_, e),
+    }
+}
+
+fn main() {
+    let report = probe_with_display(gpu_parameter(), GpuDisplay::open_stub).expect("failed to probe the device");
+    println!("{}", report);
+
+    let mut gpu = VirtioGpu::with_display(gpu_parameter(), GpuDisplay::open_stub).expect("failed to create the device");
+    let config = gpu.config();
+    println!(
+        "config: num_scanouts {} num_capsets {}",
+        config.num_scanouts.to_native(),
+        config.num_capsets.to_native()
+    );
+
+    let hdr = virtio_gpu_ctrl_hdr {
+        type_: Le32::from(VIRTIO_GPU_CMD_GET_DISPLAY_INFO),
+        ..Default::default()
+    };
+    print_response("get_display_info", VIRTIO_GPU_CMD_GET_DISPLAY_INFO, gpu.cmd_get_display_info(hdr));
+
+    let get_edid = virtio_gpu_cmd_get_edid {
+        hdr: virtio_gpu_ctrl_hdr {
+            type_: Le32::from(VIRTIO_GPU_CMD_GET_EDID),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    print_response("get_edid", VIRTIO_GPU_CMD_GET_EDID, gpu.cmd_get_edid(get_edid));
+}
@@ -0,0 +1,170 @@
+// Drives a 2D device through the commands a guest driver sends to put a framebuffer on screen,
+// from guest memory backed by a memfd like the one a VMM shares with the backend, and dumps the
+// scanout to a PPM file.
+//
+//     cargo run --example scanout_2d [out.ppm]
+
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::FromRawFd;
+
+use gpu_display::GpuDisplay;
+use vhost_gpu_backend::protocol::*;
+use vhost_gpu_backend::virtio_gpu::{sglist_to_rutabaga_iovecs, BlitFormat, GpuMode, GpuParameter};
+use vhost_gpu_backend::{VirtioGpu, VirtioGpuCommand, VirtioGpuResponseResult};
+use vm_memory::{Bytes, FileOffset, GuestAddress, GuestMemoryMmap, Le32, Le64, VolatileSlice};
+
+const GUEST_MEM_SIZE: usize = 16 << 20;
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 48;
+const RESOURCE_ID: u32 = 1;
+
+// where the script puts the command and the two halves of the framebuffer in guest memory
+const CMD_ADDR: GuestAddress = GuestAddress(0x1000);
+const FB_ADDRS: [GuestAddress; 2] = [GuestAddress(0x10000), GuestAddress(0x40000)];
+
+fn memfd_guest_memory(size: usize) -> io::Result<GuestMemoryMmap> {
+    let name = CString::new("guest-ram").unwrap();
+    // Safe because the name is NUL terminated and the returned fd is checked.
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because the fd was just created and nothing else owns it.
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size as u64)?;
+    GuestMemoryMmap::from_ranges_with_files(&[(GuestAddress(0), size, Some(FileOffset::new(file, 0)))])
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+}
+
+fn hdr(type_: u32) -> virtio_gpu_ctrl_hdr {
+    virtio_gpu_ctrl_hdr {
+        type_: Le32::from(type_),
+        ..Default::default()
+    }
+}
+
+fn rect(width: u32, height: u32) -> virtio_gpu_rect {
+    virtio_gpu_rect {
+        width: Le32::from(width),
+        height: Le32::from(height),
+        ..Default::default()
+    }
+}
+
+/// Decodes the command the script wrote at `CMD_ADDR` and runs it, like the control queue does.
+fn process(gpu: &mut VirtioGpu, mem: &GuestMemoryMmap) -> VirtioGpuResponseResult {
+    use VirtioGpuCommand::*;
+    let command = VirtioGpuCommand::decode(mem, CMD_ADDR).expect("malformed command");
+    match command {
+        CmdGetDisplayInfo(cmd) => gpu.cmd_get_display_info(cmd),
+        CmdResourceCreate2D(cmd) => gpu.cmd_resource_create_2d(cmd),
+        CmdResourceAttachBacking(cmd) => {
+            // the entries follow the command
+            let sglist = command.decode_mem_entries(mem, CMD_ADDR).expect("malformed memory entries");
+            let iovecs = sglist_to_rutabaga_iovecs(&sglist, mem)?;
+            gpu.cmd_resource_attach_backing(cmd, iovecs)
+        }
+        CmdTransferToHost2D(cmd) => gpu.cmd_transfer_to_host_2d(cmd),
+        CmdSetScanout(cmd) => gpu.cmd_set_scanout(cmd),
+        CmdResourceFlush(cmd) => gpu.cmd_flush_resource(cmd),
+        cmd => panic!("{:?} isn't part of the script", cmd),
+    }
+}
+
+fn main() {
+    let out = env::args().nth(1).unwrap_or_else(|| "scanout.ppm".to_string());
+
+    let gpu_parameter = GpuParameter {
+        mode: GpuMode::Mode2D,
+        display_width: WIDTH,
+        display_height: HEIGHT,
+        // the stub display is driven inline, no display thread
+        deterministic: true,
+        ..Default::default()
+    };
+    let mut gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).expect("failed to create the device");
+    let mem = memfd_guest_memory(GUEST_MEM_SIZE).expect("failed to allocate guest memory");
+
+    // the guest draws a gradient, split over two scattered chunks of its memory
+    let stride = WIDTH as usize * 4;
+    let mut framebuffer = Vec::with_capacity(stride * HEIGHT as usize);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            framebuffer.extend_from_slice(&[(x * 255 / WIDTH) as u8, (y * 255 / HEIGHT) as u8, 0x80, 0]);
+        }
+    }
+    let (top, bottom) = framebuffer.split_at(stride * HEIGHT as usize / 2);
+    mem.write_slice(top, FB_ADDRS[0]).unwrap();
+    mem.write_slice(bottom, FB_ADDRS[1]).unwrap();
+
+    mem.write_obj(hdr(VIRTIO_GPU_CMD_GET_DISPLAY_INFO), CMD_ADDR).unwrap();
+    println!("get_display_info: {:?}", process(&mut gpu, &mem));
+
+    let create = virtio_gpu_resource_create_2d {
+        hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
+        resource_id: Le32::from(RESOURCE_ID),
+        format: Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM),
+        width: Le32::from(WIDTH),
+        height: Le32::from(HEIGHT),
+    };
+    mem.write_obj(create, CMD_ADDR).unwrap();
+    println!("resource_create_2d: {:?}", process(&mut gpu, &mem));
+
+    let attach = virtio_gpu_resource_attach_backing {
+        hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+        resource_id: Le32::from(RESOURCE_ID),
+        nr_entries: Le32::from(FB_ADDRS.len() as u32),
+    };
+    mem.write_obj(attach, CMD_ADDR).unwrap();
+    let mut entry_addr = GuestAddress(CMD_ADDR.0 + size_of::<virtio_gpu_resource_attach_backing>() as u64);
+    for (&addr, chunk) in FB_ADDRS.iter().zip([top, bottom].iter()) {
+        let entry = virtio_gpu_mem_entry {
+            addr: Le64::from(addr.0),
+            length: Le32::from(chunk.len() as u32),
+            ..Default::default()
+        };
+        mem.write_obj(entry, entry_addr).unwrap();
+        entry_addr = GuestAddress(entry_addr.0 + size_of::<virtio_gpu_mem_entry>() as u64);
+    }
+    println!("resource_attach_backing: {:?}", process(&mut gpu, &mem));
+
+    let transfer = virtio_gpu_transfer_to_host_2d {
+        hdr: hdr(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+        r: rect(WIDTH, HEIGHT),
+        resource_id: Le32::from(RESOURCE_ID),
+        ..Default::default()
+    };
+    mem.write_obj(transfer, CMD_ADDR).unwrap();
+    println!("transfer_to_host_2d: {:?}", process(&mut gpu, &mem));
+
+    let set_scanout = virtio_gpu_set_scanout {
+        hdr: hdr(VIRTIO_GPU_CMD_SET_SCANOUT),
+        r: rect(WIDTH, HEIGHT),
+        scanout_id: Le32::from(0),
+        resource_id: Le32::from(RESOURCE_ID),
+    };
+    mem.write_obj(set_scanout, CMD_ADDR).unwrap();
+    println!("set_scanout: {:?}", process(&mut gpu, &mem));
+
+    let flush = virtio_gpu_resource_flush {
+        hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_FLUSH),
+        r: rect(WIDTH, HEIGHT),
+        resource_id: Le32::from(RESOURCE_ID),
+        ..Default::default()
+    };
+    mem.write_obj(flush, CMD_ADDR).unwrap();
+    println!("resource_flush: {:?}", process(&mut gpu, &mem));
+
+    let mut pixels = vec![0u8; (WIDTH * HEIGHT * 3) as usize];
+    let (width, height) = gpu
+        .blit_scanout(VolatileSlice::from(&mut pixels[..]), WIDTH * 3, BlitFormat::Rgb)
+        .expect("failed to read back the scanout");
+    let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    ppm.extend_from_slice(&pixels);
+    fs::write(&out, ppm).expect("failed to write the scanout");
+    println!("wrote the {}x{} scanout to {}", width, height, out);
+}
@@ -0,0 +1,160 @@
+#![no_main]
+use gpu_display::GpuDisplay;
+use libfuzzer_sys::arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use vhost_gpu_backend::protocol::*;
+use vhost_gpu_backend::virtio_gpu::{sglist_to_rutabaga_iovecs, GpuMode, GpuParameter};
+use vhost_gpu_backend::VirtioGpu;
+use vm_memory::{GuestAddress, GuestMemoryMmap, Le32, Le64};
+
+const GUEST_MEM_SIZE: usize = 16 << 20;
+
+#[derive(Arbitrary, Debug, Clone, Copy)]
+struct Rect {
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+}
+
+impl From<Rect> for virtio_gpu_rect {
+    fn from(r: Rect) -> Self {
+        virtio_gpu_rect {
+            x: Le32::from(r.x as u32),
+            y: Le32::from(r.y as u32),
+            width: Le32::from(r.width as u32),
+            height: Le32::from(r.height as u32),
+        }
+    }
+}
+
+/// Commands the guest can send in any order, with small resource ids so they hit each other.
+#[derive(Arbitrary, Debug)]
+enum Op {
+    // sizes in units of 8 pixels, keeping the host allocation of a 2D resource bounded
+    Create2d { resource_id: u8, format: u8, width: u8, height: u8 },
+    Unref { resource_id: u8 },
+    AttachBacking { resource_id: u8, entries: Vec<(u32, u32)> },
+    DetachBacking { resource_id: u8 },
+    TransferToHost2d { resource_id: u8, r: Rect, offset: u32 },
+    SetScanout { resource_id: u8, scanout_id: u8, r: Rect },
+    Flush { resource_id: u8, r: Rect },
+    UpdateCursor { resource_id: u8, x: u16, y: u16 },
+    MoveCursor { x: u16, y: u16 },
+    GetEdid { scanout: u8 },
+    AssignUuid { resource_id: u8 },
+}
+
+fn hdr(type_: u32) -> virtio_gpu_ctrl_hdr {
+    virtio_gpu_ctrl_hdr {
+        type_: Le32::from(type_),
+        ..Default::default()
+    }
+}
+
+fn cursor(type_: u32, resource_id: u8, x: u16, y: u16) -> virtio_gpu_update_cursor {
+    let mut cmd = virtio_gpu_update_cursor {
+        hdr: hdr(type_),
+        resource_id: Le32::from(resource_id as u32),
+        ..Default::default()
+    };
+    cmd.pos.x = Le32::from(x as u32);
+    cmd.pos.y = Le32::from(y as u32);
+    cmd
+}
+
+fn run(gpu: &mut VirtioGpu, mem: &GuestMemoryMmap, op: Op) {
+    // only crashes matter here, error responses are what a misbehaving guest gets back
+    let _ = match op {
+        Op::Create2d { resource_id, format, width, height } => {
+            gpu.cmd_resource_create_2d(virtio_gpu_resource_create_2d {
+                hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
+                resource_id: Le32::from(resource_id as u32),
+                format: Le32::from(format as u32),
+                width: Le32::from(width as u32 * 8),
+                height: Le32::from(height as u32 * 8),
+            })
+        }
+        Op::Unref { resource_id } => gpu.cmd_resource_unref(virtio_gpu_resource_unref {
+            hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_UNREF),
+            resource_id: Le32::from(resource_id as u32),
+            ..Default::default()
+        }),
+        Op::AttachBacking { resource_id, entries } => {
+            let sglist: Vec<(GuestAddress, usize)> = entries
+                .iter()
+                .map(|&(addr, len)| (GuestAddress(addr as u64), len as usize))
+                .collect();
+            match sglist_to_rutabaga_iovecs(&sglist, mem) {
+                Ok(iovecs) => gpu.cmd_resource_attach_backing(
+                    virtio_gpu_resource_attach_backing {
+                        hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+                        resource_id: Le32::from(resource_id as u32),
+                        nr_entries: Le32::from(entries.len() as u32),
+                    },
+                    iovecs,
+                ),
+                Err(e) => Err(e),
+            }
+        }
+        Op::DetachBacking { resource_id } => {
+            gpu.cmd_resource_detach_backing(virtio_gpu_resource_detach_backing {
+                hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING),
+                resource_id: Le32::from(resource_id as u32),
+                ..Default::default()
+            })
+        }
+        Op::TransferToHost2d { resource_id, r, offset } => {
+            gpu.cmd_transfer_to_host_2d(virtio_gpu_transfer_to_host_2d {
+                hdr: hdr(VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D),
+                r: r.into(),
+                offset: Le64::from(offset as u64),
+                resource_id: Le32::from(resource_id as u32),
+                ..Default::default()
+            })
+        }
+        Op::SetScanout { resource_id, scanout_id, r } => gpu.cmd_set_scanout(virtio_gpu_set_scanout {
+            hdr: hdr(VIRTIO_GPU_CMD_SET_SCANOUT),
+            r: r.into(),
+            scanout_id: Le32::from(scanout_id as u32),
+            resource_id: Le32::from(resource_id as u32),
+        }),
+        Op::Flush { resource_id, r } => gpu.cmd_flush_resource(virtio_gpu_resource_flush {
+            hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_FLUSH),
+            r: r.into(),
+            resource_id: Le32::from(resource_id as u32),
+            ..Default::default()
+        }),
+        Op::UpdateCursor { resource_id, x, y } => {
+            gpu.cmd_update_cursor(cursor(VIRTIO_GPU_CMD_UPDATE_CURSOR, resource_id, x, y))
+        }
+        Op::MoveCursor { x, y } => gpu.cmd_move_curosr(cursor(VIRTIO_GPU_CMD_MOVE_CURSOR, 0, x, y)),
+        Op::GetEdid { scanout } => gpu.cmd_get_edid(virtio_gpu_cmd_get_edid {
+            hdr: hdr(VIRTIO_GPU_CMD_GET_EDID),
+            scanout: Le32::from(scanout as u32),
+            ..Default::default()
+        }),
+        Op::AssignUuid { resource_id } => {
+            gpu.cmd_resource_assign_uuid(virtio_gpu_resource_assign_uuid {
+                hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_ASSIGN_UUID),
+                resource_id: Le32::from(resource_id as u32),
+                ..Default::default()
+            })
+        }
+    };
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), GUEST_MEM_SIZE)]).unwrap();
+    let gpu_parameter = GpuParameter {
+        mode: GpuMode::Mode2D,
+        // reproducible runs: no display thread, timers or deferred transfers
+        deterministic: true,
+        ..Default::default()
+    };
+    let mut gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+    for op in ops {
+        run(&mut gpu, &mem, op);
+    }
+});
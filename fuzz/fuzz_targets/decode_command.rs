@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use vhost_gpu_backend::VirtioGpuCommand;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(cmd) = VirtioGpuCommand::decode_from_slice(data) {
+        // a decoded command never claims more bytes than the guest provided
+        assert!(cmd.size() <= data.len());
+    }
+});
@@ -0,0 +1,55 @@
+#![no_main]
+use libfuzzer_sys::arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use vhost_gpu_backend::protocol::*;
+use vhost_gpu_backend::{VirtioGpuCommand, VirtioGpuResponse};
+use vm_memory::ByteValued;
+
+#[derive(Arbitrary, Debug)]
+enum Response {
+    OkNoData,
+    OkDisplayInfo(Vec<(u32, u32)>),
+    OkCapsetInfo { capset_id: u32, version: u32, size: u32 },
+    OkCapset(Vec<u8>),
+    OkResourceUuid { uuid: [u8; 16] },
+    ErrUnspec,
+    ErrInvalidResourceId,
+}
+
+impl From<Response> for VirtioGpuResponse {
+    fn from(resp: Response) -> Self {
+        match resp {
+            Response::OkNoData => VirtioGpuResponse::OkNoData,
+            Response::OkDisplayInfo(modes) => VirtioGpuResponse::OkDisplayInfo(modes),
+            Response::OkCapsetInfo { capset_id, version, size } => {
+                VirtioGpuResponse::OkCapsetInfo { capset_id, version, size }
+            }
+            Response::OkCapset(data) => VirtioGpuResponse::OkCapset(data),
+            Response::OkResourceUuid { uuid } => VirtioGpuResponse::OkResourceUuid { uuid },
+            Response::ErrUnspec => VirtioGpuResponse::ErrUnspec,
+            Response::ErrInvalidResourceId => VirtioGpuResponse::ErrInvalidResourceId,
+        }
+    }
+}
+
+fuzz_target!(|input: (Response, u32, u64, u32)| {
+    let (resp, flags, fence_id, ctx_id) = input;
+    let resp = VirtioGpuResponse::from(resp);
+    let encoded = match resp.encode(flags, fence_id, ctx_id) {
+        Ok(encoded) => encoded,
+        Err(VirtioGpuResponse::TooManyScanout(_)) => return,
+        Err(e) => panic!("unexpected encode error: {:?}", e),
+    };
+
+    // every response starts with the control header it was encoded with
+    let mut hdr = virtio_gpu_ctrl_hdr::default();
+    hdr.as_mut_slice()
+        .copy_from_slice(&encoded[..std::mem::size_of::<virtio_gpu_ctrl_hdr>()]);
+    assert_eq!(hdr.type_.to_native(), resp.get_resp_command_const());
+    assert_eq!(hdr.flags.to_native(), flags);
+    assert_eq!(hdr.fence_id.to_native(), fence_id);
+    assert_eq!(hdr.ctx_id.to_native(), ctx_id);
+
+    // the header of a response never decodes as a command
+    assert!(VirtioGpuCommand::decode_from_slice(&encoded).is_err());
+});
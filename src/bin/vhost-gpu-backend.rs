@@ -0,0 +1,293 @@
+// The vhost-user GPU daemon: builds the device from the command line and serves a VMM on a socket.
+//
+//     vhost-gpu-backend --socket-path /tmp/gpu.sock --mode 2d --width 1280 --height 720
+
+use std::env;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use gpu_display::{FrameDumpFormat, GpuDisplay, GpuDisplayError};
+use vhost_gpu_backend::daemon::serve;
+use vhost_gpu_backend::privileges::Privileges;
+use vhost_gpu_backend::probe::probe_with_display;
+use vhost_gpu_backend::recorder::FrameRecorder;
+use vhost_gpu_backend::vhost::GpuBackend;
+use vhost_gpu_backend::virtio_gpu::{DisplayBackend, GpuMode, GpuParameter};
+use vhost_gpu_backend::VirtioGpu;
+
+const USAGE: &str = "\
+usage: vhost-gpu-backend --socket-path PATH [options]
+
+options:
+    --socket-path PATH      vhost-user socket to listen on
+    --reconnect             keep the device for the next VMM connecting after one disconnects
+    --forward-scanout       have the VMM scan out the resources exported as a dmabuf
+    --uid N                 user to switch to once the socket, render node and display are open,
+                            needs --gid
+    --gid N                 group to switch to, the supplementary groups are dropped
+    --chroot DIR            directory to confine the daemon to, frames are dumped in it
+    --width N               width of the scanout
+    --height N              height of the scanout
+    --mode 2d|3d            renderer, 3d needs virglrenderer (default 3d)
+    --display auto|x|wayland|winit|none|dump:DIR
+                            where the scanout is shown: the Wayland compositor or X server the
+                            environment names, nowhere without either (default), the X server,
+                            the Wayland compositor, a window on either, nowhere, or one PPM file
+                            per frame in DIR
+    --vm-name NAME          name of the VM, shown in the window title
+    --vsync                 flip the scanout on the vertical blank of the display
+    --record FILE           record the presented frames in FILE as a Y4M video, each one read
+                            back and converted on the control queue, which slows the guest down
+    --record-every N        only record every Nth frame (default 1)
+    --no-egl                don't let virglrenderer use EGL
+    --no-gles               don't let virglrenderer use GLES
+    --no-glx                don't let virglrenderer use GLX
+    --no-surfaceless        don't let virglrenderer use surfaceless EGL
+    --async-fences          retire fences on virglrenderer's sync thread
+    --no-blob               don't offer blob resources
+    --no-edid               don't offer EDIDs
+    --check                 print what the renderer and display support and exit
+    --help                  print this message";
+
+#[derive(Clone, Debug, PartialEq)]
+enum DisplayOption {
+    /// the `display_backend` of the parameters
+    Backend,
+    Dump(PathBuf),
+}
+
+impl DisplayOption {
+    fn open(&self, gpu_parameter: &GpuParameter) -> impl FnMut() -> Result<GpuDisplay, GpuDisplayError> + Send + 'static {
+        let display = self.clone();
+        let mut open_backend = gpu_parameter.display_opener();
+        move || match display {
+            DisplayOption::Backend => open_backend(),
+            DisplayOption::Dump(ref directory) => GpuDisplay::open_dump(directory, FrameDumpFormat::Ppm, true),
+        }
+    }
+}
+
+struct Options {
+    socket_path:     Option<PathBuf>,
+    gpu_parameter:   GpuParameter,
+    display:         DisplayOption,
+    reconnect:       bool,
+    forward_scanout: bool,
+    privileges:      Privileges,
+    /// Y4M file the frames are recorded in
+    record:          Option<PathBuf>,
+    record_every:    NonZeroU32,
+    check:           bool,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut options = Options {
+        socket_path:     None,
+        gpu_parameter:   GpuParameter::default(),
+        display:         DisplayOption::Backend,
+        reconnect:       false,
+        forward_scanout: false,
+        privileges:      Privileges::default(),
+        record:          None,
+        record_every:    NonZeroU32::new(1).unwrap(),
+        check:           false,
+    };
+    let parameter = &mut options.gpu_parameter;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--socket-path" => options.socket_path = Some(PathBuf::from(value()?)),
+            "--width" => parameter.display_width = value()?.parse().map_err(|_| "invalid --width".to_string())?,
+            "--height" => parameter.display_height = value()?.parse().map_err(|_| "invalid --height".to_string())?,
+            "--mode" => {
+                parameter.mode = match value()?.as_str() {
+                    "2d" => GpuMode::Mode2D,
+                    "3d" => GpuMode::Mode3D,
+                    mode => return Err(format!("unknown mode {}", mode)),
+                }
+            }
+            "--display" => {
+                let display = value()?;
+                options.display = DisplayOption::Backend;
+                parameter.display_backend = match display.as_str() {
+                    "auto" => DisplayBackend::Auto,
+                    "x" => DisplayBackend::X,
+                    "wayland" => DisplayBackend::Wayland,
+                    "winit" => DisplayBackend::Winit,
+                    "none" => DisplayBackend::Headless,
+                    _ if display.starts_with("dump:") => {
+                        options.display = DisplayOption::Dump(PathBuf::from(&display[5..]));
+                        parameter.display_backend
+                    }
+                    _ => return Err(format!("unknown display {}", display)),
+                }
+            }
+            "--vm-name" => parameter.vm_name = Some(value()?),
+            "--vsync" => parameter.vsync = true,
+            "--record" => options.record = Some(PathBuf::from(value()?)),
+            "--record-every" => {
+                options.record_every = value()?.parse().map_err(|_| "invalid --record-every".to_string())?
+            }
+            "--no-egl" => parameter.renderer_use_egl = false,
+            "--no-gles" => parameter.renderer_use_gles = false,
+            "--no-glx" => parameter.renderer_use_glx = false,
+            "--no-surfaceless" => parameter.renderer_use_surfaceless = false,
+            "--async-fences" => parameter.renderer_async_fences = true,
+            "--no-blob" => parameter.use_resource_blob = false,
+            "--no-edid" => parameter.use_edid = false,
+            "--reconnect" => options.reconnect = true,
+            "--forward-scanout" => options.forward_scanout = true,
+            "--uid" => options.privileges.uid = Some(value()?.parse().map_err(|_| "invalid --uid".to_string())?),
+            "--gid" => options.privileges.gid = Some(value()?.parse().map_err(|_| "invalid --gid".to_string())?),
+            "--chroot" => options.privileges.chroot = Some(PathBuf::from(value()?)),
+            "--check" => options.check = true,
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    if options.socket_path.is_none() && !options.check {
+        return Err("--socket-path is required".to_string());
+    }
+    // the root group would be kept
+    if options.privileges.uid.is_some() && options.privileges.gid.is_none() {
+        return Err("--uid needs --gid".to_string());
+    }
+    Ok(options)
+}
+
+fn main() {
+    if env::args().any(|arg| arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if options.check {
+        let open_display = options.display.open(&options.gpu_parameter);
+        match probe_with_display(options.gpu_parameter, open_display) {
+            Ok(report) => print!("{}", report),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let open_display = options.display.open(&options.gpu_parameter);
+    let mut gpu = match VirtioGpu::with_display(options.gpu_parameter, open_display) {
+        Ok(gpu) => gpu,
+        Err(e) => {
+            eprintln!("failed to create the device: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(ref path) = options.record {
+        // created before the privileges are dropped, the chroot needn't contain it
+        match FrameRecorder::create(path) {
+            Ok(recorder) => gpu = gpu.with_frame_recorder(recorder.every(options.record_every)),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+    let mut backend = GpuBackend::new(gpu);
+    if options.forward_scanout {
+        backend = match backend.with_scanout_forwarding() {
+            Ok(backend) => backend,
+            Err(e) => {
+                eprintln!("failed to forward the scanout: {}", e);
+                process::exit(1);
+            }
+        };
+    }
+    let backend = Arc::new(Mutex::new(backend));
+    let socket_path = options.socket_path.unwrap();
+    let served = serve(&socket_path, backend.clone(), options.reconnect, &options.privileges);
+    let mut failed = false;
+    if let Err(e) = served {
+        eprintln!("{}: {}", socket_path.display(), e);
+        failed = true;
+    }
+    let recorder = backend.lock().unwrap().gpu().take_frame_recorder();
+    if let (Some(recorder), Some(path)) = (recorder, options.record) {
+        if let Err(e) = recorder.finish() {
+            eprintln!("{}: {}", path.display(), e);
+            failed = true;
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> impl Iterator<Item = String> {
+        args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>().into_iter()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(args(&[
+            "--socket-path", "/tmp/gpu.sock", "--mode", "2d", "--width", "640", "--display", "dump:/tmp/frames",
+            "--no-glx",
+        ]))
+        .unwrap();
+        assert_eq!(options.socket_path, Some(PathBuf::from("/tmp/gpu.sock")));
+        assert_eq!(options.gpu_parameter.mode, GpuMode::Mode2D);
+        assert_eq!(options.gpu_parameter.display_width, 640);
+        assert!(!options.gpu_parameter.renderer_use_glx);
+        assert_eq!(options.display, DisplayOption::Dump(PathBuf::from("/tmp/frames")));
+        let options = parse_args(args(&["--socket-path", "s", "--display", "wayland"])).unwrap();
+        assert_eq!(options.display, DisplayOption::Backend);
+        assert_eq!(options.gpu_parameter.display_backend, DisplayBackend::Wayland);
+        let options = parse_args(args(&["--socket-path", "s"])).unwrap();
+        assert_eq!(options.gpu_parameter.display_backend, DisplayBackend::Auto);
+        assert_eq!(options.gpu_parameter.vm_name, None);
+        let options = parse_args(args(&["--socket-path", "s", "--display", "winit", "--vm-name", "guest"])).unwrap();
+        assert_eq!(options.gpu_parameter.display_backend, DisplayBackend::Winit);
+        assert_eq!(options.gpu_parameter.vm_name.as_deref(), Some("guest"));
+        assert!(!options.gpu_parameter.vsync);
+        assert!(parse_args(args(&["--socket-path", "s", "--vsync"])).unwrap().gpu_parameter.vsync);
+        assert_eq!(options.record, None);
+        let options = parse_args(args(&["--socket-path", "s", "--record", "/tmp/guest.y4m", "--record-every", "3"])).unwrap();
+        assert_eq!(options.record, Some(PathBuf::from("/tmp/guest.y4m")));
+        assert_eq!(options.record_every.get(), 3);
+        assert!(parse_args(args(&["--socket-path", "s", "--record-every", "0"])).is_err());
+        assert!(!options.check);
+        assert!(!options.reconnect);
+        assert!(!options.forward_scanout);
+        assert!(parse_args(args(&["--socket-path", "s", "--reconnect"])).unwrap().reconnect);
+        assert!(parse_args(args(&["--socket-path", "s", "--forward-scanout"])).unwrap().forward_scanout);
+        assert!(options.privileges.is_empty());
+        let options = parse_args(args(&["--socket-path", "s", "--uid", "1000", "--gid", "100", "--chroot", "/var/empty"])).unwrap();
+        assert_eq!(
+            options.privileges,
+            Privileges {
+                uid:    Some(1000),
+                gid:    Some(100),
+                chroot: Some(PathBuf::from("/var/empty")),
+            }
+        );
+        assert!(parse_args(args(&["--socket-path", "s", "--uid", "nobody"])).is_err());
+        assert!(parse_args(args(&["--socket-path", "s", "--uid", "1000"])).is_err());
+        assert!(parse_args(args(&["--socket-path", "s", "--gid", "100"])).is_ok());
+
+        // probing needs no socket
+        assert!(parse_args(args(&["--check", "--display", "none"])).unwrap().check);
+        assert!(parse_args(args(&["--mode", "2d"])).is_err());
+        assert!(parse_args(args(&["--socket-path"])).is_err());
+        assert!(parse_args(args(&["--socket-path", "s", "--mode", "4d"])).is_err());
+    }
+}
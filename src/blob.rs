@@ -0,0 +1,40 @@
+// Host visible blob resources are mapped by the guest through a virtio shared memory region, the
+// host visible region, that the VMM exposes.  RESOURCE_MAP_BLOB asks for the blob at an offset of
+// the region, so the device hands the blob's memory to a `SharedMemoryMapper` the VMM provides.
+
+use std::io;
+
+use base::ExternalMapping;
+use rutabaga_gfx::RutabagaHandle;
+
+/// Id of the host visible shared memory region.
+pub const VIRTIO_GPU_SHM_ID_HOST_VISIBLE: u8 = 1;
+
+/// The memory of a blob resource, as the renderer exports it.
+pub enum BlobMemory {
+    /// A descriptor the VMM can mmap, e.g. a dmabuf.
+    Handle(RutabagaHandle),
+    /// Memory the renderer mapped in this process, for a VMM running the device in process.
+    /// Unmapped when dropped.
+    Mapping(ExternalMapping),
+}
+
+/// Places blob memory in the host visible region of the guest.
+pub trait SharedMemoryMapper {
+    /// Size of the region, mappings have to fit in it.
+    fn region_size(&self) -> u64;
+
+    /// Maps the first `size` bytes of `memory` at `offset` of the region.
+    fn add_mapping(&mut self, memory: &BlobMemory, offset: u64, size: u64) -> io::Result<()>;
+
+    /// Removes the mapping added at `offset`.
+    fn remove_mapping(&mut self, offset: u64) -> io::Result<()>;
+}
+
+/// A blob mapped in the host visible region.
+pub(crate) struct BlobMapping {
+    pub offset: u64,
+    /// Kept until the blob is unmapped, an in process mapping goes away with it
+    #[allow(dead_code)]
+    pub memory: BlobMemory,
+}
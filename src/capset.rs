@@ -0,0 +1,98 @@
+// Masks applied to the capset blobs before the guest reads them, so that a fleet of hosts with
+// different GPUs can present the same lowest common denominator to guests that migrate between
+// them.  A mask only ever clears bits, a host can't advertise something its renderer lacks.
+
+use crate::protocol::{VIRTIO_GPU_CAPSET_VIRGL, VIRTIO_GPU_CAPSET_VIRGL2};
+
+// Layout of struct virgl_caps_v1, which virgl_caps_v2 starts with, in dwords: max_version, then
+// the sampler, render, depth/stencil and vertex buffer format masks of 16 dwords each, then the
+// boolean capabilities.
+const FORMAT_MASK_DWORDS: usize = 16;
+const SAMPLER_FORMATS: usize = 1;
+const RENDER_FORMATS: usize = SAMPLER_FORMATS + FORMAT_MASK_DWORDS;
+const DEPTHSTENCIL_FORMATS: usize = RENDER_FORMATS + FORMAT_MASK_DWORDS;
+const VERTEXBUFFER_FORMATS: usize = DEPTHSTENCIL_FORMATS + FORMAT_MASK_DWORDS;
+const BOOL_CAPS: usize = VERTEXBUFFER_FORMATS + FORMAT_MASK_DWORDS;
+
+const VIRGL_CAPSETS: [u32; 2] = [VIRTIO_GPU_CAPSET_VIRGL, VIRTIO_GPU_CAPSET_VIRGL2];
+
+/// Clears the `clear` bits of the little endian dword `dword` of capset `capset_id`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CapsetMask {
+    pub capset_id: u32,
+    pub dword:     usize,
+    pub clear:     u32,
+}
+
+impl CapsetMask {
+    pub fn new(capset_id: u32, dword: usize, clear: u32) -> Self {
+        CapsetMask { capset_id, dword, clear }
+    }
+
+    /// Hides the virgl format `format` from sampling, rendering, depth/stencil and vertex
+    /// buffers in both virgl capsets.
+    pub fn hide_format(format: u32) -> Vec<CapsetMask> {
+        let (index, bit) = (format as usize / 32, 1 << (format % 32));
+        if index >= FORMAT_MASK_DWORDS {
+            return Vec::new();
+        }
+        let mut masks = Vec::new();
+        for &capset_id in &VIRGL_CAPSETS {
+            for &formats in &[SAMPLER_FORMATS, RENDER_FORMATS, DEPTHSTENCIL_FORMATS, VERTEXBUFFER_FORMATS] {
+                masks.push(CapsetMask::new(capset_id, formats + index, bit));
+            }
+        }
+        masks
+    }
+
+    /// Clears the `clear` bits of the boolean capabilities of both virgl capsets, the bits of
+    /// union virgl_caps_bool_set1 in virgl_hw.h.
+    pub fn hide_bool_caps(clear: u32) -> Vec<CapsetMask> {
+        VIRGL_CAPSETS
+            .iter()
+            .map(|&capset_id| CapsetMask::new(capset_id, BOOL_CAPS, clear))
+            .collect()
+    }
+}
+
+/// Applies the `masks` of `capset_id` to its blob, dwords past the end of the blob are left alone.
+pub fn apply_capset_masks(capset_id: u32, capset: &mut [u8], masks: &[CapsetMask]) {
+    for mask in masks.iter().filter(|mask| mask.capset_id == capset_id) {
+        if let Some(bytes) = capset.chunks_exact_mut(4).nth(mask.dword) {
+            let mut value = [0u8; 4];
+            value.copy_from_slice(bytes);
+            bytes.copy_from_slice(&(u32::from_le_bytes(value) & !mask.clear).to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::capset::*;
+
+    #[test]
+    fn test_capset_masks() {
+        let masks = CapsetMask::hide_format(33);
+        assert_eq!(masks.len(), 8);
+        assert!(CapsetMask::hide_format(16 * 32).is_empty());
+
+        let mut capset = vec![0xffu8; (BOOL_CAPS + 1) * 4];
+        apply_capset_masks(VIRTIO_GPU_CAPSET_VIRGL2, &mut capset, &masks);
+        // format 33 is bit 1 of the second dword of each format mask
+        for &formats in &[SAMPLER_FORMATS, RENDER_FORMATS, DEPTHSTENCIL_FORMATS, VERTEXBUFFER_FORMATS] {
+            let offset = (formats + 1) * 4;
+            assert_eq!(capset[offset..offset + 4], [0xfd, 0xff, 0xff, 0xff]);
+        }
+        assert_eq!(capset.iter().filter(|&&byte| byte != 0xff).count(), 4);
+
+        // other capsets are left alone, and so is what is past the blob
+        let mut other = vec![0xffu8; 8];
+        apply_capset_masks(3, &mut other, &CapsetMask::hide_bool_caps(!0));
+        assert_eq!(other, vec![0xffu8; 8]);
+        apply_capset_masks(VIRTIO_GPU_CAPSET_VIRGL, &mut other, &CapsetMask::hide_bool_caps(!0));
+        assert_eq!(other, vec![0xffu8; 8]);
+
+        apply_capset_masks(VIRTIO_GPU_CAPSET_VIRGL, &mut capset, &CapsetMask::hide_bool_caps(0x5));
+        assert_eq!(capset[BOOL_CAPS * 4..], [0xfa, 0xff, 0xff, 0xff]);
+    }
+}
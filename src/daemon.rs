@@ -0,0 +1,109 @@
+// The vhost-user daemon loop: listens on the socket, accepts the frontend and handles its messages
+// along with the queues' kicks and the device's own events, all on the thread that created the
+// `VirtioGpu`.  With reconnection, the device outlives the frontend: the next one connecting to the
+// socket picks up the guest's resources and contexts where the previous one left them.
+//
+// The daemon drops its privileges once it listens on the socket, before any frontend connects.
+
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use ::vhost::vhost_user::{BackendListener, BackendReqHandler, Error as VhostUserError, Listener};
+
+use crate::event_loop::{device_sources, EventLoop, EventToken};
+use crate::privileges::Privileges;
+use crate::queue::signal_error;
+use crate::vhost::{GpuBackend, CONTROL_QUEUE, CURSOR_QUEUE};
+
+/// How long the fences in flight get to signal when the daemon exits.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn vhost_user_error(e: VhostUserError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// Serves the frontend connecting to `socket_path` until it disconnects, or keeps serving the
+/// frontends connecting after it with `reconnect`.  A stale socket file is replaced, then the
+/// daemon switches to `privileges`.  The caller keeps `backend` to take what it needs back from
+/// the device once this returns.
+pub fn serve<P: AsRef<Path>>(
+    socket_path: P,
+    backend: Arc<Mutex<GpuBackend>>,
+    reconnect: bool,
+    privileges: &Privileges,
+) -> io::Result<()> {
+    let listener = Listener::new(socket_path, true).map_err(vhost_user_error)?;
+    let mut listener = BackendListener::new(listener, backend.clone()).map_err(vhost_user_error)?;
+    privileges.drop_privileges()?;
+    loop {
+        // the listener blocks until a frontend connects
+        let mut handler = loop {
+            if let Some(handler) = listener.accept().map_err(vhost_user_error)? {
+                break handler;
+            }
+        };
+        serve_frontend(&mut handler, &backend)?;
+        if !reconnect {
+            backend.lock().unwrap().shutdown(SHUTDOWN_TIMEOUT);
+            return Ok(());
+        }
+        backend.lock().unwrap().disconnect();
+    }
+}
+
+/// Handles the messages of a connected frontend, the kicks and the device's events until the
+/// frontend disconnects.
+fn serve_frontend(handler: &mut BackendReqHandler<Mutex<GpuBackend>>, backend: &Mutex<GpuBackend>) -> io::Result<()> {
+    let mut event_loop = EventLoop::new()?;
+    loop {
+        {
+            let mut backend = backend.lock().unwrap();
+            let mut wanted = vec![(EventToken::Frontend, handler.as_raw_fd())];
+            for &index in &[CURSOR_QUEUE, CONTROL_QUEUE] {
+                let vring = backend.vring(index);
+                if let Some(kick) = vring.kick.as_ref().filter(|_| vring.ready()) {
+                    wanted.push((EventToken::Kick(index), kick.as_raw_fd()));
+                }
+            }
+            wanted.extend(device_sources(backend.gpu().event_sources()));
+            event_loop.watch(&wanted)?;
+        }
+        let events = event_loop.wait(None)?;
+
+        if events.contains(&EventToken::Frontend) {
+            match handler.handle_request() {
+                Ok(()) => {}
+                Err(VhostUserError::Disconnected) | Err(VhostUserError::PartialMessage) => return Ok(()),
+                Err(e) => return Err(vhost_user_error(e)),
+            }
+        }
+        let mut backend = backend.lock().unwrap();
+        for &event in &events {
+            if let EventToken::Device(source) = event {
+                backend.gpu().handle_event(source);
+            }
+        }
+        // the cursor queue comes first, so the pointer moves before a long control queue drain
+        for &index in &[CURSOR_QUEUE, CONTROL_QUEUE] {
+            if !events.contains(&EventToken::Kick(index)) {
+                continue;
+            }
+            // the kick may have been replaced while handling the request, a blocking read of the
+            // new one would hang
+            let kick_fd = event_loop.fd(EventToken::Kick(index));
+            if let Some(kick) = backend.vring(index).kick.as_ref().filter(|kick| Some(kick.as_raw_fd()) == kick_fd) {
+                let _ = kick.read();
+            }
+            if backend.process_queue(index).is_err() {
+                signal_error(backend.vring(index));
+            }
+        }
+        if let Err((index, _)) = backend.process_fences() {
+            signal_error(backend.vring(index));
+        }
+        backend.notify_config_changes()?;
+    }
+}
@@ -0,0 +1,139 @@
+// Accumulates the areas of a scanout the guest changed between two flushes, so that only those
+// are read back from the renderer and sent to the display.  Touching or overlapping rects are
+// merged, a text console writing glyph after glyph ends up with a few line sized rects.
+
+/// `(x, y, width, height)` of a damaged area.
+pub type DamageRect = (u32, u32, u32, u32);
+
+/// Past this many disjoint rects the damage collapses to their bounding box, reading a bit more
+/// back is cheaper than many small transfers.
+const MAX_DAMAGE_RECTS: usize = 16;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DamageTracker {
+    rects: Vec<DamageRect>,
+    /// Everything must be read back, e.g. because the scanout changed
+    full:  bool,
+}
+
+/// The bounding box of `a` and `b` when they overlap or share an edge.
+fn merge(a: DamageRect, b: DamageRect) -> Option<DamageRect> {
+    let (ax1, ay1) = (a.0 as u64 + a.2 as u64, a.1 as u64 + a.3 as u64);
+    let (bx1, by1) = (b.0 as u64 + b.2 as u64, b.1 as u64 + b.3 as u64);
+    if (a.0 as u64) > bx1 || (b.0 as u64) > ax1 || (a.1 as u64) > by1 || (b.1 as u64) > ay1 {
+        return None;
+    }
+    Some(bounding_box(a, b))
+}
+
+/// The smallest rect containing `a` and `b`.
+pub fn bounding_box(a: DamageRect, b: DamageRect) -> DamageRect {
+    let x = a.0.min(b.0);
+    let y = a.1.min(b.1);
+    let x1 = (a.0 as u64 + a.2 as u64).max(b.0 as u64 + b.2 as u64);
+    let y1 = (a.1 as u64 + a.3 as u64).max(b.1 as u64 + b.3 as u64);
+    (x, y, (x1 - x as u64) as u32, (y1 - y as u64) as u32)
+}
+
+impl DamageTracker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Adds `rect`, clipped to a `width`x`height` scanout.
+    pub fn add(&mut self, rect: DamageRect, width: u32, height: u32) {
+        if self.full {
+            return;
+        }
+        let (x, y) = (rect.0.min(width), rect.1.min(height));
+        let w = (rect.2 as u64).min((width - x) as u64) as u32;
+        let h = (rect.3 as u64).min((height - y) as u64) as u32;
+        if w == 0 || h == 0 {
+            return;
+        }
+
+        // a merged rect may now touch others, so it is added again until nothing merges
+        let mut rect = (x, y, w, h);
+        while let Some(index) = self.rects.iter().position(|&other| merge(rect, other).is_some()) {
+            rect = bounding_box(rect, self.rects.swap_remove(index));
+        }
+        self.rects.push(rect);
+
+        if self.rects.len() > MAX_DAMAGE_RECTS {
+            let all = self.rects.drain(..).fold(rect, bounding_box);
+            self.rects.push(all);
+        }
+    }
+
+    /// Marks the whole scanout as damaged.
+    pub fn damage_all(&mut self) {
+        self.rects.clear();
+        self.full = true;
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.full
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.full && self.rects.is_empty()
+    }
+
+    /// Returns the damaged rects and starts over, `None` when everything is damaged.
+    pub fn take(&mut self) -> Option<Vec<DamageRect>> {
+        let full = std::mem::replace(&mut self.full, false);
+        let rects = std::mem::take(&mut self.rects);
+        if full {
+            None
+        } else {
+            Some(rects)
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::damage::*;
+
+    #[test]
+    fn test_damage_merge() {
+        let mut damage = DamageTracker::new();
+        assert!(damage.is_empty());
+        // two glyphs next to each other on a line, and one on the next line
+        damage.add((0, 0, 8, 16), 640, 480);
+        damage.add((8, 0, 8, 16), 640, 480);
+        damage.add((0, 32, 8, 16), 640, 480);
+        let mut rects = damage.take().unwrap();
+        rects.sort();
+        assert_eq!(rects, vec![(0, 0, 16, 16), (0, 32, 8, 16)]);
+        assert!(damage.is_empty());
+
+        // bridging two rects merges all three
+        damage.add((0, 0, 8, 8), 640, 480);
+        damage.add((16, 0, 8, 8), 640, 480);
+        damage.add((4, 4, 16, 4), 640, 480);
+        assert_eq!(damage.take().unwrap(), vec![(0, 0, 24, 8)]);
+    }
+
+    #[test]
+    fn test_damage_bounds() {
+        let mut damage = DamageTracker::new();
+        // clipped to the scanout, and dropped outside of it
+        damage.add((600, 400, 100, 100), 640, 480);
+        damage.add((700, 0, 8, 8), 640, 480);
+        assert_eq!(damage.take().unwrap(), vec![(600, 400, 40, 80)]);
+
+        for i in 0..=MAX_DAMAGE_RECTS as u32 {
+            damage.add((i * 10, i * 10, 1, 1), 640, 480);
+        }
+        let max = MAX_DAMAGE_RECTS as u32 * 10;
+        assert_eq!(damage.take().unwrap(), vec![(0, 0, max + 1, max + 1)]);
+
+        damage.add((0, 0, 1, 1), 640, 480);
+        damage.damage_all();
+        damage.add((0, 0, 1, 1), 640, 480);
+        assert!(damage.is_full());
+        assert_eq!(damage.take(), None);
+        assert!(damage.is_empty());
+    }
+}
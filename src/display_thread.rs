@@ -0,0 +1,1216 @@
+// The display event thread and the message bus connecting it to the queue workers.
+//
+// `GpuDisplay` backends hold connections that can't leave the thread they were opened on, so the
+// display is opened and driven on its own thread.  Queue workers send it `DisplayRequest`s and
+// receive `DisplayEvent`s back, instead of borrowing the display directly.  An eventfd is
+// signalled along with the events so that they can be waited on in an epoll loop, and another one
+// along with the requests: the thread sleeps until either a request or the display server wakes it.
+//
+// In the deterministic execution mode the same worker runs on the caller's thread instead, see
+// `InlineDisplay`.
+//
+// When the connection to the display server drops, e.g. because the compositor restarted, the
+// worker forgets the surfaces, closes the lost display, reports `DisplayEvent::ConnectionLost` and
+// opens the display again with a growing backoff.  The queue workers rebuild their surfaces on
+// `DisplayEvent::Reconnected`, until then only surface creation fails.  The X and Wayland backends
+// notice the server hanging up, winit can't be opened twice and keeps its lost display.
+//
+// Surfaces with vsync enabled flip at most once per vertical blank on displays that report it: a
+// frame flushed while the previous one waits to be shown is kept and flipped by the event loop
+// once the display is done with it, later flushes within the frame only update the kept one.  A
+// frame flushed while the display still reads the buffer it would be drawn in is kept the same
+// way, vsync or not.
+//
+// Input devices added by the embedder are attached to a top level surface, the one shown first,
+// and move to another when that surface is released.  See `crate::input`.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
+use gpu_display::{EventDevice, GpuDisplay, GpuDisplayCapabilities, GpuDisplayError, GpuDisplayOutput, GpuDisplayRect};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
+
+use crate::staging::StagingBuffer;
+
+/// Delay before the first attempt to open a lost display again, doubled on every failure.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Opens the display, again after the connection to the display server was lost.
+type DisplayOpener = Box<dyn FnMut() -> Result<GpuDisplay, GpuDisplayError>>;
+
+/// Requests sent from the queue workers to the display thread.
+#[derive(Debug)]
+pub enum DisplayRequest {
+    /// Create a surface, the id (or the error) is sent back on `reply`.
+    CreateSurface {
+        parent_surface_id: Option<u32>,
+        width:             u32,
+        height:            u32,
+        reply:             Sender<Result<u32, GpuDisplayError>>,
+    },
+    ReleaseSurface(u32),
+    /// Copy `pixels`, `height` lines of `stride` bytes, into the surface and flip it.
+    Flush {
+        surface_id: u32,
+        stride:     u32,
+        height:     u32,
+        pixels:     StagingBuffer,
+    },
+    /// Copy `regions` into the last frame flushed to the surface, which is `width`x`height`
+    /// pixels, and show the result.
+    FlushRegions {
+        surface_id: u32,
+        width:      u32,
+        height:     u32,
+        regions:    Vec<FlushRegion>,
+    },
+    /// Show the identified imported buffer on the surface.
+    FlipTo {
+        surface_id: u32,
+        import_id:  u32,
+    },
+    SetPosition {
+        surface_id: u32,
+        x:          u32,
+        y:          u32,
+    },
+    /// Move a subsurface right away, falling back to `set_position` and a commit of `parent`.
+    MoveSurface {
+        surface_id: u32,
+        parent:     Option<u32>,
+        x:          u32,
+        y:          u32,
+    },
+    Commit(u32),
+    /// Hint of how often the surface is flushed, `None` when it isn't following the guest.
+    SetFrameInterval {
+        surface_id: u32,
+        interval:   Option<Duration>,
+    },
+    /// Defer the flips of the surface to the vertical blank, see `GpuDisplay::frame_pending`.
+    SetVsync {
+        surface_id: u32,
+        enabled:    bool,
+    },
+    /// Send the input of the top level surfaces to the device, dropped when the display has no
+    /// input of its kind.
+    AddInputDevice(EventDevice),
+    /// Stop the display thread, releasing the display.
+    Shutdown,
+}
+
+/// Pixels of a rect of a surface, rows of `width` 4 bytes pixels without padding.
+#[derive(Debug)]
+pub struct FlushRegion {
+    pub x:      u32,
+    pub y:      u32,
+    pub width:  u32,
+    pub height: u32,
+    pub pixels: StagingBuffer,
+}
+
+/// The last frame flushed to a surface, rows of `stride` bytes.  A flushed buffer goes back to
+/// its pool once the next one replaces it.
+struct SurfaceFrame {
+    stride: u32,
+    height: u32,
+    pixels: StagingBuffer,
+    /// Rects changed since the frame was last flipped, `None` when the whole frame did
+    damage: Option<Vec<GpuDisplayRect>>,
+}
+
+impl SurfaceFrame {
+    fn new(stride: u32, height: u32) -> Self {
+        SurfaceFrame {
+            stride,
+            height,
+            pixels: vec![0; stride as usize * height as usize].into(),
+            damage: None,
+        }
+    }
+
+    /// Copies the part of `region` within the frame into it.
+    fn copy_region(&mut self, region: &FlushRegion) {
+        let row = region.width as usize * 4;
+        let x = region.x as usize * 4;
+        let stride = self.stride as usize;
+        if row == 0 || x >= stride {
+            return;
+        }
+        if let Some(ref mut damage) = self.damage {
+            damage.push(GpuDisplayRect {
+                x:      region.x,
+                y:      region.y,
+                width:  region.width,
+                height: region.height,
+            });
+        }
+        let len = row.min(stride - x);
+        let rows = region.pixels.chunks(row).take(region.height as usize);
+        for (y, src) in (region.y as usize..self.height as usize).zip(rows) {
+            let start = y * stride + x;
+            let len = len.min(src.len());
+            if let Some(dst) = self.pixels.get_mut(start..start + len) {
+                dst.copy_from_slice(&src[..len]);
+            }
+        }
+    }
+}
+
+/// Events sent from the display thread to the queue workers.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DisplayEvent {
+    /// The user asked to close the identified top level surface.
+    CloseRequested(u32),
+    /// The host outputs changed, see `GpuDisplay::take_output_changes`.
+    OutputsChanged(Vec<GpuDisplayOutput>),
+    /// The connection to the display server dropped, taking every surface with it.
+    ConnectionLost,
+    /// The display was opened again, surfaces can be created on it.
+    Reconnected(GpuDisplayCapabilities),
+}
+
+/// The queue worker side of the bus.
+#[derive(Clone)]
+pub struct DisplayBus {
+    requests: Sender<DisplayRequest>,
+    events:   Receiver<DisplayEvent>,
+    /// Readable while events may be waiting
+    events_ready: Arc<EventFd>,
+    /// Dropped after `requests`, see `RequestsReady`
+    requests_ready: Arc<RequestsReady>,
+    capabilities: GpuDisplayCapabilities,
+}
+
+/// Wakes the display thread when requests are sent, and once more when the last `DisplayBus` is
+/// dropped so that it notices the channel closing.
+struct RequestsReady(EventFd);
+
+impl Drop for RequestsReady {
+    fn drop(&mut self) {
+        let _ = self.0.write(1);
+    }
+}
+
+impl DisplayBus {
+    /// Sends a request without waiting for the display thread.  Returns false if the thread is
+    /// gone.
+    pub fn send(&self, request: DisplayRequest) -> bool {
+        if self.requests.send(request).is_err() {
+            return false;
+        }
+        let _ = self.requests_ready.0.write(1);
+        true
+    }
+
+    pub fn create_surface(
+        &self,
+        parent_surface_id: Option<u32>,
+        width: u32,
+        height: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        let (reply, result) = bounded(1);
+        if !self.send(DisplayRequest::CreateSurface {
+            parent_surface_id,
+            width,
+            height,
+            reply,
+        }) {
+            return Err(GpuDisplayError::Connect);
+        }
+        result.recv().unwrap_or(Err(GpuDisplayError::Connect))
+    }
+
+    /// The events received so far, without blocking.
+    pub fn try_events(&self) -> Vec<DisplayEvent> {
+        // cleared before draining, events sent meanwhile signal it again
+        let _ = self.events_ready.read();
+        self.events.try_iter().collect()
+    }
+
+    /// Becomes readable when the display thread sent events, `try_events` clears it.
+    pub fn events_fd(&self) -> RawFd {
+        self.events_ready.as_raw_fd()
+    }
+
+    /// The receiving end of the events, to wait on it alongside other channels.
+    pub fn events(&self) -> &Receiver<DisplayEvent> {
+        &self.events
+    }
+
+    /// What the display opened by the thread supports.
+    pub fn capabilities(&self) -> GpuDisplayCapabilities {
+        self.capabilities
+    }
+}
+
+/// The display driven on the calling thread: requests are handled as they are sent and the display
+/// server is only polled by `try_events`, so everything happens in the order the caller chose.
+pub struct InlineDisplay {
+    worker:       RefCell<DisplayWorker>,
+    events:       Receiver<DisplayEvent>,
+    events_ready: Arc<EventFd>,
+}
+
+impl InlineDisplay {
+    pub fn open<F>(mut open: F) -> io::Result<Self>
+    where
+        F: FnMut() -> Result<GpuDisplay, GpuDisplayError> + 'static,
+    {
+        let display = open().map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        let (event_tx, event_rx) = unbounded();
+        let events_ready = Arc::new(EventFd::new(EFD_NONBLOCK)?);
+        Ok(InlineDisplay {
+            worker: RefCell::new(DisplayWorker::new(
+                display,
+                Box::new(open),
+                event_tx,
+                events_ready.clone(),
+            )),
+            events: event_rx,
+            events_ready,
+        })
+    }
+
+    /// Handles the request right away.
+    pub fn send(&self, request: DisplayRequest) -> bool {
+        let mut worker = self.worker.borrow_mut();
+        worker.check_connection();
+        worker.handle_request(request);
+        true
+    }
+
+    /// Polls the display server and returns the resulting events.
+    pub fn try_events(&self) -> Vec<DisplayEvent> {
+        self.worker.borrow_mut().dispatch_events();
+        let _ = self.events_ready.read();
+        self.events.try_iter().collect()
+    }
+
+    /// Signalled when `try_events` has something to report, which only happens within it.
+    pub fn events_fd(&self) -> RawFd {
+        self.events_ready.as_raw_fd()
+    }
+
+    pub fn capabilities(&self) -> GpuDisplayCapabilities {
+        self.worker.borrow().display.capabilities()
+    }
+}
+
+/// The display of a device, on its own thread or on the device's thread.
+pub enum DisplayHandle {
+    Thread(DisplayBus),
+    Inline(InlineDisplay),
+}
+
+impl DisplayHandle {
+    pub fn send(&self, request: DisplayRequest) -> bool {
+        match self {
+            DisplayHandle::Thread(bus) => bus.send(request),
+            DisplayHandle::Inline(display) => display.send(request),
+        }
+    }
+
+    pub fn create_surface(
+        &self,
+        parent_surface_id: Option<u32>,
+        width: u32,
+        height: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        match self {
+            DisplayHandle::Thread(bus) => bus.create_surface(parent_surface_id, width, height),
+            DisplayHandle::Inline(display) => {
+                let (reply, result) = bounded(1);
+                display.send(DisplayRequest::CreateSurface {
+                    parent_surface_id,
+                    width,
+                    height,
+                    reply,
+                });
+                result.recv().unwrap_or(Err(GpuDisplayError::Connect))
+            }
+        }
+    }
+
+    pub fn try_events(&self) -> Vec<DisplayEvent> {
+        match self {
+            DisplayHandle::Thread(bus) => bus.try_events(),
+            DisplayHandle::Inline(display) => display.try_events(),
+        }
+    }
+
+    pub fn events_fd(&self) -> RawFd {
+        match self {
+            DisplayHandle::Thread(bus) => bus.events_fd(),
+            DisplayHandle::Inline(display) => display.events_fd(),
+        }
+    }
+
+    pub fn capabilities(&self) -> GpuDisplayCapabilities {
+        match self {
+            DisplayHandle::Thread(bus) => bus.capabilities(),
+            DisplayHandle::Inline(display) => display.capabilities(),
+        }
+    }
+}
+
+/// A display thread that may still be opening its display, see `start_display_thread`.
+pub struct PendingDisplayThread {
+    requests:       Sender<DisplayRequest>,
+    events:         Receiver<DisplayEvent>,
+    events_ready:   Arc<EventFd>,
+    requests_ready: Arc<RequestsReady>,
+    opened:         Receiver<Result<GpuDisplayCapabilities, GpuDisplayError>>,
+    handle:         JoinHandle<()>,
+}
+
+impl PendingDisplayThread {
+    /// Waits for the display to be opened.
+    pub fn wait(self) -> io::Result<(DisplayBus, JoinHandle<()>)> {
+        match self.opened.recv() {
+            Ok(Ok(capabilities)) => Ok((
+                DisplayBus {
+                    requests: self.requests,
+                    events: self.events,
+                    events_ready: self.events_ready,
+                    requests_ready: self.requests_ready,
+                    capabilities,
+                },
+                self.handle,
+            )),
+            Ok(Err(e)) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "display thread exited before opening the display",
+            )),
+        }
+    }
+}
+
+/// Opens the display with `open` on a new thread and runs its event loop there, without waiting
+/// for the display to be opened so that the caller can do other work meanwhile.
+pub fn start_display_thread<F>(mut open: F) -> io::Result<PendingDisplayThread>
+where
+    F: FnMut() -> Result<GpuDisplay, GpuDisplayError> + Send + 'static,
+{
+    let (request_tx, request_rx) = unbounded();
+    let (event_tx, event_rx) = unbounded();
+    let (opened_tx, opened_rx) = bounded(1);
+    let events_ready = Arc::new(EventFd::new(EFD_NONBLOCK)?);
+    let worker_events_ready = events_ready.clone();
+    let requests_ready = EventFd::new(EFD_NONBLOCK)?;
+    let worker_requests_ready = requests_ready.try_clone()?;
+
+    let handle = thread::Builder::new()
+        .name("gpu_display".to_string())
+        .spawn(move || {
+            let display = match open() {
+                Ok(display) => {
+                    let _ = opened_tx.send(Ok(display.capabilities()));
+                    display
+                }
+                Err(e) => {
+                    let _ = opened_tx.send(Err(e));
+                    return;
+                }
+            };
+            DisplayWorker::new(display, Box::new(open), event_tx, worker_events_ready)
+                .run(request_rx, worker_requests_ready);
+        })?;
+
+    Ok(PendingDisplayThread {
+        requests: request_tx,
+        events: event_rx,
+        events_ready,
+        requests_ready: Arc::new(RequestsReady(requests_ready)),
+        opened: opened_rx,
+        handle,
+    })
+}
+
+/// Opens the display with `open` on a new thread and runs its event loop there.
+pub fn spawn_display_thread<F>(open: F) -> io::Result<(DisplayBus, JoinHandle<()>)>
+where
+    F: FnMut() -> Result<GpuDisplay, GpuDisplayError> + Send + 'static,
+{
+    start_display_thread(open)?.wait()
+}
+
+struct DisplayWorker {
+    display:            GpuDisplay,
+    open:               DisplayOpener,
+    /// When to try opening the display again and the delay after that, while disconnected
+    reconnect:          Option<(Instant, Duration)>,
+    top_level_surfaces: BTreeSet<u32>,
+    closed_surfaces:    BTreeSet<u32>,
+    /// Kept so that partial flushes can fill the surface's framebuffer, which may hold an older
+    /// frame when the surface is multi-buffered
+    frames:             BTreeMap<u32, SurfaceFrame>,
+    vsync_surfaces:     BTreeSet<u32>,
+    /// Flips waiting for the vertical blank, or for the display to release the next buffer of the
+    /// surface: the import to flip to or the kept frame when `None`
+    deferred_flips:     BTreeMap<u32, Option<u32>>,
+    /// Imported input devices waiting for a top level surface
+    input_devices:      BTreeSet<u32>,
+    /// Input devices attached to each top level surface
+    attached_inputs:    BTreeMap<u32, Vec<u32>>,
+    events:             Sender<DisplayEvent>,
+    events_ready:       Arc<EventFd>,
+}
+
+impl DisplayWorker {
+    fn new(
+        display: GpuDisplay,
+        open: DisplayOpener,
+        events: Sender<DisplayEvent>,
+        events_ready: Arc<EventFd>,
+    ) -> Self {
+        DisplayWorker {
+            display,
+            open,
+            reconnect: None,
+            top_level_surfaces: BTreeSet::new(),
+            closed_surfaces: BTreeSet::new(),
+            frames: BTreeMap::new(),
+            vsync_surfaces: BTreeSet::new(),
+            deferred_flips: BTreeMap::new(),
+            input_devices: BTreeSet::new(),
+            attached_inputs: BTreeMap::new(),
+            events,
+            events_ready,
+        }
+    }
+
+    fn run(mut self, requests: Receiver<DisplayRequest>, requests_ready: EventFd) {
+        loop {
+            // cleared before draining, requests sent meanwhile signal it again
+            let _ = requests_ready.read();
+            if !requests.is_empty() {
+                self.check_connection();
+            }
+            loop {
+                match requests.try_recv() {
+                    Ok(DisplayRequest::Shutdown) | Err(TryRecvError::Disconnected) => return,
+                    Ok(request) => self.handle_request(request),
+                    Err(TryRecvError::Empty) => break,
+                }
+            }
+
+            if !self.dispatch_events() {
+                break;
+            }
+            self.wait(&requests_ready);
+        }
+    }
+
+    /// Sleeps until a request is sent or the display server has something for
+    /// `dispatch_events`, at most until the next attempt to open a lost display.
+    fn wait(&self, requests_ready: &EventFd) {
+        let mut fds = vec![requests_ready.as_raw_fd()];
+        fds.extend(self.display.event_fd());
+        let mut pollfds: Vec<libc::pollfd> = fds
+            .into_iter()
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = match self.reconnect {
+            // rounded up, waking before the attempt is due would only sleep again
+            Some((next_attempt, _)) => {
+                let left = next_attempt.saturating_duration_since(Instant::now());
+                left.as_micros().div_ceil(1000).min(libc::c_int::MAX as u128) as libc::c_int
+            }
+            None => -1,
+        };
+        // Safe because the pollfds are valid for their length and only their revents are written.
+        unsafe {
+            libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout);
+        }
+    }
+
+    /// Notices a lost connection before anything is sent on it.  Returns false while
+    /// disconnected.
+    fn check_connection(&mut self) -> bool {
+        if self.reconnect.is_some() {
+            return false;
+        }
+        if !self.display.connection_lost() {
+            return true;
+        }
+
+        // the surfaces are gone with the server, the new display numbers them anew
+        self.top_level_surfaces.clear();
+        self.closed_surfaces.clear();
+        self.frames.clear();
+        self.vsync_surfaces.clear();
+        self.deferred_flips.clear();
+        // the devices were dropped with the display, closing their sockets
+        self.input_devices.clear();
+        self.attached_inputs.clear();
+        // close the lost connection now rather than once the display is back, the stub stands in
+        // until then
+        if let Ok(stub) = GpuDisplay::open_stub() {
+            self.display = stub;
+        }
+        self.reconnect = Some((Instant::now() + RECONNECT_BACKOFF_MIN, RECONNECT_BACKOFF_MIN));
+        self.send_events(vec![DisplayEvent::ConnectionLost]);
+        false
+    }
+
+    /// Opens the display again once the backoff elapsed.
+    fn try_reconnect(&mut self) {
+        let (next_attempt, backoff) = match self.reconnect {
+            Some(reconnect) => reconnect,
+            None => return,
+        };
+        let now = Instant::now();
+        if now < next_attempt {
+            return;
+        }
+
+        match (self.open)() {
+            Ok(display) => {
+                self.display = display;
+                self.reconnect = None;
+                let capabilities = self.display.capabilities();
+                self.send_events(vec![DisplayEvent::Reconnected(capabilities)]);
+            }
+            Err(_) => {
+                let backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                self.reconnect = Some((now + backoff, backoff));
+            }
+        }
+    }
+
+    fn handle_request(&mut self, request: DisplayRequest) {
+        if self.reconnect.is_some() {
+            // nothing to draw on until the display is back
+            if let DisplayRequest::CreateSurface { reply, .. } = request {
+                let _ = reply.send(Err(GpuDisplayError::Connect));
+            }
+            return;
+        }
+
+        match request {
+            DisplayRequest::CreateSurface {
+                parent_surface_id,
+                width,
+                height,
+                reply,
+            } => {
+                let result = self.display.create_surface(parent_surface_id, width, height);
+                if let (Ok(surface_id), None) = (&result, parent_surface_id) {
+                    self.top_level_surfaces.insert(*surface_id);
+                    let input_devices = std::mem::take(&mut self.input_devices);
+                    for event_device_id in input_devices {
+                        self.attach_input_device(event_device_id);
+                    }
+                }
+                let _ = reply.send(result);
+            }
+            DisplayRequest::ReleaseSurface(surface_id) => {
+                self.top_level_surfaces.remove(&surface_id);
+                self.closed_surfaces.remove(&surface_id);
+                self.frames.remove(&surface_id);
+                self.vsync_surfaces.remove(&surface_id);
+                self.deferred_flips.remove(&surface_id);
+                self.display.release_surface(surface_id);
+                // the display took the devices back from the surface
+                for event_device_id in self.attached_inputs.remove(&surface_id).unwrap_or_default() {
+                    self.attach_input_device(event_device_id);
+                }
+            }
+            DisplayRequest::Flush {
+                surface_id,
+                stride,
+                height,
+                pixels,
+            } => {
+                self.frames.insert(
+                    surface_id,
+                    SurfaceFrame {
+                        stride,
+                        height,
+                        pixels,
+                        damage: None,
+                    },
+                );
+                self.flip_or_defer(surface_id, None);
+            }
+            DisplayRequest::FlushRegions {
+                surface_id,
+                width,
+                height,
+                regions,
+            } => {
+                let stride = width * 4;
+                let frame = self.frames.entry(surface_id).or_insert_with(|| SurfaceFrame::new(stride, height));
+                if frame.stride != stride || frame.height != height {
+                    *frame = SurfaceFrame::new(stride, height);
+                }
+                for region in regions {
+                    frame.copy_region(&region);
+                }
+                self.flip_or_defer(surface_id, None);
+            }
+            DisplayRequest::FlipTo {
+                surface_id,
+                import_id,
+            } => self.flip_or_defer(surface_id, Some(import_id)),
+            DisplayRequest::SetPosition { surface_id, x, y } => {
+                self.display.set_position(surface_id, x, y)
+            }
+            DisplayRequest::MoveSurface {
+                surface_id,
+                parent,
+                x,
+                y,
+            } => {
+                if self.display.move_surface(surface_id, x, y).is_err() {
+                    self.display.set_position(surface_id, x, y);
+                    if let Some(parent) = parent {
+                        self.display.commit(parent);
+                    }
+                }
+            }
+            DisplayRequest::Commit(surface_id) => self.display.commit(surface_id),
+            DisplayRequest::SetFrameInterval {
+                surface_id,
+                interval,
+            } => self.display.set_frame_interval(surface_id, interval),
+            DisplayRequest::SetVsync {
+                surface_id,
+                enabled,
+            } => {
+                if enabled {
+                    self.vsync_surfaces.insert(surface_id);
+                } else {
+                    self.vsync_surfaces.remove(&surface_id);
+                    if let Some(import_id) = self.deferred_flips.remove(&surface_id) {
+                        self.flip(surface_id, import_id);
+                    }
+                }
+            }
+            DisplayRequest::AddInputDevice(event_device) => {
+                if let Ok(event_device_id) = self.display.import_event_device(event_device) {
+                    self.attach_input_device(event_device_id);
+                }
+            }
+            DisplayRequest::Shutdown => {}
+        }
+    }
+
+    /// Attaches the imported input device to a top level surface, or keeps it for the next one.
+    fn attach_input_device(&mut self, event_device_id: u32) {
+        match self.top_level_surfaces.iter().next() {
+            Some(&surface_id) => {
+                self.display.attach_event_device(surface_id, event_device_id);
+                self.attached_inputs.entry(surface_id).or_default().push(event_device_id);
+            }
+            None => {
+                self.input_devices.insert(event_device_id);
+            }
+        }
+    }
+
+    /// Flips the surface, or keeps the flip for the vertical blank when vsync is enabled on it
+    /// and its last frame isn't shown yet.
+    fn flip_or_defer(&mut self, surface_id: u32, import_id: Option<u32>) {
+        if self.vsync_surfaces.contains(&surface_id) && self.display.frame_pending(surface_id) {
+            self.deferred_flips.insert(surface_id, import_id);
+            return;
+        }
+        self.deferred_flips.remove(&surface_id);
+        self.flip(surface_id, import_id);
+    }
+
+    fn flip(&mut self, surface_id: u32, import_id: Option<u32>) {
+        match import_id {
+            Some(import_id) => {
+                self.display.flip_to(surface_id, import_id);
+                // the kept frame replaces the import as a whole
+                if let Some(frame) = self.frames.get_mut(&surface_id) {
+                    frame.damage = None;
+                }
+            }
+            None => self.present_frame(surface_id),
+        }
+    }
+
+    /// Flips the deferred frames whose surface is done with the previous one, and whose next
+    /// buffer is free to draw the kept frame in.
+    fn flip_deferred(&mut self) {
+        let display = &self.display;
+        let ready: Vec<(u32, Option<u32>)> = self
+            .deferred_flips
+            .iter()
+            .filter(|(&surface_id, import_id)| {
+                !display.frame_pending(surface_id)
+                    && (import_id.is_some() || !display.next_buffer_in_use(surface_id))
+            })
+            .map(|(&surface_id, &import_id)| (surface_id, import_id))
+            .collect();
+        for (surface_id, import_id) in ready {
+            self.deferred_flips.remove(&surface_id);
+            self.flip(surface_id, import_id);
+        }
+    }
+
+    /// Copies the last frame flushed to the surface into its next framebuffer and flips it, with
+    /// what changed since its last flip as the damage.  The frame is kept for the event loop while
+    /// the display still reads that framebuffer.
+    fn present_frame(&mut self, surface_id: u32) {
+        // Prevent overwriting a buffer that is currently being used by the compositor.
+        if self.display.next_buffer_in_use(surface_id) {
+            self.deferred_flips.insert(surface_id, None);
+            return;
+        }
+        let frame = match self.frames.get_mut(&surface_id) {
+            Some(frame) => frame,
+            None => return,
+        };
+        if let Some(fb) = self.display.framebuffer(surface_id) {
+            let fb_stride = fb.stride() as usize;
+            let line = (frame.stride as usize).min(fb_stride);
+            let slice = fb.as_volatile_slice();
+            for (y, src) in frame
+                .pixels
+                .chunks(frame.stride as usize)
+                .take(frame.height as usize)
+                .enumerate()
+            {
+                let len = line.min(src.len());
+                if let Ok(dst) = slice.sub_slice(y * fb_stride, len) {
+                    dst.copy_from(&src[..len]);
+                }
+            }
+        }
+        match frame.damage.replace(Vec::new()) {
+            Some(damage) => self.display.flip_damage(surface_id, &damage),
+            None => self.display.flip(surface_id),
+        }
+    }
+
+    /// Processes the display server events and forwards the interesting ones.  Returns false
+    /// once nobody listens to the events anymore.
+    fn dispatch_events(&mut self) -> bool {
+        if !self.check_connection() {
+            self.try_reconnect();
+            return true;
+        }
+        self.display.dispatch_events();
+        self.flip_deferred();
+
+        let mut events = Vec::new();
+        if let Some(outputs) = self.display.take_output_changes() {
+            events.push(DisplayEvent::OutputsChanged(outputs));
+        }
+        for &surface_id in &self.top_level_surfaces {
+            if !self.closed_surfaces.contains(&surface_id)
+                && self.display.close_requested(surface_id)
+            {
+                self.closed_surfaces.insert(surface_id);
+                events.push(DisplayEvent::CloseRequested(surface_id));
+            }
+        }
+
+        self.send_events(events)
+    }
+
+    /// Forwards `events` to the queue workers.  Returns false once nobody listens to them.
+    fn send_events(&self, events: Vec<DisplayEvent>) -> bool {
+        if events.is_empty() {
+            return true;
+        }
+        let sent = events.into_iter().all(|event| self.events.send(event).is_ok());
+        let _ = self.events_ready.write(1);
+        sent
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::display_thread::{
+        spawn_display_thread, start_display_thread, DisplayEvent, DisplayHandle, DisplayRequest,
+        FlushRegion, InlineDisplay, RECONNECT_BACKOFF_MIN,
+    };
+    use crossbeam_channel::bounded;
+    use gpu_display::{
+        GpuDisplay, GpuDisplayBackend, GpuDisplayError, GpuDisplayFramebuffer, GpuDisplayRect,
+    };
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::thread;
+
+    /// The damage of every flip, `None` for a flip of the whole surface.
+    type Flips = Rc<RefCell<Vec<Option<Vec<GpuDisplayRect>>>>>;
+
+    /// A single surface backend keeping what each flip damaged, whose last frame waits for the
+    /// vertical blank while `pending` is set and whose next buffer is read while `in_use` is.
+    struct FlipBackend {
+        buffer:  Vec<u8>,
+        stride:  u32,
+        flips:   Flips,
+        pending: Rc<Cell<bool>>,
+        in_use:  Rc<Cell<bool>>,
+    }
+
+    impl GpuDisplayBackend for FlipBackend {
+        fn dispatch_events(&mut self) {}
+
+        fn create_surface(
+            &mut self,
+            _parent_surface_id: Option<u32>,
+            width: u32,
+            height: u32,
+        ) -> Result<u32, GpuDisplayError> {
+            self.buffer = vec![0; width as usize * height as usize * 4];
+            self.stride = width * 4;
+            Ok(1)
+        }
+
+        fn release_surface(&mut self, _surface_id: u32) {}
+
+        fn framebuffer(&mut self, _surface_id: u32) -> Option<GpuDisplayFramebuffer<'_>> {
+            Some(GpuDisplayFramebuffer::new(
+                data_model::VolatileSlice::new(&mut self.buffer),
+                self.stride,
+                4,
+            ))
+        }
+
+        fn flip(&mut self, _surface_id: u32) {
+            self.flips.borrow_mut().push(None);
+        }
+
+        fn flip_damage(&mut self, _surface_id: u32, damage: &[GpuDisplayRect]) {
+            self.flips.borrow_mut().push(Some(damage.to_vec()));
+        }
+
+        fn frame_pending(&self, _surface_id: u32) -> bool {
+            self.pending.get()
+        }
+
+        fn next_buffer_in_use(&self, _surface_id: u32) -> bool {
+            self.in_use.get()
+        }
+    }
+
+    fn open_flip_backend(
+        flips: &Flips,
+        pending: &Rc<Cell<bool>>,
+        in_use: &Rc<Cell<bool>>,
+    ) -> InlineDisplay {
+        let (flips, pending, in_use) = (flips.clone(), pending.clone(), in_use.clone());
+        InlineDisplay::open(move || {
+            Ok(GpuDisplay::from_backend(Box::new(FlipBackend {
+                buffer:  Vec::new(),
+                stride:  0,
+                flips:   flips.clone(),
+                pending: pending.clone(),
+                in_use:  in_use.clone(),
+            })))
+        })
+        .unwrap()
+    }
+
+    fn create_surface(display: &InlineDisplay, width: u32, height: u32) -> u32 {
+        let (reply, result) = bounded(1);
+        display.send(DisplayRequest::CreateSurface {
+            parent_surface_id: None,
+            width,
+            height,
+            reply,
+        });
+        result.recv().unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_display_thread_surface_lifecycle() {
+        let (bus, handle) = spawn_display_thread(GpuDisplay::open_stub).unwrap();
+
+        let surface_id = bus.create_surface(None, 64, 32).unwrap();
+        assert!(bus.send(DisplayRequest::Flush {
+            surface_id,
+            stride: 64 * 4,
+            height: 32,
+            pixels: vec![0xff; 64 * 4 * 32].into(),
+        }));
+        // the stub display doesn't support subsurfaces
+        assert!(bus.create_surface(Some(surface_id), 16, 16).is_err());
+        assert!(bus.send(DisplayRequest::ReleaseSurface(surface_id)));
+
+        assert!(bus.send(DisplayRequest::Shutdown));
+        handle.join().unwrap();
+        assert!(bus.try_events().is_empty());
+    }
+
+    #[test]
+    fn test_start_display_thread() {
+        let pending = start_display_thread(|| Err(GpuDisplayError::Connect)).unwrap();
+        assert!(pending.wait().is_err());
+
+        let pending = start_display_thread(GpuDisplay::open_stub).unwrap();
+        let (bus, handle) = pending.wait().unwrap();
+        assert!(bus.send(DisplayRequest::Shutdown));
+        handle.join().unwrap();
+
+        // the idle thread is woken up when the last bus is dropped
+        let (bus, handle) = spawn_display_thread(GpuDisplay::open_stub).unwrap();
+        drop(bus.clone());
+        drop(bus);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_inline_display() {
+        let display = DisplayHandle::Inline(InlineDisplay::open(GpuDisplay::open_stub).unwrap());
+
+        let surface_id = display.create_surface(None, 64, 32).unwrap();
+        assert!(display.send(DisplayRequest::Flush {
+            surface_id,
+            stride: 64 * 4,
+            height: 32,
+            pixels: vec![0xff; 64 * 4 * 32].into(),
+        }));
+        assert!(display.send(DisplayRequest::ReleaseSurface(surface_id)));
+        assert!(display.try_events().is_empty());
+    }
+
+    #[test]
+    fn test_vsync_without_vsync_events() {
+        let display = InlineDisplay::open(GpuDisplay::open_stub).unwrap();
+        let (reply, result) = bounded(1);
+        display.send(DisplayRequest::CreateSurface {
+            parent_surface_id: None,
+            width: 64,
+            height: 32,
+            reply,
+        });
+        let surface_id = result.recv().unwrap().unwrap();
+        display.send(DisplayRequest::SetVsync {
+            surface_id,
+            enabled: true,
+        });
+        assert!(display.worker.borrow().vsync_surfaces.contains(&surface_id));
+
+        // the stub never has a frame waiting for the vertical blank, every flush flips
+        for _ in 0..2 {
+            display.send(DisplayRequest::Flush {
+                surface_id,
+                stride: 64 * 4,
+                height: 32,
+                pixels: vec![0xff; 64 * 4 * 32].into(),
+            });
+            assert!(display.worker.borrow().deferred_flips.is_empty());
+        }
+        display.send(DisplayRequest::ReleaseSurface(surface_id));
+        assert!(display.worker.borrow().vsync_surfaces.is_empty());
+    }
+
+    #[test]
+    fn test_flip_damage() {
+        let flips = Flips::default();
+        let display = open_flip_backend(&flips, &Rc::default(), &Rc::default());
+        let surface_id = create_surface(&display, 8, 4);
+        let region = |x, y| FlushRegion {
+            x,
+            y,
+            width: 2,
+            height: 1,
+            pixels: vec![0xff; 8].into(),
+        };
+
+        for (x, y) in [(0, 0), (4, 2)].iter().cloned() {
+            display.send(DisplayRequest::FlushRegions {
+                surface_id,
+                width: 8,
+                height: 4,
+                regions: vec![region(x, y)],
+            });
+        }
+        display.send(DisplayRequest::Flush {
+            surface_id,
+            stride: 8 * 4,
+            height: 4,
+            pixels: vec![0; 8 * 4 * 4].into(),
+        });
+
+        // the surface's first frame and full flushes damage all of it
+        let damage = GpuDisplayRect {
+            x:      4,
+            y:      2,
+            width:  2,
+            height: 1,
+        };
+        assert_eq!(*flips.borrow(), vec![None, Some(vec![damage]), None]);
+    }
+
+    #[test]
+    fn test_vsync_flips_once_per_frame() {
+        let flips = Flips::default();
+        let pending = Rc::new(Cell::new(false));
+        let display = open_flip_backend(&flips, &pending, &Rc::default());
+        let surface_id = create_surface(&display, 8, 4);
+        display.send(DisplayRequest::SetVsync {
+            surface_id,
+            enabled: true,
+        });
+        let flush = |x| DisplayRequest::FlushRegions {
+            surface_id,
+            width: 8,
+            height: 4,
+            regions: vec![FlushRegion {
+                x,
+                y: 0,
+                width: 1,
+                height: 1,
+                pixels: vec![0xff; 4].into(),
+            }],
+        };
+        display.send(flush(0));
+        assert_eq!(flips.borrow().len(), 1);
+
+        // the flushes while the frame waits for the vertical blank are flipped together after it
+        pending.set(true);
+        for x in 1..4 {
+            display.send(flush(x));
+        }
+        display.try_events();
+        assert_eq!(flips.borrow().len(), 1);
+        pending.set(false);
+        display.try_events();
+        let rect = |x| GpuDisplayRect {
+            x,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        assert_eq!(flips.borrow()[1..], [Some(vec![rect(1), rect(2), rect(3)])]);
+        display.try_events();
+        assert_eq!(flips.borrow().len(), 2);
+    }
+
+    #[test]
+    fn test_flush_waits_for_free_buffer() {
+        let flips = Flips::default();
+        let in_use = Rc::new(Cell::new(false));
+        let display = open_flip_backend(&flips, &Rc::default(), &in_use);
+        let surface_id = create_surface(&display, 8, 4);
+        let flush = |x| DisplayRequest::FlushRegions {
+            surface_id,
+            width: 8,
+            height: 4,
+            regions: vec![FlushRegion {
+                x,
+                y: 0,
+                width: 1,
+                height: 1,
+                pixels: vec![0xff; 4].into(),
+            }],
+        };
+        display.send(flush(0));
+        assert_eq!(flips.borrow().len(), 1);
+
+        // kept rather than dropped while the display reads the buffer, then flipped as a whole
+        in_use.set(true);
+        display.send(flush(1));
+        display.send(flush(2));
+        display.try_events();
+        assert_eq!(flips.borrow().len(), 1);
+        in_use.set(false);
+        display.try_events();
+        let rect = |x| GpuDisplayRect {
+            x,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        assert_eq!(flips.borrow()[1..], [Some(vec![rect(1), rect(2)])]);
+        assert!(display.worker.borrow().deferred_flips.is_empty());
+    }
+
+    /// A backend whose connection is gone while `lost` is set, counting the open ones in `open`.
+    struct LosableBackend {
+        lost: Rc<Cell<bool>>,
+        open: Rc<Cell<u32>>,
+    }
+
+    impl Drop for LosableBackend {
+        fn drop(&mut self) {
+            self.open.set(self.open.get() - 1);
+        }
+    }
+
+    impl GpuDisplayBackend for LosableBackend {
+        fn dispatch_events(&mut self) {}
+
+        fn create_surface(
+            &mut self,
+            _parent_surface_id: Option<u32>,
+            _width: u32,
+            _height: u32,
+        ) -> Result<u32, GpuDisplayError> {
+            Ok(1)
+        }
+
+        fn release_surface(&mut self, _surface_id: u32) {}
+
+        fn framebuffer(&mut self, _surface_id: u32) -> Option<GpuDisplayFramebuffer<'_>> {
+            None
+        }
+
+        fn flip(&mut self, _surface_id: u32) {}
+
+        fn connection_lost(&self) -> bool {
+            self.lost.get()
+        }
+    }
+
+    #[test]
+    fn test_reconnect_closes_lost_display() {
+        let lost = Rc::new(Cell::new(false));
+        let open = Rc::new(Cell::new(0));
+        // how many displays were still open whenever one was opened
+        let opened_with = Rc::new(RefCell::new(Vec::new()));
+        let display = {
+            let (lost, open, opened_with) = (lost.clone(), open.clone(), opened_with.clone());
+            InlineDisplay::open(move || {
+                opened_with.borrow_mut().push(open.get());
+                open.set(open.get() + 1);
+                lost.set(false);
+                Ok(GpuDisplay::from_backend(Box::new(LosableBackend {
+                    lost: lost.clone(),
+                    open: open.clone(),
+                })))
+            })
+            .unwrap()
+        };
+
+        lost.set(true);
+        assert!(matches!(display.try_events()[..], [DisplayEvent::ConnectionLost]));
+        assert_eq!(open.get(), 0);
+        let (reply, result) = bounded(1);
+        display.send(DisplayRequest::CreateSurface {
+            parent_surface_id: None,
+            width: 8,
+            height: 4,
+            reply,
+        });
+        assert!(result.recv().unwrap().is_err());
+
+        thread::sleep(RECONNECT_BACKOFF_MIN);
+        assert!(matches!(display.try_events()[..], [DisplayEvent::Reconnected(_)]));
+        assert_eq!(*opened_with.borrow(), vec![0, 0]);
+        assert_eq!(open.get(), 1);
+    }
+}
@@ -0,0 +1,34 @@
+// Zero-copy scanout: when the renderer exports the scanout resource as a dmabuf, the descriptor is
+// handed to the VMM, which displays it itself instead of the device reading the resource back into
+// a surface of its own.  The VMM is told once when the scanout changes, then only which area
+// changed on every flush.  Resources the renderer can't export, e.g. those of the 2D renderer, keep
+// going through the CPU copy.
+
+use std::io;
+
+use rutabaga_gfx::RutabagaHandle;
+
+use crate::damage::DamageRect;
+
+/// How the pixels of an exported scanout lie in its dmabuf.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DmabufScanout {
+    pub width:      u32,
+    pub height:     u32,
+    pub stride:     u32,
+    pub drm_fourcc: u32,
+    pub modifier:   u64,
+}
+
+/// Hands the scanouts the renderer exports to the VMM.
+pub trait ScanoutForwarder {
+    /// Scans `dmabuf`, laid out as `scanout` says, out on `scanout_id` in place of what was
+    /// there.
+    fn set_scanout(&mut self, scanout_id: u32, scanout: &DmabufScanout, dmabuf: &RutabagaHandle) -> io::Result<()>;
+
+    /// Stops scanning out on `scanout_id`, the device presents it on its own again.
+    fn disable_scanout(&mut self, scanout_id: u32) -> io::Result<()>;
+
+    /// Tells the VMM `rect` of the dmabuf on `scanout_id` changed.
+    fn flush(&mut self, scanout_id: u32, rect: DamageRect) -> io::Result<()>;
+}
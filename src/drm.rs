@@ -0,0 +1,178 @@
+// Enumeration of the host DRM devices: the primary (card*) and render (renderD*) nodes with
+// their driver, PCI id and the capabilities relevant to buffer sharing.
+
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem::size_of;
+use std::os::raw::{c_char, c_int, c_ulong};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+const DRM_DIR: &str = "/dev/dri";
+const DRM_SYSFS_DIR: &str = "/sys/class/drm";
+
+const DRM_IOCTL_BASE: c_ulong = b'd' as c_ulong;
+const DRM_CAP_PRIME: u64 = 0x5;
+const DRM_CAP_ADDFB2_MODIFIERS: u64 = 0x10;
+const DRM_PRIME_CAP_IMPORT: u64 = 0x1;
+const DRM_PRIME_CAP_EXPORT: u64 = 0x2;
+
+#[repr(C)]
+#[derive(Default)]
+struct drm_version {
+    version_major:      c_int,
+    version_minor:      c_int,
+    version_patchlevel: c_int,
+    name_len:           usize,
+    name:               usize,
+    date_len:           usize,
+    date:               usize,
+    desc_len:           usize,
+    desc:               usize,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct drm_get_cap {
+    capability: u64,
+    value:      u64,
+}
+
+/// _IOWR('d', nr, T)
+const fn drm_iowr<T>(nr: c_ulong) -> c_ulong {
+    (3 << 30) | ((size_of::<T>() as c_ulong) << 16) | (DRM_IOCTL_BASE << 8) | nr
+}
+
+const DRM_IOCTL_VERSION: c_ulong = drm_iowr::<drm_version>(0x00);
+const DRM_IOCTL_GET_CAP: c_ulong = drm_iowr::<drm_get_cap>(0x0c);
+
+/// The kind of a DRM device node.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DrmNodeType {
+    /// `card*`, allows modesetting.
+    Primary,
+    /// `renderD*`, rendering only, usable without being DRM master.
+    Render,
+}
+
+/// A DRM device node of the host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DrmDevice {
+    pub path:         PathBuf,
+    pub node_type:    DrmNodeType,
+    /// Kernel driver, e.g. `i915` or `amdgpu`.
+    pub driver:       String,
+    /// PCI `(vendor, device)` id, `None` for platform devices.
+    pub pci_id:       Option<(u16, u16)>,
+    pub prime_import: bool,
+    pub prime_export: bool,
+    /// Framebuffers can be created with format modifiers.
+    pub modifiers:    bool,
+}
+
+/// Lists the DRM nodes of the host, sorted by path.  Nodes that can't be opened, for example
+/// because of their permissions, are skipped.
+pub fn enumerate_drm_devices() -> io::Result<Vec<DrmDevice>> {
+    let mut devices = Vec::new();
+    for entry in fs::read_dir(DRM_DIR)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let node_type = if name.starts_with("renderD") {
+            DrmNodeType::Render
+        } else if name.starts_with("card") {
+            DrmNodeType::Primary
+        } else {
+            continue;
+        };
+
+        if let Ok(device) = probe_device(&path, &name, node_type) {
+            devices.push(device);
+        }
+    }
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(devices)
+}
+
+fn probe_device(path: &Path, name: &str, node_type: DrmNodeType) -> io::Result<DrmDevice> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let prime = get_cap(&file, DRM_CAP_PRIME).unwrap_or(0);
+    let uevent = fs::read_to_string(Path::new(DRM_SYSFS_DIR).join(name).join("device/uevent"))
+        .unwrap_or_default();
+
+    Ok(DrmDevice {
+        path: path.to_path_buf(),
+        node_type,
+        driver: driver_name(&file)?,
+        pci_id: parse_pci_id(&uevent),
+        prime_import: prime & DRM_PRIME_CAP_IMPORT != 0,
+        prime_export: prime & DRM_PRIME_CAP_EXPORT != 0,
+        modifiers: get_cap(&file, DRM_CAP_ADDFB2_MODIFIERS).unwrap_or(0) != 0,
+    })
+}
+
+fn driver_name(file: &File) -> io::Result<String> {
+    let mut name = [0 as c_char; 64];
+    let mut version = drm_version {
+        name_len: name.len() - 1,
+        name: name.as_mut_ptr() as usize,
+        ..Default::default()
+    };
+    // Safe because the kernel writes at most `name_len` bytes to `name`, which outlives the
+    // call, and the result is checked.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), DRM_IOCTL_VERSION, &mut version) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because `name` is zero initialised and one byte longer than what the kernel writes.
+    let name = unsafe { CStr::from_ptr(name.as_ptr()) };
+    Ok(name.to_string_lossy().into_owned())
+}
+
+fn get_cap(file: &File, capability: u64) -> io::Result<u64> {
+    let mut cap = drm_get_cap {
+        capability,
+        value: 0,
+    };
+    // Safe because the kernel only writes to `cap` and the result is checked.
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), DRM_IOCTL_GET_CAP, &mut cap) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cap.value)
+}
+
+/// Extracts the `PCI_ID=vvvv:dddd` entry of a sysfs uevent file.
+fn parse_pci_id(uevent: &str) -> Option<(u16, u16)> {
+    let id = uevent
+        .lines()
+        .find_map(|line| line.strip_prefix("PCI_ID="))?;
+    let mut parts = id.trim().split(':');
+    let vendor = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let device = u16::from_str_radix(parts.next()?, 16).ok()?;
+    Some((vendor, device))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::drm::{parse_pci_id, DRM_IOCTL_GET_CAP, DRM_IOCTL_VERSION};
+
+    #[test]
+    fn test_drm_ioctl_numbers() {
+        // values from the linux uapi drm.h on 64 bit
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(DRM_IOCTL_VERSION, 0xc040_6400);
+        }
+        assert_eq!(DRM_IOCTL_GET_CAP, 0xc010_640c);
+    }
+
+    #[test]
+    fn test_parse_pci_id() {
+        let uevent = "DRIVER=i915\nPCI_CLASS=30000\nPCI_ID=8086:3E92\nPCI_SUBSYS_ID=1028:085A\n";
+        assert_eq!(parse_pci_id(uevent), Some((0x8086, 0x3e92)));
+        assert_eq!(parse_pci_id("DRIVER=vc4\nOF_NAME=gpu\n"), None);
+    }
+}
@@ -0,0 +1,588 @@
+// EDID generation for the virtio-gpu scanouts
+//
+// The layout follows VESA E-EDID 1.4 for the base block and CEA-861-F for the extension block.
+// Detailed timings are computed with the CVT reduced blanking formula, which is what most
+// monitors advertise for their digital inputs.
+
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub const EDID_BLOCK_SIZE: usize = 128;
+pub const EDID_HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+/// Size of a detailed timing / display descriptor
+const DESCRIPTOR_SIZE: usize = 18;
+/// Offset of the first descriptor in the base block
+const BASE_DESCRIPTORS_OFFSET: usize = 54;
+
+const CEA_EXTENSION_TAG: u8 = 0x02;
+const CEA_EXTENSION_REVISION: u8 = 0x03;
+/// The CEA block has no data block collection, so the DTDs start right after the 4 byte header.
+const CEA_DTD_OFFSET: usize = 4;
+const CEA_MAX_DTDS: usize = (EDID_BLOCK_SIZE - 1 - CEA_DTD_OFFSET) / DESCRIPTOR_SIZE;
+/// Byte 3 of the CEA block: bit 6 is "basic audio", bit 7 "underscan".  Both left cleared so the
+/// guest does not expect an audio sink behind this display.
+const CEA_FLAGS_NO_AUDIO: u8 = 0x00;
+
+/// Largest pixel clock a detailed timing descriptor can carry (16 bits in units of 10kHz)
+const DTD_MAX_PIXEL_CLOCK_KHZ: u32 = 0xffff * 10;
+
+/// The virtio-gpu EDID response carries at most 1024 bytes
+pub const EDID_MAX_SIZE: usize = 1024;
+
+const DEFAULT_DPI: u32 = 96;
+const FALLBACK_REFRESH_RATE: u32 = 30;
+/// Lowest vertical rate of the range limits when the refresh rate is fixed
+const MIN_VERTICAL_RATE: u32 = 24;
+
+/// Byte 24 of the base block: RGB 4:4:4, sRGB default color space, preferred timing is native
+const FEATURES: u8 = 0x06;
+/// Feature bit telling the display runs at any rate within the range limits, which is what
+/// drivers look for to enable adaptive sync
+const FEATURE_CONTINUOUS_FREQUENCY: u8 = 0x01;
+
+/// Modes advertised in the CEA extension when the configured display is large enough
+const UHD_MODE: (u32, u32, u32) = (3840, 2160, 60);
+const HIGH_REFRESH_RATES: [u32; 2] = [120, 144];
+
+const MONITOR_NAME: &[u8] = b"virtio-gpu";
+const MANUFACTURER_ID: [u8; 3] = *b"VGB";
+const PRODUCT_CODE: u16 = 0x1af4;
+
+/// A display timing in the form used by EDID detailed timing descriptors.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DisplayTiming {
+    pub pixel_clock_khz: u32,
+    pub h_active:        u32,
+    pub h_blank:         u32,
+    pub h_front_porch:   u32,
+    pub h_sync:          u32,
+    pub v_active:        u32,
+    pub v_blank:         u32,
+    pub v_front_porch:   u32,
+    pub v_sync:          u32,
+}
+
+impl DisplayTiming {
+    /// Computes a CVT reduced blanking (v1) timing for the given mode.
+    pub fn cvt_rb(width: u32, height: u32, refresh_rate: u32) -> DisplayTiming {
+        const H_BLANK: u32 = 160;
+        const H_SYNC: u32 = 32;
+        const H_FRONT_PORCH: u32 = 48;
+        const V_FRONT_PORCH: u32 = 3;
+        const V_SYNC: u32 = 5;
+        const MIN_V_BACK_PORCH: u32 = 6;
+        const MIN_V_BLANK_US: f64 = 460.0;
+        const CLOCK_STEP_KHZ: u32 = 250;
+
+        let refresh_rate = refresh_rate.max(1);
+        let frame_us = 1_000_000.0 / refresh_rate as f64;
+        let h_period_us = (frame_us - MIN_V_BLANK_US) / height.max(1) as f64;
+        let v_blank = ((MIN_V_BLANK_US / h_period_us) as u32 + 1)
+            .max(V_FRONT_PORCH + V_SYNC + MIN_V_BACK_PORCH);
+
+        let h_total = (width + H_BLANK) as u64;
+        let v_total = (height + v_blank) as u64;
+        let pixel_clock_khz = (h_total * v_total * refresh_rate as u64 / 1000) as u32;
+
+        DisplayTiming {
+            pixel_clock_khz: pixel_clock_khz - pixel_clock_khz % CLOCK_STEP_KHZ,
+            h_active: width,
+            h_blank: H_BLANK,
+            h_front_porch: H_FRONT_PORCH,
+            h_sync: H_SYNC,
+            v_active: height,
+            v_blank,
+            v_front_porch: V_FRONT_PORCH,
+            v_sync: V_SYNC,
+        }
+    }
+
+    /// Whether the timing can be expressed in a detailed timing descriptor.
+    pub fn fits_descriptor(&self) -> bool {
+        self.pixel_clock_khz <= DTD_MAX_PIXEL_CLOCK_KHZ
+            && self.h_active < 1 << 12
+            && self.h_blank < 1 << 12
+            && self.v_active < 1 << 12
+            && self.v_blank < 1 << 12
+    }
+
+    /// Horizontal frequency in kHz
+    pub fn h_freq_khz(&self) -> u32 {
+        self.pixel_clock_khz / (self.h_active + self.h_blank)
+    }
+
+    fn encode(&self, size_mm: (u32, u32), descriptor: &mut [u8]) {
+        let clock = (self.pixel_clock_khz / 10) as u16;
+        let (h_mm, v_mm) = size_mm;
+
+        descriptor[0..2].copy_from_slice(&clock.to_le_bytes());
+        descriptor[2] = self.h_active as u8;
+        descriptor[3] = self.h_blank as u8;
+        descriptor[4] = (((self.h_active >> 8) & 0xf) << 4 | ((self.h_blank >> 8) & 0xf)) as u8;
+        descriptor[5] = self.v_active as u8;
+        descriptor[6] = self.v_blank as u8;
+        descriptor[7] = (((self.v_active >> 8) & 0xf) << 4 | ((self.v_blank >> 8) & 0xf)) as u8;
+        descriptor[8] = self.h_front_porch as u8;
+        descriptor[9] = self.h_sync as u8;
+        descriptor[10] = ((self.v_front_porch & 0xf) << 4 | (self.v_sync & 0xf)) as u8;
+        descriptor[11] = (((self.h_front_porch >> 8) & 0x3) << 6
+            | ((self.h_sync >> 8) & 0x3) << 4
+            | ((self.v_front_porch >> 4) & 0x3) << 2
+            | ((self.v_sync >> 4) & 0x3)) as u8;
+        descriptor[12] = h_mm as u8;
+        descriptor[13] = v_mm as u8;
+        descriptor[14] = (((h_mm >> 8) & 0xf) << 4 | ((v_mm >> 8) & 0xf)) as u8;
+        descriptor[15] = 0;
+        descriptor[16] = 0;
+        // digital separate sync, hsync positive, vsync negative as required by CVT-RB
+        descriptor[17] = 0x1a;
+    }
+}
+
+/// Information used to generate the EDID of one scanout.
+#[derive(Debug, Copy, Clone)]
+pub struct EdidInfo {
+    pub width:         u32,
+    pub height:        u32,
+    pub refresh_rate:  u32,
+    /// `(min, max)` refresh rates of a variable refresh rate display
+    pub adaptive_sync: Option<(u32, u32)>,
+    /// Serial number telling the displays of a guest apart, 0 for none
+    pub serial:        u32,
+}
+
+impl EdidInfo {
+    /// Fails for modes a detailed timing descriptor can't carry, 4096 pixels wide or high and
+    /// larger.
+    pub fn new(width: u32, height: u32, refresh_rate: u32) -> Result<EdidInfo, EdidError> {
+        let info = EdidInfo {
+            width,
+            height,
+            refresh_rate,
+            adaptive_sync: None,
+            serial: 0,
+        };
+        if !info.preferred_timing().fits_descriptor() {
+            return Err(EdidError::UnsupportedMode { width, height });
+        }
+        Ok(info)
+    }
+
+    /// Gives the display serial number `serial`.
+    pub fn with_serial(self, serial: u32) -> EdidInfo {
+        EdidInfo { serial, ..self }
+    }
+
+    /// Advertises a display refreshing at any rate between `min` and `max` Hz.
+    pub fn with_adaptive_sync(self, min: u32, max: u32) -> EdidInfo {
+        EdidInfo {
+            adaptive_sync: Some((min, max)),
+            ..self
+        }
+    }
+
+    /// Vertical rates of the range limits descriptor.  A variable refresh rate range is kept as
+    /// is, the guest driver takes it as the range it may present at.
+    fn vertical_rate_range(&self) -> (u32, u32) {
+        match self.adaptive_sync {
+            Some((min, max)) => (min.min(self.refresh_rate), max.max(self.refresh_rate)),
+            None => {
+                let max = HIGH_REFRESH_RATES
+                    .iter()
+                    .cloned()
+                    .fold(self.refresh_rate, u32::max);
+                (MIN_VERTICAL_RATE, max)
+            }
+        }
+    }
+
+    /// Physical size in millimeters, derived from a 96 DPI panel.
+    fn size_mm(&self) -> (u32, u32) {
+        (self.width * 254 / (DEFAULT_DPI * 10), self.height * 254 / (DEFAULT_DPI * 10))
+    }
+
+    /// The preferred timing of the display.  Modes whose pixel clock can't be described by a
+    /// detailed timing descriptor fall back to a lower refresh rate.
+    fn preferred_timing(&self) -> DisplayTiming {
+        let timing = DisplayTiming::cvt_rb(self.width, self.height, self.refresh_rate);
+        if timing.fits_descriptor() {
+            timing
+        } else {
+            DisplayTiming::cvt_rb(self.width, self.height, FALLBACK_REFRESH_RATE)
+        }
+    }
+
+    /// Additional timings put in the CEA extension block: UHD when the display is at least that
+    /// large and high refresh rate variants of the preferred mode.
+    fn extension_timings(&self) -> Vec<DisplayTiming> {
+        let mut modes = Vec::new();
+        if self.width >= UHD_MODE.0 && self.height >= UHD_MODE.1 {
+            modes.push(UHD_MODE);
+        }
+        let (_, max_vertical_rate) = self.vertical_rate_range();
+        for &refresh_rate in HIGH_REFRESH_RATES.iter() {
+            if refresh_rate > self.refresh_rate && refresh_rate <= max_vertical_rate {
+                modes.push((self.width, self.height, refresh_rate));
+            }
+        }
+
+        let mut timings: Vec<DisplayTiming> = Vec::new();
+        for (width, height, refresh_rate) in modes {
+            let timing = DisplayTiming::cvt_rb(width, height, refresh_rate);
+            if timing.fits_descriptor() && !timings.contains(&timing) {
+                timings.push(timing);
+            }
+        }
+        timings.truncate(CEA_MAX_DTDS);
+        timings
+    }
+
+    /// Generates the EDID blob: the base block, followed by a CEA-861 extension block when there
+    /// are additional modes to advertise.
+    pub fn generate(&self) -> Vec<u8> {
+        let preferred = self.preferred_timing();
+        let extension_timings = self.extension_timings();
+
+        let mut max_timing = preferred;
+        for timing in &extension_timings {
+            if timing.pixel_clock_khz > max_timing.pixel_clock_khz {
+                max_timing = *timing;
+            }
+        }
+
+        let mut edid = vec![0u8; EDID_BLOCK_SIZE];
+        self.encode_base_block(&preferred, &max_timing, &mut edid);
+        if !extension_timings.is_empty() {
+            edid[126] = 1;
+            let mut extension = [0u8; EDID_BLOCK_SIZE];
+            self.encode_cea_block(&extension_timings, &mut extension);
+            edid.extend_from_slice(&extension);
+        }
+        fix_checksums(&mut edid);
+        edid
+    }
+
+    fn encode_base_block(&self, preferred: &DisplayTiming, max_timing: &DisplayTiming, block: &mut [u8]) {
+        let (h_mm, v_mm) = self.size_mm();
+
+        block[0..8].copy_from_slice(&EDID_HEADER);
+        // manufacturer id, three 5 bits letters big endian
+        let id = MANUFACTURER_ID
+            .iter()
+            .fold(0u16, |acc, c| (acc << 5) | (*c - b'A' + 1) as u16);
+        block[8..10].copy_from_slice(&id.to_be_bytes());
+        block[10..12].copy_from_slice(&PRODUCT_CODE.to_le_bytes());
+        block[12..16].copy_from_slice(&self.serial.to_le_bytes());
+        // no manufacture week
+        block[16] = 0;
+        // year of manufacture, 2021
+        block[17] = (2021 - 1990) as u8;
+        // EDID 1.4
+        block[18] = 1;
+        block[19] = 4;
+        // digital input, 8 bits per color, DisplayPort
+        block[20] = 0xa5;
+        block[21] = (h_mm / 10).min(0xff) as u8;
+        block[22] = (v_mm / 10).min(0xff) as u8;
+        // gamma 2.2
+        block[23] = 0x78;
+        block[24] = match self.adaptive_sync {
+            Some(_) => FEATURES | FEATURE_CONTINUOUS_FREQUENCY,
+            None => FEATURES,
+        };
+        // sRGB chromaticity
+        block[25..35].copy_from_slice(&[0xee, 0x91, 0xa3, 0x54, 0x4c, 0x99, 0x26, 0x0f, 0x50, 0x54]);
+        // no established timings, unused standard timings
+        for standard_timing in block[38..54].chunks_mut(2) {
+            standard_timing.copy_from_slice(&[0x01, 0x01]);
+        }
+
+        let mut descriptors = block[BASE_DESCRIPTORS_OFFSET..BASE_DESCRIPTORS_OFFSET + 4 * DESCRIPTOR_SIZE]
+            .chunks_mut(DESCRIPTOR_SIZE);
+        preferred.encode((h_mm, v_mm), descriptors.next().unwrap());
+        encode_range_limits(self.vertical_rate_range(), max_timing, descriptors.next().unwrap());
+        encode_text_descriptor(0xfc, MONITOR_NAME, descriptors.next().unwrap());
+        if self.serial != 0 {
+            let serial = format!("{:08}", self.serial);
+            encode_text_descriptor(0xff, serial.as_bytes(), descriptors.next().unwrap());
+        } else {
+            // dummy descriptor
+            descriptors.next().unwrap()[3] = 0x10;
+        }
+    }
+
+    fn encode_cea_block(&self, timings: &[DisplayTiming], block: &mut [u8]) {
+        let size_mm = self.size_mm();
+
+        block[0] = CEA_EXTENSION_TAG;
+        block[1] = CEA_EXTENSION_REVISION;
+        block[2] = CEA_DTD_OFFSET as u8;
+        block[3] = CEA_FLAGS_NO_AUDIO;
+        for (timing, descriptor) in timings
+            .iter()
+            .zip(block[CEA_DTD_OFFSET..EDID_BLOCK_SIZE - 1].chunks_mut(DESCRIPTOR_SIZE))
+        {
+            timing.encode(size_mm, descriptor);
+        }
+    }
+}
+
+/// Display range limits descriptor covering every advertised timing
+fn encode_range_limits(vertical_rates: (u32, u32), max_timing: &DisplayTiming, descriptor: &mut [u8]) {
+    let (min_refresh_rate, max_refresh_rate) = vertical_rates;
+
+    descriptor[3] = 0xfd;
+    descriptor[5] = min_refresh_rate.max(1).min(0xff) as u8;
+    descriptor[6] = max_refresh_rate.min(0xff) as u8;
+    descriptor[7] = 15;
+    descriptor[8] = (max_timing.h_freq_khz() + 1).min(0xff) as u8;
+    // maximum pixel clock in units of 10MHz, rounded up
+    descriptor[9] = ((max_timing.pixel_clock_khz + 9999) / 10000).min(0xff) as u8;
+    // range limits only, no secondary timing formula
+    descriptor[10] = 0x01;
+    descriptor[11] = 0x0a;
+    for byte in descriptor[12..].iter_mut() {
+        *byte = 0x20;
+    }
+}
+
+/// Text descriptor (monitor name, serial, ...), terminated with a line feed and space padded
+fn encode_text_descriptor(tag: u8, text: &[u8], descriptor: &mut [u8]) {
+    descriptor[3] = tag;
+    let text_area = &mut descriptor[5..];
+    for byte in text_area.iter_mut() {
+        *byte = 0x20;
+    }
+    let len = text.len().min(text_area.len() - 1);
+    text_area[..len].copy_from_slice(&text[..len]);
+    text_area[len] = 0x0a;
+}
+
+/// Computes the checksum byte making the sum of the block's bytes a multiple of 256.  The last
+/// byte of `block` is the checksum slot and is ignored.
+pub fn block_checksum(block: &[u8]) -> u8 {
+    let sum = block[..EDID_BLOCK_SIZE - 1]
+        .iter()
+        .fold(0u8, |acc, b| acc.wrapping_add(*b));
+    0u8.wrapping_sub(sum)
+}
+
+/// Rewrites the checksum byte of every complete block of `edid`.
+pub fn fix_checksums(edid: &mut [u8]) {
+    for block in edid.chunks_exact_mut(EDID_BLOCK_SIZE) {
+        block[EDID_BLOCK_SIZE - 1] = block_checksum(block);
+    }
+}
+
+/// An error found while validating an EDID blob.
+#[derive(Debug)]
+pub enum EdidError {
+    /// The EDID file couldn't be read.
+    Io(io::Error),
+    /// The blob length is not a non-zero multiple of the block size or is too large.
+    InvalidLength(usize),
+    /// The base block doesn't start with the fixed EDID header.
+    InvalidHeader,
+    /// The bytes of the given block don't sum up to zero.
+    InvalidChecksum { block: usize, expected: u8, found: u8 },
+    /// The extension count of the base block doesn't match the number of blocks.
+    ExtensionCountMismatch { declared: usize, found: usize },
+    /// The mode doesn't fit in a detailed timing descriptor.
+    UnsupportedMode { width: u32, height: u32 },
+}
+
+impl Display for EdidError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::EdidError::*;
+
+        match self {
+            Io(e) => write!(f, "failed to read EDID: {}", e),
+            InvalidLength(len) => write!(
+                f,
+                "invalid EDID length {}: must be a multiple of {} up to {} bytes",
+                len, EDID_BLOCK_SIZE, EDID_MAX_SIZE
+            ),
+            InvalidHeader => write!(f, "invalid EDID header"),
+            InvalidChecksum { block, expected, found } => write!(
+                f,
+                "invalid checksum in EDID block {}: expected {:#04x}, found {:#04x}",
+                block, expected, found
+            ),
+            ExtensionCountMismatch { declared, found } => write!(
+                f,
+                "EDID declares {} extension blocks but contains {}",
+                declared, found
+            ),
+            UnsupportedMode { width, height } => write!(f, "{}x{} can't be described in an EDID", width, height),
+        }
+    }
+}
+
+impl From<io::Error> for EdidError {
+    fn from(e: io::Error) -> Self {
+        EdidError::Io(e)
+    }
+}
+
+/// Checks the length, header, checksums and extension count of an EDID blob.
+pub fn validate_edid(edid: &[u8]) -> Result<(), EdidError> {
+    if edid.is_empty() || edid.len() % EDID_BLOCK_SIZE != 0 || edid.len() > EDID_MAX_SIZE {
+        return Err(EdidError::InvalidLength(edid.len()));
+    }
+
+    if edid[..EDID_HEADER.len()] != EDID_HEADER {
+        return Err(EdidError::InvalidHeader);
+    }
+
+    for (index, block) in edid.chunks_exact(EDID_BLOCK_SIZE).enumerate() {
+        let expected = block_checksum(block);
+        let found = block[EDID_BLOCK_SIZE - 1];
+        if expected != found {
+            return Err(EdidError::InvalidChecksum {
+                block: index,
+                expected,
+                found,
+            });
+        }
+    }
+
+    let declared = edid[126] as usize;
+    let found = edid.len() / EDID_BLOCK_SIZE - 1;
+    if declared != found {
+        return Err(EdidError::ExtensionCountMismatch { declared, found });
+    }
+
+    Ok(())
+}
+
+/// Reads a user supplied EDID file and validates it.
+pub fn load_edid_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, EdidError> {
+    let edid = fs::read(path)?;
+    validate_edid(&edid)?;
+    Ok(edid)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::edid::*;
+
+    fn find_timing(block: &[u8], width: u32, height: u32) -> bool {
+        block[CEA_DTD_OFFSET..EDID_BLOCK_SIZE - 1]
+            .chunks(DESCRIPTOR_SIZE)
+            .filter(|d| d.len() == DESCRIPTOR_SIZE && (d[0] != 0 || d[1] != 0))
+            .any(|d| {
+                let h_active = d[2] as u32 | ((d[4] as u32 >> 4) << 8);
+                let v_active = d[5] as u32 | ((d[7] as u32 >> 4) << 8);
+                h_active == width && v_active == height
+            })
+    }
+
+    #[test]
+    fn test_cvt_rb_uhd() {
+        let timing = DisplayTiming::cvt_rb(3840, 2160, 60);
+        assert_eq!(timing.pixel_clock_khz, 533_250);
+        assert_eq!(timing.v_blank, 62);
+        assert!(timing.fits_descriptor());
+    }
+
+    #[test]
+    fn test_generate_edid() {
+        let edid = EdidInfo::new(3840, 2160, 60).unwrap().generate();
+        assert_eq!(edid.len(), 2 * EDID_BLOCK_SIZE);
+        assert_eq!(edid[0..8], EDID_HEADER);
+        assert_eq!(edid[126], 1);
+        for block in edid.chunks(EDID_BLOCK_SIZE) {
+            let sum = block.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+            assert_eq!(sum, 0);
+        }
+
+        let cea = &edid[EDID_BLOCK_SIZE..];
+        assert_eq!(cea[0], CEA_EXTENSION_TAG);
+        assert_eq!(cea[3] & 0x40, 0);
+        assert!(find_timing(cea, 3840, 2160));
+    }
+
+    #[test]
+    fn test_generate_edid_uhd_extension() {
+        let edid = EdidInfo::new(4000, 2400, 30).unwrap().generate();
+        assert!(find_timing(&edid[EDID_BLOCK_SIZE..], 3840, 2160));
+
+        let edid = EdidInfo::new(1920, 1080, 60).unwrap().generate();
+        assert!(!find_timing(&edid[EDID_BLOCK_SIZE..], 3840, 2160));
+        assert!(find_timing(&edid[EDID_BLOCK_SIZE..], 1920, 1080));
+    }
+
+    #[test]
+    fn test_generate_edid_adaptive_sync() {
+        let edid = EdidInfo::new(1920, 1080, 60).unwrap().generate();
+        assert_eq!(edid[24] & FEATURE_CONTINUOUS_FREQUENCY, 0);
+
+        let edid = EdidInfo::new(1920, 1080, 60).unwrap().with_adaptive_sync(48, 120).generate();
+        assert!(validate_edid(&edid).is_ok());
+        assert_eq!(edid[24] & FEATURE_CONTINUOUS_FREQUENCY, FEATURE_CONTINUOUS_FREQUENCY);
+        let range_limits = &edid[BASE_DESCRIPTORS_OFFSET + DESCRIPTOR_SIZE..][..DESCRIPTOR_SIZE];
+        assert_eq!(range_limits[3], 0xfd);
+        assert_eq!((range_limits[5], range_limits[6]), (48, 120));
+        // 144Hz is outside of the range the display can present at
+        let timings = EdidInfo::new(1920, 1080, 60).unwrap().with_adaptive_sync(48, 120).extension_timings();
+        assert_eq!(timings, vec![DisplayTiming::cvt_rb(1920, 1080, 120)]);
+    }
+
+    #[test]
+    fn test_unsupported_mode() {
+        // the descriptor has 12 bits for the active pixels
+        assert!(matches!(
+            EdidInfo::new(4096, 2160, 60),
+            Err(EdidError::UnsupportedMode { width: 4096, height: 2160 })
+        ));
+        assert!(EdidInfo::new(2160, 4096, 60).is_err());
+        // a pixel clock too high falls back to a lower refresh rate instead
+        let info = EdidInfo::new(4000, 3000, 60).unwrap();
+        assert_eq!(info.preferred_timing(), DisplayTiming::cvt_rb(4000, 3000, FALLBACK_REFRESH_RATE));
+    }
+
+    #[test]
+    fn test_validate_edid() {
+        let mut edid = EdidInfo::new(1920, 1080, 60).unwrap().generate();
+        assert!(validate_edid(&edid).is_ok());
+
+        assert!(matches!(validate_edid(&edid[..100]), Err(EdidError::InvalidLength(100))));
+
+        edid[20] ^= 0xff;
+        assert!(matches!(
+            validate_edid(&edid),
+            Err(EdidError::InvalidChecksum { block: 0, .. })
+        ));
+        fix_checksums(&mut edid);
+        assert!(validate_edid(&edid).is_ok());
+
+        edid[126] = 2;
+        fix_checksums(&mut edid);
+        assert!(matches!(
+            validate_edid(&edid),
+            Err(EdidError::ExtensionCountMismatch { declared: 2, found: 1 })
+        ));
+
+        edid[0] = 0xff;
+        assert!(matches!(validate_edid(&edid), Err(EdidError::InvalidHeader)));
+    }
+
+    #[test]
+    fn test_generate_edid_serial() {
+        let edid = EdidInfo::new(1280, 800, 75).unwrap().with_serial(2).generate();
+        assert!(validate_edid(&edid).is_ok());
+        assert_eq!(edid[12..16], [2, 0, 0, 0]);
+        let serial = &edid[BASE_DESCRIPTORS_OFFSET + 3 * DESCRIPTOR_SIZE..][..DESCRIPTOR_SIZE];
+        assert_eq!(serial[3], 0xff);
+        assert_eq!(serial[5..14], *b"00000002\n");
+
+        // the preferred mode is the configured one
+        let preferred = &edid[BASE_DESCRIPTORS_OFFSET..][..DESCRIPTOR_SIZE];
+        let timing = DisplayTiming::cvt_rb(1280, 800, 75);
+        assert_eq!(u16::from_le_bytes([preferred[0], preferred[1]]) as u32, timing.pixel_clock_khz / 10);
+        assert_eq!(EdidInfo::new(1280, 800, 75).unwrap().generate()[12..16], [0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,166 @@
+// One epoll for everything the daemon waits on: the frontend's socket, the vrings' kicks and the
+// device's event sources, the display events, the renderer's fences and the frame timer.  The
+// caller says what it wants watched before every wait, so vrings coming and going and fences
+// switching to the renderer's sync thread are picked up without it tracking registrations.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+use vmm_sys_util::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+
+use crate::virtio_gpu::GpuEventSource;
+
+/// What a watched descriptor stands for.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EventToken {
+    /// the vhost-user socket of the frontend
+    Frontend,
+    /// the kick of the vring of this index
+    Kick(usize),
+    /// an event source of the device, handled by `VirtioGpu::handle_event`
+    Device(GpuEventSource),
+}
+
+impl EventToken {
+    fn to_u64(self) -> u64 {
+        match self {
+            EventToken::Frontend => 0,
+            EventToken::Kick(index) => 1 << 32 | index as u64,
+            EventToken::Device(GpuEventSource::Display) => 2 << 32,
+            EventToken::Device(GpuEventSource::Fences) => 2 << 32 | 1,
+            EventToken::Device(GpuEventSource::FrameTimer) => 2 << 32 | 2,
+        }
+    }
+
+    fn from_u64(data: u64) -> Option<Self> {
+        let index = data & 0xffff_ffff;
+        Some(match (data >> 32, index) {
+            (0, 0) => EventToken::Frontend,
+            (1, _) => EventToken::Kick(index as usize),
+            (2, 0) => EventToken::Device(GpuEventSource::Display),
+            (2, 1) => EventToken::Device(GpuEventSource::Fences),
+            (2, 2) => EventToken::Device(GpuEventSource::FrameTimer),
+            _ => return None,
+        })
+    }
+}
+
+/// The device's event sources as the tokens to watch them with.
+pub fn device_sources(sources: Vec<(GpuEventSource, RawFd)>) -> impl Iterator<Item = (EventToken, RawFd)> {
+    sources.into_iter().map(|(source, fd)| (EventToken::Device(source), fd))
+}
+
+/// An epoll watching descriptors for reading, level triggered.
+pub struct EventLoop {
+    epoll:   Epoll,
+    watched: Vec<(EventToken, RawFd)>,
+}
+
+impl EventLoop {
+    pub fn new() -> io::Result<Self> {
+        Ok(EventLoop {
+            epoll:   Epoll::new()?,
+            watched: Vec::new(),
+        })
+    }
+
+    /// What is currently watched.
+    pub fn watched(&self) -> &[(EventToken, RawFd)] {
+        &self.watched
+    }
+
+    /// The descriptor watched for `token`.
+    pub fn fd(&self, token: EventToken) -> Option<RawFd> {
+        self.watched.iter().find(|&&(watched, _)| watched == token).map(|&(_, fd)| fd)
+    }
+
+    /// Watches exactly `wanted` from now on.  Descriptors watched before are registered again: a
+    /// descriptor closed and reopened under the same number, like a vring's kick replaced by the
+    /// frontend, left the epoll with its old file.
+    pub fn watch(&mut self, wanted: &[(EventToken, RawFd)]) -> io::Result<()> {
+        for &(token, fd) in self.watched.iter().filter(|watched| !wanted.contains(watched)) {
+            match self.epoll.ctl(ControlOperation::Delete, fd, EpollEvent::new(EventSet::IN, token.to_u64())) {
+                // closed, the epoll already forgot it
+                Err(ref e) if e.raw_os_error() == Some(libc::EBADF) || e.raw_os_error() == Some(libc::ENOENT) => {}
+                result => result?,
+            }
+        }
+        for &(token, fd) in wanted {
+            let event = || EpollEvent::new(EventSet::IN, token.to_u64());
+            let modified = if self.watched.contains(&(token, fd)) {
+                self.epoll.ctl(ControlOperation::Modify, fd, event())
+            } else {
+                Err(io::Error::from_raw_os_error(libc::ENOENT))
+            };
+            match modified {
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => self.epoll.ctl(ControlOperation::Add, fd, event())?,
+                result => result?,
+            }
+        }
+        self.watched = wanted.to_vec();
+        Ok(())
+    }
+
+    /// Waits for watched descriptors to be readable, for at most `timeout` if there is one, and
+    /// returns their tokens.  Returns nothing when interrupted by a signal.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<Vec<EventToken>> {
+        let timeout = timeout.map_or(-1, |timeout| timeout.as_millis().min(i32::MAX as u128) as i32);
+        let mut events = vec![EpollEvent::default(); self.watched.len().max(1)];
+        let count = match self.epoll.wait(timeout, &mut events) {
+            Ok(count) => count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(events[..count]
+            .iter()
+            .filter_map(|event| EventToken::from_u64(event.data()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::event_loop::*;
+    use std::os::unix::io::AsRawFd;
+    use vmm_sys_util::eventfd::EventFd;
+
+    #[test]
+    fn test_event_loop() {
+        let kick = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let fences = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        let mut event_loop = EventLoop::new().unwrap();
+        let wanted = [
+            (EventToken::Kick(1), kick.as_raw_fd()),
+            (EventToken::Device(GpuEventSource::Fences), fences.as_raw_fd()),
+        ];
+        event_loop.watch(&wanted).unwrap();
+        assert!(event_loop.wait(Some(Duration::from_millis(0))).unwrap().is_empty());
+
+        kick.write(1).unwrap();
+        fences.write(1).unwrap();
+        let mut events = event_loop.wait(None).unwrap();
+        events.sort_by_key(|&token| token.to_u64());
+        assert_eq!(events, vec![EventToken::Kick(1), EventToken::Device(GpuEventSource::Fences)]);
+        // level triggered, still readable
+        assert_eq!(event_loop.wait(None).unwrap().len(), 2);
+
+        // the kick is dropped, watching the same set again is fine
+        event_loop.watch(&wanted[1..]).unwrap();
+        event_loop.watch(&wanted[1..]).unwrap();
+        assert_eq!(event_loop.fd(EventToken::Kick(1)), None);
+        assert_eq!(event_loop.wait(None).unwrap(), vec![EventToken::Device(GpuEventSource::Fences)]);
+        fences.read().unwrap();
+        assert!(event_loop.wait(Some(Duration::from_millis(0))).unwrap().is_empty());
+
+        // replaced by another file, usually under the same number
+        event_loop.watch(&[(EventToken::Kick(1), kick.as_raw_fd())]).unwrap();
+        drop(kick);
+        let kick = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+        event_loop.watch(&[(EventToken::Kick(1), kick.as_raw_fd())]).unwrap();
+        assert!(event_loop.wait(Some(Duration::from_millis(0))).unwrap().is_empty());
+        kick.write(1).unwrap();
+        assert_eq!(event_loop.wait(None).unwrap(), vec![EventToken::Kick(1)]);
+        assert_eq!(EventToken::from_u64(3 << 32), None);
+    }
+}
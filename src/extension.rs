@@ -0,0 +1,120 @@
+// Commands outside of the virtio-gpu specification, registered by the embedder.
+//
+// Forks carrying experimental virtio-gpu commands register them here instead of patching
+// `VirtioGpuCommand`: each command type comes with its struct, which gives the number of bytes
+// read from the guest, and the handler run on the device.  Commands nobody registered go to the
+// unknown command handler, which answers ERR_UNSPEC unless the embedder replaces it.
+
+use std::collections::BTreeMap;
+use std::mem::size_of;
+
+use vm_memory::{ByteValued, GuestAddress, GuestMemoryMmap};
+
+use crate::protocol::{is_standard_command, virtio_gpu_ctrl_hdr, VirtioGpuResponse, VirtioGpuResponseResult};
+use crate::virtio_gpu::VirtioGpu;
+
+#[derive(Debug)]
+pub enum ExtensionError {
+    /// the command type is already decoded by `VirtioGpuCommand::decode`
+    StandardCommand(u32),
+    /// the command type was registered twice
+    AlreadyRegistered(u32),
+}
+
+type ExtensionHandler = Box<dyn FnMut(&mut VirtioGpu, &[u8]) -> VirtioGpuResponseResult>;
+
+/// Called with the header of a command no extension handles and the guest memory holding it.
+pub type UnknownCommandHandler =
+    Box<dyn FnMut(virtio_gpu_ctrl_hdr, &GuestMemoryMmap, GuestAddress) -> VirtioGpuResponseResult>;
+
+struct CommandExtension {
+    size:    usize,
+    handler: ExtensionHandler,
+}
+
+/// The extension commands of a `VirtioGpu`, see `VirtioGpu::with_extensions`.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    commands: BTreeMap<u32, CommandExtension>,
+    unknown:  Option<UnknownCommandHandler>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Registers the command `type_`, whose request is a `T` starting with a
+    /// `virtio_gpu_ctrl_hdr`.
+    pub fn register<T, F>(&mut self, type_: u32, mut handler: F) -> Result<(), ExtensionError>
+    where
+        T: ByteValued,
+        F: FnMut(&mut VirtioGpu, T) -> VirtioGpuResponseResult + 'static,
+    {
+        self.register_raw(type_, size_of::<T>(), move |gpu, buf| {
+            let mut cmd = T::default();
+            cmd.as_mut_slice().copy_from_slice(buf);
+            handler(gpu, cmd)
+        })
+    }
+
+    /// Registers the command `type_` taking `size` bytes, header included, decoded by the
+    /// handler itself.
+    pub fn register_raw<F>(&mut self, type_: u32, size: usize, handler: F) -> Result<(), ExtensionError>
+    where
+        F: FnMut(&mut VirtioGpu, &[u8]) -> VirtioGpuResponseResult + 'static,
+    {
+        if is_standard_command(type_) {
+            return Err(ExtensionError::StandardCommand(type_));
+        }
+        if self.commands.contains_key(&type_) {
+            return Err(ExtensionError::AlreadyRegistered(type_));
+        }
+        self.commands.insert(type_, CommandExtension {
+            size,
+            handler: Box::new(handler),
+        });
+        Ok(())
+    }
+
+    /// Handles the commands that are neither standard nor registered, instead of answering
+    /// ERR_UNSPEC.
+    pub fn set_unknown_command_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(virtio_gpu_ctrl_hdr, &GuestMemoryMmap, GuestAddress) -> VirtioGpuResponseResult + 'static,
+    {
+        self.unknown = Some(Box::new(handler));
+    }
+
+    /// Size of the request of the command `type_`, `None` if it isn't registered.
+    pub fn command_size(&self, type_: u32) -> Option<usize> {
+        self.commands.get(&type_).map(|command| command.size)
+    }
+
+    /// Runs the handler of the command `type_`, `None` if it isn't registered.  `buf` must hold
+    /// at least `command_size(type_)` bytes.
+    pub(crate) fn handle(
+        &mut self,
+        gpu: &mut VirtioGpu,
+        type_: u32,
+        buf: &[u8],
+    ) -> Option<VirtioGpuResponseResult> {
+        let command = self.commands.get_mut(&type_)?;
+        if buf.len() < command.size {
+            return Some(Err(VirtioGpuResponse::ErrInvalidParameter));
+        }
+        Some((command.handler)(gpu, &buf[..command.size]))
+    }
+
+    pub(crate) fn handle_unknown(
+        &mut self,
+        hdr: virtio_gpu_ctrl_hdr,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+    ) -> VirtioGpuResponseResult {
+        match self.unknown {
+            Some(ref mut handler) => handler(hdr, mem, addr),
+            None => Err(VirtioGpuResponse::ErrUnspec),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+// Deterministic fault injection, used to exercise the error paths of this crate and the error
+// handling of guest drivers without depending on real renderer or display failures.  The hooks in
+// `VirtioGpu` are only compiled with the `fault-injection` feature.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A failure that can be forced on a command.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Fault {
+    /// The renderer runs out of memory while creating a resource, attaching backing or running a
+    /// command stream; the command fails with `ErrOutOfMemory`.
+    RendererOom,
+    /// Presenting a resource on the display fails; the flush fails with `ErrUnspec`.
+    DisplayFlip,
+    /// A fence is accepted but never signalled.
+    FenceTimeout,
+}
+
+/// Counts the commands reaching each fault's path and tells which of them must fail.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    seen:  BTreeMap<Fault, u64>,
+    armed: BTreeMap<Fault, BTreeSet<u64>>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Fails the `nth` command (starting at 1) reaching the path of `fault` from now on.
+    pub fn inject(&mut self, fault: Fault, nth: u64) {
+        let seen = self.seen.get(&fault).cloned().unwrap_or(0);
+        self.armed
+            .entry(fault)
+            .or_insert_with(BTreeSet::new)
+            .insert(seen + nth);
+    }
+
+    /// Disarms every pending fault.
+    pub fn clear(&mut self) {
+        self.armed.clear();
+    }
+
+    /// Records one more command on the path of `fault`, returning true if it must fail.
+    pub fn hit(&mut self, fault: Fault) -> bool {
+        let seen = self.seen.entry(fault).or_insert(0);
+        *seen += 1;
+        self.armed
+            .get_mut(&fault)
+            .map(|armed| armed.remove(seen))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::fault_injection::{Fault, FaultInjector};
+
+    #[test]
+    fn test_fault_on_nth_command() {
+        let mut faults = FaultInjector::new();
+        faults.inject(Fault::RendererOom, 2);
+        faults.inject(Fault::DisplayFlip, 1);
+
+        assert!(!faults.hit(Fault::RendererOom));
+        assert!(faults.hit(Fault::RendererOom));
+        assert!(!faults.hit(Fault::RendererOom));
+        assert!(faults.hit(Fault::DisplayFlip));
+        assert!(!faults.hit(Fault::FenceTimeout));
+
+        // counted from the time the fault is injected
+        faults.inject(Fault::RendererOom, 1);
+        assert!(faults.hit(Fault::RendererOom));
+
+        faults.inject(Fault::FenceTimeout, 1);
+        faults.clear();
+        assert!(!faults.hit(Fault::FenceTimeout));
+    }
+}
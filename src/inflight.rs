@@ -0,0 +1,245 @@
+// Chains in flight tracked in memory the frontend keeps across reconnections, with the
+// INFLIGHT_SHMFD protocol feature.  The frontend asks for the region with GET_INFLIGHT_FD on the
+// first start and hands it back with SET_INFLIGHT_FD to the daemon that connects after a crash,
+// which resubmits the chains the previous one popped but never returned.
+//
+// The layout is libvhost-user's for split queues: per queue a header, then an entry per descriptor
+// of the table, marked while the chain of that head is in flight along with a counter giving the
+// order the chains were popped in.
+
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::FromRawFd;
+
+use vm_memory::{Address, ByteValued, Bytes, FileOffset, GuestAddress, GuestMemoryError, GuestMemoryMmap, Le16, Le64};
+
+/// Layout of the region, 0 in the header of a queue the frontend reset.
+pub const INFLIGHT_VERSION: u16 = 1;
+const INFLIGHT_ALIGNMENT: u64 = 64;
+// fields of `inflight_queue_header` written on their own
+const LAST_BATCH_HEAD_OFFSET: u64 = 12;
+const USED_IDX_OFFSET: u64 = 14;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct inflight_queue_header {
+    pub features:        Le64,
+    pub version:         Le16,
+    pub desc_num:        Le16,
+    /// the head last returned, in case the daemon died between returning it and clearing it
+    pub last_batch_head: Le16,
+    pub used_idx:        Le16,
+}
+
+unsafe impl ByteValued for inflight_queue_header {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct inflight_desc {
+    pub inflight: u8,
+    pub padding:  [u8; 5],
+    pub next:     Le16,
+    pub counter:  Le64,
+}
+
+unsafe impl ByteValued for inflight_desc {}
+
+/// Bytes tracking a queue of `queue_size` descriptors.
+pub fn queue_region_size(queue_size: u16) -> u64 {
+    let size = size_of::<inflight_queue_header>() as u64 + queue_size as u64 * size_of::<inflight_desc>() as u64;
+    (size + INFLIGHT_ALIGNMENT - 1) & !(INFLIGHT_ALIGNMENT - 1)
+}
+
+/// The region shared with the frontend, one area per queue.
+pub struct InflightRegion {
+    mem:        GuestMemoryMmap,
+    num_queues: u16,
+    queue_size: u16,
+}
+
+impl InflightRegion {
+    /// Maps the region the frontend passed, `offset` bytes into `file`.
+    pub fn new(file: File, offset: u64, num_queues: u16, queue_size: u16) -> io::Result<Self> {
+        let size = num_queues as u64 * queue_region_size(queue_size);
+        if size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty inflight region"));
+        }
+        let ranges = [(GuestAddress(0), size as usize, Some(FileOffset::new(file, offset)))];
+        let mem = GuestMemoryMmap::from_ranges_with_files(&ranges)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+        Ok(InflightRegion {
+            mem,
+            num_queues,
+            queue_size,
+        })
+    }
+
+    /// Creates a region for the frontend to keep, returned along with its descriptor.
+    pub fn create(num_queues: u16, queue_size: u16) -> io::Result<(Self, File)> {
+        let name = CString::new("vhost-gpu-inflight").unwrap();
+        // Safe because the name is NUL terminated and the returned fd is checked.
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because the fd was just created and nothing else owns it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(num_queues as u64 * queue_region_size(queue_size))?;
+        let region = InflightRegion::new(file.try_clone()?, 0, num_queues, queue_size)?;
+        Ok((region, file))
+    }
+
+    pub fn size(&self) -> u64 {
+        self.num_queues as u64 * queue_region_size(self.queue_size)
+    }
+
+    /// The area tracking the `index` queue.
+    pub fn queue(&self, index: usize) -> Option<InflightQueue> {
+        if index >= self.num_queues as usize {
+            return None;
+        }
+        Some(InflightQueue {
+            mem:      self.mem.clone(),
+            base:     GuestAddress(index as u64 * queue_region_size(self.queue_size)),
+            size:     self.queue_size,
+            counter:  0,
+            resubmit: VecDeque::new(),
+        })
+    }
+}
+
+/// The chains in flight on a queue, marked as the device pops and returns them.
+pub struct InflightQueue {
+    mem:      GuestMemoryMmap,
+    base:     GuestAddress,
+    size:     u16,
+    counter:  u64,
+    /// heads left in flight by the previous daemon, to be processed before the available ring
+    resubmit: VecDeque<u16>,
+}
+
+impl InflightQueue {
+    fn header(&self) -> Result<inflight_queue_header, GuestMemoryError> {
+        self.mem.read_obj(self.base)
+    }
+
+    fn desc_addr(&self, head: u16) -> GuestAddress {
+        self.base
+            .unchecked_add(size_of::<inflight_queue_header>() as u64 + head as u64 * size_of::<inflight_desc>() as u64)
+    }
+
+    fn set_inflight(&self, head: u16, inflight: bool) -> Result<(), GuestMemoryError> {
+        self.mem.write_obj(inflight as u8, self.desc_addr(head))
+    }
+
+    /// Marks the chain of `head` as popped.
+    pub fn get(&mut self, head: u16) -> Result<(), GuestMemoryError> {
+        if head >= self.size {
+            return Ok(());
+        }
+        let desc = inflight_desc {
+            inflight: 1,
+            counter: Le64::from(self.counter),
+            ..Default::default()
+        };
+        self.counter += 1;
+        self.mem.write_obj(desc, self.desc_addr(head))
+    }
+
+    /// Records `head` is about to be returned, before the used ring is written.
+    pub fn pre_put(&self, head: u16) -> Result<(), GuestMemoryError> {
+        self.mem.write_obj(Le16::from(head), self.base.unchecked_add(LAST_BATCH_HEAD_OFFSET))
+    }
+
+    /// Clears `head` once it was returned and the used index moved to `used_idx`.
+    pub fn post_put(&self, head: u16, used_idx: u16) -> Result<(), GuestMemoryError> {
+        if head < self.size {
+            self.set_inflight(head, false)?;
+        }
+        self.mem.write_obj(Le16::from(used_idx), self.base.unchecked_add(USED_IDX_OFFSET))
+    }
+
+    /// Picks up what the previous daemon left in flight, given the index of the used ring.
+    /// Returns the index of the available ring to carry on from, the chains in flight were popped
+    /// but not returned, or `None` on a fresh region.
+    pub fn restore(&mut self, used_idx: u16) -> Result<Option<u16>, GuestMemoryError> {
+        let mut header = self.header()?;
+        self.counter = 0;
+        self.resubmit.clear();
+        // never used, or reset by the frontend along with the device
+        if header.version.to_native() == 0 {
+            header.version = Le16::from(INFLIGHT_VERSION);
+            header.desc_num = Le16::from(self.size);
+            header.used_idx = Le16::from(used_idx);
+            self.mem.write_obj(header, self.base)?;
+            return Ok(None);
+        }
+        // the daemon died after returning the last batch but before clearing it
+        if header.used_idx.to_native() != used_idx {
+            let last_batch_head = header.last_batch_head.to_native();
+            if last_batch_head < self.size {
+                self.set_inflight(last_batch_head, false)?;
+            }
+            header.used_idx = Le16::from(used_idx);
+            self.mem.write_obj(header, self.base)?;
+        }
+
+        let mut inflight = Vec::new();
+        for head in 0..self.size {
+            let desc: inflight_desc = self.mem.read_obj(self.desc_addr(head))?;
+            if desc.inflight == 1 {
+                inflight.push((desc.counter.to_native(), head));
+            }
+        }
+        inflight.sort_unstable();
+        self.counter = inflight.last().map_or(0, |&(counter, _)| counter + 1);
+        self.resubmit = inflight.iter().map(|&(_, head)| head).collect();
+        Ok(Some(used_idx.wrapping_add(self.resubmit.len() as u16)))
+    }
+
+    /// The next head to resubmit, if the previous daemon left any in flight.
+    pub fn next_resubmit(&mut self) -> Option<u16> {
+        self.resubmit.pop_front()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::inflight::*;
+
+    #[test]
+    fn test_inflight_queue() {
+        assert_eq!(queue_region_size(256), 4160);
+        let (region, file) = InflightRegion::create(2, 4).unwrap();
+        assert_eq!(region.size(), 2 * 128);
+        assert!(region.queue(2).is_none());
+
+        let mut queue = region.queue(1).unwrap();
+        assert_eq!(queue.restore(0).unwrap(), None);
+        for head in &[2, 0, 3] {
+            queue.get(*head).unwrap();
+        }
+        queue.pre_put(0).unwrap();
+        queue.post_put(0, 1).unwrap();
+        // died returning 3, after the used ring moved
+        queue.pre_put(3).unwrap();
+
+        // the next daemon maps what the frontend kept
+        let region = InflightRegion::new(file, 0, 2, 4).unwrap();
+        let mut queue = region.queue(1).unwrap();
+        assert_eq!(queue.restore(2).unwrap(), Some(3));
+        assert_eq!(queue.next_resubmit(), Some(2));
+        assert_eq!(queue.next_resubmit(), None);
+        queue.get(1).unwrap();
+        let desc: inflight_desc = queue.mem.read_obj(queue.desc_addr(1)).unwrap();
+        assert_eq!(desc.counter.to_native(), 1);
+
+        // the other queue was never started
+        let mut queue = region.queue(0).unwrap();
+        assert_eq!(queue.restore(5).unwrap(), None);
+        assert_eq!(queue.next_resubmit(), None);
+    }
+}
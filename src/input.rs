@@ -0,0 +1,100 @@
+// Input of the display window for the guest.  The display backends translate the events of the
+// window to `virtio_input_event`s and write them, each report followed by a SYN_REPORT, on the
+// socket of an event device of the matching kind.  `VirtioGpu::add_input_device` gives the
+// embedder the other end of that socket as an `InputReceiver`, and the embedder passes the events
+// as they are to a virtio-input device of the guest, which stays the VMM's.
+//
+// Only the X display produces input so far, and only for keyboards and touchscreens: keys go to
+// the keyboards, the left button and the motion while it is held to the touchscreens as a single
+// touch.  A mouse device gets nothing, and the other displays close the socket right away.  The
+// devices go away with the connection to the display server as well, the receiver then sees the
+// socket closed and the device can be added again once the display is back.
+
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use linux_input_sys::{virtio_input_event, InputEventDecoder};
+
+/// The embedder's end of an input device of the display window.
+pub struct InputReceiver {
+    stream:  UnixStream,
+    /// Bytes of an event not completely read yet
+    partial: Vec<u8>,
+    closed:  bool,
+}
+
+impl InputReceiver {
+    pub(crate) fn new(stream: UnixStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(InputReceiver {
+            stream,
+            partial: Vec::new(),
+            closed: false,
+        })
+    }
+
+    /// Reads the events the display sent, without blocking.  Fails with `UnexpectedEof` once the
+    /// display dropped the device and every event was received.
+    pub fn try_recv(&mut self) -> io::Result<Vec<virtio_input_event>> {
+        let mut buf = [0u8; 16 * virtio_input_event::SIZE];
+        while !self.closed {
+            match self.stream.read(&mut buf) {
+                Ok(0) => self.closed = true,
+                Ok(len) => self.partial.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let complete = self.partial.len() - self.partial.len() % virtio_input_event::SIZE;
+        if complete == 0 && self.closed {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the display dropped the input device"));
+        }
+        let events = self.partial[..complete]
+            .chunks_exact(virtio_input_event::SIZE)
+            .map(virtio_input_event::decode)
+            .collect();
+        self.partial.drain(..complete);
+        Ok(events)
+    }
+}
+
+/// Readable while events wait, to poll the receiver along with the rest of the VMM.
+impl AsRawFd for InputReceiver {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::input::*;
+    use data_model::DataInit;
+    use gpu_display::EventDevice;
+    use std::io::Write;
+
+    #[test]
+    fn test_input_receiver() {
+        let (display_end, embedder_end) = UnixStream::pair().unwrap();
+        let mut device = EventDevice::keyboard(display_end.try_clone().unwrap());
+        let mut receiver = InputReceiver::new(embedder_end).unwrap();
+        assert!(receiver.try_recv().unwrap().is_empty());
+
+        // a report is followed by SYN_REPORT
+        device.send_report(vec![virtio_input_event::key(30, true)]).unwrap();
+        assert_eq!(receiver.try_recv().unwrap(), vec![virtio_input_event::key(30, true), virtio_input_event::syn()]);
+
+        // events split across reads are put back together
+        let event = virtio_input_event::key(30, false);
+        (&display_end).write_all(&event.as_slice()[..3]).unwrap();
+        assert!(receiver.try_recv().unwrap().is_empty());
+        (&display_end).write_all(&event.as_slice()[3..]).unwrap();
+        assert_eq!(receiver.try_recv().unwrap(), vec![event]);
+
+        drop(device);
+        drop(display_end);
+        assert_eq!(receiver.try_recv().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
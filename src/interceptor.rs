@@ -0,0 +1,46 @@
+// Hooks run around every command, for logging, latency measurement or policy enforcement without
+// touching the dispatcher.  Interceptors run in registration order before the command and in the
+// reverse order after it.
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use crate::protocol::{VirtioGpuCommand, VirtioGpuResponse, VirtioGpuResponseResult};
+
+pub trait CommandInterceptor {
+    /// Called before `cmd` runs.  Returning a result skips the command, and the interceptors
+    /// after this one, and answers the guest with it.
+    fn before(&mut self, _cmd: &VirtioGpuCommand) -> Option<VirtioGpuResponseResult> {
+        None
+    }
+
+    /// Called with the result of `cmd`, `elapsed` is the time spent in the command itself.
+    fn after(&mut self, _cmd: &VirtioGpuCommand, _result: &VirtioGpuResponseResult, _elapsed: Duration) {}
+}
+
+/// Rejects the listed command types with ERR_UNSPEC, e.g. to deny GET_EDID to a guest.
+#[derive(Debug, Default)]
+pub struct CommandFilter {
+    denied: BTreeSet<u32>,
+}
+
+impl CommandFilter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn deny(mut self, type_: u32) -> Self {
+        self.denied.insert(type_);
+        self
+    }
+}
+
+impl CommandInterceptor for CommandFilter {
+    fn before(&mut self, cmd: &VirtioGpuCommand) -> Option<VirtioGpuResponseResult> {
+        if self.denied.contains(&cmd.hdr().type_.to_native()) {
+            Some(Err(VirtioGpuResponse::ErrUnspec))
+        } else {
+            None
+        }
+    }
+}
@@ -1,6 +1,35 @@
+pub mod blob;
+pub mod capset;
+pub mod daemon;
+pub mod damage;
+pub mod display_thread;
+pub mod dmabuf;
+pub mod drm;
+pub mod edid;
+pub mod event_loop;
+pub mod extension;
+pub mod fault_injection;
+pub mod inflight;
+pub mod input;
+pub mod interceptor;
+pub mod migration;
+pub mod perfetto;
+pub mod postcopy;
+pub mod present;
+pub mod privileges;
+pub mod probe;
 pub mod protocol;
+pub mod queue;
+pub mod quirks;
+pub mod recorder;
+pub mod screenshot;
+pub mod shm;
+pub mod staging;
+pub mod vhost;
 pub mod virtio_gpu;
 pub mod virtio_utils;
+pub mod worker;
+pub mod yuv;
 
 pub use virtio_gpu::VirtioGpu;
 pub use protocol::VirtioGpuResponseResult;
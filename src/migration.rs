@@ -0,0 +1,290 @@
+// Device state moved from the source to the destination daemon of a live migration, through the
+// pipe the frontend passes with SET_DEVICE_STATE_FD.  Guest memory is migrated by the VMM, so only
+// what lives on the host is saved: the 2D resources and their host copy of the contents, where
+// their backings are in guest memory, the scanouts, the cursor and the fence counters.
+//
+// The 3D renderer keeps its state inside virglrenderer with no way to export it, devices that
+// negotiated VIRTIO_GPU_F_VIRGL refuse to be saved.
+
+use std::fmt::{self, Display};
+use std::io;
+
+use rutabaga_gfx::RutabagaError;
+use vm_memory::GuestAddress;
+
+/// "VGPU", first in every saved state.
+pub const STATE_MAGIC: u32 = 0x5550_4756;
+/// Layout of the saved state, bumped when it changes.
+pub const STATE_VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// the device runs the 3D renderer or holds resources only the renderer knows the contents of
+    Unsupported,
+    /// the saved state is truncated or of another layout
+    InvalidState(&'static str),
+    /// the backing of this resource lies outside of guest memory
+    InvalidBacking(u32),
+    /// the renderer failed to save or restore this resource
+    Renderer(u32, RutabagaError),
+    /// the state couldn't be moved through the frontend's descriptor
+    Io(io::Error),
+}
+
+impl Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::MigrationError::*;
+
+        match self {
+            Unsupported => write!(f, "the device state can't be migrated"),
+            InvalidState(what) => write!(f, "invalid device state: {}", what),
+            InvalidBacking(resource_id) => write!(f, "backing of resource {} is outside of guest memory", resource_id),
+            Renderer(resource_id, e) => write!(f, "failed to migrate resource {}: {}", resource_id, e),
+            Io(e) => write!(f, "failed to transfer the device state: {}", e),
+        }
+    }
+}
+
+impl From<io::Error> for MigrationError {
+    fn from(e: io::Error) -> Self {
+        MigrationError::Io(e)
+    }
+}
+
+impl From<MigrationError> for io::Error {
+    fn from(e: MigrationError) -> Self {
+        match e {
+            MigrationError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+/// A 2D resource as saved.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceState {
+    pub resource_id:      u32,
+    pub width:            u32,
+    pub height:           u32,
+    pub format:           u32,
+    /// guest ranges of the attached backing
+    pub backing:          Option<Vec<(GuestAddress, usize)>>,
+    /// the guest detached the backing and expects the contents back on the next attach
+    pub backing_detached: bool,
+    /// the host copy of the contents, in the renderer's layout
+    pub contents:         Vec<u8>,
+}
+
+/// Everything the destination needs to carry on where the source stopped.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceState {
+    pub scanouts:            Vec<(u32, u32)>,
+    pub events_read:         u32,
+    /// 0 when nothing is scanned out
+    pub scanout_resource_id: u32,
+    /// 0 when the cursor is hidden
+    pub cursor_resource_id:  u32,
+    pub cursor_position:     (u32, u32),
+    pub cursor_hotspot:      (u32, u32),
+    /// last fence signalled on each `(ctx_id, ring_idx)` ring
+    pub signalled_fences:    Vec<((u32, u32), u64)>,
+    pub resources:           Vec<ResourceState>,
+}
+
+struct StateWriter(Vec<u8>);
+
+impl StateWriter {
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+}
+
+struct StateReader<'a>(&'a [u8]);
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MigrationError> {
+        if self.0.len() < len {
+            return Err(MigrationError::InvalidState("truncated"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, MigrationError> {
+        let mut buf = [0u8; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64, MigrationError> {
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    /// A count of entries at least `entry_size` bytes long each, checked against what is left
+    /// so a corrupted count doesn't allocate the world.
+    fn count(&mut self, entry_size: usize) -> Result<usize, MigrationError> {
+        let count = self.u64()?;
+        if count > (self.0.len() / entry_size) as u64 {
+            return Err(MigrationError::InvalidState("truncated"));
+        }
+        Ok(count as usize)
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, MigrationError> {
+        let len = self.count(1)?;
+        Ok(self.take(len)?.to_vec())
+    }
+}
+
+impl DeviceState {
+    /// Serializes the state, little endian, behind `STATE_MAGIC` and `STATE_VERSION`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter(Vec::new());
+        w.u32(STATE_MAGIC);
+        w.u32(STATE_VERSION);
+        w.u64(self.scanouts.len() as u64);
+        for &(width, height) in &self.scanouts {
+            w.u32(width);
+            w.u32(height);
+        }
+        w.u32(self.events_read);
+        w.u32(self.scanout_resource_id);
+        w.u32(self.cursor_resource_id);
+        w.u32(self.cursor_position.0);
+        w.u32(self.cursor_position.1);
+        w.u32(self.cursor_hotspot.0);
+        w.u32(self.cursor_hotspot.1);
+        w.u64(self.signalled_fences.len() as u64);
+        for &((ctx_id, ring_idx), fence_id) in &self.signalled_fences {
+            w.u32(ctx_id);
+            w.u32(ring_idx);
+            w.u64(fence_id);
+        }
+        w.u64(self.resources.len() as u64);
+        for resource in &self.resources {
+            w.u32(resource.resource_id);
+            w.u32(resource.width);
+            w.u32(resource.height);
+            w.u32(resource.format);
+            w.u32(resource.backing_detached as u32);
+            match resource.backing {
+                Some(ref backing) => {
+                    w.u32(1);
+                    w.u64(backing.len() as u64);
+                    for &(addr, len) in backing {
+                        w.u64(addr.0);
+                        w.u64(len as u64);
+                    }
+                }
+                None => w.u32(0),
+            }
+            w.bytes(&resource.contents);
+        }
+        w.0
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, MigrationError> {
+        let mut r = StateReader(buf);
+        if r.u32()? != STATE_MAGIC {
+            return Err(MigrationError::InvalidState("not a virtio-gpu state"));
+        }
+        if r.u32()? != STATE_VERSION {
+            return Err(MigrationError::InvalidState("unknown version"));
+        }
+
+        let mut state = DeviceState::default();
+        for _ in 0..r.count(8)? {
+            state.scanouts.push((r.u32()?, r.u32()?));
+        }
+        state.events_read = r.u32()?;
+        state.scanout_resource_id = r.u32()?;
+        state.cursor_resource_id = r.u32()?;
+        state.cursor_position = (r.u32()?, r.u32()?);
+        state.cursor_hotspot = (r.u32()?, r.u32()?);
+        for _ in 0..r.count(16)? {
+            state.signalled_fences.push(((r.u32()?, r.u32()?), r.u64()?));
+        }
+        for _ in 0..r.count(32)? {
+            let mut resource = ResourceState {
+                resource_id: r.u32()?,
+                width: r.u32()?,
+                height: r.u32()?,
+                format: r.u32()?,
+                backing_detached: r.u32()? != 0,
+                ..Default::default()
+            };
+            if r.u32()? != 0 {
+                let mut backing = Vec::new();
+                for _ in 0..r.count(16)? {
+                    backing.push((GuestAddress(r.u64()?), r.u64()? as usize));
+                }
+                resource.backing = Some(backing);
+            }
+            resource.contents = r.bytes()?;
+            state.resources.push(resource);
+        }
+        if !r.0.is_empty() {
+            return Err(MigrationError::InvalidState("trailing bytes"));
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::migration::*;
+
+    #[test]
+    fn test_device_state() {
+        let state = DeviceState {
+            scanouts: vec![(1024, 768), (0, 0)],
+            events_read: 1,
+            scanout_resource_id: 2,
+            cursor_resource_id: 3,
+            cursor_position: (10, 20),
+            cursor_hotspot: (4, 4),
+            signalled_fences: vec![((0, 0), 7), ((1, 2), 3)],
+            resources: vec![
+                ResourceState {
+                    resource_id: 2,
+                    width: 2,
+                    height: 1,
+                    format: 1,
+                    backing: Some(vec![(GuestAddress(0x1000), 8)]),
+                    backing_detached: false,
+                    contents: vec![1, 2, 3, 4, 5, 6, 7, 8],
+                },
+                ResourceState {
+                    resource_id: 3,
+                    backing_detached: true,
+                    ..Default::default()
+                },
+            ],
+        };
+        let buf = state.to_bytes();
+        assert_eq!(DeviceState::from_bytes(&buf).unwrap(), state);
+
+        assert!(matches!(
+            DeviceState::from_bytes(&buf[..buf.len() - 1]),
+            Err(MigrationError::InvalidState(_))
+        ));
+        let mut trailing = buf.clone();
+        trailing.push(0);
+        assert!(matches!(DeviceState::from_bytes(&trailing), Err(MigrationError::InvalidState(_))));
+        let mut version = buf;
+        version[4] = 1;
+        assert!(matches!(DeviceState::from_bytes(&version), Err(MigrationError::InvalidState(_))));
+    }
+}
@@ -0,0 +1,237 @@
+// Records the command stream, fences and scanout flips as a Perfetto trace, so guest frame stutters
+// can be lined up with host GPU scheduling in ui.perfetto.dev or trace_processor.  The file is a
+// `Trace` protobuf written one packet at a time, timestamped on CLOCK_BOOTTIME like the kernel and
+// GPU driver tracks of a system trace, and can be loaded on its own or merged with one.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::interceptor::CommandInterceptor;
+use crate::present::{PresentHook, PresentInfo};
+use crate::protocol::{VirtioGpuCommand, VirtioGpuResponseResult, VIRTIO_GPU_FLAG_FENCE};
+use crate::RutabagaFenceData;
+
+// Field numbers of the protos in perfetto/protos/perfetto/trace.
+const TRACE_PACKET: u32 = 1;
+const PACKET_TIMESTAMP: u32 = 8;
+const PACKET_SEQUENCE_ID: u32 = 10;
+const PACKET_TRACK_EVENT: u32 = 11;
+const PACKET_TRACK_DESCRIPTOR: u32 = 60;
+const DESCRIPTOR_UUID: u32 = 1;
+const DESCRIPTOR_NAME: u32 = 2;
+const EVENT_TYPE: u32 = 9;
+const EVENT_TRACK_UUID: u32 = 11;
+const EVENT_NAME: u32 = 23;
+
+const TYPE_SLICE_BEGIN: u64 = 1;
+const TYPE_SLICE_END: u64 = 2;
+const TYPE_INSTANT: u64 = 3;
+
+/// Every packet is written on this sequence, there is only one writer.
+const SEQUENCE_ID: u64 = 1;
+
+/// uuids of the tracks the events go on.
+pub const COMMANDS_TRACK: u64 = 1;
+pub const FENCES_TRACK: u64 = 2;
+pub const FRAMES_TRACK: u64 = 3;
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_LEN: u32 = 2;
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_uint(buf: &mut Vec<u8>, field: u32, value: u64) {
+    put_varint(buf, (field << 3 | WIRE_VARINT) as u64);
+    put_varint(buf, value);
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_varint(buf, (field << 3 | WIRE_LEN) as u64);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// A `TracePacket`, framed as a packet of the `Trace`.
+fn trace_packet(timestamp: u64, field: u32, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(payload.len() + 16);
+    put_uint(&mut packet, PACKET_TIMESTAMP, timestamp);
+    put_uint(&mut packet, PACKET_SEQUENCE_ID, SEQUENCE_ID);
+    put_bytes(&mut packet, field, payload);
+    let mut framed = Vec::with_capacity(packet.len() + 4);
+    put_bytes(&mut framed, TRACE_PACKET, &packet);
+    framed
+}
+
+/// The packet naming track `uuid`.
+pub fn encode_track_descriptor(timestamp: u64, uuid: u64, name: &str) -> Vec<u8> {
+    let mut descriptor = Vec::new();
+    put_uint(&mut descriptor, DESCRIPTOR_UUID, uuid);
+    put_bytes(&mut descriptor, DESCRIPTOR_NAME, name.as_bytes());
+    trace_packet(timestamp, PACKET_TRACK_DESCRIPTOR, &descriptor)
+}
+
+/// The packet of an event of type `type_` on track `uuid`, the end of a slice has no name.
+fn encode_track_event(timestamp: u64, uuid: u64, type_: u64, name: Option<&str>) -> Vec<u8> {
+    let mut event = Vec::new();
+    put_uint(&mut event, EVENT_TYPE, type_);
+    put_uint(&mut event, EVENT_TRACK_UUID, uuid);
+    if let Some(name) = name {
+        put_bytes(&mut event, EVENT_NAME, name.as_bytes());
+    }
+    trace_packet(timestamp, PACKET_TRACK_EVENT, &event)
+}
+
+/// Nanoseconds of CLOCK_BOOTTIME, the default clock of trace packets.
+fn boottime_ns() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // Safe because `ts` is a valid timespec for the call to fill in.
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts);
+    }
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+struct TraceWriter {
+    out:   Box<dyn Write>,
+    /// The first write that failed, nothing is written after it
+    error: Option<io::Error>,
+}
+
+impl TraceWriter {
+    fn write(&mut self, packet: &[u8]) {
+        if self.error.is_none() {
+            if let Err(e) = self.out.write_all(packet) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+/// A trace being recorded.  Clones write to the same trace, one goes to the device as an
+/// interceptor and one as a present hook, and fences are reported through `fence_signalled`,
+/// typically from the `on_fence_complete` callback.
+#[derive(Clone)]
+pub struct PerfettoTrace(Rc<RefCell<TraceWriter>>);
+
+impl PerfettoTrace {
+    /// Starts a trace written to `out`.
+    pub fn new<W: Write + 'static>(out: W) -> Self {
+        let trace = PerfettoTrace(Rc::new(RefCell::new(TraceWriter {
+            out:   Box::new(out),
+            error: None,
+        })));
+        let now = boottime_ns();
+        let tracks = [
+            (COMMANDS_TRACK, "virtio-gpu commands"),
+            (FENCES_TRACK, "virtio-gpu fences"),
+            (FRAMES_TRACK, "virtio-gpu frames"),
+        ];
+        for &(uuid, name) in &tracks {
+            trace.write(&encode_track_descriptor(now, uuid, name));
+        }
+        trace
+    }
+
+    /// Starts a trace written to the file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    fn write(&self, packet: &[u8]) {
+        self.0.borrow_mut().write(packet);
+    }
+
+    fn event(&self, uuid: u64, type_: u64, name: Option<&str>) {
+        self.write(&encode_track_event(boottime_ns(), uuid, type_, name));
+    }
+
+    /// Records `fence` signalling, an instant on the fences track.
+    pub fn fence_signalled(&self, fence: &RutabagaFenceData) {
+        self.event(FENCES_TRACK, TYPE_INSTANT, Some(&format!("signal fence {}", fence.fence_id)));
+    }
+
+    /// Writes out what is buffered, returning the first error the trace ran into.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut writer = self.0.borrow_mut();
+        if let Some(e) = writer.error.take() {
+            return Err(e);
+        }
+        writer.out.flush()
+    }
+}
+
+/// Every command is a slice on the commands track, and fenced commands that succeeded an instant
+/// on the fences track.
+impl CommandInterceptor for PerfettoTrace {
+    fn before(&mut self, cmd: &VirtioGpuCommand) -> Option<VirtioGpuResponseResult> {
+        self.event(COMMANDS_TRACK, TYPE_SLICE_BEGIN, Some(cmd.name()));
+        None
+    }
+
+    fn after(&mut self, cmd: &VirtioGpuCommand, result: &VirtioGpuResponseResult, _elapsed: Duration) {
+        self.event(COMMANDS_TRACK, TYPE_SLICE_END, None);
+        let hdr = cmd.hdr();
+        if hdr.flags.to_native() & VIRTIO_GPU_FLAG_FENCE != 0 && result.is_ok() {
+            let name = format!("submit fence {}", hdr.fence_id.to_native());
+            self.event(FENCES_TRACK, TYPE_INSTANT, Some(&name));
+        }
+    }
+}
+
+/// Every flip is an instant on the frames track.
+impl PresentHook for PerfettoTrace {
+    fn after_present(&mut self, info: &PresentInfo) {
+        self.event(FRAMES_TRACK, TYPE_INSTANT, Some(&format!("frame {}", info.frame)));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::perfetto::*;
+
+    #[test]
+    fn test_varint() {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, 1);
+        put_varint(&mut buf, 300);
+        put_varint(&mut buf, u64::MAX);
+        assert_eq!(buf[..3], [0x01, 0xac, 0x02]);
+        assert_eq!(buf.len(), 3 + 10);
+    }
+
+    #[test]
+    fn test_encode_packets() {
+        assert_eq!(
+            encode_track_descriptor(5, FRAMES_TRACK, "f"),
+            [
+                0x0a, 0x0c, // packet, 12 bytes
+                0x40, 0x05, // timestamp
+                0x50, 0x01, // trusted_packet_sequence_id
+                0xe2, 0x03, 0x05, // track_descriptor, 5 bytes
+                0x08, 0x03, // uuid
+                0x12, 0x01, b'f', // name
+            ]
+        );
+        assert_eq!(
+            encode_track_event(5, COMMANDS_TRACK, TYPE_SLICE_END, None),
+            [
+                0x0a, 0x0a, // packet, 10 bytes
+                0x40, 0x05, // timestamp
+                0x50, 0x01, // trusted_packet_sequence_id
+                0x5a, 0x04, // track_event, 4 bytes
+                0x48, 0x02, // type
+                0x58, 0x01, // track_uuid
+            ]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+// Postcopy live migration: the guest runs on the destination before all of its memory arrived, the
+// pages still on the source are fetched by the VMM as they are touched.  The VMM learns what the
+// daemon touches through a userfaultfd the daemon creates on POSTCOPY_ADVISE and registers guest
+// memory with on POSTCOPY_LISTEN.  Touching a missing page then blocks the daemon until the VMM
+// copied it in, rather than the renderer reading zeroes ahead of the page's delivery.
+
+use std::fs::File;
+use std::io;
+use std::mem::size_of;
+use std::os::raw::c_ulong;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+const UFFDIO: c_ulong = 0xaa;
+const UFFD_API: u64 = 0xaa;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct uffdio_api {
+    api:      u64,
+    features: u64,
+    ioctls:   u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct uffdio_range {
+    start: u64,
+    len:   u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct uffdio_register {
+    range:  uffdio_range,
+    mode:   u64,
+    ioctls: u64,
+}
+
+/// _IOWR(UFFDIO, nr, T)
+const fn uffd_iowr<T>(nr: c_ulong) -> c_ulong {
+    (3 << 30) | ((size_of::<T>() as c_ulong) << 16) | (UFFDIO << 8) | nr
+}
+
+/// _IOR(UFFDIO, nr, T)
+const fn uffd_ior<T>(nr: c_ulong) -> c_ulong {
+    (2 << 30) | ((size_of::<T>() as c_ulong) << 16) | (UFFDIO << 8) | nr
+}
+
+const UFFDIO_API: c_ulong = uffd_iowr::<uffdio_api>(0x3f);
+const UFFDIO_REGISTER: c_ulong = uffd_iowr::<uffdio_register>(0x00);
+const UFFDIO_UNREGISTER: c_ulong = uffd_ior::<uffdio_range>(0x01);
+
+/// A userfaultfd the VMM reads the daemon's faults on guest memory from.
+pub struct Userfaultfd {
+    file: File,
+}
+
+impl Userfaultfd {
+    /// Creates the userfaultfd and negotiates the API.  Kernel accesses to guest memory must wait
+    /// for the page too, so this fails (EPERM) rather than settling for a user mode only fd when
+    /// the daemon may not handle kernel faults.
+    pub fn new() -> io::Result<Self> {
+        let flags = libc::O_CLOEXEC | libc::O_NONBLOCK;
+        // Safe because the syscall takes no pointer and the result is checked.
+        let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because the fd was just created and nothing else owns it.
+        let file = unsafe { File::from_raw_fd(fd as i32) };
+        let uffd = Userfaultfd { file };
+
+        let mut api = uffdio_api {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        uffd.ioctl(UFFDIO_API, &mut api)?;
+        Ok(uffd)
+    }
+
+    fn ioctl<T>(&self, request: c_ulong, arg: &mut T) -> io::Result<()> {
+        // Safe because `arg` has the layout `request` expects and the result is checked.
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), request, arg as *mut T) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Reports the faults on the missing pages of the `len` bytes mapped at `addr`.
+    pub fn register(&self, addr: usize, len: usize) -> io::Result<()> {
+        let mut register = uffdio_register {
+            range: uffdio_range {
+                start: addr as u64,
+                len:   len as u64,
+            },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ..Default::default()
+        };
+        self.ioctl(UFFDIO_REGISTER, &mut register)
+    }
+
+    /// Stops reporting the faults of the `len` bytes mapped at `addr`.
+    pub fn unregister(&self, addr: usize, len: usize) -> io::Result<()> {
+        let mut range = uffdio_range {
+            start: addr as u64,
+            len:   len as u64,
+        };
+        self.ioctl(UFFDIO_UNREGISTER, &mut range)
+    }
+
+    /// The descriptor handed to the VMM.
+    pub fn try_clone_file(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::postcopy::*;
+
+    #[test]
+    fn test_uffd_ioctl_numbers() {
+        // values from the linux uapi userfaultfd.h
+        assert_eq!(UFFDIO_API, 0xc018_aa3f);
+        assert_eq!(UFFDIO_REGISTER, 0xc020_aa00);
+        assert_eq!(UFFDIO_UNREGISTER, 0x8010_aa01);
+    }
+
+    #[test]
+    fn test_userfaultfd() {
+        let uffd = match Userfaultfd::new() {
+            Ok(uffd) => uffd,
+            // userfaultfd disabled on this host
+            Err(ref e) if [libc::EPERM, libc::ENOSYS, libc::EINVAL].contains(&e.raw_os_error().unwrap_or(0)) => return,
+            Err(e) => panic!("{}", e),
+        };
+        let mut pages = vec![0u8; 3 * 4096];
+        let addr = (pages.as_mut_ptr() as usize + 4095) & !4095;
+        // anonymous memory, only the page aligned part
+        uffd.register(addr, 4096).unwrap();
+        uffd.unregister(addr, 4096).unwrap();
+        assert!(uffd.register(addr + 1, 4096).is_err());
+        assert!(uffd.try_clone_file().is_ok());
+    }
+}
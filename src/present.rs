@@ -0,0 +1,21 @@
+// Hooks run around every flip of a scanout, for watermarking, frame counting or synchronizing
+// with something outside of the device without touching the flush path.  Hooks run in
+// registration order before the flip and in the reverse order after it.
+
+/// The flip a `PresentHook` is called for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PresentInfo {
+    pub scanout_id: u32,
+    /// Number of the frame on the scanout, counting from 0
+    pub frame:      u64,
+    /// `(x, y, width, height)` of the area the guest flushed
+    pub damage:     (u32, u32, u32, u32),
+}
+
+pub trait PresentHook {
+    /// Called before the resource is sent to the display.
+    fn before_present(&mut self, _info: &PresentInfo) {}
+
+    /// Called once the display was handed the frame, not when the flip failed.
+    fn after_present(&mut self, _info: &PresentInfo) {}
+}
@@ -0,0 +1,93 @@
+// Dropping the daemon's privileges once what needs them is open: the render node and the display
+// connection when the device is created, the vhost-user socket when the daemon starts listening.
+// The daemon then handles what the guest sends as an unprivileged user, optionally confined to a
+// directory with chroot.  Nothing is opened by path afterwards, reconnecting frontends come in on
+// the socket that is already listening.
+
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::ptr;
+
+/// The user, group and root directory the daemon switches to, `None` for what it keeps.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Privileges {
+    pub uid:    Option<u32>,
+    pub gid:    Option<u32>,
+    pub chroot: Option<PathBuf>,
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Privileges {
+    /// Whether the daemon keeps running as it was started.
+    pub fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none() && self.chroot.is_none()
+    }
+
+    /// Enters the chroot, then switches to the group and the user, in that order since each step
+    /// needs the privileges the next one drops.  The supplementary groups are dropped along, and
+    /// the ids are changed for every thread of the process, the display thread included.
+    pub fn drop_privileges(&self) -> io::Result<()> {
+        if let Some(ref dir) = self.chroot {
+            let dir = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "chroot path contains a NUL byte"))?;
+            // Safe because both paths are NUL terminated and the results are checked.
+            check(unsafe { libc::chroot(dir.as_ptr()) })?;
+            check(unsafe { libc::chdir(b"/\0".as_ptr() as *const libc::c_char) })?;
+        }
+        if self.uid.is_some() || self.gid.is_some() {
+            // Safe because the list holds as many groups as passed and the result is checked.
+            let ret = match self.gid {
+                Some(gid) => unsafe { libc::setgroups(1, &gid) },
+                None => unsafe { libc::setgroups(0, ptr::null()) },
+            };
+            check(ret)?;
+        }
+        if let Some(gid) = self.gid {
+            // Safe because the call takes no pointer and the result is checked.
+            check(unsafe { libc::setresgid(gid, gid, gid) })?;
+        }
+        if let Some(uid) = self.uid {
+            // Safe for the same reason.
+            check(unsafe { libc::setresuid(uid, uid, uid) })?;
+            // Safe for the same reason.
+            if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "root privileges could be regained"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::privileges::*;
+
+    #[test]
+    fn test_privileges() {
+        let privileges = Privileges::default();
+        assert!(privileges.is_empty());
+        privileges.drop_privileges().unwrap();
+
+        // fails before changing anything, whether running as root or not
+        let privileges = Privileges {
+            uid: Some(65534),
+            chroot: Some(PathBuf::from("/nonexistent/vhost-gpu-chroot")),
+            ..Default::default()
+        };
+        assert!(!privileges.is_empty());
+        assert!(privileges.drop_privileges().is_err());
+        let privileges = Privileges {
+            chroot: Some(PathBuf::from("/tmp/\0")),
+            ..Default::default()
+        };
+        assert_eq!(privileges.drop_privileges().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,159 @@
+// Provisioning-time check of a host: brings up the renderer and the display the way the device
+// would, and reports what they support without serving any guest.
+
+use std::fmt::{self, Display};
+
+use gpu_display::{GpuDisplay, GpuDisplayError};
+
+use crate::drm::{enumerate_drm_devices, DrmDevice, DrmNodeType};
+use crate::protocol::capset_name;
+use crate::virtio_gpu::{GpuParameter, RendererInfo, VirtioGpu, VirtioGpuError};
+
+#[cfg(feature = "virgl_renderer")]
+mod egl {
+    use std::os::raw::{c_char, c_int, c_void};
+
+    pub type EGLDisplay = *mut c_void;
+    pub const EGL_DEFAULT_DISPLAY: *mut c_void = 0 as *mut c_void;
+    pub const EGL_NO_DISPLAY: EGLDisplay = 0 as EGLDisplay;
+    pub const EGL_EXTENSIONS: c_int = 0x3055;
+
+    #[link(name = "EGL")]
+    extern "C" {
+        pub fn eglGetDisplay(display_id: *mut c_void) -> EGLDisplay;
+        pub fn eglInitialize(display: EGLDisplay, major: *mut c_int, minor: *mut c_int) -> u32;
+        pub fn eglTerminate(display: EGLDisplay) -> u32;
+        pub fn eglQueryString(display: EGLDisplay, name: c_int) -> *const c_char;
+    }
+}
+
+/// What a host offers to the device, see `probe`.
+#[derive(Clone, Debug)]
+pub struct ProbeReport {
+    pub renderer:       RendererInfo,
+    /// `(id, version, max size)` of each capset
+    pub capsets:        Vec<(u32, u32, u32)>,
+    /// Extensions of the default EGL display, empty without virglrenderer
+    pub egl_extensions: Vec<String>,
+    /// The render node virglrenderer picks, the first one of the host
+    pub render_node:    Option<DrmDevice>,
+}
+
+impl ProbeReport {
+    /// Buffers rendered on the host can be shown on the display without a copy.
+    pub fn dmabuf_import(&self) -> bool {
+        self.renderer.display.dmabuf_import
+            && self.render_node.as_ref().map_or(false, |node| node.prime_export)
+    }
+}
+
+impl Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let renderer = if self.renderer.virgl { "virglrenderer" } else { "2D" };
+        writeln!(f, "renderer: {}", renderer)?;
+        match &self.render_node {
+            Some(node) => {
+                write!(f, "gpu: {} ({})", node.path.display(), node.driver)?;
+                if let Some((vendor, device)) = node.pci_id {
+                    write!(f, " {:04x}:{:04x}", vendor, device)?;
+                }
+                writeln!(f)?;
+            }
+            None => writeln!(f, "gpu: none")?,
+        }
+        writeln!(f, "features: {:#x}", self.renderer.features)?;
+        for (id, version, size) in &self.capsets {
+            writeln!(f, "capset: {} (id {}) version {} size {}", capset_name(*id), id, version, size)?;
+        }
+        writeln!(f, "dmabuf import: {}", self.dmabuf_import())?;
+        if let Some((width, height)) = self.renderer.display.max_surface_size {
+            writeln!(f, "max surface size: {}x{}", width, height)?;
+        }
+        write!(f, "egl extensions: {}", self.egl_extensions.join(" "))
+    }
+}
+
+#[cfg(feature = "virgl_renderer")]
+fn egl_extensions() -> Vec<String> {
+    use std::ffi::CStr;
+
+    // Safe because the display is only used between a successful eglInitialize and its
+    // eglTerminate, and the returned string is copied before the display is terminated.
+    unsafe {
+        let display = egl::eglGetDisplay(egl::EGL_DEFAULT_DISPLAY);
+        if display == egl::EGL_NO_DISPLAY
+            || egl::eglInitialize(display, std::ptr::null_mut(), std::ptr::null_mut()) == 0
+        {
+            return Vec::new();
+        }
+        let extensions = egl::eglQueryString(display, egl::EGL_EXTENSIONS);
+        let extensions = if extensions.is_null() {
+            Vec::new()
+        } else {
+            CStr::from_ptr(extensions)
+                .to_string_lossy()
+                .split_whitespace()
+                .map(String::from)
+                .collect()
+        };
+        egl::eglTerminate(display);
+        extensions
+    }
+}
+
+#[cfg(not(feature = "virgl_renderer"))]
+fn egl_extensions() -> Vec<String> {
+    Vec::new()
+}
+
+fn render_node() -> Option<DrmDevice> {
+    enumerate_drm_devices()
+        .ok()?
+        .into_iter()
+        .find(|device| device.node_type == DrmNodeType::Render)
+}
+
+/// Initializes the renderer and the display of `display_backend` as `VirtioGpu::new` would and
+/// reports what they support.  Both are released before returning.
+pub fn probe(gpu_parameter: GpuParameter) -> Result<ProbeReport, VirtioGpuError> {
+    let open_display = gpu_parameter.display_opener();
+    probe_with_display(gpu_parameter, open_display)
+}
+
+/// `probe` on the display opened by `open_display`.
+pub fn probe_with_display<F>(
+    gpu_parameter: GpuParameter,
+    open_display: F,
+) -> Result<ProbeReport, VirtioGpuError>
+where
+    F: FnMut() -> Result<GpuDisplay, GpuDisplayError> + Send + 'static,
+{
+    let gpu = VirtioGpu::with_display(gpu_parameter, open_display)?;
+    Ok(ProbeReport {
+        renderer: gpu.renderer_info(),
+        capsets: gpu.capsets(),
+        egl_extensions: egl_extensions(),
+        render_node: render_node(),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::probe::probe_with_display;
+    use crate::virtio_gpu::{GpuMode, GpuParameter};
+    use gpu_display::GpuDisplay;
+
+    #[test]
+    fn test_probe() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let report = probe_with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        assert!(!report.renderer.virgl);
+        assert!(report.capsets.is_empty());
+        // the stub display imports nothing
+        assert!(!report.dmabuf_import());
+        assert!(report.to_string().starts_with("renderer: 2D\n"));
+    }
+}
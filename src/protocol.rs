@@ -1,17 +1,17 @@
 // virtio-gpu protocol structure and const
-use std::marker::PhantomData;
 
 use std::fmt;
 use std::fmt::Formatter;
 use std::str::from_utf8;
 use std::cmp::min;
+use std::io::IoSliceMut;
 
-use ::vm_memory::{ Le32, Le64, GuestAddress, ByteValued, Bytes, GuestMemoryError, GuestMemoryMmap };
+use ::vm_memory::{ Address, Le32, Le64, GuestAddress, ByteValued, Bytes, GuestMemoryError, GuestMemoryMmap };
 use std::mem::{size_of_val, size_of};
 use vm_memory::guest_memory::Error;
 use crate::protocol::VirtioGpuCommandDecodeError::ParserError;
 use std::num::TryFromIntError;
-use rutabaga_gfx::RutabagaError;
+use rutabaga_gfx::{RutabagaError, RutabagaFenceData};
 use gpu_display::GpuDisplayError;
 
 
@@ -31,6 +31,7 @@ pub const VIRTIO_GPU_CMD_GET_CAPSET_INFO: u32            = 0x0108;
 pub const VIRTIO_GPU_CMD_GET_CAPSET: u32                 = 0x0109;
 pub const VIRTIO_GPU_CMD_GET_EDID: u32                   = 0x010a;
 pub const VIRTIO_GPU_CMD_RESOURCE_ASSIGN_UUID: u32       = 0x010b;
+pub const VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB: u32       = 0x010c;
 
 // 3D command based on qemu virtio_gpu
 // https://github.com/qemu/qemu/blob/master/include/standard-headers/linux/virtio_gpu.h
@@ -43,6 +44,8 @@ pub const VIRTIO_GPU_CMD_RESOURCE_CREATE_3D: u32        = 0x0204;
 pub const VIRTIO_GPU_CMD_TRANSFER_TO_HOST_3D: u32       = 0x0205;
 pub const VIRTIO_GPU_CMD_TRANSFER_FROM_HOST_3D: u32     = 0x0206;
 pub const VIRTIO_GPU_CMD_SUBMIT_3D: u32                 = 0x0207;
+pub const VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB: u32         = 0x0208;
+pub const VIRTIO_GPU_CMD_RESOURCE_UNMAP_BLOB: u32       = 0x0209;
 
 /* cursor commands */
 pub const VIRTIO_GPU_CMD_UPDATE_CURSOR: u32             = 0x0301;
@@ -56,6 +59,7 @@ pub const VIRTIO_GPU_RESP_OK_CAPSET_INFO: u32           = 0x1102;
 pub const VIRTIO_GPU_RESP_OK_CAPSET: u32                = 0x1103;
 pub const VIRTIO_GPU_RESP_OK_EDID: u32                  = 0x1104;
 pub const VIRTIO_GPU_RESP_OK_RESOURCE_UUID: u32         = 0x1105;
+pub const VIRTIO_GPU_RESP_OK_MAP_INFO: u32              = 0x1106;
 
 /* error responses */
 pub const VIRTIO_GPU_RESP_ERR_UNSPEC: u32               = 0x1200;
@@ -66,8 +70,13 @@ pub const VIRTIO_GPU_RESP_ERR_INVALID_CONTEXT_ID: u32   = 0x1204;
 pub const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32    = 0x1205;
 
 pub const VIRTIO_GPU_FLAG_FENCE: u32 = 1 << 0;
-/* Fence context index info flag not upstreamed. */
-pub const VIRTIO_GPU_FLAG_INFO_FENCE_CTX_IDX: u32 = 1 << 1;
+/* With VIRTIO_GPU_F_CONTEXT_INIT, the fence is on ring `ring_idx` of the context. */
+pub const VIRTIO_GPU_FLAG_INFO_RING_IDX: u32 = 1 << 1;
+/* Name of the ring index flag before it was upstreamed. */
+pub const VIRTIO_GPU_FLAG_INFO_FENCE_CTX_IDX: u32 = VIRTIO_GPU_FLAG_INFO_RING_IDX;
+
+/* Rings a context can have fences on. */
+pub const VIRTIO_GPU_MAX_RINGS: u32 = 64;
 
 
 // Device type
@@ -78,6 +87,10 @@ pub const VIRTIO_GPU_DEVICE_TYPE: u32 = 16;
 pub const VIRTIO_GPU_F_VIRGL: u32         = 0;
 pub const VIRTIO_GPU_F_EDID: u32          = 1;
 pub const VIRTIO_GPU_F_RESOURCE_UUID: u32 = 2;
+pub const VIRTIO_GPU_F_RESOURCE_BLOB: u32 = 3;
+pub const VIRTIO_GPU_F_CONTEXT_INIT: u32  = 4;
+
+pub const VIRTIO_GPU_CONTEXT_INIT_CAPSET_ID_MASK: u32 = 0x000000ff;
 
 //----- virtio-gpu control header and command header ----
 #[derive(Debug, Copy, Clone, Default)]
@@ -87,11 +100,37 @@ pub struct virtio_gpu_ctrl_hdr {
     pub flags:    Le32,
     pub fence_id: Le64,
     pub ctx_id:   Le32,
-    pub padding:  Le32,
+    pub ring_idx: u8,
+    pub padding:  [u8; 3],
 }
 
 unsafe impl ByteValued for virtio_gpu_ctrl_hdr{}
 
+impl virtio_gpu_ctrl_hdr {
+    /// The ring of the context the fence of the command is on, `None` for the global timeline.
+    pub fn ring_idx(&self) -> Option<u32> {
+        if self.flags.to_native() & VIRTIO_GPU_FLAG_INFO_RING_IDX != 0 {
+            Some(self.ring_idx as u32)
+        } else {
+            None
+        }
+    }
+
+    /// The fence the command asks for, if it is fenced.
+    pub fn fence(&self) -> Option<RutabagaFenceData> {
+        let flags = self.flags.to_native();
+        if flags & VIRTIO_GPU_FLAG_FENCE == 0 {
+            return None;
+        }
+        Some(RutabagaFenceData {
+            flags:         flags & (VIRTIO_GPU_FLAG_FENCE | VIRTIO_GPU_FLAG_INFO_RING_IDX),
+            fence_id:      self.fence_id.to_native(),
+            ctx_id:        self.ctx_id.to_native(),
+            fence_ctx_idx: self.ring_idx().unwrap_or(0),
+        })
+    }
+}
+
 /* data passed in the cursor wq */
 #[derive(Debug, Copy, Clone, Default)]
 #[repr(C)]
@@ -112,6 +151,7 @@ pub struct virtio_gpu_update_cursor {
     pub pos:           virtio_gpu_cursor_pos, /* update & move */
     pub resource_id:   Le32,                  /* update only */
     pub hot_x:         Le32,                  /* update only */
+    pub hot_y:         Le32,                  /* update only */
     pub padding:       Le32,
 }
 
@@ -201,6 +241,12 @@ pub struct virtio_gpu_mem_entry {
 
 unsafe impl ByteValued for virtio_gpu_mem_entry{}
 
+/* Memory entries a command can have, QEMU's limit. */
+pub const VIRTIO_GPU_MAX_MEM_ENTRIES: u32 = 16384;
+
+/* Bytes the command stream of SUBMIT_3D can have, well above what guest drivers submit at once. */
+pub const VIRTIO_GPU_MAX_SUBMIT_SIZE: u32 = 16 << 20;
+
 /* VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING */
 #[derive(Debug, Copy, Clone, Default)]
 #[repr(C)]
@@ -233,7 +279,7 @@ pub struct virtio_gpu_display_one {
 
 unsafe impl ByteValued for virtio_gpu_display_one{}
 
-const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
+pub const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
 /* VIRTIO_GPU_RESP_OK_DISPLAY_INFO */
 #[derive(Debug, Copy, Clone, Default)]
 #[repr(C)]
@@ -299,10 +345,11 @@ unsafe impl ByteValued for virtio_gpu_resource_create_3d{}
 #[derive(Copy)]
 #[repr(C)]
 pub struct virtio_gpu_ctx_create {
-    pub hdr:        virtio_gpu_ctrl_hdr,
-    pub nlen:       Le32,
-    pub padding:    Le32,
-    pub debug_name: [u8; 64],
+    pub hdr:          virtio_gpu_ctrl_hdr,
+    pub nlen:         Le32,
+    /* with VIRTIO_GPU_F_CONTEXT_INIT, the capset of the context in the low bits */
+    pub context_init: Le32,
+    pub debug_name:   [u8; 64],
 }
 
 unsafe impl ByteValued for virtio_gpu_ctx_create{}
@@ -321,15 +368,25 @@ impl Clone for virtio_gpu_ctx_create {
     }
 }
 
-impl fmt::Debug for virtio_gpu_ctx_create {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+impl virtio_gpu_ctx_create {
+    /// The name the guest gave the context, usually its process name.  `None` when the guest
+    /// didn't name it or the name isn't UTF-8.
+    pub fn debug_name(&self) -> Option<&str> {
         // name should not be longer than 64
         let name_size = min(self.nlen.to_native() as usize, 64);
-        let debug_name = from_utf8(&self.debug_name[..name_size])
-            .unwrap_or("<unkown>");
+        from_utf8(&self.debug_name[..name_size])
+            .ok()
+            .filter(|name| !name.is_empty())
+    }
+}
+
+impl fmt::Debug for virtio_gpu_ctx_create {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let debug_name = self.debug_name().unwrap_or("<unkown>");
         f.debug_struct("virtio_gpu_ctx_create")
             .field("hdr", &self.hdr)
             .field("nlen", &self.nlen)
+            .field("context_init", &self.context_init)
             .field("debug_name", &debug_name)
             .finish()
     }
@@ -366,8 +423,25 @@ pub struct virtio_gpu_cmd_submit {
 
 unsafe impl ByteValued for virtio_gpu_cmd_submit{}
 
-pub const VIRTIO_GPU_CAPSET_VIRGL: u32 =  1;
-pub const VIRTIO_GPU_CAPSET_VIRGL2: u32 = 2;
+pub const VIRTIO_GPU_CAPSET_VIRGL: u32        = 1;
+pub const VIRTIO_GPU_CAPSET_VIRGL2: u32       = 2;
+pub const VIRTIO_GPU_CAPSET_GFXSTREAM: u32    = 3;
+pub const VIRTIO_GPU_CAPSET_VENUS: u32        = 4;
+pub const VIRTIO_GPU_CAPSET_CROSS_DOMAIN: u32 = 5;
+pub const VIRTIO_GPU_CAPSET_DRM: u32          = 6;
+
+/// Name of the capset `capset_id`, for diagnostics.
+pub fn capset_name(capset_id: u32) -> &'static str {
+    match capset_id {
+        VIRTIO_GPU_CAPSET_VIRGL        => "virgl",
+        VIRTIO_GPU_CAPSET_VIRGL2       => "virgl2",
+        VIRTIO_GPU_CAPSET_GFXSTREAM    => "gfxstream",
+        VIRTIO_GPU_CAPSET_VENUS        => "venus",
+        VIRTIO_GPU_CAPSET_CROSS_DOMAIN => "cross-domain",
+        VIRTIO_GPU_CAPSET_DRM          => "drm",
+        _                              => "unknown",
+    }
+}
 
 /* VIRTIO_GPU_CMD_GET_CAPSET_INFO */
 #[derive(Debug, Copy, Clone, Default)]
@@ -404,15 +478,32 @@ pub struct virtio_gpu_get_capset {
 
 unsafe impl ByteValued for virtio_gpu_get_capset{}
 
-/* VIRTIO_GPU_RESP_OK_CAPSET */
-#[derive(Debug, Copy, Clone, Default)]
-#[repr(C)]
-pub struct virtio_gpu_resp_capset {
+/* VIRTIO_GPU_RESP_OK_CAPSET, the header followed by the capset data */
+#[derive(Debug, Copy, Clone)]
+pub struct virtio_gpu_resp_capset<'a> {
     pub hdr:            virtio_gpu_ctrl_hdr,
-    pub capset_data:    PhantomData<[u8]>,
+    pub capset_data:    &'a [u8],
 }
 
-unsafe impl ByteValued for virtio_gpu_resp_capset{}
+impl virtio_gpu_resp_capset<'_> {
+    /// Size of the response on the queue.
+    pub fn encoded_len(&self) -> usize {
+        size_of::<virtio_gpu_ctrl_hdr>() + self.capset_data.len()
+    }
+
+    /// Writes the response at the start of `buf`, the writable part of the descriptor chain,
+    /// which has to hold all of it.  Returns the number of bytes written.
+    pub fn write_to(&self, buf: &mut [u8]) -> Result<usize, VirtioGpuResponse> {
+        let len = self.encoded_len();
+        if buf.len() < len {
+            return Err(VirtioGpuResponse::ResponseTooLarge(len));
+        }
+        let (hdr, capset_data) = buf[..len].split_at_mut(size_of::<virtio_gpu_ctrl_hdr>());
+        hdr.copy_from_slice(self.hdr.as_slice());
+        capset_data.copy_from_slice(self.capset_data);
+        Ok(len)
+    }
+}
 
 /* VIRTIO_GPU_CMD_GET_EDID */
 #[derive(Debug, Copy, Clone, Default)]
@@ -465,6 +556,10 @@ pub const VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM: u32  = 68;
 pub const VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM: u32  = 121;
 pub const VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM: u32  = 134;
 
+/* multi-planar YUV formats for video */
+pub const VIRTIO_GPU_FORMAT_YV12: u32            = 163;
+pub const VIRTIO_GPU_FORMAT_NV12: u32            = 166;
+
 
 /* VIRTIO_GPU_CMD_RESOURCE_ASSIGN_UUID */
 #[derive(Debug, Copy, Clone, Default)]
@@ -487,10 +582,100 @@ pub struct virtio_gpu_resp_resource_uuid {
 
 unsafe impl ByteValued for virtio_gpu_resp_resource_uuid{}
 
+pub const VIRTIO_GPU_BLOB_MEM_GUEST: u32          = 0x0001;
+pub const VIRTIO_GPU_BLOB_MEM_HOST3D: u32         = 0x0002;
+pub const VIRTIO_GPU_BLOB_MEM_HOST3D_GUEST: u32   = 0x0003;
+
+pub const VIRTIO_GPU_BLOB_FLAG_USE_MAPPABLE: u32     = 0x0001;
+pub const VIRTIO_GPU_BLOB_FLAG_USE_SHAREABLE: u32    = 0x0002;
+pub const VIRTIO_GPU_BLOB_FLAG_USE_CROSS_DEVICE: u32 = 0x0004;
+
+/* VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB, followed by nr_entries virtio_gpu_mem_entry */
+#[derive(Debug, Copy, Clone, Default)]
+#[repr(C)]
+pub struct virtio_gpu_resource_create_blob {
+    pub hdr:         virtio_gpu_ctrl_hdr,
+    pub resource_id: Le32,
+    pub blob_mem:    Le32,
+    pub blob_flags:  Le32,
+    pub nr_entries:  Le32,
+    pub blob_id:     Le64,
+    pub size:        Le64,
+}
+
+unsafe impl ByteValued for virtio_gpu_resource_create_blob{}
+
+/* VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB */
+#[derive(Debug, Copy, Clone, Default)]
+#[repr(C)]
+pub struct virtio_gpu_resource_map_blob {
+    pub hdr:         virtio_gpu_ctrl_hdr,
+    pub resource_id: Le32,
+    pub padding:     Le32,
+    /* offset of the mapping in the host visible region */
+    pub offset:      Le64,
+}
+
+unsafe impl ByteValued for virtio_gpu_resource_map_blob{}
+
+/* VIRTIO_GPU_CMD_RESOURCE_UNMAP_BLOB */
+#[derive(Debug, Copy, Clone, Default)]
+#[repr(C)]
+pub struct virtio_gpu_resource_unmap_blob {
+    pub hdr:         virtio_gpu_ctrl_hdr,
+    pub resource_id: Le32,
+    pub padding:     Le32,
+}
+
+unsafe impl ByteValued for virtio_gpu_resource_unmap_blob{}
+
+pub const VIRTIO_GPU_MAP_CACHE_MASK: u32     = 0x0f;
+pub const VIRTIO_GPU_MAP_CACHE_NONE: u32     = 0x00;
+pub const VIRTIO_GPU_MAP_CACHE_CACHED: u32   = 0x01;
+pub const VIRTIO_GPU_MAP_CACHE_UNCACHED: u32 = 0x02;
+pub const VIRTIO_GPU_MAP_CACHE_WC: u32       = 0x03;
+
+/* VIRTIO_GPU_RESP_OK_MAP_INFO */
+#[derive(Debug, Copy, Clone, Default)]
+#[repr(C)]
+pub struct virtio_gpu_resp_map_info {
+    pub hdr:      virtio_gpu_ctrl_hdr,
+    pub map_info: Le32,
+    pub padding:  Le32,
+}
+
+unsafe impl ByteValued for virtio_gpu_resp_map_info{}
+
 #[derive(Debug)]
 pub enum VirtioGpuCommandDecodeError {
     InvalidCommand(u32),
     ParserError(GuestMemoryError),
+    /// the buffer is shorter than the command it holds
+    BufferTooShort(usize),
+    /// the command has more memory entries than `VIRTIO_GPU_MAX_MEM_ENTRIES`
+    TooManyMemEntries(u32),
+    /// the command stream of SUBMIT_3D is longer than `VIRTIO_GPU_MAX_SUBMIT_SIZE`
+    SubmitTooLarge(u32),
+    /// the memory entry of this index is empty or wraps around the address space
+    InvalidMemEntry(usize),
+    /// strict mode: the descriptor holds fewer bytes than the command
+    DescriptorTooShort(usize),
+    /// strict mode: a reserved field isn't zero
+    NonZeroPadding,
+    /// strict mode: the command carries a context id it doesn't take
+    UnexpectedCtxId(u32),
+}
+
+impl VirtioGpuCommandDecodeError {
+    /// The response the guest gets for a command that failed to decode.  What strict mode
+    /// rejects is a well formed command with invalid parameters.
+    pub fn response(&self) -> VirtioGpuResponse {
+        use self::VirtioGpuCommandDecodeError::*;
+        match self {
+            DescriptorTooShort(_) | NonZeroPadding | UnexpectedCtxId(_) => VirtioGpuResponse::ErrInvalidParameter,
+            _ => VirtioGpuResponse::ErrUnspec,
+        }
+    }
 }
 
 impl From<GuestMemoryError> for VirtioGpuCommandDecodeError {
@@ -515,6 +700,7 @@ pub enum VirtioGpuCommand {
     CmdGetCapset(virtio_gpu_get_capset),
     CmdGetEdid(virtio_gpu_cmd_get_edid),
     CmdResourceAssignUuid(virtio_gpu_resource_assign_uuid),
+    CmdResourceCreateBlob(virtio_gpu_resource_create_blob),
 
 
     // 3D command
@@ -526,6 +712,8 @@ pub enum VirtioGpuCommand {
     CmdTransferToHost3D(virtio_gpu_transfer_host_3d),
     CmdTransferFromHost3D(virtio_gpu_transfer_host_3d),
     CmdSubmit3D(virtio_gpu_cmd_submit),
+    CmdResourceMapBlob(virtio_gpu_resource_map_blob),
+    CmdResourceUnmapBlob(virtio_gpu_resource_unmap_blob),
 
 
     // Cursor command
@@ -535,8 +723,151 @@ pub enum VirtioGpuCommand {
 
 pub type VirtioGpuCommandResult = std::result::Result<VirtioGpuCommand, VirtioGpuCommandDecodeError>;
 
+/// Whether `VirtioGpuCommand::decode` knows the command type.
+pub fn is_standard_command(type_: u32) -> bool {
+    matches!(
+        type_,
+        VIRTIO_GPU_CMD_GET_DISPLAY_INFO..=VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB
+            | VIRTIO_GPU_CMD_CTX_CREATE..=VIRTIO_GPU_CMD_RESOURCE_UNMAP_BLOB
+            | VIRTIO_GPU_CMD_UPDATE_CURSOR..=VIRTIO_GPU_CMD_MOVE_CURSOR
+    )
+}
+
+/// Size of the request of command `type_`, without the data following it.
+pub fn command_size(type_: u32) -> Option<usize> {
+    Some(match type_ {
+        VIRTIO_GPU_CMD_GET_DISPLAY_INFO         => size_of::<virtio_gpu_ctrl_hdr>(),
+        VIRTIO_GPU_CMD_RESOURCE_CREATE_2D       => size_of::<virtio_gpu_resource_create_2d>(),
+        VIRTIO_GPU_CMD_RESOURCE_UNREF           => size_of::<virtio_gpu_resource_unref>(),
+        VIRTIO_GPU_CMD_SET_SCANOUT              => size_of::<virtio_gpu_set_scanout>(),
+        VIRTIO_GPU_CMD_RESOURCE_FLUSH           => size_of::<virtio_gpu_resource_flush>(),
+        VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D      => size_of::<virtio_gpu_transfer_to_host_2d>(),
+        VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING  => size_of::<virtio_gpu_resource_attach_backing>(),
+        VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING  => size_of::<virtio_gpu_resource_detach_backing>(),
+        VIRTIO_GPU_CMD_GET_CAPSET_INFO          => size_of::<virtio_gpu_get_capset_info>(),
+        VIRTIO_GPU_CMD_GET_CAPSET               => size_of::<virtio_gpu_get_capset>(),
+        VIRTIO_GPU_CMD_GET_EDID                 => size_of::<virtio_gpu_cmd_get_edid>(),
+        VIRTIO_GPU_CMD_RESOURCE_ASSIGN_UUID     => size_of::<virtio_gpu_resource_assign_uuid>(),
+        VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB     => size_of::<virtio_gpu_resource_create_blob>(),
+        VIRTIO_GPU_CMD_CTX_CREATE               => size_of::<virtio_gpu_ctx_create>(),
+        VIRTIO_GPU_CMD_CTX_DESTROY              => size_of::<virtio_gpu_ctx_destroy>(),
+        VIRTIO_GPU_CMD_CTX_ATTACH_RESOURCE      => size_of::<virtio_gpu_ctx_resource>(),
+        VIRTIO_GPU_CMD_CTX_DETACH_RESOURCE      => size_of::<virtio_gpu_ctx_resource>(),
+        VIRTIO_GPU_CMD_RESOURCE_CREATE_3D       => size_of::<virtio_gpu_resource_create_3d>(),
+        VIRTIO_GPU_CMD_TRANSFER_TO_HOST_3D      => size_of::<virtio_gpu_transfer_host_3d>(),
+        VIRTIO_GPU_CMD_TRANSFER_FROM_HOST_3D    => size_of::<virtio_gpu_transfer_host_3d>(),
+        VIRTIO_GPU_CMD_SUBMIT_3D                => size_of::<virtio_gpu_cmd_submit>(),
+        VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB        => size_of::<virtio_gpu_resource_map_blob>(),
+        VIRTIO_GPU_CMD_RESOURCE_UNMAP_BLOB      => size_of::<virtio_gpu_resource_unmap_blob>(),
+        VIRTIO_GPU_CMD_UPDATE_CURSOR            => size_of::<virtio_gpu_update_cursor>(),
+        VIRTIO_GPU_CMD_MOVE_CURSOR              => size_of::<virtio_gpu_update_cursor>(),
+        _ => return None,
+    })
+}
+
+/// Whether the command belongs on the cursor queue.
+pub fn is_cursor_command(type_: u32) -> bool {
+    matches!(type_, VIRTIO_GPU_CMD_UPDATE_CURSOR..=VIRTIO_GPU_CMD_MOVE_CURSOR)
+}
+
+/// Where a command is read from: guest memory or an already copied buffer.
+trait CommandSource {
+    /// Reads a `T` `offset` bytes into the command.
+    fn read_at<T: ByteValued>(&self, offset: usize) -> Result<T, VirtioGpuCommandDecodeError>;
+
+    fn read_cmd<T: ByteValued>(&self) -> Result<T, VirtioGpuCommandDecodeError> {
+        self.read_at(0)
+    }
+}
+
+impl CommandSource for (&GuestMemoryMmap, GuestAddress) {
+    fn read_at<T: ByteValued>(&self, offset: usize) -> Result<T, VirtioGpuCommandDecodeError> {
+        let addr = self
+            .1
+            .checked_add(offset as u64)
+            .ok_or(ParserError(GuestMemoryError::InvalidGuestAddress(self.1)))?;
+        Ok(self.0.read_obj(addr)?)
+    }
+}
+
+impl CommandSource for &[u8] {
+    fn read_at<T: ByteValued>(&self, offset: usize) -> Result<T, VirtioGpuCommandDecodeError> {
+        let mut obj = T::default();
+        let len = size_of::<T>();
+        if self.len() < offset + len {
+            return Err(VirtioGpuCommandDecodeError::BufferTooShort(self.len()));
+        }
+        obj.as_mut_slice().copy_from_slice(&self[offset..offset + len]);
+        Ok(obj)
+    }
+}
+
 
 impl VirtioGpuCommand {
+    /// The header every command starts with.
+    pub fn hdr(&self) -> &virtio_gpu_ctrl_hdr {
+        use VirtioGpuCommand::*;
+        match self {
+            CmdGetDisplayInfo(hdr)           => hdr,
+            CmdResourceCreate2D(cmd)         => &cmd.hdr,
+            CmdResourceUnref(cmd)            => &cmd.hdr,
+            CmdSetScanout(cmd)               => &cmd.hdr,
+            CmdResourceFlush(cmd)            => &cmd.hdr,
+            CmdTransferToHost2D(cmd)         => &cmd.hdr,
+            CmdResourceAttachBacking(cmd)    => &cmd.hdr,
+            CmdResourceDetachBacking(cmd)    => &cmd.hdr,
+            CmdGetCapsetInfo(cmd)            => &cmd.hdr,
+            CmdGetCapset(cmd)                => &cmd.hdr,
+            CmdGetEdid(cmd)                  => &cmd.hdr,
+            CmdResourceAssignUuid(cmd)       => &cmd.hdr,
+            CmdResourceCreateBlob(cmd)       => &cmd.hdr,
+            CmdCtxCreate(cmd)                => &cmd.hdr,
+            CmdCtxDestroy(cmd)               => &cmd.hdr,
+            CmdCtxAttachResource(cmd)        => &cmd.hdr,
+            CmdCtxDetachResource(cmd)        => &cmd.hdr,
+            CmdResourceCreate3D(cmd)         => &cmd.hdr,
+            CmdTransferToHost3D(cmd)         => &cmd.hdr,
+            CmdTransferFromHost3D(cmd)       => &cmd.hdr,
+            CmdSubmit3D(cmd)                 => &cmd.hdr,
+            CmdResourceMapBlob(cmd)          => &cmd.hdr,
+            CmdResourceUnmapBlob(cmd)        => &cmd.hdr,
+            CmdUpdateCursor(cmd)             => &cmd.hdr,
+            CmdMoveCursor(cmd)               => &cmd.hdr,
+        }
+    }
+
+    /// The name of the command type, as in the spec without the VIRTIO_GPU_CMD_ prefix.
+    pub fn name(&self) -> &'static str {
+        use VirtioGpuCommand::*;
+        match self {
+            CmdGetDisplayInfo(_)           => "GET_DISPLAY_INFO",
+            CmdResourceCreate2D(_)         => "RESOURCE_CREATE_2D",
+            CmdResourceUnref(_)            => "RESOURCE_UNREF",
+            CmdSetScanout(_)               => "SET_SCANOUT",
+            CmdResourceFlush(_)            => "RESOURCE_FLUSH",
+            CmdTransferToHost2D(_)         => "TRANSFER_TO_HOST_2D",
+            CmdResourceAttachBacking(_)    => "RESOURCE_ATTACH_BACKING",
+            CmdResourceDetachBacking(_)    => "RESOURCE_DETACH_BACKING",
+            CmdGetCapsetInfo(_)            => "GET_CAPSET_INFO",
+            CmdGetCapset(_)                => "GET_CAPSET",
+            CmdGetEdid(_)                  => "GET_EDID",
+            CmdResourceAssignUuid(_)       => "RESOURCE_ASSIGN_UUID",
+            CmdResourceCreateBlob(_)       => "RESOURCE_CREATE_BLOB",
+            CmdCtxCreate(_)                => "CTX_CREATE",
+            CmdCtxDestroy(_)               => "CTX_DESTROY",
+            CmdCtxAttachResource(_)        => "CTX_ATTACH_RESOURCE",
+            CmdCtxDetachResource(_)        => "CTX_DETACH_RESOURCE",
+            CmdResourceCreate3D(_)         => "RESOURCE_CREATE_3D",
+            CmdTransferToHost3D(_)         => "TRANSFER_TO_HOST_3D",
+            CmdTransferFromHost3D(_)       => "TRANSFER_FROM_HOST_3D",
+            CmdSubmit3D(_)                 => "SUBMIT_3D",
+            CmdResourceMapBlob(_)          => "RESOURCE_MAP_BLOB",
+            CmdResourceUnmapBlob(_)        => "RESOURCE_UNMAP_BLOB",
+            CmdUpdateCursor(_)             => "UPDATE_CURSOR",
+            CmdMoveCursor(_)               => "MOVE_CURSOR",
+        }
+    }
+
     pub fn size(&self) -> usize {
         match self {
             VirtioGpuCommand::CmdGetDisplayInfo(_)        => size_of::<virtio_gpu_display_one>(),
@@ -558,9 +889,12 @@ impl VirtioGpuCommand {
             VirtioGpuCommand::CmdTransferToHost3D(_)      => size_of::<virtio_gpu_transfer_host_3d>(),
             VirtioGpuCommand::CmdTransferFromHost3D(_)    => size_of::<virtio_gpu_transfer_host_3d>(),
             VirtioGpuCommand::CmdSubmit3D(_)              => size_of::<virtio_gpu_cmd_submit>(),
+            VirtioGpuCommand::CmdResourceMapBlob(_)       => size_of::<virtio_gpu_resource_map_blob>(),
+            VirtioGpuCommand::CmdResourceUnmapBlob(_)     => size_of::<virtio_gpu_resource_unmap_blob>(),
             VirtioGpuCommand::CmdUpdateCursor(_)          => size_of::<virtio_gpu_update_cursor>(),
             VirtioGpuCommand::CmdMoveCursor(_)            => size_of::<virtio_gpu_update_cursor>(),
             VirtioGpuCommand::CmdResourceAssignUuid(..)   => size_of::<virtio_gpu_resource_assign_uuid>(),
+            VirtioGpuCommand::CmdResourceCreateBlob(..)   => size_of::<virtio_gpu_resource_create_blob>(),
         }
     }
 
@@ -568,33 +902,278 @@ impl VirtioGpuCommand {
         cmd: &GuestMemoryMmap,
         addr: GuestAddress
     ) -> VirtioGpuCommandResult  {
+        Self::decode_from(&(cmd, addr))
+    }
+
+    /// Decode a command from a buffer already copied out of the guest
+    pub fn decode_from_slice(buf: &[u8]) -> VirtioGpuCommandResult {
+        Self::decode_from(&buf)
+    }
+
+    /// Decodes the command at `addr` of a descriptor `len` bytes long, rejecting what `decode`
+    /// lets through, see `validate_strict`.
+    pub fn decode_strict(cmd: &GuestMemoryMmap, addr: GuestAddress, len: usize) -> VirtioGpuCommandResult {
+        let command = Self::decode(cmd, addr)?;
+        command.validate_strict(len)?;
+        Ok(command)
+    }
+
+    /// Decodes the command filling `buf`, rejecting what `decode_from_slice` lets through.
+    pub fn decode_strict_from_slice(buf: &[u8]) -> VirtioGpuCommandResult {
+        let command = Self::decode_from_slice(buf)?;
+        command.validate_strict(buf.len())?;
+        Ok(command)
+    }
+
+    /// Whether the command belongs to a rendering context, only those may set `ctx_id`.
+    pub fn takes_ctx_id(&self) -> bool {
+        use VirtioGpuCommand::*;
+        matches!(
+            self,
+            CmdCtxCreate(_)
+                | CmdCtxDestroy(_)
+                | CmdCtxAttachResource(_)
+                | CmdCtxDetachResource(_)
+                | CmdResourceCreate3D(_)
+                | CmdTransferToHost3D(_)
+                | CmdTransferFromHost3D(_)
+                | CmdSubmit3D(_)
+                | CmdResourceCreateBlob(_)
+        )
+    }
+
+    /// The reserved fields of the command, the guest has to leave them zeroed.
+    fn padding_is_zero(&self) -> bool {
+        use VirtioGpuCommand::*;
+        let padding = match self {
+            CmdResourceUnref(cmd)         => cmd.padding,
+            CmdResourceFlush(cmd)         => cmd.padding,
+            CmdTransferToHost2D(cmd)      => cmd.padding,
+            CmdResourceDetachBacking(cmd) => cmd.padding,
+            CmdGetCapsetInfo(cmd)         => cmd.padding,
+            CmdGetEdid(cmd)               => cmd.padding,
+            CmdResourceAssignUuid(cmd)    => cmd.padding,
+            CmdCtxAttachResource(cmd)     => cmd.padding,
+            CmdCtxDetachResource(cmd)     => cmd.padding,
+            CmdResourceCreate3D(cmd)      => cmd.padding,
+            CmdSubmit3D(cmd)              => cmd.padding,
+            CmdResourceMapBlob(cmd)       => cmd.padding,
+            CmdResourceUnmapBlob(cmd)     => cmd.padding,
+            CmdUpdateCursor(cmd) | CmdMoveCursor(cmd) => {
+                if cmd.pos.padding.to_native() != 0 {
+                    return false;
+                }
+                cmd.padding
+            }
+            _ => Le32::from(0),
+        };
+        self.hdr().padding == [0; 3] && padding.to_native() == 0
+    }
+
+    /// Strict mode checks on a command decoded from a descriptor `len` bytes long: the
+    /// descriptor holds the whole command rather than the guest memory past it, reserved fields
+    /// are zero and only context commands carry a `ctx_id`.  Guests following the spec pass
+    /// them, `decode` doesn't check so that sloppy drivers keep working.
+    pub fn validate_strict(&self, len: usize) -> Result<(), VirtioGpuCommandDecodeError> {
+        if len < self.size() {
+            return Err(VirtioGpuCommandDecodeError::DescriptorTooShort(len));
+        }
+        if !self.padding_is_zero() {
+            return Err(VirtioGpuCommandDecodeError::NonZeroPadding);
+        }
+        let ctx_id = self.hdr().ctx_id.to_native();
+        if ctx_id != 0 && !self.takes_ctx_id() {
+            return Err(VirtioGpuCommandDecodeError::UnexpectedCtxId(ctx_id));
+        }
+        Ok(())
+    }
+
+    /// The type of the command, the one its header carries when the guest sent it.
+    pub fn command_type(&self) -> u32 {
+        use VirtioGpuCommand::*;
+        match self {
+            CmdGetDisplayInfo(_)        => VIRTIO_GPU_CMD_GET_DISPLAY_INFO,
+            CmdResourceCreate2D(_)      => VIRTIO_GPU_CMD_RESOURCE_CREATE_2D,
+            CmdResourceUnref(_)         => VIRTIO_GPU_CMD_RESOURCE_UNREF,
+            CmdSetScanout(_)            => VIRTIO_GPU_CMD_SET_SCANOUT,
+            CmdResourceFlush(_)         => VIRTIO_GPU_CMD_RESOURCE_FLUSH,
+            CmdTransferToHost2D(_)      => VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D,
+            CmdResourceAttachBacking(_) => VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
+            CmdResourceDetachBacking(_) => VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING,
+            CmdGetCapsetInfo(_)         => VIRTIO_GPU_CMD_GET_CAPSET_INFO,
+            CmdGetCapset(_)             => VIRTIO_GPU_CMD_GET_CAPSET,
+            CmdGetEdid(_)               => VIRTIO_GPU_CMD_GET_EDID,
+            CmdResourceAssignUuid(_)    => VIRTIO_GPU_CMD_RESOURCE_ASSIGN_UUID,
+            CmdResourceCreateBlob(_)    => VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB,
+            CmdCtxCreate(_)             => VIRTIO_GPU_CMD_CTX_CREATE,
+            CmdCtxDestroy(_)            => VIRTIO_GPU_CMD_CTX_DESTROY,
+            CmdCtxAttachResource(_)     => VIRTIO_GPU_CMD_CTX_ATTACH_RESOURCE,
+            CmdCtxDetachResource(_)     => VIRTIO_GPU_CMD_CTX_DETACH_RESOURCE,
+            CmdResourceCreate3D(_)      => VIRTIO_GPU_CMD_RESOURCE_CREATE_3D,
+            CmdTransferToHost3D(_)      => VIRTIO_GPU_CMD_TRANSFER_TO_HOST_3D,
+            CmdTransferFromHost3D(_)    => VIRTIO_GPU_CMD_TRANSFER_FROM_HOST_3D,
+            CmdSubmit3D(_)              => VIRTIO_GPU_CMD_SUBMIT_3D,
+            CmdResourceMapBlob(_)       => VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB,
+            CmdResourceUnmapBlob(_)     => VIRTIO_GPU_CMD_RESOURCE_UNMAP_BLOB,
+            CmdUpdateCursor(_)          => VIRTIO_GPU_CMD_UPDATE_CURSOR,
+            CmdMoveCursor(_)            => VIRTIO_GPU_CMD_MOVE_CURSOR,
+        }
+    }
+
+    /// Serializes the command, the inverse of `decode`.  The header gets the type of the variant
+    /// whatever it held.  The data following the command, like memory entries or the command
+    /// stream of SUBMIT_3D, isn't part of it.
+    pub fn encode(&self) -> Vec<u8> {
         use VirtioGpuCommand::*;
-        let hdr = cmd.read_obj::<virtio_gpu_ctrl_hdr>(addr)?;
+        let mut buf = match self {
+            CmdGetDisplayInfo(cmd)        => cmd.as_slice().to_vec(),
+            CmdResourceCreate2D(cmd)      => cmd.as_slice().to_vec(),
+            CmdResourceUnref(cmd)         => cmd.as_slice().to_vec(),
+            CmdSetScanout(cmd)            => cmd.as_slice().to_vec(),
+            CmdResourceFlush(cmd)         => cmd.as_slice().to_vec(),
+            CmdTransferToHost2D(cmd)      => cmd.as_slice().to_vec(),
+            CmdResourceAttachBacking(cmd) => cmd.as_slice().to_vec(),
+            CmdResourceDetachBacking(cmd) => cmd.as_slice().to_vec(),
+            CmdGetCapsetInfo(cmd)         => cmd.as_slice().to_vec(),
+            CmdGetCapset(cmd)             => cmd.as_slice().to_vec(),
+            CmdGetEdid(cmd)               => cmd.as_slice().to_vec(),
+            CmdResourceAssignUuid(cmd)    => cmd.as_slice().to_vec(),
+            CmdResourceCreateBlob(cmd)    => cmd.as_slice().to_vec(),
+            CmdCtxCreate(cmd)             => cmd.as_slice().to_vec(),
+            CmdCtxDestroy(cmd)            => cmd.as_slice().to_vec(),
+            CmdCtxAttachResource(cmd)     => cmd.as_slice().to_vec(),
+            CmdCtxDetachResource(cmd)     => cmd.as_slice().to_vec(),
+            CmdResourceCreate3D(cmd)      => cmd.as_slice().to_vec(),
+            CmdTransferToHost3D(cmd)      => cmd.as_slice().to_vec(),
+            CmdTransferFromHost3D(cmd)    => cmd.as_slice().to_vec(),
+            CmdSubmit3D(cmd)              => cmd.as_slice().to_vec(),
+            CmdResourceMapBlob(cmd)       => cmd.as_slice().to_vec(),
+            CmdResourceUnmapBlob(cmd)     => cmd.as_slice().to_vec(),
+            CmdUpdateCursor(cmd)          => cmd.as_slice().to_vec(),
+            CmdMoveCursor(cmd)            => cmd.as_slice().to_vec(),
+        };
+        buf[..4].copy_from_slice(&self.command_type().to_le_bytes());
+        buf
+    }
+
+    /// Writes the command at `addr` of guest memory, returns the number of bytes written.
+    pub fn encode_into(&self, mem: &GuestMemoryMmap, addr: GuestAddress) -> Result<usize, GuestMemoryError> {
+        let buf = self.encode();
+        mem.write_slice(&buf, addr)?;
+        Ok(buf.len())
+    }
+
+    /// Serializes the memory entries following RESOURCE_ATTACH_BACKING or RESOURCE_CREATE_BLOB.
+    pub fn encode_mem_entries(entries: &[(GuestAddress, usize)]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(entries.len() * size_of::<virtio_gpu_mem_entry>());
+        for &(addr, length) in entries {
+            let entry = virtio_gpu_mem_entry {
+                addr:    Le64::from(addr.0),
+                length:  Le32::from(length as u32),
+                padding: Default::default(),
+            };
+            buf.extend_from_slice(entry.as_slice());
+        }
+        buf
+    }
+
+    /// Decodes the memory entries following RESOURCE_ATTACH_BACKING or RESOURCE_CREATE_BLOB, the
+    /// command `self` was decoded from `addr`, as `(address, length)` pairs ready for
+    /// `sglist_to_rutabaga_iovecs`.  Other commands have no entries.
+    pub fn decode_mem_entries(
+        &self,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+    ) -> Result<Vec<(GuestAddress, usize)>, VirtioGpuCommandDecodeError> {
+        self.decode_mem_entries_from(&(mem, addr))
+    }
+
+    /// Decodes the memory entries following the command `self` was decoded from in `buf`.
+    pub fn decode_mem_entries_from_slice(&self, buf: &[u8]) -> Result<Vec<(GuestAddress, usize)>, VirtioGpuCommandDecodeError> {
+        self.decode_mem_entries_from(&buf)
+    }
+
+    fn decode_mem_entries_from<S: CommandSource>(
+        &self,
+        cmd: &S,
+    ) -> Result<Vec<(GuestAddress, usize)>, VirtioGpuCommandDecodeError> {
+        let (start, nr_entries) = match self {
+            VirtioGpuCommand::CmdResourceAttachBacking(cmd) => {
+                (size_of::<virtio_gpu_resource_attach_backing>(), cmd.nr_entries.to_native())
+            }
+            VirtioGpuCommand::CmdResourceCreateBlob(cmd) => {
+                (size_of::<virtio_gpu_resource_create_blob>(), cmd.nr_entries.to_native())
+            }
+            _ => return Ok(Vec::new()),
+        };
+        if nr_entries > VIRTIO_GPU_MAX_MEM_ENTRIES {
+            return Err(VirtioGpuCommandDecodeError::TooManyMemEntries(nr_entries));
+        }
+
+        let mut entries = Vec::with_capacity(nr_entries as usize);
+        for index in 0..nr_entries as usize {
+            let entry: virtio_gpu_mem_entry = cmd.read_at(start + index * size_of::<virtio_gpu_mem_entry>())?;
+            let (addr, length) = (entry.addr.to_native(), entry.length.to_native());
+            if length == 0 || addr.checked_add(length as u64).is_none() {
+                return Err(VirtioGpuCommandDecodeError::InvalidMemEntry(index));
+            }
+            entries.push((GuestAddress(addr), length as usize));
+        }
+        Ok(entries)
+    }
+
+    /// Decode a command of the cursor queue, which only carries UPDATE_CURSOR and MOVE_CURSOR.
+    pub fn decode_cursor(
+        cmd: &GuestMemoryMmap,
+        addr: GuestAddress
+    ) -> VirtioGpuCommandResult  {
+        Self::decode_cursor_from(&(cmd, addr))
+    }
+
+    /// Decode a command of the cursor queue from a buffer already copied out of the guest
+    pub fn decode_cursor_from_slice(buf: &[u8]) -> VirtioGpuCommandResult {
+        Self::decode_cursor_from(&buf)
+    }
+
+    fn decode_cursor_from<S: CommandSource>(cmd: &S) -> VirtioGpuCommandResult {
+        let hdr = cmd.read_cmd::<virtio_gpu_ctrl_hdr>()?;
+        if !is_cursor_command(hdr.type_.to_native()) {
+            return Err(VirtioGpuCommandDecodeError::InvalidCommand(hdr.type_.to_native()));
+        }
+        Self::decode_from(cmd)
+    }
+
+    fn decode_from<S: CommandSource>(cmd: &S) -> VirtioGpuCommandResult {
+        use VirtioGpuCommand::*;
+        let hdr = cmd.read_cmd::<virtio_gpu_ctrl_hdr>()?;
         Ok(match hdr.type_.into() {
-            VIRTIO_GPU_CMD_GET_DISPLAY_INFO         => CmdGetDisplayInfo(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D       => CmdResourceCreate2D(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_RESOURCE_UNREF           => CmdResourceUnref(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D      => CmdTransferToHost2D(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_SET_SCANOUT              => CmdSetScanout(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_RESOURCE_FLUSH           => CmdResourceFlush(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING  => CmdResourceAttachBacking(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING  => CmdResourceDetachBacking(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_GET_CAPSET_INFO          => CmdGetCapsetInfo(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_GET_CAPSET               => CmdGetCapset(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_GET_EDID                 => CmdGetEdid(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_RESOURCE_ASSIGN_UUID     => CmdResourceAssignUuid(cmd.read_obj(addr)?),
-
-            VIRTIO_GPU_CMD_CTX_CREATE               => CmdCtxCreate(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_CTX_DESTROY              => CmdCtxDestroy(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_CTX_ATTACH_RESOURCE      => CmdCtxAttachResource(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_CTX_DETACH_RESOURCE      => CmdCtxDetachResource(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_RESOURCE_CREATE_3D       => CmdResourceCreate3D(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_3D      => CmdTransferToHost3D(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_TRANSFER_FROM_HOST_3D    => CmdTransferFromHost3D(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_SUBMIT_3D                => CmdSubmit3D(cmd.read_obj(addr)?),
-
-            VIRTIO_GPU_CMD_UPDATE_CURSOR            => CmdUpdateCursor(cmd.read_obj(addr)?),
-            VIRTIO_GPU_CMD_MOVE_CURSOR              => CmdMoveCursor(cmd.read_obj(addr)?),
+            VIRTIO_GPU_CMD_GET_DISPLAY_INFO         => CmdGetDisplayInfo(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_2D       => CmdResourceCreate2D(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_RESOURCE_UNREF           => CmdResourceUnref(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D      => CmdTransferToHost2D(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_SET_SCANOUT              => CmdSetScanout(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_RESOURCE_FLUSH           => CmdResourceFlush(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING  => CmdResourceAttachBacking(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING  => CmdResourceDetachBacking(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_GET_CAPSET_INFO          => CmdGetCapsetInfo(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_GET_CAPSET               => CmdGetCapset(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_GET_EDID                 => CmdGetEdid(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_RESOURCE_ASSIGN_UUID     => CmdResourceAssignUuid(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB     => CmdResourceCreateBlob(cmd.read_cmd()?),
+
+            VIRTIO_GPU_CMD_CTX_CREATE               => CmdCtxCreate(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_CTX_DESTROY              => CmdCtxDestroy(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_CTX_ATTACH_RESOURCE      => CmdCtxAttachResource(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_CTX_DETACH_RESOURCE      => CmdCtxDetachResource(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_RESOURCE_CREATE_3D       => CmdResourceCreate3D(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_TRANSFER_TO_HOST_3D      => CmdTransferToHost3D(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_TRANSFER_FROM_HOST_3D    => CmdTransferFromHost3D(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_SUBMIT_3D                => CmdSubmit3D(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_RESOURCE_MAP_BLOB        => CmdResourceMapBlob(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_RESOURCE_UNMAP_BLOB      => CmdResourceUnmapBlob(cmd.read_cmd()?),
+
+            VIRTIO_GPU_CMD_UPDATE_CURSOR            => CmdUpdateCursor(cmd.read_cmd()?),
+            VIRTIO_GPU_CMD_MOVE_CURSOR              => CmdMoveCursor(cmd.read_cmd()?),
 
             type_ => return Err(VirtioGpuCommandDecodeError::InvalidCommand(type_)),
         })
@@ -639,6 +1218,10 @@ pub enum VirtioGpuResponse {
         size:        u32,
         edid:        [u8; 1024],
     },
+    /// VIRTIO_GPU_MAP_CACHE_* caching of a mapped blob
+    OkMapInfo {
+        map_info:    u32,
+    },
 
     // Err response
     ErrUnspec,
@@ -654,7 +1237,11 @@ pub enum VirtioGpuResponse {
     RutabagaError(RutabagaError),
     UnsupportPlatform(TryFromIntError),
     DisplayErr(GpuDisplayError),
-    InvalidSglistRegion()
+    /// index of the first scatter-gather entry outside of guest memory, the entry count when
+    /// the list is too short
+    InvalidSglistRegion(usize),
+    /// the response needs more bytes than the writable descriptors provide
+    ResponseTooLarge(usize),
 }
 
 impl VirtioGpuResponse {
@@ -665,15 +1252,66 @@ impl VirtioGpuResponse {
         fence_id: u64,
         ctx_id:   u32,
     ) -> Result<Vec<u8>, VirtioGpuResponse> {
-        let hdr = virtio_gpu_ctrl_hdr {
+        self.encode_with_hdr(virtio_gpu_ctrl_hdr {
             type_:    Le32::from(self.get_resp_command_const()),
             flags:    Le32::from(flags),
             fence_id: Le64::from(fence_id),
             ctx_id:   Le32::from(ctx_id),
+            ..Default::default()
+        })
+    }
+
+    /// Encode the `VirtioGpuResponse` to the command of header `cmd`, echoing its fence, context
+    /// and ring index as the guest expects of fenced commands.
+    pub fn encode_reply(&self, cmd: &virtio_gpu_ctrl_hdr) -> Result<Vec<u8>, VirtioGpuResponse> {
+        self.encode_with_hdr(self.reply_hdr(cmd))
+    }
+
+    fn reply_hdr(&self, cmd: &virtio_gpu_ctrl_hdr) -> virtio_gpu_ctrl_hdr {
+        virtio_gpu_ctrl_hdr {
+            type_:    Le32::from(self.get_resp_command_const()),
+            flags:    cmd.flags,
+            fence_id: cmd.fence_id,
+            ctx_id:   cmd.ctx_id,
+            ring_idx: cmd.ring_idx,
             padding:  Default::default(),
-        };
+        }
+    }
+
+    fn encode_with_hdr(&self, hdr: virtio_gpu_ctrl_hdr) -> Result<Vec<u8>, VirtioGpuResponse> {
+        self.encode_parts(hdr, |head, tail| Ok([head, tail].concat()))
+    }
+
+    /// Encode the `VirtioGpuResponse` to the command of header `cmd` straight into the response
+    /// descriptors `regions` of guest memory, filling them in order without going through an
+    /// intermediate buffer.  The reply echoes the command's fence as `encode_reply` does.
+    ///
+    /// Returns the number of bytes written.
+    pub fn encode_into(
+        &self,
+        cmd:     &virtio_gpu_ctrl_hdr,
+        mem:     &GuestMemoryMmap,
+        regions: &[(GuestAddress, usize)],
+    ) -> Result<usize, VirtioGpuResponse> {
+        self.encode_parts(self.reply_hdr(cmd), |head, tail| {
+            let len = head.len() + tail.len();
+            let capacity: usize = regions.iter().map(|&(_, len)| len).sum();
+            if len > capacity {
+                return Err(VirtioGpuResponse::ResponseTooLarge(len));
+            }
+            write_regions(mem, regions, 0, head)?;
+            write_regions(mem, regions, head.len(), tail)?;
+            Ok(len)
+        })
+    }
 
-        let result: Vec<u8> = match *self {
+    /// Hands the response to `emit` as its fixed size part followed by its variable length data,
+    /// only capsets have any.
+    fn encode_parts<R, F>(&self, hdr: virtio_gpu_ctrl_hdr, emit: F) -> Result<R, VirtioGpuResponse>
+    where
+        F: FnOnce(&[u8], &[u8]) -> Result<R, VirtioGpuResponse>,
+    {
+        match *self {
             VirtioGpuResponse::OkDisplayInfo(ref inner) => {
                 if inner.len() > VIRTIO_GPU_MAX_SCANOUTS {
                     return Err(VirtioGpuResponse::TooManyScanout(inner.len()));
@@ -685,11 +1323,11 @@ impl VirtioGpuResponse {
                 for (pmode, &(width, height)) in resp.pmodes.iter_mut().zip(inner) {
                     pmode.r.width = Le32::from(width);
                     pmode.r.height = Le32::from(height);
-                    // enable the display screen
-                    pmode.enabled = Le32::from(1)
+                    // enable the display screen, scanouts without a mode are disabled
+                    pmode.enabled = Le32::from((width != 0 && height != 0) as u32)
                 }
 
-                resp.as_slice().iter().cloned().collect()
+                emit(resp.as_slice(), &[])
             }
             VirtioGpuResponse::OkCapsetInfo{
                 capset_id,
@@ -703,7 +1341,7 @@ impl VirtioGpuResponse {
                     capset_max_size:    Le32::from(size),
                     padding: Default::default()
                 };
-                resp.as_slice().iter().cloned().collect()
+                emit(resp.as_slice(), &[])
             }
             VirtioGpuResponse::OkEdid {
                 size,
@@ -715,25 +1353,123 @@ impl VirtioGpuResponse {
                     padding: Default::default(),
                     edid,
                 };
-                resp.as_slice().iter().cloned().collect()
+                emit(resp.as_slice(), &[])
             }
             VirtioGpuResponse::OkCapset(ref inner) => {
-                let resp = [hdr.as_slice(), inner.as_slice()].concat();
-                resp.iter().cloned().collect()
+                let resp = virtio_gpu_resp_capset {
+                    hdr,
+                    capset_data: inner,
+                };
+                emit(resp.hdr.as_slice(), resp.capset_data)
             }
             VirtioGpuResponse::OkResourceUuid{ uuid } => {
                 let uuid_resp = virtio_gpu_resp_resource_uuid {
                     hdr,
                     uuid,
                 };
-                uuid_resp.as_slice().iter().cloned().collect()
+                emit(uuid_resp.as_slice(), &[])
+            }
+            VirtioGpuResponse::OkMapInfo { map_info } => {
+                let resp = virtio_gpu_resp_map_info {
+                    hdr,
+                    map_info: Le32::from(map_info),
+                    padding: Default::default(),
+                };
+                emit(resp.as_slice(), &[])
             }
             _ => {
-                hdr.as_slice().iter().cloned().collect()
+                emit(hdr.as_slice(), &[])
+            }
+        }
+    }
+
+    /// Decode a response buffer as written by `encode`, returning its header along with the
+    /// response.  Display info responses list the scanouts up to the last enabled one.
+    pub fn decode(
+        buf: &[u8],
+    ) -> Result<(virtio_gpu_ctrl_hdr, VirtioGpuResponse), VirtioGpuCommandDecodeError> {
+        let hdr: virtio_gpu_ctrl_hdr = buf.read_cmd()?;
+        let resp = match hdr.type_.to_native() {
+            VIRTIO_GPU_RESP_OK_NODATA => VirtioGpuResponse::OkNoData,
+            VIRTIO_GPU_RESP_OK_DISPLAY_INFO => {
+                let resp: virtio_gpu_resp_display_info = buf.read_cmd()?;
+                let num_scanouts = resp.pmodes
+                    .iter()
+                    .rposition(|pmode| pmode.enabled.to_native() != 0)
+                    .map_or(0, |last| last + 1);
+                VirtioGpuResponse::OkDisplayInfo(
+                    resp.pmodes[..num_scanouts]
+                        .iter()
+                        .map(|pmode| (pmode.r.width.to_native(), pmode.r.height.to_native()))
+                        .collect(),
+                )
+            }
+            VIRTIO_GPU_RESP_OK_CAPSET_INFO => {
+                let resp: virtio_gpu_resp_capset_info = buf.read_cmd()?;
+                VirtioGpuResponse::OkCapsetInfo {
+                    capset_id: resp.capset_id.to_native(),
+                    version:   resp.capset_max_version.to_native(),
+                    size:      resp.capset_max_size.to_native(),
+                }
             }
+            VIRTIO_GPU_RESP_OK_CAPSET => {
+                VirtioGpuResponse::OkCapset(buf[size_of::<virtio_gpu_ctrl_hdr>()..].to_vec())
+            }
+            VIRTIO_GPU_RESP_OK_EDID => {
+                let resp: virtio_gpu_resp_edid = buf.read_cmd()?;
+                VirtioGpuResponse::OkEdid {
+                    size: resp.size.to_native(),
+                    edid: resp.edid,
+                }
+            }
+            VIRTIO_GPU_RESP_OK_RESOURCE_UUID => {
+                let resp: virtio_gpu_resp_resource_uuid = buf.read_cmd()?;
+                VirtioGpuResponse::OkResourceUuid { uuid: resp.uuid }
+            }
+            VIRTIO_GPU_RESP_OK_MAP_INFO => {
+                let resp: virtio_gpu_resp_map_info = buf.read_cmd()?;
+                VirtioGpuResponse::OkMapInfo { map_info: resp.map_info.to_native() }
+            }
+            VIRTIO_GPU_RESP_ERR_UNSPEC => VirtioGpuResponse::ErrUnspec,
+            VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY => VirtioGpuResponse::ErrOutOfMemory,
+            VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID => VirtioGpuResponse::ErrInvalidScanoutId,
+            VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID => VirtioGpuResponse::ErrInvalidResourceId,
+            VIRTIO_GPU_RESP_ERR_INVALID_CONTEXT_ID => VirtioGpuResponse::ErrInvalidContextId,
+            VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER => VirtioGpuResponse::ErrInvalidParameter,
+            type_ => return Err(VirtioGpuCommandDecodeError::InvalidCommand(type_)),
         };
+        Ok((hdr, resp))
+    }
 
-        Ok(result)
+    /// Encode the `VirtioGpuResponse` across several writable buffers, filling them in order.
+    /// Guest drivers don't have to provide large responses such as `OkCapset` in a single
+    /// contiguous descriptor.
+    ///
+    /// Returns the number of bytes written.
+    pub fn encode_to_slices(
+        &self,
+        flags:    u32,
+        fence_id: u64,
+        ctx_id:   u32,
+        bufs:     &mut [IoSliceMut],
+    ) -> Result<usize, VirtioGpuResponse> {
+        let encoded = self.encode(flags, fence_id, ctx_id)?;
+        let available: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if available < encoded.len() {
+            return Err(VirtioGpuResponse::ResponseTooLarge(encoded.len()));
+        }
+
+        let mut remaining = encoded.as_slice();
+        for buf in bufs.iter_mut() {
+            if remaining.is_empty() {
+                break;
+            }
+            let len = min(buf.len(), remaining.len());
+            buf[..len].copy_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+        }
+
+        Ok(encoded.len())
     }
 
     pub fn get_resp_command_const(&self) -> u32 {
@@ -742,7 +1478,9 @@ impl VirtioGpuResponse {
             Self::OkDisplayInfo(_)     => VIRTIO_GPU_RESP_OK_DISPLAY_INFO,
             Self::OkCapsetInfo{..}     => VIRTIO_GPU_RESP_OK_CAPSET_INFO,
             Self::OkCapset(_)          => VIRTIO_GPU_RESP_OK_CAPSET,
+            Self::OkEdid{..}           => VIRTIO_GPU_RESP_OK_EDID,
             Self::OkResourceUuid{..}   => VIRTIO_GPU_RESP_OK_RESOURCE_UUID,
+            Self::OkMapInfo{..}        => VIRTIO_GPU_RESP_OK_MAP_INFO,
 
             Self::ErrUnspec            => VIRTIO_GPU_RESP_ERR_UNSPEC,
             Self::ErrOutOfMemory       => VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY,
@@ -750,15 +1488,43 @@ impl VirtioGpuResponse {
             Self::ErrInvalidResourceId => VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID,
             Self::ErrInvalidContextId  => VIRTIO_GPU_RESP_ERR_INVALID_CONTEXT_ID,
             Self::ErrInvalidParameter  => VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER,
+            Self::InvalidSglistRegion(_) => VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER,
             _                          => VIRTIO_GPU_RESP_ERR_UNSPEC,
         }
     }
 }
 
+/// Writes `data` at `offset` bytes into the concatenation of the guest memory `regions`.
+fn write_regions(
+    mem:        &GuestMemoryMmap,
+    regions:    &[(GuestAddress, usize)],
+    mut offset: usize,
+    mut data:   &[u8],
+) -> Result<(), GuestMemoryError> {
+    for &(addr, len) in regions {
+        if data.is_empty() {
+            break;
+        }
+        if offset >= len {
+            offset -= len;
+            continue;
+        }
+        let n = min(len - offset, data.len());
+        let at = addr
+            .checked_add(offset as u64)
+            .ok_or(GuestMemoryError::InvalidGuestAddress(addr))?;
+        mem.write_slice(&data[..n], at)?;
+        data = &data[n..];
+        offset = 0;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
-    use crate::VirtioGpuResponse;
-    use crate::protocol::VIRTIO_GPU_MAX_SCANOUTS;
+    use crate::protocol::*;
+    use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap, Le32, Le64};
+    use std::io::IoSliceMut;
 
     #[test]
     fn test_encode_resp() {
@@ -782,6 +1548,15 @@ pub(crate) mod tests {
                     0x01, 0x00, 0x00, 0x00, // enabled
                     0x00, 0x00, 0x00, 0x00, // flags
                 ], vec![0; 24 * (VIRTIO_GPU_MAX_SCANOUTS - 1)]].concat()),
+            (VirtioGpuResponse::OkDisplayInfo(vec![(1920, 1080), (0, 0)]), 0x01, 0x11,
+                [vec![
+                    0x00, 0x00, 0x00, 0x00, // x
+                    0x00, 0x00, 0x00, 0x00, // y
+                    0x80, 0x07, 0x00, 0x00, // width
+                    0x38, 0x04, 0x00, 0x00, // height
+                    0x01, 0x00, 0x00, 0x00, // enabled
+                    0x00, 0x00, 0x00, 0x00, // flags
+                ], vec![0; 24 * (VIRTIO_GPU_MAX_SCANOUTS - 1)]].concat()),
             (VirtioGpuResponse::OkCapsetInfo {
                     capset_id: 1,
                     version: 2,
@@ -796,6 +1571,10 @@ pub(crate) mod tests {
                     0x00, 0x01, 0x02
                 ]),
             (VirtioGpuResponse::OkResourceUuid { uuid: [0x02; 16] }, 0x05, 0x11, vec![0x02;16]),
+            (VirtioGpuResponse::OkMapInfo { map_info: 0x03 }, 0x06, 0x11, vec![
+                    0x03, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00, 0x00,
+                ]),
             (VirtioGpuResponse::ErrUnspec, 0x00, 0x12, vec![]),
             (VirtioGpuResponse::ErrOutOfMemory, 0x01, 0x12, vec![]),
             (VirtioGpuResponse::ErrInvalidScanoutId, 0x02, 0x12, vec![]),
@@ -814,4 +1593,351 @@ pub(crate) mod tests {
         }
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_encode_to_slices() {
+        let resp = VirtioGpuResponse::OkCapset((0..40).collect());
+        let expected = resp.encode(0x00, 0x00, 0x00).unwrap();
+
+        let (mut first, mut second, mut third) = ([0u8; 10], [0u8; 30], [0u8; 64]);
+        let written = {
+            let mut bufs = [
+                IoSliceMut::new(&mut first),
+                IoSliceMut::new(&mut second),
+                IoSliceMut::new(&mut third),
+            ];
+            resp.encode_to_slices(0x00, 0x00, 0x00, &mut bufs).unwrap()
+        };
+        assert_eq!(written, expected.len());
+        let result = [&first[..], &second[..], &third[..]].concat();
+        assert_eq!(&result[..written], expected.as_slice());
+
+        let mut small = [0u8; 16];
+        match resp.encode_to_slices(0x00, 0x00, 0x00, &mut [IoSliceMut::new(&mut small)]) {
+            Err(VirtioGpuResponse::ResponseTooLarge(size)) => assert_eq!(size, expected.len()),
+            _ => panic!("encoding into a too small buffer should fail"),
+        }
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let cmd = virtio_gpu_ctrl_hdr {
+            flags: Le32::from(0x01),
+            fence_id: Le64::from(0x10),
+            ctx_id: Le32::from(0x02),
+            ..Default::default()
+        };
+        let resp = VirtioGpuResponse::OkCapset((0..40).collect());
+        let expected = resp.encode(0x01, 0x10, 0x02).unwrap();
+        let written = resp.encode_into(&cmd, &mem, &[(GuestAddress(0x100), 128)]).unwrap();
+        assert_eq!(written, expected.len());
+        let mut buf = vec![0u8; written];
+        mem.read_slice(&mut buf, GuestAddress(0x100)).unwrap();
+        assert_eq!(buf, expected);
+
+        // split across descriptors, the header straddling the first two
+        let regions = [(GuestAddress(0x400), 20), (GuestAddress(0x500), 10), (GuestAddress(0x600), 64)];
+        assert_eq!(resp.encode_into(&cmd, &mem, &regions).unwrap(), expected.len());
+        let mut buf = vec![0u8; expected.len()];
+        mem.read_slice(&mut buf[..20], GuestAddress(0x400)).unwrap();
+        mem.read_slice(&mut buf[20..30], GuestAddress(0x500)).unwrap();
+        mem.read_slice(&mut buf[30..], GuestAddress(0x600)).unwrap();
+        assert_eq!(buf, expected);
+
+        let cmd = virtio_gpu_ctrl_hdr::default();
+        let resp = VirtioGpuResponse::OkMapInfo { map_info: VIRTIO_GPU_MAP_CACHE_WC };
+        let written = resp.encode_into(&cmd, &mem, &[(GuestAddress(0x200), 32)]).unwrap();
+        let mut buf = vec![0u8; written];
+        mem.read_slice(&mut buf, GuestAddress(0x200)).unwrap();
+        assert_eq!(buf, resp.encode(0, 0, 0).unwrap());
+
+        // the descriptors are too short, or outside of guest memory
+        assert!(matches!(
+            resp.encode_into(&cmd, &mem, &[(GuestAddress(0x200), 16), (GuestAddress(0x300), 15)]),
+            Err(VirtioGpuResponse::ResponseTooLarge(32))
+        ));
+        assert!(matches!(
+            resp.encode_into(&cmd, &mem, &[(GuestAddress(0xff0), 32)]),
+            Err(VirtioGpuResponse::EncodeError(_))
+        ));
+    }
+
+    #[test]
+    fn test_encode_capset() {
+        let capset: Vec<u8> = (0..40).collect();
+        let resp = virtio_gpu_resp_capset {
+            hdr: virtio_gpu_ctrl_hdr {
+                type_: Le32::from(VIRTIO_GPU_RESP_OK_CAPSET),
+                ..Default::default()
+            },
+            capset_data: &capset,
+        };
+        assert_eq!(resp.encoded_len(), 24 + 40);
+
+        // the descriptor may be larger than the response, the rest is left alone
+        let mut buf = [0xffu8; 80];
+        assert_eq!(resp.write_to(&mut buf).unwrap(), 64);
+        assert_eq!(buf[..4], VIRTIO_GPU_RESP_OK_CAPSET.to_le_bytes());
+        assert_eq!(buf[24..64], capset[..]);
+        assert_eq!(buf[64..], [0xffu8; 16]);
+        assert_eq!(VirtioGpuResponse::OkCapset(capset.clone()).encode(0, 0, 0).unwrap(), buf[..64].to_vec());
+
+        assert!(matches!(resp.write_to(&mut buf[..63]), Err(VirtioGpuResponse::ResponseTooLarge(64))));
+    }
+
+    #[test]
+    fn test_decode_resp() {
+        let buf = VirtioGpuResponse::OkDisplayInfo(vec![(1920, 1080), (0, 0), (1280, 720)])
+            .encode(0x01, 0x10, 0x02)
+            .unwrap();
+        match VirtioGpuResponse::decode(&buf).unwrap() {
+            (hdr, VirtioGpuResponse::OkDisplayInfo(scanouts)) => {
+                assert_eq!(hdr.flags.to_native(), 0x01);
+                assert_eq!(hdr.fence_id.to_native(), 0x10);
+                assert_eq!(hdr.ctx_id.to_native(), 0x02);
+                assert_eq!(scanouts, vec![(1920, 1080), (0, 0), (1280, 720)]);
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        let buf = VirtioGpuResponse::OkCapset((0..40).collect()).encode(0, 0, 0).unwrap();
+        match VirtioGpuResponse::decode(&buf).unwrap().1 {
+            VirtioGpuResponse::OkCapset(capset) => assert_eq!(capset, (0..40).collect::<Vec<u8>>()),
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        let mut edid = [0u8; 1024];
+        edid[..4].copy_from_slice(&[0x00, 0xff, 0xff, 0xff]);
+        let buf = VirtioGpuResponse::OkEdid { size: 128, edid }.encode(0, 0, 0).unwrap();
+        match VirtioGpuResponse::decode(&buf).unwrap().1 {
+            VirtioGpuResponse::OkEdid { size, edid: decoded } => {
+                assert_eq!(size, 128);
+                assert_eq!(&decoded[..], &edid[..]);
+            }
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        let buf = VirtioGpuResponse::ErrInvalidResourceId.encode(0, 0, 0).unwrap();
+        assert!(matches!(VirtioGpuResponse::decode(&buf).unwrap().1, VirtioGpuResponse::ErrInvalidResourceId));
+        assert!(matches!(
+            VirtioGpuResponse::decode(&buf[..8]),
+            Err(VirtioGpuCommandDecodeError::BufferTooShort(8))
+        ));
+    }
+
+    #[test]
+    fn test_ctx_create_debug_name() {
+        let mut cmd = virtio_gpu_ctx_create::default();
+        assert_eq!(cmd.debug_name(), None);
+
+        cmd.debug_name[..8].copy_from_slice(b"glxgears");
+        // only the first nlen bytes are the name
+        cmd.nlen = Le32::from(7);
+        assert_eq!(cmd.debug_name(), Some("glxgear"));
+
+        // the length is clamped to the name field
+        cmd.debug_name = [b'a'; 64];
+        cmd.nlen = Le32::from(100);
+        assert_eq!(cmd.debug_name().map(str::len), Some(64));
+
+        cmd.debug_name[0] = 0xff;
+        assert_eq!(cmd.debug_name(), None);
+    }
+
+    #[test]
+    fn test_decode_create_blob() {
+        let mut cmd = virtio_gpu_resource_create_blob::default();
+        cmd.hdr.type_ = Le32::from(VIRTIO_GPU_CMD_RESOURCE_CREATE_BLOB);
+        cmd.resource_id = Le32::from(3);
+        cmd.blob_mem = Le32::from(VIRTIO_GPU_BLOB_MEM_HOST3D);
+        cmd.blob_id = Le64::from(0x1234);
+        cmd.size = Le64::from(4096);
+        match VirtioGpuCommand::decode_from_slice(cmd.as_slice()).unwrap() {
+            VirtioGpuCommand::CmdResourceCreateBlob(decoded) => {
+                assert_eq!(decoded.resource_id.to_native(), 3);
+                assert_eq!(decoded.blob_mem.to_native(), VIRTIO_GPU_BLOB_MEM_HOST3D);
+                assert_eq!(decoded.blob_id.to_native(), 0x1234);
+                assert_eq!(decoded.size.to_native(), 4096);
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+        assert!(matches!(
+            VirtioGpuCommand::decode_from_slice(&cmd.as_slice()[..40]),
+            Err(VirtioGpuCommandDecodeError::BufferTooShort(40))
+        ));
+    }
+
+    #[test]
+    fn test_ring_idx() {
+        let mut hdr = virtio_gpu_ctrl_hdr {
+            flags: Le32::from(VIRTIO_GPU_FLAG_FENCE),
+            fence_id: Le64::from(7),
+            ctx_id: Le32::from(2),
+            ring_idx: 3,
+            ..Default::default()
+        };
+        // the ring index only counts with its flag
+        assert_eq!(hdr.ring_idx(), None);
+        assert_eq!(hdr.fence().unwrap().fence_ctx_idx, 0);
+
+        hdr.flags = Le32::from(VIRTIO_GPU_FLAG_FENCE | VIRTIO_GPU_FLAG_INFO_RING_IDX);
+        let fence = hdr.fence().unwrap();
+        assert_eq!(hdr.ring_idx(), Some(3));
+        assert_eq!((fence.fence_id, fence.ctx_id, fence.fence_ctx_idx), (7, 2, 3));
+        assert_eq!(fence.flags, VIRTIO_GPU_FLAG_FENCE | VIRTIO_GPU_FLAG_INFO_RING_IDX);
+
+        let buf = VirtioGpuResponse::OkNoData.encode_reply(&hdr).unwrap();
+        let (reply, _) = VirtioGpuResponse::decode(&buf).unwrap();
+        assert_eq!(reply.type_.to_native(), VIRTIO_GPU_RESP_OK_NODATA);
+        assert_eq!((reply.fence_id.to_native(), reply.ring_idx()), (7, Some(3)));
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let len = VirtioGpuResponse::OkNoData.encode_into(&hdr, &mem, &[(GuestAddress(0), 64)]).unwrap();
+        let mut written = vec![0u8; len];
+        mem.read_slice(&mut written, GuestAddress(0)).unwrap();
+        assert_eq!(written, buf);
+
+        hdr.flags = Le32::from(VIRTIO_GPU_FLAG_INFO_RING_IDX);
+        assert!(hdr.fence().is_none());
+    }
+
+    #[test]
+    fn test_encode_command() {
+        let mut cursor = virtio_gpu_update_cursor::default();
+        cursor.pos.x = Le32::from(5);
+        cursor.resource_id = Le32::from(2);
+        cursor.hot_y = Le32::from(3);
+        // the header type comes from the variant
+        let buf = VirtioGpuCommand::CmdMoveCursor(cursor).encode();
+        assert_eq!(buf.len(), size_of::<virtio_gpu_update_cursor>());
+        assert_eq!(buf.len(), 56);
+        match VirtioGpuCommand::decode_from_slice(&buf).unwrap() {
+            VirtioGpuCommand::CmdMoveCursor(decoded) => {
+                assert_eq!(decoded.hdr.type_.to_native(), VIRTIO_GPU_CMD_MOVE_CURSOR);
+                assert_eq!(decoded.pos.x.to_native(), 5);
+                assert_eq!(decoded.resource_id.to_native(), 2);
+                assert_eq!(decoded.hot_y.to_native(), 3);
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+
+        let resource = virtio_gpu_ctx_resource::default();
+        let cmd = VirtioGpuCommand::CmdCtxDetachResource(resource);
+        assert_eq!(cmd.command_type(), VIRTIO_GPU_CMD_CTX_DETACH_RESOURCE);
+        assert!(matches!(
+            VirtioGpuCommand::decode_from_slice(&cmd.encode()).unwrap(),
+            VirtioGpuCommand::CmdCtxDetachResource(_)
+        ));
+
+        // with its memory entries, through guest memory
+        let mut attach = virtio_gpu_resource_attach_backing::default();
+        attach.nr_entries = Le32::from(1);
+        let cmd = VirtioGpuCommand::CmdResourceAttachBacking(attach);
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let len = cmd.encode_into(&mem, GuestAddress(0x100)).unwrap();
+        let entries = VirtioGpuCommand::encode_mem_entries(&[(GuestAddress(0x800), 64)]);
+        mem.write_slice(&entries, GuestAddress(0x100 + len as u64)).unwrap();
+        let decoded = VirtioGpuCommand::decode(&mem, GuestAddress(0x100)).unwrap();
+        assert_eq!(
+            decoded.decode_mem_entries(&mem, GuestAddress(0x100)).unwrap(),
+            vec![(GuestAddress(0x800), 64)]
+        );
+    }
+
+    #[test]
+    fn test_decode_strict() {
+        let mut flush = virtio_gpu_resource_flush::default();
+        flush.hdr.type_ = Le32::from(VIRTIO_GPU_CMD_RESOURCE_FLUSH);
+        let buf = flush.as_slice().to_vec();
+        assert!(VirtioGpuCommand::decode_strict_from_slice(&buf).is_ok());
+
+        // the guest memory past a short descriptor isn't part of the command
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        mem.write_slice(&buf, GuestAddress(0x100)).unwrap();
+        assert!(VirtioGpuCommand::decode(&mem, GuestAddress(0x100)).is_ok());
+        let short = VirtioGpuCommand::decode_strict(&mem, GuestAddress(0x100), buf.len() - 4);
+        assert!(matches!(short, Err(VirtioGpuCommandDecodeError::DescriptorTooShort(_))));
+        assert!(matches!(short.unwrap_err().response(), VirtioGpuResponse::ErrInvalidParameter));
+
+        flush.padding = Le32::from(1);
+        assert!(VirtioGpuCommand::decode_from_slice(flush.as_slice()).is_ok());
+        assert!(matches!(
+            VirtioGpuCommand::decode_strict_from_slice(flush.as_slice()),
+            Err(VirtioGpuCommandDecodeError::NonZeroPadding)
+        ));
+        flush.padding = Le32::from(0);
+        flush.hdr.padding = [0, 1, 0];
+        assert!(matches!(
+            VirtioGpuCommand::decode_strict_from_slice(flush.as_slice()),
+            Err(VirtioGpuCommandDecodeError::NonZeroPadding)
+        ));
+
+        // a context id only goes with context commands
+        flush.hdr.padding = [0; 3];
+        flush.hdr.ctx_id = Le32::from(3);
+        assert!(matches!(
+            VirtioGpuCommand::decode_strict_from_slice(flush.as_slice()),
+            Err(VirtioGpuCommandDecodeError::UnexpectedCtxId(3))
+        ));
+        let mut submit = virtio_gpu_cmd_submit::default();
+        submit.hdr.type_ = Le32::from(VIRTIO_GPU_CMD_SUBMIT_3D);
+        submit.hdr.ctx_id = Le32::from(3);
+        assert!(VirtioGpuCommand::decode_strict_from_slice(submit.as_slice()).is_ok());
+
+        assert!(matches!(
+            VirtioGpuCommandDecodeError::InvalidCommand(0).response(),
+            VirtioGpuResponse::ErrUnspec
+        ));
+    }
+
+    #[test]
+    fn test_decode_mem_entries() {
+        let cmd = virtio_gpu_resource_attach_backing {
+            hdr: virtio_gpu_ctrl_hdr {
+                type_: Le32::from(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+                ..Default::default()
+            },
+            resource_id: Le32::from(1),
+            nr_entries: Le32::from(2),
+        };
+        let entry = |addr: u64, length: u32| virtio_gpu_mem_entry {
+            addr: Le64::from(addr),
+            length: Le32::from(length),
+            ..Default::default()
+        };
+        let mut buf = cmd.as_slice().to_vec();
+        buf.extend_from_slice(entry(0x1000, 4096).as_slice());
+        buf.extend_from_slice(entry(0x8000, 512).as_slice());
+
+        let decoded = VirtioGpuCommand::decode_from_slice(&buf).unwrap();
+        assert_eq!(
+            decoded.decode_mem_entries_from_slice(&buf).unwrap(),
+            vec![(GuestAddress(0x1000), 4096), (GuestAddress(0x8000), 512)]
+        );
+        // the entries have to be all there
+        assert!(matches!(
+            decoded.decode_mem_entries_from_slice(&buf[..buf.len() - 1]),
+            Err(VirtioGpuCommandDecodeError::BufferTooShort(_))
+        ));
+
+        let mut bad = cmd.as_slice().to_vec();
+        bad.extend_from_slice(entry(0x1000, 4096).as_slice());
+        bad.extend_from_slice(entry(u64::MAX - 16, 32).as_slice());
+        assert!(matches!(
+            decoded.decode_mem_entries_from_slice(&bad),
+            Err(VirtioGpuCommandDecodeError::InvalidMemEntry(1))
+        ));
+
+        let mut many = cmd;
+        many.nr_entries = Le32::from(VIRTIO_GPU_MAX_MEM_ENTRIES + 1);
+        assert!(matches!(
+            VirtioGpuCommand::CmdResourceAttachBacking(many).decode_mem_entries_from_slice(many.as_slice()),
+            Err(VirtioGpuCommandDecodeError::TooManyMemEntries(_))
+        ));
+        assert!(VirtioGpuCommand::CmdGetDisplayInfo(cmd.hdr)
+            .decode_mem_entries_from_slice(&buf)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,524 @@
+// Virtqueues as the driver lays them out in guest memory: the device pops descriptor chains
+// from the available ring and returns them on the used ring.  The rings are the ones the frontend
+// set up on a `Vring`.  With an inflight region, the chains are marked there while the device holds
+// them, see `inflight`.
+//
+// With VIRTIO_F_RING_PACKED, a single ring of descriptors replaces the three rings of the split
+// layout.  The driver makes descriptors available in ring order, flipping their AVAIL flag on every
+// lap, and the device writes the used ones over them.  Chains are identified by the buffer id the
+// driver put in their last descriptor, and are returned in whatever order their commands complete,
+// each used entry taking the ring slots of as many descriptors as the chain had.  Inflight tracking
+// only follows split rings.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::mem::size_of;
+use std::sync::atomic::{fence, Ordering};
+
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap, Le16, Le32, Le64};
+
+use crate::inflight::InflightQueue;
+use crate::vhost::Vring;
+use crate::virtio_utils::ChainDescriptor;
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+pub const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+pub const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+pub const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+/// Feature bit of the packed layout.
+pub const VIRTIO_F_RING_PACKED: u32 = 34;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct virtq_desc {
+    pub addr:  Le64,
+    pub len:   Le32,
+    pub flags: Le16,
+    pub next:  Le16,
+}
+
+unsafe impl ByteValued for virtq_desc {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct virtq_used_elem {
+    pub id:  Le32,
+    pub len: Le32,
+}
+
+unsafe impl ByteValued for virtq_used_elem {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+pub struct virtq_packed_desc {
+    pub addr:  Le64,
+    pub len:   Le32,
+    pub id:    Le16,
+    pub flags: Le16,
+}
+
+unsafe impl ByteValued for virtq_packed_desc {}
+
+/// Where the device is in a packed ring, `next_avail` of the `Vring` being the slot of the next
+/// available descriptor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PackedRing {
+    /// the wrap counter of the lap `next_avail` is in
+    pub avail_wrap: bool,
+    /// the slot the next used descriptor is written in
+    pub next_used:  u16,
+    pub used_wrap:  bool,
+    /// descriptors in the chain of each buffer id the device holds
+    chain_lens:     BTreeMap<u16, u16>,
+}
+
+impl Default for PackedRing {
+    /// Both wrap counters start at 1.
+    fn default() -> Self {
+        PackedRing {
+            avail_wrap: true,
+            next_used:  0,
+            used_wrap:  true,
+            chain_lens: BTreeMap::new(),
+        }
+    }
+}
+
+impl PackedRing {
+    /// The ring as given by SET_VRING_BASE: the next available slot and its wrap counter in the
+    /// low half, the next used slot and its wrap counter in the high half.
+    pub fn from_base(base: u32) -> (u16, Self) {
+        let ring = PackedRing {
+            avail_wrap: base & 1 << 15 != 0,
+            next_used: (base >> 16) as u16 & 0x7fff,
+            used_wrap: base & 1 << 31 != 0,
+            chain_lens: BTreeMap::new(),
+        };
+        (base as u16 & 0x7fff, ring)
+    }
+
+    /// The base GET_VRING_BASE returns, laid out as `from_base` takes it.
+    pub fn base(&self, next_avail: u16) -> u32 {
+        let avail = next_avail as u32 | (self.avail_wrap as u32) << 15;
+        let used = self.next_used as u32 | (self.used_wrap as u32) << 15;
+        avail | used << 16
+    }
+}
+
+#[derive(Debug)]
+pub enum QueueError {
+    /// the rings reach outside of guest memory
+    Memory(GuestMemoryError),
+    /// the available ring holds a head past the end of the descriptor table
+    InvalidHead(u16),
+    /// a chain loops or holds more descriptors than its table
+    ChainTooLong(u16),
+    /// an indirect descriptor is nested, chained or of a length that isn't a table
+    InvalidIndirect(u16),
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::QueueError::*;
+
+        match self {
+            Memory(e) => write!(f, "failed to access a ring: {:?}", e),
+            InvalidHead(head) => write!(f, "descriptor head {} is out of the table", head),
+            ChainTooLong(head) => write!(f, "chain of head {} is too long", head),
+            InvalidIndirect(head) => write!(f, "chain of head {} has an invalid indirect table", head),
+        }
+    }
+}
+
+impl From<GuestMemoryError> for QueueError {
+    fn from(e: GuestMemoryError) -> Self {
+        QueueError::Memory(e)
+    }
+}
+
+/// `offset` bytes into the ring or table at `base`, which fails like an access outside of guest
+/// memory past the end of the address space.
+fn ring_addr(base: GuestAddress, offset: u64) -> Result<GuestAddress, QueueError> {
+    base.checked_add(offset)
+        .ok_or(QueueError::Memory(GuestMemoryError::InvalidGuestAddress(base)))
+}
+
+fn read_desc(mem: &GuestMemoryMmap, table: GuestAddress, index: u16) -> Result<virtq_desc, QueueError> {
+    let addr = ring_addr(table, index as u64 * size_of::<virtq_desc>() as u64)?;
+    Ok(mem.read_obj(addr)?)
+}
+
+/// Reads the chain starting at `head` of the `size` entries descriptor table at `table`,
+/// following an indirect table if the chain has one.
+pub fn read_chain(
+    mem: &GuestMemoryMmap,
+    table: GuestAddress,
+    size: u16,
+    head: u16,
+) -> Result<Vec<ChainDescriptor>, QueueError> {
+    if head >= size {
+        return Err(QueueError::InvalidHead(head));
+    }
+    let (mut table, mut size, mut index) = (table, size, head);
+    let mut indirect = false;
+    let mut chain = Vec::new();
+    loop {
+        let desc = read_desc(mem, table, index)?;
+        let flags = desc.flags.to_native();
+        if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+            let len = desc.len.to_native() as usize;
+            if indirect || flags & VIRTQ_DESC_F_NEXT != 0 || len == 0 || len % size_of::<virtq_desc>() != 0 {
+                return Err(QueueError::InvalidIndirect(head));
+            }
+            let entries = len / size_of::<virtq_desc>();
+            // the table is guest supplied, none of its entries may wrap around the address space
+            if entries > u16::MAX as usize || desc.addr.to_native().checked_add(len as u64).is_none() {
+                return Err(QueueError::InvalidIndirect(head));
+            }
+            table = GuestAddress(desc.addr.to_native());
+            size = entries as u16;
+            index = 0;
+            indirect = true;
+            continue;
+        }
+
+        if chain.len() >= size as usize {
+            return Err(QueueError::ChainTooLong(head));
+        }
+        chain.push(ChainDescriptor {
+            addr:     GuestAddress(desc.addr.to_native()),
+            len:      desc.len.to_native() as usize,
+            writable: flags & VIRTQ_DESC_F_WRITE != 0,
+        });
+        if flags & VIRTQ_DESC_F_NEXT == 0 {
+            return Ok(chain);
+        }
+        index = desc.next.to_native();
+        if index >= size {
+            return Err(QueueError::InvalidHead(index));
+        }
+    }
+}
+
+fn packed_desc_addr(table: GuestAddress, index: u16) -> Result<GuestAddress, QueueError> {
+    ring_addr(table, index as u64 * size_of::<virtq_packed_desc>() as u64)
+}
+
+/// Pops the next chain the driver made available on a packed `vring`, with its buffer id.
+fn pop_packed(mem: &GuestMemoryMmap, vring: &mut Vring) -> Result<Option<(u16, Vec<ChainDescriptor>)>, QueueError> {
+    let (table, size, first) = (vring.desc_table, vring.size, vring.next_avail);
+    let packed = vring.packed.as_mut().unwrap();
+    let flags: Le16 = mem.read_obj(ring_addr(packed_desc_addr(table, first)?, 14)?)?;
+    let flags = flags.to_native();
+    if (flags & VIRTQ_DESC_F_AVAIL != 0) != packed.avail_wrap || (flags & VIRTQ_DESC_F_USED != 0) == packed.avail_wrap {
+        return Ok(None);
+    }
+    // the descriptors are read after the flags that made them available
+    fence(Ordering::Acquire);
+
+    let (mut index, mut wrap) = (first, packed.avail_wrap);
+    let mut count = 0;
+    let mut chain = Vec::new();
+    let id = loop {
+        if count >= size {
+            return Err(QueueError::ChainTooLong(first));
+        }
+        let desc: virtq_packed_desc = mem.read_obj(packed_desc_addr(table, index)?)?;
+        count += 1;
+        index += 1;
+        if index == size {
+            index = 0;
+            wrap = !wrap;
+        }
+        let flags = desc.flags.to_native();
+        if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+            // the table's descriptors are consecutive, their NEXT flags don't matter
+            let len = desc.len.to_native() as usize;
+            if !chain.is_empty()
+                || flags & VIRTQ_DESC_F_NEXT != 0
+                || len == 0
+                || len % size_of::<virtq_packed_desc>() != 0
+                || len / size_of::<virtq_packed_desc>() > u16::MAX as usize
+                || desc.addr.to_native().checked_add(len as u64).is_none()
+            {
+                return Err(QueueError::InvalidIndirect(first));
+            }
+            let indirect = GuestAddress(desc.addr.to_native());
+            for entry in 0..(len / size_of::<virtq_packed_desc>()) as u16 {
+                let desc: virtq_packed_desc = mem.read_obj(packed_desc_addr(indirect, entry)?)?;
+                if desc.flags.to_native() & VIRTQ_DESC_F_INDIRECT != 0 {
+                    return Err(QueueError::InvalidIndirect(first));
+                }
+                chain.push(ChainDescriptor {
+                    addr:     GuestAddress(desc.addr.to_native()),
+                    len:      desc.len.to_native() as usize,
+                    writable: desc.flags.to_native() & VIRTQ_DESC_F_WRITE != 0,
+                });
+            }
+            break desc.id.to_native();
+        }
+        chain.push(ChainDescriptor {
+            addr:     GuestAddress(desc.addr.to_native()),
+            len:      desc.len.to_native() as usize,
+            writable: flags & VIRTQ_DESC_F_WRITE != 0,
+        });
+        if flags & VIRTQ_DESC_F_NEXT == 0 {
+            break desc.id.to_native();
+        }
+    };
+    if id >= size {
+        return Err(QueueError::InvalidHead(id));
+    }
+
+    vring.next_avail = index;
+    packed.avail_wrap = wrap;
+    packed.chain_lens.insert(id, count);
+    Ok(Some((id, chain)))
+}
+
+/// Returns the chain of buffer `id` on a packed `vring`.
+fn add_used_packed(mem: &GuestMemoryMmap, vring: &mut Vring, id: u16, len: u32) -> Result<(), QueueError> {
+    let (table, size) = (vring.desc_table, vring.size);
+    let packed = vring.packed.as_mut().unwrap();
+    let addr = packed_desc_addr(table, packed.next_used)?;
+    mem.write_obj(Le32::from(len), ring_addr(addr, 8)?)?;
+    mem.write_obj(Le16::from(id), ring_addr(addr, 12)?)?;
+    let mut flags = if packed.used_wrap {
+        VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+    } else {
+        0
+    };
+    if len != 0 {
+        flags |= VIRTQ_DESC_F_WRITE;
+    }
+    // the driver sees the id and the length before the flags that publish them
+    fence(Ordering::Release);
+    mem.write_obj(Le16::from(flags), ring_addr(addr, 14)?)?;
+
+    // the used entry stands for the whole chain
+    let count = packed.chain_lens.remove(&id).unwrap_or(1);
+    let next_used = packed.next_used as u32 + count as u32;
+    if next_used >= size as u32 {
+        packed.used_wrap = !packed.used_wrap;
+    }
+    packed.next_used = (next_used % size as u32) as u16;
+    Ok(())
+}
+
+/// Pops the next chain the driver made available on `vring`, with the index of its head, or its
+/// buffer id on a packed ring.
+pub fn pop_avail(mem: &GuestMemoryMmap, vring: &mut Vring) -> Result<Option<(u16, Vec<ChainDescriptor>)>, QueueError> {
+    if vring.size == 0 {
+        return Ok(None);
+    }
+    if vring.packed.is_some() {
+        return pop_packed(mem, vring);
+    }
+    // the chains a previous daemon left in flight go first, they are already behind `next_avail`
+    if let Some(head) = vring.inflight.as_mut().and_then(InflightQueue::next_resubmit) {
+        let chain = read_chain(mem, vring.desc_table, vring.size, head)?;
+        return Ok(Some((head, chain)));
+    }
+    let avail_idx: Le16 = mem.read_obj(ring_addr(vring.avail_ring, 2)?)?;
+    if avail_idx.to_native() == vring.next_avail {
+        return Ok(None);
+    }
+    // the ring entries are read after the index that published them
+    fence(Ordering::Acquire);
+
+    let slot = (vring.next_avail % vring.size) as u64;
+    let head: Le16 = mem.read_obj(ring_addr(vring.avail_ring, 4 + 2 * slot)?)?;
+    let head = head.to_native();
+    vring.next_avail = vring.next_avail.wrapping_add(1);
+    if let Some(ref mut inflight) = vring.inflight {
+        inflight.get(head)?;
+    }
+    let chain = read_chain(mem, vring.desc_table, vring.size, head)?;
+    Ok(Some((head, chain)))
+}
+
+/// Returns the chain of `head` to the driver, `len` bytes of it written by the device.
+pub fn add_used(mem: &GuestMemoryMmap, vring: &mut Vring, head: u16, len: u32) -> Result<(), QueueError> {
+    if vring.packed.is_some() {
+        return add_used_packed(mem, vring, head, len);
+    }
+    let used_idx: Le16 = mem.read_obj(ring_addr(vring.used_ring, 2)?)?;
+    let used_idx = used_idx.to_native();
+    if let Some(ref inflight) = vring.inflight {
+        inflight.pre_put(head)?;
+    }
+    let slot = (used_idx % vring.size) as u64;
+    let elem = virtq_used_elem {
+        id:  Le32::from(head as u32),
+        len: Le32::from(len),
+    };
+    mem.write_obj(elem, ring_addr(vring.used_ring, 4 + slot * size_of::<virtq_used_elem>() as u64)?)?;
+    // the driver sees the element before the index that publishes it
+    fence(Ordering::Release);
+    mem.write_obj(Le16::from(used_idx.wrapping_add(1)), ring_addr(vring.used_ring, 2)?)?;
+    if let Some(ref inflight) = vring.inflight {
+        inflight.post_put(head, used_idx.wrapping_add(1))?;
+    }
+    Ok(())
+}
+
+/// Interrupts the driver for the chains returned on `vring`.
+pub fn signal_used(vring: &Vring) {
+    if let Some(ref call) = vring.call {
+        // only fails when the counter would overflow, the driver gets interrupted anyway
+        let _ = call.write(1);
+    }
+}
+
+/// Tells the frontend the driver broke `vring`, e.g. with a chain out of guest memory.
+pub fn signal_error(vring: &Vring) {
+    if let Some(ref err) = vring.err {
+        let _ = err.write(1);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::queue::*;
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+
+    fn desc(addr: u64, len: u32, flags: u16, next: u16) -> virtq_desc {
+        virtq_desc {
+            addr: Le64::from(addr),
+            len: Le32::from(len),
+            flags: Le16::from(flags),
+            next: Le16::from(next),
+        }
+    }
+
+    fn vring() -> Vring {
+        let mut vring = Vring::default();
+        vring.size = 4;
+        vring.desc_table = GuestAddress(DESC_TABLE);
+        vring.avail_ring = GuestAddress(AVAIL_RING);
+        vring.used_ring = GuestAddress(USED_RING);
+        vring
+    }
+
+    #[test]
+    fn test_split_queue() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vring = vring();
+        assert!(pop_avail(&mem, &mut vring).unwrap().is_none());
+
+        // a request and its response, then a chain of one indirect table
+        mem.write_obj(desc(0x4000, 24, VIRTQ_DESC_F_NEXT, 2), GuestAddress(DESC_TABLE)).unwrap();
+        mem.write_obj(desc(0x5000, 24, VIRTQ_DESC_F_WRITE, 0), GuestAddress(DESC_TABLE + 32)).unwrap();
+        mem.write_obj(desc(0x6000, 32, VIRTQ_DESC_F_INDIRECT, 0), GuestAddress(DESC_TABLE + 16)).unwrap();
+        mem.write_obj(desc(0x4100, 48, VIRTQ_DESC_F_NEXT, 1), GuestAddress(0x6000)).unwrap();
+        mem.write_obj(desc(0x5100, 24, VIRTQ_DESC_F_WRITE, 0), GuestAddress(0x6010)).unwrap();
+        mem.write_obj(Le16::from(0), GuestAddress(AVAIL_RING + 4)).unwrap();
+        mem.write_obj(Le16::from(1), GuestAddress(AVAIL_RING + 6)).unwrap();
+        mem.write_obj(Le16::from(2), GuestAddress(AVAIL_RING + 2)).unwrap();
+
+        let (head, chain) = pop_avail(&mem, &mut vring).unwrap().unwrap();
+        assert_eq!(head, 0);
+        assert_eq!(chain.len(), 2);
+        assert_eq!((chain[1].addr, chain[1].len, chain[1].writable), (GuestAddress(0x5000), 24, true));
+        let (head, chain) = pop_avail(&mem, &mut vring).unwrap().unwrap();
+        assert_eq!(head, 1);
+        assert_eq!(chain.iter().map(|desc| desc.addr).collect::<Vec<_>>(), vec![GuestAddress(0x4100), GuestAddress(0x5100)]);
+        assert!(pop_avail(&mem, &mut vring).unwrap().is_none());
+
+        add_used(&mem, &mut vring, 1, 24).unwrap();
+        add_used(&mem, &mut vring, 0, 24).unwrap();
+        let used_idx: Le16 = mem.read_obj(GuestAddress(USED_RING + 2)).unwrap();
+        assert_eq!(used_idx.to_native(), 2);
+        let elem: virtq_used_elem = mem.read_obj(GuestAddress(USED_RING + 4)).unwrap();
+        assert_eq!((elem.id.to_native(), elem.len.to_native()), (1, 24));
+
+        // a chain looping on itself and a head out of the table
+        mem.write_obj(desc(0x4000, 24, VIRTQ_DESC_F_NEXT, 3), GuestAddress(DESC_TABLE + 48)).unwrap();
+        assert!(matches!(
+            read_chain(&mem, GuestAddress(DESC_TABLE), 4, 3),
+            Err(QueueError::ChainTooLong(3))
+        ));
+        assert!(matches!(
+            read_chain(&mem, GuestAddress(DESC_TABLE), 4, 4),
+            Err(QueueError::InvalidHead(4))
+        ));
+
+        // an indirect table wrapping around the address space
+        mem.write_obj(desc(u64::MAX - 15, 32, VIRTQ_DESC_F_INDIRECT, 0), GuestAddress(DESC_TABLE + 48)).unwrap();
+        assert!(matches!(
+            read_chain(&mem, GuestAddress(DESC_TABLE), 4, 3),
+            Err(QueueError::InvalidIndirect(3))
+        ));
+    }
+
+    fn packed_desc(addr: u64, len: u32, id: u16, flags: u16) -> virtq_packed_desc {
+        virtq_packed_desc {
+            addr: Le64::from(addr),
+            len: Le32::from(len),
+            id: Le16::from(id),
+            flags: Le16::from(flags),
+        }
+    }
+
+    #[test]
+    fn test_packed_queue() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vring = vring();
+        vring.packed = Some(PackedRing::default());
+        assert!(pop_avail(&mem, &mut vring).unwrap().is_none());
+
+        // a request and its response, then a chain of one indirect table
+        let avail = VIRTQ_DESC_F_AVAIL;
+        let slot = |index: u64| GuestAddress(DESC_TABLE + 16 * index);
+        mem.write_obj(packed_desc(0x4000, 24, 0, avail | VIRTQ_DESC_F_NEXT), slot(0)).unwrap();
+        mem.write_obj(packed_desc(0x5000, 24, 1, avail | VIRTQ_DESC_F_WRITE), slot(1)).unwrap();
+        mem.write_obj(packed_desc(0x6000, 32, 3, avail | VIRTQ_DESC_F_INDIRECT), slot(2)).unwrap();
+        mem.write_obj(packed_desc(0x4100, 48, 0, 0), GuestAddress(0x6000)).unwrap();
+        mem.write_obj(packed_desc(0x5100, 24, 0, VIRTQ_DESC_F_WRITE), GuestAddress(0x6010)).unwrap();
+
+        let (id, chain) = pop_avail(&mem, &mut vring).unwrap().unwrap();
+        assert_eq!(id, 1);
+        assert_eq!((chain[1].addr, chain[1].len, chain[1].writable), (GuestAddress(0x5000), 24, true));
+        let (id, chain) = pop_avail(&mem, &mut vring).unwrap().unwrap();
+        assert_eq!(id, 3);
+        assert_eq!(chain.iter().map(|desc| desc.writable).collect::<Vec<_>>(), vec![false, true]);
+        assert!(pop_avail(&mem, &mut vring).unwrap().is_none());
+
+        // returned out of order, each over as many slots as its chain had
+        add_used(&mem, &mut vring, 3, 24).unwrap();
+        add_used(&mem, &mut vring, 1, 0).unwrap();
+        let used: virtq_packed_desc = mem.read_obj(slot(0)).unwrap();
+        assert_eq!((used.id.to_native(), used.len.to_native()), (3, 24));
+        assert_eq!(used.flags.to_native(), VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED | VIRTQ_DESC_F_WRITE);
+        let used: virtq_packed_desc = mem.read_obj(slot(1)).unwrap();
+        assert_eq!(used.flags.to_native(), VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED);
+        assert_eq!(vring.packed.as_ref().unwrap().next_used, 3);
+
+        // a chain across the end of the ring, the second lap has the AVAIL flag cleared
+        mem.write_obj(packed_desc(0x4200, 8, 0, avail | VIRTQ_DESC_F_NEXT), slot(3)).unwrap();
+        mem.write_obj(packed_desc(0x5200, 8, 2, VIRTQ_DESC_F_USED | VIRTQ_DESC_F_WRITE), slot(0)).unwrap();
+        let (id, chain) = pop_avail(&mem, &mut vring).unwrap().unwrap();
+        assert_eq!((id, chain.len()), (2, 2));
+        add_used(&mem, &mut vring, 2, 8).unwrap();
+        let used: virtq_packed_desc = mem.read_obj(slot(3)).unwrap();
+        assert_eq!(used.id.to_native(), 2);
+        let packed = vring.packed.clone().unwrap();
+        assert_eq!((vring.next_avail, packed.avail_wrap, packed.next_used, packed.used_wrap), (1, false, 1, false));
+        assert!(pop_avail(&mem, &mut vring).unwrap().is_none());
+
+        // the base the frontend saves and restores the ring with
+        let base = packed.base(vring.next_avail);
+        assert_eq!(base, 0x0001_0001);
+        let (next_avail, restored) = PackedRing::from_base(base);
+        assert_eq!((next_avail, restored.avail_wrap, restored.next_used, restored.used_wrap), (1, false, 1, false));
+        assert_eq!(PackedRing::from_base(0x8000_8000).1, PackedRing::default());
+
+        // a buffer id out of the ring
+        mem.write_obj(packed_desc(0x4000, 8, 4, VIRTQ_DESC_F_USED), slot(1)).unwrap();
+        assert!(matches!(pop_avail(&mem, &mut vring), Err(QueueError::InvalidHead(4))));
+    }
+}
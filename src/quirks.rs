@@ -0,0 +1,191 @@
+// Corner cases where QEMU's virtio-gpu device and virglrenderer behave differently from this
+// backend.  The spec leaves them open, but guest drivers only ever validated against QEMU can
+// depend on them, so each one can be turned on separately, or all together with `Quirks::qemu`.
+// A `GuestProfile` adds the ones a particular guest driver needs.
+
+use crate::damage::DamageRect;
+use crate::protocol::*;
+
+/// Whether `rect` lies within a `width`x`height` resource.
+pub fn rect_within(rect: DamageRect, width: u32, height: u32) -> bool {
+    rect.0 as u64 + rect.2 as u64 <= width as u64 && rect.1 as u64 + rect.3 as u64 <= height as u64
+}
+
+/// Sets the byte at `alpha` of every 4 bytes pixel of `pixels` to opaque.
+pub fn make_opaque(pixels: &mut [u8], alpha: usize) {
+    for pixel in pixels.chunks_exact_mut(4) {
+        pixel[alpha] = 0xff;
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// Clip the rects of TRANSFER_TO_HOST_2D and RESOURCE_FLUSH to the resource, like
+    /// virglrenderer clips transfer boxes, instead of failing the transfer.
+    pub clamp_rects:            bool,
+    /// Fail rects going past the resource with ERR_INVALID_PARAMETER, like QEMU, when they aren't
+    /// clipped.  SET_SCANOUT rects are never clipped, so they are always checked.
+    pub invalid_rect_parameter: bool,
+    /// Fail RESOURCE_CREATE_2D of id 0 or of an id in use with ERR_INVALID_RESOURCE_ID instead of
+    /// leaving it to the renderer.
+    pub check_resource_ids:     bool,
+    /// Fail RESOURCE_ATTACH_BACKING of a resource that already has a backing with ERR_UNSPEC
+    /// instead of replacing the backing.
+    pub reject_double_attach:   bool,
+    /// Show scanout resources of formats with an alpha channel as their opaque counterpart, BGRA
+    /// as BGRX, so a desktop that leaves garbage in the alpha channel isn't shown translucent.
+    /// Cursors keep their alpha.
+    pub opaque_formats:         bool,
+    /// Hide the cursor on UPDATE_CURSOR of an unknown resource instead of failing it, and keep
+    /// the cursor within the scanout when the guest moves it past the edges.
+    pub tolerant_cursor:        bool,
+}
+
+impl Quirks {
+    /// Everything QEMU does.
+    pub fn qemu() -> Self {
+        Quirks {
+            clamp_rects:            true,
+            invalid_rect_parameter: true,
+            check_resource_ids:     true,
+            reject_double_attach:   true,
+            opaque_formats:         false,
+            tolerant_cursor:        false,
+        }
+    }
+
+    /// The quirks of both `self` and `other`.
+    pub fn with(self, other: Quirks) -> Self {
+        Quirks {
+            clamp_rects:            self.clamp_rects || other.clamp_rects,
+            invalid_rect_parameter: self.invalid_rect_parameter || other.invalid_rect_parameter,
+            check_resource_ids:     self.check_resource_ids || other.check_resource_ids,
+            reject_double_attach:   self.reject_double_attach || other.reject_double_attach,
+            opaque_formats:         self.opaque_formats || other.opaque_formats,
+            tolerant_cursor:        self.tolerant_cursor || other.tolerant_cursor,
+        }
+    }
+
+    /// Byte offset of the alpha channel to make opaque in the pixels of a scanout resource of
+    /// `format`, `None` when they are shown as they are.
+    pub fn scanout_alpha(&self, format: u32) -> Option<usize> {
+        if !self.opaque_formats {
+            return None;
+        }
+        match format {
+            VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM | VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM => Some(3),
+            VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM | VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM => Some(0),
+            _ => None,
+        }
+    }
+
+    /// Where the cursor goes when the guest puts it at (`x`, `y`) on a scanout showing a
+    /// `width`x`height` rect.  Positions are signed to the guest, a hotspot past the top left edge
+    /// wraps around.
+    pub fn cursor_position(&self, x: u32, y: u32, width: u32, height: u32) -> (u32, u32) {
+        if !self.tolerant_cursor {
+            return (x, y);
+        }
+        let clamp = |value: u32, size: u32| (value as i32).max(0).min(size.saturating_sub(1) as i32) as u32;
+        (clamp(x, width), clamp(y, height))
+    }
+
+    /// The rect a command should use for `rect` on a `width`x`height` resource.  Rects within the
+    /// resource are left alone, others are clipped or rejected as configured, or left to fail
+    /// further down.  A clipped rect may be empty.
+    pub fn fit_rect(&self, rect: DamageRect, width: u32, height: u32) -> Result<DamageRect, VirtioGpuResponse> {
+        if rect_within(rect, width, height) {
+            return Ok(rect);
+        }
+        if self.clamp_rects {
+            let (x, y, w, h) = rect;
+            let (x, y) = (x.min(width), y.min(height));
+            return Ok((x, y, w.min(width - x), h.min(height - y)));
+        }
+        if self.invalid_rect_parameter {
+            return Err(VirtioGpuResponse::ErrInvalidParameter);
+        }
+        Ok(rect)
+    }
+}
+
+/// The guest driver the device is set up for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GuestProfile {
+    Generic,
+    /// The viogpu WDDM driver of the Windows virtio drivers.  It only presents 32bpp formats,
+    /// whose rows the 2D renderer already lays out on the DWORD boundaries it expects.
+    Windows,
+}
+
+impl Default for GuestProfile {
+    fn default() -> Self {
+        GuestProfile::Generic
+    }
+}
+
+impl GuestProfile {
+    /// The quirks the guest driver needs on top of the configured ones.
+    pub fn quirks(self) -> Quirks {
+        match self {
+            GuestProfile::Generic => Quirks::default(),
+            GuestProfile::Windows => Quirks {
+                opaque_formats: true,
+                tolerant_cursor: true,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::protocol::*;
+    use crate::quirks::*;
+
+    #[test]
+    fn test_fit_rect() {
+        let none = Quirks::default();
+        let qemu = Quirks::qemu();
+        assert_eq!(none.fit_rect((0, 0, 64, 48), 64, 48).unwrap(), (0, 0, 64, 48));
+        assert_eq!(qemu.fit_rect((8, 8, 16, 16), 64, 48).unwrap(), (8, 8, 16, 16));
+
+        // out of bounds rects are left to the renderer by default
+        assert_eq!(none.fit_rect((32, 32, 64, 64), 64, 48).unwrap(), (32, 32, 64, 64));
+        assert_eq!(qemu.fit_rect((32, 32, 64, 64), 64, 48).unwrap(), (32, 32, 32, 16));
+        assert_eq!(qemu.fit_rect((u32::MAX, 0, 2, 2), 64, 48).unwrap(), (64, 0, 0, 2));
+
+        let strict = Quirks {
+            invalid_rect_parameter: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            strict.fit_rect((0, 0, 65, 48), 64, 48),
+            Err(VirtioGpuResponse::ErrInvalidParameter)
+        ));
+        assert!(matches!(
+            strict.fit_rect((0, u32::MAX, 1, 2), 64, 48),
+            Err(VirtioGpuResponse::ErrInvalidParameter)
+        ));
+    }
+
+    #[test]
+    fn test_windows_profile() {
+        let quirks = Quirks::qemu().with(GuestProfile::Windows.quirks());
+        assert!(quirks.clamp_rects && quirks.opaque_formats && quirks.tolerant_cursor);
+        assert_eq!(GuestProfile::default().quirks(), Quirks::default());
+
+        assert_eq!(quirks.scanout_alpha(VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM), Some(3));
+        assert_eq!(quirks.scanout_alpha(VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM), Some(0));
+        assert_eq!(quirks.scanout_alpha(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM), None);
+        assert_eq!(quirks.scanout_alpha(VIRTIO_GPU_FORMAT_NV12), None);
+        assert_eq!(Quirks::default().scanout_alpha(VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM), None);
+        let mut pixels = [1, 2, 3, 4, 5, 6, 7, 8];
+        make_opaque(&mut pixels, 3);
+        assert_eq!(pixels, [1, 2, 3, 0xff, 5, 6, 7, 0xff]);
+
+        assert_eq!(quirks.cursor_position(10, 20, 64, 48), (10, 20));
+        assert_eq!(quirks.cursor_position(-4i32 as u32, 100, 64, 48), (0, 47));
+        assert_eq!(Quirks::default().cursor_position(-4i32 as u32, 100, 64, 48), (-4i32 as u32, 100));
+    }
+}
@@ -0,0 +1,216 @@
+// Recording of the presented frames as a video, for bug reports and for comparing the guest output
+// of a test run against a reference.  The frames are read back like the screenshots, so they show
+// what the guest drew whatever the display backend, and written as a YUV4MPEG2 stream: raw 4:2:0
+// frames behind a one line header, which needs no encoder and which ffmpeg or gstreamer turn into
+// H.264 or VP8 when the recording should be kept.
+//
+// A Y4M stream has one size, the one of the first frame recorded.  Frames of another size, after
+// the guest changed the mode, are cropped or padded with black to it.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::num::NonZeroU32;
+use std::path::Path;
+
+use crate::screenshot::Image;
+use crate::yuv::rgb_to_yuv_pixel;
+
+/// Writes the frames `VirtioGpu::with_frame_recorder` hands it as a Y4M stream.
+pub struct FrameRecorder {
+    writer:     Box<dyn Write>,
+    /// Frames per second written in the header, the players' pace
+    frame_rate: u32,
+    /// Only every `every`th presented frame is recorded
+    every:      NonZeroU32,
+    /// `(width, height)` of the stream, known with the first frame
+    size:       Option<(u32, u32)>,
+    presented:  u64,
+    recorded:   u64,
+    /// The first write error, recording stops with it
+    error:      Option<io::Error>,
+}
+
+impl FrameRecorder {
+    /// Records into `writer`, every frame at 60 frames per second.
+    pub fn new<W: Write + 'static>(writer: W) -> Self {
+        FrameRecorder {
+            writer:     Box::new(writer),
+            frame_rate: 60,
+            every:      NonZeroU32::new(1).unwrap(),
+            size:       None,
+            presented:  0,
+            recorded:   0,
+            error:      None,
+        }
+    }
+
+    /// Records into the file at `path`, replacing it.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    /// Only records every `every`th frame, the first one included.
+    pub fn every(mut self, every: NonZeroU32) -> Self {
+        self.every = every;
+        self
+    }
+
+    /// The frame rate of the stream, 60 by default.  The frames aren't timed, a guest presenting
+    /// less often plays back faster.
+    pub fn frame_rate(mut self, frame_rate: NonZeroU32) -> Self {
+        self.frame_rate = frame_rate.get();
+        self
+    }
+
+    /// Number of frames written so far.
+    pub fn frames_recorded(&self) -> u64 {
+        self.recorded
+    }
+
+    /// Counts a presented frame, whether it is one to record.
+    pub(crate) fn next_frame(&mut self) -> bool {
+        let record = self.error.is_none() && self.presented % self.every.get() as u64 == 0;
+        self.presented += 1;
+        record
+    }
+
+    /// Writes `image` as the next frame.  A failure is kept for `finish` and stops the recording.
+    pub(crate) fn record(&mut self, image: &Image) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.write_frame(image) {
+            self.error = Some(e);
+        }
+    }
+
+    fn write_frame(&mut self, image: &Image) -> io::Result<()> {
+        let (width, height) = match self.size {
+            Some(size) => size,
+            None => {
+                if image.width == 0 || image.height == 0 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty frame"));
+                }
+                writeln!(
+                    self.writer,
+                    "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C420jpeg",
+                    image.width, image.height, self.frame_rate
+                )?;
+                self.size = Some((image.width, image.height));
+                (image.width, image.height)
+            }
+        };
+        self.writer.write_all(b"FRAME\n")?;
+        self.writer.write_all(&yuv420(image, width, height))?;
+        // a daemon serving frontends until it's killed never gets to `finish`
+        self.writer.flush()?;
+        self.recorded += 1;
+        Ok(())
+    }
+
+    /// Flushes the stream, failing with the error that stopped the recording if there was one.
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.writer.flush()
+    }
+}
+
+/// The Y, Cb and Cr planes of `image` cropped or padded to `width`x`height`, the chroma of each
+/// 2x2 block averaged.
+fn yuv420(image: &Image, width: u32, height: u32) -> Vec<u8> {
+    let black = [0, 0, 0, 0xff];
+    let pixel = |x: u32, y: u32| image.pixel(x, y).unwrap_or(black);
+    let (chroma_width, chroma_height) = ((width + 1) / 2, (height + 1) / 2);
+    let luma_size = width as usize * height as usize;
+    let chroma_size = chroma_width as usize * chroma_height as usize;
+    let mut planes = Vec::with_capacity(luma_size + 2 * chroma_size);
+
+    for y in 0..height {
+        for x in 0..width {
+            let [r, g, b, _] = pixel(x, y);
+            planes.push(rgb_to_yuv_pixel(r, g, b)[0]);
+        }
+    }
+    let mut cr = Vec::with_capacity(chroma_size);
+    for y in 0..chroma_height {
+        for x in 0..chroma_width {
+            let mut sum = [0u32; 2];
+            let mut count = 0;
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                let (px, py) = (x * 2 + dx, y * 2 + dy);
+                if px < width && py < height {
+                    let [r, g, b, _] = pixel(px, py);
+                    let [_, u, v] = rgb_to_yuv_pixel(r, g, b);
+                    sum[0] += u as u32;
+                    sum[1] += v as u32;
+                    count += 1;
+                }
+            }
+            planes.push(((sum[0] + count / 2) / count) as u8);
+            cr.push(((sum[1] + count / 2) / count) as u8);
+        }
+    }
+    planes.extend_from_slice(&cr);
+    planes
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::recorder::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A writer the test keeps a handle on.
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedBuffer(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_frame_recorder() {
+        let buffer = SharedBuffer::default();
+        let mut recorder = FrameRecorder::new(buffer.clone()).every(NonZeroU32::new(2).unwrap());
+        let red = Image {
+            width:  2,
+            height: 2,
+            pixels: [255, 0, 0, 0xff].repeat(4),
+        };
+
+        for _ in 0..3 {
+            if recorder.next_frame() {
+                recorder.record(&red);
+            }
+        }
+        assert_eq!(recorder.frames_recorded(), 2);
+        let frame = [&b"FRAME\n"[..], &[82, 82, 82, 82, 90, 240][..]].concat();
+        let mut expected = b"YUV4MPEG2 W2 H2 F60:1 Ip A1:1 C420jpeg\n".to_vec();
+        expected.extend_from_slice(&frame);
+        expected.extend_from_slice(&frame);
+        assert_eq!(*buffer.0.lock().unwrap(), expected);
+        assert!(recorder.finish().is_ok());
+
+        // a larger frame is cropped to the stream, a smaller one padded with black
+        assert_eq!(yuv420(&red, 1, 1), vec![82, 90, 240]);
+        assert_eq!(yuv420(&red, 3, 1), vec![82, 82, 16, 90, 128, 240, 128]);
+
+        // an empty first frame fails the recording
+        let mut recorder = FrameRecorder::new(SharedBuffer::default());
+        recorder.record(&Image {
+            width:  0,
+            height: 0,
+            pixels: Vec::new(),
+        });
+        assert!(!recorder.next_frame());
+        assert_eq!(recorder.finish().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,69 @@
+// Screenshots of the scanouts, read back from the scanout resource by `VirtioGpu::capture_scanout`
+// rather than from the display, so they work with any display backend, headless included, and
+// show what the guest drew before the display scaled or composited it.  What the VMM does with
+// them, a screendump command, a CI check, is up to it; PNG encoding is behind the `png` feature.
+
+#[cfg(feature = "png")]
+use std::io::{self, Write};
+
+/// The contents of a scanout, RGBA rows of `width` pixels with an opaque alpha.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width:  u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn stride(&self) -> u32 {
+        self.width * 4
+    }
+
+    /// The RGBA bytes of the pixel at (`x`, `y`), `None` outside of the image.
+    pub fn pixel(&self, x: u32, y: u32) -> Option<[u8; 4]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let offset = y as usize * self.stride() as usize + x as usize * 4;
+        let mut pixel = [0; 4];
+        pixel.copy_from_slice(self.pixels.get(offset..offset + 4)?);
+        Some(pixel)
+    }
+
+    /// Encodes the image as a PNG into `writer`.
+    #[cfg(feature = "png")]
+    pub fn write_png<W: Write>(&self, writer: W) -> io::Result<()> {
+        let mut encoder = png_encoder::Encoder::new(writer, self.width, self.height);
+        encoder.set_color(png_encoder::ColorType::RGBA);
+        encoder.set_depth(png_encoder::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut png_writer| png_writer.write_image_data(&self.pixels))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::screenshot::*;
+
+    #[test]
+    fn test_image() {
+        let image = Image {
+            width:  2,
+            height: 1,
+            pixels: vec![1, 2, 3, 0xff, 4, 5, 6, 0xff],
+        };
+        assert_eq!(image.stride(), 8);
+        assert_eq!(image.pixel(1, 0), Some([4, 5, 6, 0xff]));
+        assert_eq!(image.pixel(2, 0), None);
+        assert_eq!(image.pixel(0, 1), None);
+
+        #[cfg(feature = "png")]
+        {
+            let mut png = Vec::new();
+            image.write_png(&mut png).unwrap();
+            assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        }
+    }
+}
@@ -0,0 +1,162 @@
+// Bookkeeping of the host visible shared memory region, VIRTIO_GPU_SHM_ID_HOST_VISIBLE.  The guest
+// picks the offset of each blob it maps, so the device checks that the mapping fits in the region
+// and doesn't overlap another one before the VMM is asked to map it.  A VMM mapping blobs on its
+// own behalf gets offsets allocated from the gaps between the guest's mappings.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+
+/// Mappings start and end on page boundaries, the granularity the VMM can map at.
+pub const SHM_ALIGNMENT: u64 = 4096;
+
+#[derive(Debug, PartialEq)]
+pub enum ShmError {
+    /// the mapping at this offset is empty or doesn't start on a page boundary
+    Misaligned(u64),
+    /// the mapping at this offset goes past the end of the region
+    OutOfBounds(u64),
+    /// the mapping overlaps the one at this offset
+    Overlap(u64),
+    /// no gap of the region is large enough for this many bytes
+    NoSpace(u64),
+}
+
+impl Display for ShmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ShmError::*;
+
+        match self {
+            Misaligned(offset) => write!(f, "mapping at {:#x} isn't page aligned", offset),
+            OutOfBounds(offset) => write!(f, "mapping at {:#x} goes past the end of the region", offset),
+            Overlap(offset) => write!(f, "mapping overlaps the one at {:#x}", offset),
+            NoSpace(size) => write!(f, "no room for {:#x} bytes in the region", size),
+        }
+    }
+}
+
+fn align_up(value: u64) -> Option<u64> {
+    value.checked_add(SHM_ALIGNMENT - 1).map(|value| value & !(SHM_ALIGNMENT - 1))
+}
+
+/// The mappings of the host visible region, by offset.
+#[derive(Debug, Default)]
+pub struct ShmRegion {
+    size:     u64,
+    /// the page aligned size of the mapping at each offset
+    mappings: BTreeMap<u64, u64>,
+}
+
+impl ShmRegion {
+    pub fn new(size: u64) -> Self {
+        ShmRegion {
+            size,
+            mappings: BTreeMap::new(),
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Bytes taken by the mappings, rounded up to pages.
+    pub fn used(&self) -> u64 {
+        self.mappings.values().sum()
+    }
+
+    /// The gaps between the mappings as `(offset, size)`, in region order.
+    pub fn gaps(&self) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        let mut start = 0;
+        for (&offset, &size) in &self.mappings {
+            if offset > start {
+                gaps.push((start, offset - start));
+            }
+            start = offset + size;
+        }
+        if self.size > start {
+            gaps.push((start, self.size - start));
+        }
+        gaps
+    }
+
+    /// The largest mapping that still fits.  Much less than the free space when the mappings left
+    /// the region fragmented.
+    pub fn largest_gap(&self) -> u64 {
+        self.gaps().iter().map(|&(_, size)| size).max().unwrap_or(0)
+    }
+
+    /// Takes `size` bytes at the `offset` the guest picked.
+    pub fn reserve(&mut self, offset: u64, size: u64) -> Result<(), ShmError> {
+        if size == 0 || offset % SHM_ALIGNMENT != 0 {
+            return Err(ShmError::Misaligned(offset));
+        }
+        let end = align_up(size)
+            .and_then(|size| offset.checked_add(size))
+            .filter(|&end| end <= self.size)
+            .ok_or(ShmError::OutOfBounds(offset))?;
+        // the last mapping starting before the end is the only one that can overlap
+        if let Some((&other, &other_size)) = self.mappings.range(..end).next_back() {
+            if other + other_size > offset {
+                return Err(ShmError::Overlap(other));
+            }
+        }
+        self.mappings.insert(offset, end - offset);
+        Ok(())
+    }
+
+    /// Takes `size` bytes in the first gap they fit in and returns their offset.
+    pub fn allocate(&mut self, size: u64) -> Result<u64, ShmError> {
+        let aligned = align_up(size).filter(|&aligned| aligned > 0).ok_or(ShmError::NoSpace(size))?;
+        let offset = self
+            .gaps()
+            .into_iter()
+            .find(|&(_, gap)| gap >= aligned)
+            .map(|(offset, _)| offset)
+            .ok_or(ShmError::NoSpace(size))?;
+        self.mappings.insert(offset, aligned);
+        Ok(offset)
+    }
+
+    /// Gives back the mapping at `offset`, returning its size.
+    pub fn release(&mut self, offset: u64) -> Option<u64> {
+        self.mappings.remove(&offset)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::shm::*;
+
+    #[test]
+    fn test_shm_region() {
+        let mut region = ShmRegion::new(16 * SHM_ALIGNMENT);
+        region.reserve(0, 100).unwrap();
+        region.reserve(4 * SHM_ALIGNMENT, 2 * SHM_ALIGNMENT).unwrap();
+        assert_eq!(region.used(), 3 * SHM_ALIGNMENT);
+
+        assert_eq!(region.reserve(100, SHM_ALIGNMENT), Err(ShmError::Misaligned(100)));
+        assert_eq!(region.reserve(SHM_ALIGNMENT, 0), Err(ShmError::Misaligned(SHM_ALIGNMENT)));
+        assert_eq!(
+            region.reserve(3 * SHM_ALIGNMENT, SHM_ALIGNMENT + 1),
+            Err(ShmError::Overlap(4 * SHM_ALIGNMENT))
+        );
+        assert_eq!(
+            region.reserve(15 * SHM_ALIGNMENT, SHM_ALIGNMENT + 1),
+            Err(ShmError::OutOfBounds(15 * SHM_ALIGNMENT))
+        );
+        assert_eq!(region.reserve(u64::MAX & !0xfff, 1), Err(ShmError::OutOfBounds(u64::MAX & !0xfff)));
+
+        // allocations fill the first gap that fits
+        assert_eq!(region.allocate(3 * SHM_ALIGNMENT), Ok(SHM_ALIGNMENT));
+        assert_eq!(region.allocate(1), Ok(6 * SHM_ALIGNMENT));
+        assert_eq!(region.gaps(), vec![(7 * SHM_ALIGNMENT, 9 * SHM_ALIGNMENT)]);
+        assert_eq!(region.allocate(10 * SHM_ALIGNMENT), Err(ShmError::NoSpace(10 * SHM_ALIGNMENT)));
+
+        // freeing the middle leaves the region fragmented
+        assert_eq!(region.release(SHM_ALIGNMENT), Some(3 * SHM_ALIGNMENT));
+        assert_eq!(region.release(SHM_ALIGNMENT), None);
+        assert_eq!(region.size() - region.used(), 12 * SHM_ALIGNMENT);
+        assert_eq!(region.largest_gap(), 9 * SHM_ALIGNMENT);
+        region.reserve(SHM_ALIGNMENT, SHM_ALIGNMENT).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+// Buffers the scanout is read back into on the copy flush paths before the display thread copies
+// it to a framebuffer.  A 4K scanout is 33MB, an allocation that size is mmapped and unmapped by
+// the allocator on every frame, so a buffer goes back to a small pool when the display is done
+// with it and the next read of the same size reuses it.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Returned buffers kept for reuse, the least recently returned one is freed past this.
+pub const STAGING_POOL_SIZE: usize = 4;
+/// Smaller buffers, like the ones of small damaged rects, come from the heap cheaply and aren't
+/// kept, they would only push the frame sized ones out.  glibc's default mmap threshold.
+pub const STAGING_MIN_SIZE: usize = 128 << 10;
+
+#[derive(Clone, Default)]
+pub struct StagingPool(Arc<Mutex<VecDeque<Vec<u8>>>>);
+
+impl StagingPool {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// A buffer of `len` bytes, reused from the pool when one of that size was returned.  Reused
+    /// buffers hold what was last read into them rather than zeroes.
+    pub fn take(&self, len: usize) -> StagingBuffer {
+        let reused = {
+            let mut buffers = self.0.lock().unwrap();
+            buffers
+                .iter()
+                .rposition(|buffer| buffer.len() == len)
+                .and_then(|index| buffers.remove(index))
+        };
+        StagingBuffer {
+            data:   reused.unwrap_or_else(|| vec![0; len]),
+            start:  0,
+            pool:   Some(self.clone()),
+        }
+    }
+
+    fn give_back(&self, buffer: Vec<u8>) {
+        if buffer.len() < STAGING_MIN_SIZE {
+            return;
+        }
+        let mut buffers = self.0.lock().unwrap();
+        buffers.push_back(buffer);
+        if buffers.len() > STAGING_POOL_SIZE {
+            buffers.pop_front();
+        }
+    }
+
+    /// Number of buffers waiting to be reused.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+/// A buffer taken from a `StagingPool`, given back to it when dropped.  Dereferences to the bytes
+/// from `start` on.
+pub struct StagingBuffer {
+    data:  Vec<u8>,
+    start: usize,
+    pool:  Option<StagingPool>,
+}
+
+impl StagingBuffer {
+    /// Drops the first `len` bytes from the view of the buffer, they stay allocated.
+    pub fn skip(mut self, len: usize) -> Self {
+        self.start = (self.start + len).min(self.data.len());
+        self
+    }
+}
+
+/// A buffer that doesn't belong to a pool.
+impl From<Vec<u8>> for StagingBuffer {
+    fn from(data: Vec<u8>) -> Self {
+        StagingBuffer { data, start: 0, pool: None }
+    }
+}
+
+impl Deref for StagingBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[self.start..]
+    }
+}
+
+impl DerefMut for StagingBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data[self.start..]
+    }
+}
+
+impl fmt::Debug for StagingBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StagingBuffer({} bytes)", self.len())
+    }
+}
+
+impl Drop for StagingBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.give_back(std::mem::take(&mut self.data));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::staging::*;
+
+    #[test]
+    fn test_staging_reuse() {
+        let size = STAGING_MIN_SIZE;
+        let pool = StagingPool::new();
+        let mut buffer = pool.take(size);
+        buffer[0] = 1;
+        let address = buffer.as_ptr();
+        drop(buffer);
+        assert_eq!(pool.len(), 1);
+
+        // only a buffer of the same size is reused, as it was left
+        let other = pool.take(size + 1);
+        assert_eq!(pool.len(), 1);
+        let buffer = pool.take(size);
+        assert_eq!(buffer.as_ptr(), address);
+        assert_eq!(buffer[0], 1);
+        assert_eq!(pool.len(), 0);
+        drop(other);
+        drop(buffer);
+        assert_eq!(pool.len(), 2);
+
+        // the view skips the head, the whole buffer is given back
+        let buffer = pool.take(size).skip(4);
+        assert_eq!(buffer.len(), size - 4);
+        drop(buffer);
+        assert_eq!(pool.take(size).len(), size);
+
+        // the least recently returned buffers, the first two, are freed
+        let sizes = (2..STAGING_POOL_SIZE + 2).map(|n| size + n);
+        let buffers: Vec<StagingBuffer> = sizes.clone().map(|len| pool.take(len)).collect();
+        drop(buffers);
+        assert_eq!(pool.len(), STAGING_POOL_SIZE);
+        let buffers: Vec<StagingBuffer> = sizes.map(|len| pool.take(len)).collect();
+        assert_eq!(pool.len(), 0);
+        drop(buffers);
+
+        // small buffers and buffers outside a pool are just freed
+        drop(pool.take(size - 1));
+        drop(StagingBuffer::from(vec![0; size]));
+        assert_eq!(pool.len(), STAGING_POOL_SIZE);
+    }
+}
@@ -0,0 +1,1518 @@
+// The vhost-user side of the device: what the frontend sees of the GPU besides its queues.
+//
+// The `virtio_gpu_config` space lives in `VirtioGpu`, the frontend reads and writes it through
+// GET_CONFIG/SET_CONFIG.  When new events are raised, e.g. a display hotplug, the frontend is sent
+// VHOST_USER_BACKEND_CONFIG_CHANGE_MSG on the backend request channel so it injects the config
+// interrupt into the guest.  That needs the CONFIG and BACKEND_REQ protocol features.
+//
+// `GpuBackend` handles the frontend's messages: feature negotiation, the memory table and the
+// vrings.  It implements the backend request handler of the vhost crate rather than the
+// vhost-user-backend crate's `VhostUserBackend`, which works on the upstream vm-memory types and
+// spreads the queues over threads, while the guest memory here is a `GuestMemoryMmap` of our
+// vm-memory and the renderer has to stay on the thread that created it.  The socket, the kicks and
+// the device's own event sources are all polled from that thread.
+//
+// For live migration the frontend passes a pipe with SET_DEVICE_STATE_FD once the vrings are
+// stopped.  The state is saved and written, or read, on a thread of its own so the frontend's
+// socket is still answered, and CHECK_DEVICE_STATE waits for that thread.  A loaded state is
+// restored as soon as guest memory is there to attach the backings to.
+//
+// When the frontend disconnects, the device and what the guest created on it are kept for the next
+// frontend, which negotiates and sets up the vrings again.  The guest memory stays mapped in the
+// meantime since the renderer still holds the backings, they move to the new mapping once the new
+// frontend sent its memory table.
+//
+// A daemon that crashed can't be asked where the vrings stopped.  With the INFLIGHT_SHMFD protocol
+// feature the chains in flight are tracked in a region the frontend keeps, see `inflight`, and the
+// next daemon resubmits them when the vrings are kicked again.
+//
+// The frontend changes the memory table at runtime, with SET_MEM_TABLE or one region at a time on
+// memory hotplug.  The new table is mapped, the vrings and the backings attached in the old one are
+// moved to it, then it replaces the old one.  Everything touching guest memory runs on the
+// daemon's thread, so nothing uses the old table past the swap.
+//
+// During postcopy migration, guest memory is registered with the userfaultfd of `postcopy` from
+// POSTCOPY_LISTEN to POSTCOPY_END, the tables the frontend sends in between included.
+//
+// A frontend presenting the scanout itself, like QEMU's vhost-user-gpu, sends the GPU socket with
+// GPU_SET_SOCKET: a second request channel from the daemon, for the display.  `FrontendScanoutForwarder`
+// passes the scanout's dmabuf on it when the renderer exports one, see `dmabuf`.
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::iter;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use ::vhost::vhost_user::message::{
+    VhostTransferStateDirection, VhostTransferStatePhase, VhostUserConfigFlags, VhostUserInflight, VhostUserLog,
+    VhostUserMemoryRegion, VhostUserProtocolFeatures, VhostUserSharedMsg, VhostUserSingleMemoryRegion,
+    VhostUserVirtioFeatures, VhostUserVringAddrFlags, VhostUserVringState,
+};
+use ::vhost::vhost_user::gpu_message::{VhostUserGpuDMABUFScanout, VhostUserGpuUpdate};
+use ::vhost::vhost_user::{
+    Backend, Error as VhostUserError, GpuBackend as GpuSocket, Result as VhostUserResult, VhostUserBackendReqHandlerMut,
+    VhostUserFrontendReqHandler,
+};
+use rutabaga_gfx::RutabagaHandle;
+use vm_memory::{Address, Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryMmap, Le16};
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::damage::{bounding_box, DamageRect};
+use crate::dmabuf::{DmabufScanout, ScanoutForwarder};
+use crate::inflight::{queue_region_size, InflightQueue, InflightRegion};
+use crate::migration::{DeviceState, MigrationError};
+use crate::postcopy::Userfaultfd;
+use crate::queue::{PackedRing, QueueError, VIRTIO_F_RING_PACKED};
+use crate::virtio_gpu::VirtioGpu;
+use crate::worker::{ControlWorker, CursorWorker};
+
+/// Config space accesses from the frontend and the config change notifications sent back.
+pub struct ConfigSpace {
+    backend_req: Option<Backend>,
+    /// `events_read` as last signalled to the frontend
+    events_read: u32,
+}
+
+impl ConfigSpace {
+    pub fn new() -> Self {
+        Self {
+            backend_req: None,
+            events_read: 0,
+        }
+    }
+
+    /// Sets the backend request channel, without it config changes are only visible on the next
+    /// GET_CONFIG.
+    pub fn set_backend_req(&mut self, backend_req: Backend) {
+        self.backend_req = Some(backend_req);
+    }
+
+    /// Handles GET_CONFIG.
+    pub fn get_config(&self, gpu: &VirtioGpu, offset: u32, size: u32) -> Vec<u8> {
+        gpu.read_config(offset, size)
+    }
+
+    /// Handles SET_CONFIG, the events cleared by the guest can be signalled again afterwards.
+    pub fn set_config(&mut self, gpu: &mut VirtioGpu, offset: u32, buf: &[u8]) -> io::Result<()> {
+        gpu.write_config(offset, buf)?;
+        self.events_read &= gpu.events_read();
+        Ok(())
+    }
+
+    /// Sends the config change message if the device raised events since the last call.
+    pub fn notify_changes(&mut self, gpu: &VirtioGpu) -> io::Result<()> {
+        let raised = gpu.events_read() & !self.events_read;
+        self.events_read = gpu.events_read();
+        if raised == 0 {
+            return Ok(());
+        }
+
+        match &self.backend_req {
+            Some(backend_req) => backend_req.handle_config_change().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// The GPU socket, once the frontend sent it.
+type GpuSocketChannel = Arc<Mutex<Option<Arc<GpuSocket>>>>;
+
+/// The layout of a dmabuf whose modifier the driver picks implicitly.
+const DRM_FORMAT_MOD_INVALID: u64 = 0x00ff_ffff_ffff_ffff;
+
+fn no_gpu_socket() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "no GPU socket")
+}
+
+/// A message `FrontendScanoutForwarder` sends on the GPU socket.
+enum ScanoutMessage {
+    Set(VhostUserGpuDMABUFScanout, File),
+    Disable(VhostUserGpuDMABUFScanout),
+    Update(VhostUserGpuUpdate),
+}
+
+/// Has the frontend scan out the dmabufs the renderer exports.  DMABUF_SCANOUT carries no modifier,
+/// scanouts of an explicit tiled layout are left to the device.  The frontend only answers a
+/// DMABUF_UPDATE once it presented it, so the messages are sent by a thread of their own: the
+/// control queue doesn't wait on the frontend's display, and the flushes coming in meanwhile are
+/// merged into the next update.  A message failing to go out fails the next call.
+pub struct FrontendScanoutForwarder {
+    gpu_socket: GpuSocketChannel,
+    messages:   Sender<ScanoutMessage>,
+    error:      Arc<Mutex<Option<io::Error>>>,
+}
+
+impl FrontendScanoutForwarder {
+    fn new(gpu_socket: GpuSocketChannel) -> io::Result<Self> {
+        let (messages, receiver) = mpsc::channel();
+        let error = Arc::new(Mutex::new(None));
+        let (channel, failed) = (gpu_socket.clone(), error.clone());
+        // ends with the forwarder, once the messages left are sent
+        thread::Builder::new()
+            .name("scanout-forwarder".to_string())
+            .spawn(move || send_scanout_messages(channel, receiver, failed))?;
+        Ok(FrontendScanoutForwarder {
+            gpu_socket,
+            messages,
+            error,
+        })
+    }
+
+    /// Queues `message` for the frontend, or fails with the error the previous ones met.
+    fn send(&self, message: ScanoutMessage) -> io::Result<()> {
+        if let Some(e) = self.error.lock().unwrap().take() {
+            return Err(e);
+        }
+        if self.gpu_socket.lock().unwrap().is_none() {
+            return Err(no_gpu_socket());
+        }
+        self.messages
+            .send(message)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "scanout forwarder thread exited"))
+    }
+}
+
+impl ScanoutForwarder for FrontendScanoutForwarder {
+    fn set_scanout(&mut self, scanout_id: u32, scanout: &DmabufScanout, dmabuf: &RutabagaHandle) -> io::Result<()> {
+        if scanout.modifier != 0 && scanout.modifier != DRM_FORMAT_MOD_INVALID {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "dmabuf has a modifier"));
+        }
+        let msg = VhostUserGpuDMABUFScanout {
+            scanout_id,
+            x: 0,
+            y: 0,
+            width: scanout.width,
+            height: scanout.height,
+            fd_width: scanout.width,
+            fd_height: scanout.height,
+            fd_stride: scanout.stride,
+            fd_flags: 0,
+            fd_drm_fourcc: scanout.drm_fourcc as i32,
+        };
+        self.send(ScanoutMessage::Set(msg, dmabuf.os_handle.try_clone()?))
+    }
+
+    fn disable_scanout(&mut self, scanout_id: u32) -> io::Result<()> {
+        // no descriptor and an empty rect
+        let msg = VhostUserGpuDMABUFScanout {
+            scanout_id,
+            ..Default::default()
+        };
+        self.send(ScanoutMessage::Disable(msg))
+    }
+
+    fn flush(&mut self, scanout_id: u32, (x, y, width, height): DamageRect) -> io::Result<()> {
+        let msg = VhostUserGpuUpdate {
+            scanout_id,
+            x,
+            y,
+            width,
+            height,
+        };
+        self.send(ScanoutMessage::Update(msg))
+    }
+}
+
+/// Sends the messages of a `FrontendScanoutForwarder` until it's dropped.  The updates of a
+/// scanout queued while the frontend presented the previous one go out as their bounding box.
+fn send_scanout_messages(
+    gpu_socket: GpuSocketChannel,
+    receiver: Receiver<ScanoutMessage>,
+    error: Arc<Mutex<Option<io::Error>>>,
+) {
+    while let Ok(message) = receiver.recv() {
+        let mut batch: Vec<ScanoutMessage> = Vec::new();
+        for message in iter::once(message).chain(receiver.try_iter()) {
+            if let (Some(ScanoutMessage::Update(last)), ScanoutMessage::Update(next)) = (batch.last_mut(), &message) {
+                if last.scanout_id == next.scanout_id {
+                    let (x, y, width, height) = bounding_box(
+                        (last.x, last.y, last.width, last.height),
+                        (next.x, next.y, next.width, next.height),
+                    );
+                    *last = VhostUserGpuUpdate {
+                        scanout_id: last.scanout_id,
+                        x,
+                        y,
+                        width,
+                        height,
+                    };
+                    continue;
+                }
+            }
+            batch.push(message);
+        }
+        for message in batch {
+            // not held while the frontend presents, the device checks it on every call
+            let socket = gpu_socket.lock().unwrap().clone();
+            let result = match socket {
+                Some(socket) => match message {
+                    ScanoutMessage::Set(msg, dmabuf) => socket.set_dmabuf_scanout(&msg, Some(&dmabuf)),
+                    ScanoutMessage::Disable(msg) => socket.set_dmabuf_scanout(&msg, None::<&File>),
+                    ScanoutMessage::Update(msg) => socket.update_dmabuf_scanout(&msg),
+                },
+                None => Err(no_gpu_socket()),
+            };
+            if let Err(e) = result {
+                *error.lock().unwrap() = Some(e);
+            }
+        }
+    }
+}
+
+/// The control queue and the cursor queue.
+pub const NUM_QUEUES: usize = 2;
+pub const CONTROL_QUEUE: usize = 0;
+pub const CURSOR_QUEUE: usize = 1;
+/// Largest queue the frontend may set up.
+pub const MAX_QUEUE_SIZE: u16 = 1024;
+/// Memory regions the frontend may add with ADD_MEM_REG.
+pub const MAX_MEM_SLOTS: u64 = 32;
+
+const VIRTIO_F_VERSION_1: u32 = 32;
+
+fn invalid_param<T>() -> VhostUserResult<T> {
+    Err(VhostUserError::InvalidParam)
+}
+
+/// A region of the memory table, as the frontend described it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MemoryRegion {
+    pub guest_addr:  u64,
+    pub size:        u64,
+    /// where the region is mapped in the frontend, vring addresses are given in that space
+    pub user_addr:   u64,
+    pub mmap_offset: u64,
+}
+
+impl MemoryRegion {
+    fn contains_user_addr(&self, user_addr: u64) -> bool {
+        user_addr >= self.user_addr && user_addr - self.user_addr < self.size
+    }
+}
+
+impl From<&VhostUserMemoryRegion> for MemoryRegion {
+    fn from(region: &VhostUserMemoryRegion) -> Self {
+        MemoryRegion {
+            guest_addr:  region.guest_phys_addr,
+            size:        region.memory_size,
+            user_addr:   region.user_addr,
+            mmap_offset: region.mmap_offset,
+        }
+    }
+}
+
+impl From<&VhostUserSingleMemoryRegion> for MemoryRegion {
+    fn from(region: &VhostUserSingleMemoryRegion) -> Self {
+        MemoryRegion {
+            guest_addr:  region.guest_phys_addr,
+            size:        region.memory_size,
+            user_addr:   region.user_addr,
+            mmap_offset: region.mmap_offset,
+        }
+    }
+}
+
+/// The guest memory the frontend shared, mapped from the descriptors of its regions.
+pub struct GuestMemoryTable {
+    regions: Vec<(MemoryRegion, File)>,
+    mem:     GuestMemoryMmap,
+}
+
+impl GuestMemoryTable {
+    fn new(regions: Vec<(MemoryRegion, File)>) -> io::Result<Self> {
+        let mut ranges = Vec::with_capacity(regions.len());
+        for (region, file) in &regions {
+            let file_offset = FileOffset::new(file.try_clone()?, region.mmap_offset);
+            ranges.push((GuestAddress(region.guest_addr), region.size as usize, Some(file_offset)));
+        }
+        let mem = GuestMemoryMmap::from_ranges_with_files(&ranges)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+        Ok(GuestMemoryTable { regions, mem })
+    }
+
+    pub fn memory(&self) -> &GuestMemoryMmap {
+        &self.mem
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &MemoryRegion> {
+        self.regions.iter().map(|(region, _)| region)
+    }
+
+    /// Where the regions are mapped in this process, with their size.
+    pub fn host_ranges(&self) -> Vec<(usize, usize)> {
+        self.regions()
+            .filter_map(|region| {
+                let base = self.mem.get_host_address(GuestAddress(region.guest_addr)).ok()?;
+                Some((base as usize, region.size as usize))
+            })
+            .collect()
+    }
+
+    /// Translates an address of the frontend's mapping of guest memory to a guest address.
+    pub fn to_guest_addr(&self, user_addr: u64) -> Option<GuestAddress> {
+        self.regions()
+            .find(|region| region.contains_user_addr(user_addr))
+            .map(|region| GuestAddress(region.guest_addr + (user_addr - region.user_addr)))
+    }
+
+    /// Translates an address of our own mapping of guest memory to a guest address.
+    pub fn host_to_guest(&self, host_addr: usize) -> Option<GuestAddress> {
+        self.regions().find_map(|region| {
+            let base = self.mem.get_host_address(GuestAddress(region.guest_addr)).ok()? as usize;
+            match host_addr.checked_sub(base) {
+                Some(offset) if (offset as u64) < region.size => Some(GuestAddress(region.guest_addr + offset as u64)),
+                _ => None,
+            }
+        })
+    }
+}
+
+/// A vring as the frontend set it up.  The rings are given as guest addresses.
+#[derive(Default)]
+pub struct Vring {
+    pub size:       u16,
+    pub desc_table: GuestAddress,
+    pub avail_ring: GuestAddress,
+    pub used_ring:  GuestAddress,
+    /// the next available descriptor the device will process
+    pub next_avail: u16,
+    pub kick:       Option<EventFd>,
+    pub call:       Option<EventFd>,
+    pub err:        Option<EventFd>,
+    /// where the chains in flight are tracked, with an inflight region
+    pub inflight:   Option<InflightQueue>,
+    /// the state of the ring with VIRTIO_F_RING_PACKED, split rings have none
+    pub packed:     Option<PackedRing>,
+    /// Set by SET_VRING_ENABLE, or by SET_VRING_KICK when the protocol features weren't
+    /// negotiated
+    pub enabled:    bool,
+    /// user addresses of the rings, translated again when the memory table changes
+    user_addrs:     Option<(u64, u64, u64)>,
+}
+
+impl Vring {
+    /// Whether the device processes the vring: it has been kicked and is enabled.
+    pub fn ready(&self) -> bool {
+        self.enabled && self.kick.is_some() && self.size != 0
+    }
+}
+
+fn eventfd(file: Option<File>) -> Option<EventFd> {
+    // Safe because the frontend sent the fd and nothing else owns it.
+    file.map(|file| unsafe { EventFd::from_raw_fd(file.into_raw_fd()) })
+}
+
+/// A device state on its way through the frontend's descriptor.
+enum StateTransfer {
+    /// the saved state being written
+    Save(JoinHandle<io::Result<()>>),
+    /// the state being read, until the frontend closes its end
+    Load(JoinHandle<io::Result<Vec<u8>>>),
+}
+
+fn join_transfer<T>(handle: JoinHandle<io::Result<T>>) -> VhostUserResult<T> {
+    handle
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "device state transfer panicked")))
+        .map_err(VhostUserError::ReqHandlerError)
+}
+
+fn migration_error(e: MigrationError) -> VhostUserError {
+    VhostUserError::ReqHandlerError(e.into())
+}
+
+/// The vhost-user GPU device: handles the frontend's messages for a `VirtioGpu`.  Wrapped in a
+/// `Mutex`, it is the handler of a vhost `BackendReqHandler`.
+pub struct GpuBackend {
+    gpu:               VirtioGpu,
+    config:            ConfigSpace,
+    owned:             bool,
+    acked_features:    u64,
+    protocol_features: VhostUserProtocolFeatures,
+    memory:            Option<GuestMemoryTable>,
+    vrings:            [Vring; NUM_QUEUES],
+    control:           ControlWorker,
+    cursor:            CursorWorker,
+    state_transfer:    Option<StateTransfer>,
+    /// a loaded state waiting for the memory table
+    pending_state:     Option<DeviceState>,
+    /// the memory of the previous frontend, until the backings moved to the next table
+    stale_memory:      Option<GuestMemoryTable>,
+    /// older tables backings failed to move out of, until none is left in them
+    retired_memory:    Vec<GuestMemoryTable>,
+    inflight:          Option<InflightRegion>,
+    /// the socket `FrontendScanoutForwarder` passes scanouts on, if the device forwards them
+    gpu_socket:        Option<GpuSocketChannel>,
+    /// created on POSTCOPY_ADVISE, with whether guest memory is registered with it
+    postcopy:          Option<(Userfaultfd, bool)>,
+}
+
+impl GpuBackend {
+    pub fn new(gpu: VirtioGpu) -> Self {
+        GpuBackend {
+            gpu,
+            config: ConfigSpace::new(),
+            owned: false,
+            acked_features: 0,
+            protocol_features: VhostUserProtocolFeatures::empty(),
+            memory: None,
+            vrings: Default::default(),
+            control: ControlWorker::new(),
+            cursor: CursorWorker::new(),
+            state_transfer: None,
+            pending_state: None,
+            stale_memory: None,
+            retired_memory: Vec::new(),
+            inflight: None,
+            gpu_socket: None,
+            postcopy: None,
+        }
+    }
+
+    /// Has the frontend scan out the resources the renderer exports as a dmabuf, once it sent
+    /// the GPU socket.  The others are still presented by the device.  Fails when the thread
+    /// talking to the frontend can't be started.
+    pub fn with_scanout_forwarding(mut self) -> io::Result<Self> {
+        let gpu_socket = GpuSocketChannel::default();
+        let forwarder = FrontendScanoutForwarder::new(gpu_socket.clone())?;
+        self.gpu = self.gpu.with_scanout_forwarder(forwarder);
+        self.gpu_socket = Some(gpu_socket);
+        Ok(self)
+    }
+
+    pub fn gpu(&mut self) -> &mut VirtioGpu {
+        &mut self.gpu
+    }
+
+    /// The guest memory, once the frontend sent the memory table.
+    pub fn memory(&self) -> Option<&GuestMemoryTable> {
+        self.memory.as_ref()
+    }
+
+    pub fn vring(&self, index: usize) -> &Vring {
+        &self.vrings[index]
+    }
+
+    pub fn vring_mut(&mut self, index: usize) -> &mut Vring {
+        &mut self.vrings[index]
+    }
+
+    /// The features the driver acked, device and transport ones.
+    pub fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    pub fn protocol_features(&self) -> VhostUserProtocolFeatures {
+        self.protocol_features
+    }
+
+    /// Runs the commands the driver made available on the control queue, once it is ready.
+    pub fn process_control_queue(&mut self) -> Result<(), QueueError> {
+        let vring = &mut self.vrings[CONTROL_QUEUE];
+        match self.memory {
+            Some(ref memory) if vring.ready() => self.control.process_queue(&mut self.gpu, memory.memory(), vring),
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the commands the driver made available on the cursor queue, once it is ready.
+    pub fn process_cursor_queue(&mut self) -> Result<(), QueueError> {
+        let vring = &mut self.vrings[CURSOR_QUEUE];
+        match self.memory {
+            Some(ref memory) if vring.ready() => self.cursor.process_queue(&mut self.gpu, memory.memory(), vring),
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the commands available on the `index` queue.
+    pub fn process_queue(&mut self, index: usize) -> Result<(), QueueError> {
+        match index {
+            CURSOR_QUEUE => self.process_cursor_queue(),
+            _ => self.process_control_queue(),
+        }
+    }
+
+    /// Returns the chains whose fences signalled, on the queue they came from.  On failure, the
+    /// index of the queue the driver broke is returned along with the error.
+    pub fn process_fences(&mut self) -> Result<(), (usize, QueueError)> {
+        let memory = match self.memory {
+            Some(ref memory) => memory.memory(),
+            None => return Ok(()),
+        };
+        self.control
+            .process_fences(&mut self.gpu, memory, &mut self.vrings[CONTROL_QUEUE])
+            .map_err(|e| (CONTROL_QUEUE, e))?;
+        self.cursor
+            .process_fences(&self.gpu, memory, &mut self.vrings[CURSOR_QUEUE])
+            .map_err(|e| (CURSOR_QUEUE, e))
+    }
+
+    /// Forgets the frontend after it disconnected, keeping the device for the next one.  The
+    /// vrings, the negotiated features and the chains held back go with the frontend, the guest
+    /// memory is kept until the next memory table.
+    pub fn disconnect(&mut self) {
+        self.owned = false;
+        self.acked_features = 0;
+        self.protocol_features = VhostUserProtocolFeatures::empty();
+        self.config.backend_req = None;
+        // the scanout is taken back on the next flush, which finds the socket gone
+        if let Some(ref gpu_socket) = self.gpu_socket {
+            *gpu_socket.lock().unwrap() = None;
+        }
+        self.vrings = Default::default();
+        self.control = ControlWorker::new();
+        self.cursor = CursorWorker::new();
+        self.state_transfer = None;
+        self.pending_state = None;
+        self.inflight = None;
+        self.postcopy = None;
+        // a frontend leaving before sending its table leaves the previous one in use
+        if self.memory.is_some() {
+            self.stale_memory = self.memory.take();
+        }
+    }
+
+    /// Stops the device for good, for a VMM tearing it down: the chains waiting on fences that
+    /// signal within `timeout` are returned to the driver, the vrings are stopped and the device
+    /// releases everything the guest created, see `VirtioGpu::shutdown`.  Returns whether no
+    /// chain was left in flight.
+    pub fn shutdown(&mut self, timeout: Duration) -> bool {
+        self.gpu.wait_fences(timeout);
+        // the renderer already gave up on what is left
+        let _ = self.process_fences();
+        let drained = self.control.pending() == 0 && self.cursor.pending() == 0;
+        self.control = ControlWorker::new();
+        self.cursor = CursorWorker::new();
+        for vring in self.vrings.iter_mut() {
+            vring.kick = None;
+            vring.enabled = false;
+        }
+        self.gpu.shutdown(Duration::from_secs(0)) && drained
+    }
+
+    /// Resets the device on RESET_DEVICE: the chains held back are dropped, the vrings and the
+    /// negotiated features go as the driver sets them up again, and the device releases what the
+    /// guest created, see `VirtioGpu::reset`.  The frontend and the guest memory are kept.
+    pub fn reset(&mut self) {
+        self.control = ControlWorker::new();
+        self.cursor = CursorWorker::new();
+        self.vrings = Default::default();
+        self.acked_features = 0;
+        // the chains tracked there were the reset driver's
+        self.inflight = None;
+        self.gpu.reset();
+        self.config.events_read = 0;
+    }
+
+    /// Sends the frontend a config change message if the device raised events since the last
+    /// call, to be called after the device processed anything.
+    pub fn notify_config_changes(&mut self) -> io::Result<()> {
+        self.config.notify_changes(&self.gpu)
+    }
+
+    /// Resizes the first scanout, see `VirtioGpu::set_display_size`, and tells the frontend
+    /// right away so the guest resizes without waiting for its next request.
+    pub fn set_display_size(&mut self, width: u32, height: u32) -> io::Result<()> {
+        self.gpu
+            .set_display_size(width, height)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+        self.notify_config_changes()
+    }
+
+    fn offered_features(&self) -> u64 {
+        self.gpu.supported_features()
+            | 1 << VIRTIO_F_VERSION_1
+            | 1 << VIRTIO_F_RING_PACKED
+            | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits()
+    }
+
+    fn vring_index(index: u32) -> VhostUserResult<usize> {
+        match index as usize {
+            index if index < NUM_QUEUES => Ok(index),
+            _ => invalid_param(),
+        }
+    }
+
+    /// Translates the ring addresses of `vring` with the current memory table.
+    fn translate_vring(memory: Option<&GuestMemoryTable>, vring: &mut Vring) -> VhostUserResult<()> {
+        let (desc, avail, used) = match vring.user_addrs {
+            Some(addrs) => addrs,
+            None => return Ok(()),
+        };
+        // translated when the memory table arrives
+        let memory = match memory {
+            Some(memory) => memory,
+            None => return Ok(()),
+        };
+        let translate = |addr| memory.to_guest_addr(addr).ok_or(VhostUserError::InvalidParam);
+        vring.desc_table = translate(desc)?;
+        vring.avail_ring = translate(avail)?;
+        vring.used_ring = translate(used)?;
+        Ok(())
+    }
+
+    fn set_memory(&mut self, regions: Vec<(MemoryRegion, File)>) -> VhostUserResult<()> {
+        let memory = GuestMemoryTable::new(regions).map_err(VhostUserError::ReqHandlerError)?;
+        for vring in self.vrings.iter_mut() {
+            Self::translate_vring(Some(&memory), vring)?;
+        }
+        // the old table's registration goes with its mapping
+        self.register_postcopy(&memory)?;
+        // the renderer reads the backings through the old mapping until they moved, the old table
+        // is only unmapped after
+        let rebound = match self.memory.take().or_else(|| self.stale_memory.take()) {
+            Some(old) => {
+                let rebound = self.gpu.rebind_backings(|host_addr| old.host_to_guest(host_addr), memory.memory());
+                // the backings left behind are still read through the old mapping
+                if rebound.is_err() {
+                    self.retired_memory.push(old);
+                }
+                rebound
+                    .map(|_| ())
+                    .map_err(|e| VhostUserError::ReqHandlerError(io::Error::new(io::ErrorKind::Other, format!("{:?}", e))))
+            }
+            None => Ok(()),
+        };
+        self.memory = Some(memory);
+        self.release_retired_memory();
+        rebound?;
+        self.restore_pending_state()
+    }
+
+    /// Unmaps the retired tables no backing lives in anymore.
+    fn release_retired_memory(&mut self) {
+        let gpu = &self.gpu;
+        self.retired_memory.retain(|table| {
+            table
+                .host_ranges()
+                .iter()
+                .any(|&(base, len)| !gpu.resources_backed_by(base, len).is_empty())
+        });
+    }
+
+    /// Tracks the chains in flight on the `index` vring in the inflight region, and resubmits
+    /// the ones a previous daemon left there.
+    fn restore_inflight(&mut self, index: usize) -> VhostUserResult<()> {
+        let (region, memory) = match (&self.inflight, &self.memory) {
+            (Some(region), Some(memory)) => (region, memory.memory()),
+            _ => return Ok(()),
+        };
+        let vring = &mut self.vrings[index];
+        // the region's layout for packed rings isn't followed
+        if vring.size == 0 || vring.packed.is_some() {
+            return Ok(());
+        }
+        let mut inflight = region.queue(index).ok_or(VhostUserError::InvalidParam)?;
+        let used_idx: Le16 = memory
+            .read_obj(vring.used_ring.unchecked_add(2))
+            .map_err(|_| VhostUserError::InvalidParam)?;
+        let next_avail = inflight
+            .restore(used_idx.to_native())
+            .map_err(|_| VhostUserError::InvalidParam)?;
+        vring.inflight = Some(inflight);
+        if let Some(next_avail) = next_avail {
+            vring.next_avail = next_avail;
+            // the driver may not kick again before the resubmitted chains are done
+            if let Some(ref kick) = vring.kick {
+                let _ = kick.write(1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers the memory table with the userfaultfd while postcopy migration listens.
+    fn register_postcopy(&self, memory: &GuestMemoryTable) -> VhostUserResult<()> {
+        if let Some((ref uffd, true)) = self.postcopy {
+            for (addr, len) in memory.host_ranges() {
+                uffd.register(addr, len).map_err(VhostUserError::ReqHandlerError)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn restore_pending_state(&mut self) -> VhostUserResult<()> {
+        let memory = match self.memory {
+            Some(ref memory) => memory,
+            None => return Ok(()),
+        };
+        match self.pending_state.take() {
+            Some(state) => self.gpu.restore_state(state, memory.memory()).map_err(migration_error),
+            None => Ok(()),
+        }
+    }
+
+    fn memory_regions(&self) -> VhostUserResult<Vec<(MemoryRegion, File)>> {
+        let mut regions = Vec::new();
+        if let Some(ref memory) = self.memory {
+            for (region, file) in &memory.regions {
+                regions.push((*region, file.try_clone().map_err(VhostUserError::ReqHandlerError)?));
+            }
+        }
+        Ok(regions)
+    }
+}
+
+impl VhostUserBackendReqHandlerMut for GpuBackend {
+    fn set_owner(&mut self) -> VhostUserResult<()> {
+        if self.owned {
+            return Err(VhostUserError::InvalidOperation("already claimed"));
+        }
+        self.owned = true;
+        Ok(())
+    }
+
+    fn reset_owner(&mut self) -> VhostUserResult<()> {
+        self.owned = false;
+        self.reset();
+        Ok(())
+    }
+
+    fn get_features(&mut self) -> VhostUserResult<u64> {
+        Ok(self.offered_features())
+    }
+
+    fn set_features(&mut self, features: u64) -> VhostUserResult<()> {
+        if features & !self.offered_features() != 0 {
+            return Err(VhostUserError::InvalidParam);
+        }
+        self.acked_features = features;
+        self.gpu.ack_features(features);
+        let packed = features & 1 << VIRTIO_F_RING_PACKED != 0;
+        for vring in self.vrings.iter_mut() {
+            vring.packed = if packed { Some(PackedRing::default()) } else { None };
+        }
+        // without the protocol features the vrings start as soon as they are kicked
+        if features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() == 0 {
+            for vring in self.vrings.iter_mut() {
+                vring.enabled = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_mem_table(&mut self, ctx: &[VhostUserMemoryRegion], files: Vec<File>) -> VhostUserResult<()> {
+        if ctx.len() != files.len() {
+            return invalid_param();
+        }
+        self.set_memory(ctx.iter().map(MemoryRegion::from).zip(files).collect())
+    }
+
+    fn set_vring_num(&mut self, index: u32, num: u32) -> VhostUserResult<()> {
+        let index = Self::vring_index(index)?;
+        if num == 0 || num > MAX_QUEUE_SIZE as u32 || !num.is_power_of_two() {
+            return invalid_param();
+        }
+        self.vrings[index].size = num as u16;
+        Ok(())
+    }
+
+    fn set_vring_addr(
+        &mut self,
+        index: u32,
+        _flags: VhostUserVringAddrFlags,
+        descriptor: u64,
+        used: u64,
+        available: u64,
+        _log: u64,
+    ) -> VhostUserResult<()> {
+        let index = Self::vring_index(index)?;
+        let vring = &mut self.vrings[index];
+        let previous = vring.user_addrs.replace((descriptor, available, used));
+        let translated = Self::translate_vring(self.memory.as_ref(), vring);
+        // addresses outside the table would fail every later table too
+        if translated.is_err() {
+            vring.user_addrs = previous;
+        }
+        translated
+    }
+
+    fn set_vring_base(&mut self, index: u32, base: u32) -> VhostUserResult<()> {
+        let index = Self::vring_index(index)?;
+        let vring = &mut self.vrings[index];
+        if vring.packed.is_some() {
+            let (next_avail, packed) = PackedRing::from_base(base);
+            vring.next_avail = next_avail;
+            vring.packed = Some(packed);
+        } else {
+            vring.next_avail = base as u16;
+        }
+        Ok(())
+    }
+
+    fn get_vring_base(&mut self, index: u32) -> VhostUserResult<VhostUserVringState> {
+        let vring_index = Self::vring_index(index)?;
+        // the frontend stops the vring with it
+        // the chains held back are the frontend's to resubmit
+        match vring_index {
+            CONTROL_QUEUE => self.control = ControlWorker::new(),
+            _ => self.cursor = CursorWorker::new(),
+        }
+        let vring = &mut self.vrings[vring_index];
+        vring.kick = None;
+        vring.call = None;
+        if self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() != 0 {
+            vring.enabled = false;
+        }
+        let base = match vring.packed {
+            Some(ref packed) => packed.base(vring.next_avail),
+            None => vring.next_avail as u32,
+        };
+        Ok(VhostUserVringState::new(index, base))
+    }
+
+    fn set_vring_kick(&mut self, index: u8, fd: Option<File>) -> VhostUserResult<()> {
+        let index = Self::vring_index(index as u32)?;
+        self.vrings[index].kick = eventfd(fd);
+        self.restore_inflight(index)
+    }
+
+    fn set_vring_call(&mut self, index: u8, fd: Option<File>) -> VhostUserResult<()> {
+        let index = Self::vring_index(index as u32)?;
+        self.vrings[index].call = eventfd(fd);
+        Ok(())
+    }
+
+    fn set_vring_err(&mut self, index: u8, fd: Option<File>) -> VhostUserResult<()> {
+        let index = Self::vring_index(index as u32)?;
+        self.vrings[index].err = eventfd(fd);
+        Ok(())
+    }
+
+    fn get_protocol_features(&mut self) -> VhostUserResult<VhostUserProtocolFeatures> {
+        Ok(VhostUserProtocolFeatures::MQ
+            | VhostUserProtocolFeatures::REPLY_ACK
+            | VhostUserProtocolFeatures::CONFIG
+            | VhostUserProtocolFeatures::BACKEND_REQ
+            | VhostUserProtocolFeatures::CONFIGURE_MEM_SLOTS
+            | VhostUserProtocolFeatures::DEVICE_STATE
+            | VhostUserProtocolFeatures::INFLIGHT_SHMFD
+            | VhostUserProtocolFeatures::PAGEFAULT
+            | VhostUserProtocolFeatures::RESET_DEVICE)
+    }
+
+    fn reset_device(&mut self) -> VhostUserResult<()> {
+        self.reset();
+        Ok(())
+    }
+
+    fn set_protocol_features(&mut self, features: u64) -> VhostUserResult<()> {
+        self.protocol_features =
+            VhostUserProtocolFeatures::from_bits(features).ok_or(VhostUserError::InvalidParam)?;
+        Ok(())
+    }
+
+    fn get_queue_num(&mut self) -> VhostUserResult<u64> {
+        Ok(NUM_QUEUES as u64)
+    }
+
+    fn set_vring_enable(&mut self, index: u32, enable: bool) -> VhostUserResult<()> {
+        if self.acked_features & VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits() == 0 {
+            return Err(VhostUserError::InvalidOperation("protocol features not negotiated"));
+        }
+        let index = Self::vring_index(index)?;
+        self.vrings[index].enabled = enable;
+        Ok(())
+    }
+
+    fn get_config(&mut self, offset: u32, size: u32, _flags: VhostUserConfigFlags) -> VhostUserResult<Vec<u8>> {
+        Ok(self.config.get_config(&self.gpu, offset, size))
+    }
+
+    fn set_config(&mut self, offset: u32, buf: &[u8], _flags: VhostUserConfigFlags) -> VhostUserResult<()> {
+        self.config
+            .set_config(&mut self.gpu, offset, buf)
+            .map_err(VhostUserError::ReqHandlerError)
+    }
+
+    fn set_backend_req_fd(&mut self, backend: Backend) {
+        self.config.set_backend_req(backend);
+    }
+
+    fn set_gpu_socket(&mut self, gpu_socket: GpuSocket) -> VhostUserResult<()> {
+        // without forwarding the device presents the scanout itself, the socket is left unused
+        if let Some(ref channel) = self.gpu_socket {
+            *channel.lock().unwrap() = Some(Arc::new(gpu_socket));
+        }
+        Ok(())
+    }
+
+    fn get_inflight_fd(&mut self, inflight: &VhostUserInflight) -> VhostUserResult<(VhostUserInflight, File)> {
+        if inflight.num_queues as usize != NUM_QUEUES || inflight.queue_size == 0 {
+            return invalid_param();
+        }
+        let (region, file) = InflightRegion::create(inflight.num_queues, inflight.queue_size)
+            .map_err(VhostUserError::ReqHandlerError)?;
+        let reply = VhostUserInflight {
+            mmap_size:   region.size(),
+            mmap_offset: 0,
+            num_queues:  inflight.num_queues,
+            queue_size:  inflight.queue_size,
+        };
+        self.inflight = Some(region);
+        Ok((reply, file))
+    }
+
+    fn set_inflight_fd(&mut self, inflight: &VhostUserInflight, file: File) -> VhostUserResult<()> {
+        // the region is mapped whole, it can't reach past what the frontend passed
+        let size = inflight.num_queues as u64 * queue_region_size(inflight.queue_size);
+        if inflight.num_queues as usize != NUM_QUEUES || size > inflight.mmap_size {
+            return invalid_param();
+        }
+        let region = InflightRegion::new(file, inflight.mmap_offset, inflight.num_queues, inflight.queue_size)
+            .map_err(VhostUserError::ReqHandlerError)?;
+        self.inflight = Some(region);
+        Ok(())
+    }
+
+    fn get_max_mem_slots(&mut self) -> VhostUserResult<u64> {
+        Ok(MAX_MEM_SLOTS)
+    }
+
+    fn add_mem_region(&mut self, region: &VhostUserSingleMemoryRegion, fd: File) -> VhostUserResult<()> {
+        let mut regions = self.memory_regions()?;
+        if regions.len() as u64 >= MAX_MEM_SLOTS {
+            return invalid_param();
+        }
+        regions.push((MemoryRegion::from(region), fd));
+        self.set_memory(regions)
+    }
+
+    fn remove_mem_region(&mut self, region: &VhostUserSingleMemoryRegion) -> VhostUserResult<()> {
+        let removed = MemoryRegion::from(region);
+        let mut regions = self.memory_regions()?;
+        let count = regions.len();
+        regions.retain(|(region, _)| region.guest_addr != removed.guest_addr || region.size != removed.size);
+        if regions.len() == count {
+            return invalid_param();
+        }
+        self.set_memory(regions)
+    }
+
+    fn get_shared_object(&mut self, _uuid: VhostUserSharedMsg) -> VhostUserResult<File> {
+        Err(VhostUserError::InvalidOperation("shared objects not supported"))
+    }
+
+    fn set_log_base(&mut self, _log: &VhostUserLog, _file: File) -> VhostUserResult<()> {
+        Err(VhostUserError::InvalidOperation("dirty page logging not supported"))
+    }
+
+    fn postcopy_advice(&mut self) -> VhostUserResult<File> {
+        let uffd = Userfaultfd::new().map_err(VhostUserError::ReqHandlerError)?;
+        let file = uffd.try_clone_file().map_err(VhostUserError::ReqHandlerError)?;
+        self.postcopy = Some((uffd, false));
+        Ok(file)
+    }
+
+    fn postcopy_listen(&mut self) -> VhostUserResult<()> {
+        match self.postcopy {
+            Some((_, ref mut listening)) => *listening = true,
+            None => return Err(VhostUserError::InvalidOperation("postcopy not advised")),
+        }
+        match self.memory {
+            Some(ref memory) => self.register_postcopy(memory),
+            None => Ok(()),
+        }
+    }
+
+    fn postcopy_end(&mut self) -> VhostUserResult<()> {
+        let (uffd, listening) = self
+            .postcopy
+            .take()
+            .ok_or(VhostUserError::InvalidOperation("postcopy not advised"))?;
+        if let (Some(ref memory), true) = (&self.memory, listening) {
+            for (addr, len) in memory.host_ranges() {
+                uffd.unregister(addr, len).map_err(VhostUserError::ReqHandlerError)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_device_state_fd(
+        &mut self,
+        direction: VhostTransferStateDirection,
+        phase: VhostTransferStatePhase,
+        mut file: File,
+    ) -> VhostUserResult<Option<File>> {
+        if phase != VhostTransferStatePhase::STOPPED {
+            return Err(VhostUserError::InvalidOperation("device state only transferred while stopped"));
+        }
+        if self.state_transfer.is_some() {
+            return Err(VhostUserError::InvalidOperation("device state transfer in progress"));
+        }
+
+        self.state_transfer = Some(match direction {
+            VhostTransferStateDirection::SAVE => {
+                let memory = self.memory.as_ref();
+                let state = self
+                    .gpu
+                    .save_state(|host_addr| memory.and_then(|memory| memory.host_to_guest(host_addr)))
+                    .map_err(migration_error)?;
+                let buf = state.to_bytes();
+                StateTransfer::Save(thread::spawn(move || file.write_all(&buf)))
+            }
+            VhostTransferStateDirection::LOAD => StateTransfer::Load(thread::spawn(move || {
+                let mut buf = Vec::new();
+                file.read_to_end(&mut buf)?;
+                Ok(buf)
+            })),
+        });
+        // the state goes through the frontend's descriptor, no other one is needed
+        Ok(None)
+    }
+
+    fn check_device_state(&mut self) -> VhostUserResult<()> {
+        match self.state_transfer.take() {
+            Some(StateTransfer::Save(handle)) => join_transfer(handle),
+            Some(StateTransfer::Load(handle)) => {
+                let buf = join_transfer(handle)?;
+                self.pending_state = Some(DeviceState::from_bytes(&buf).map_err(migration_error)?);
+                self.restore_pending_state()
+            }
+            None => Err(VhostUserError::InvalidOperation("no device state transfer")),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::protocol::{
+        virtio_gpu_config, virtio_gpu_resource_attach_backing, virtio_gpu_resource_create_2d, VIRTIO_GPU_EVENT_DISPLAY,
+        VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM,
+    };
+    use crate::queue::pop_avail;
+    use crate::vhost::*;
+    use crate::virtio_gpu::{sglist_to_rutabaga_iovecs, GpuMode, GpuParameter};
+    use gpu_display::GpuDisplay;
+    use rutabaga_gfx::RUTABAGA_MEM_HANDLE_TYPE_DMABUF;
+    use std::ffi::CString;
+    use std::mem::size_of;
+    use std::os::unix::net::UnixStream;
+    use vm_memory::{ByteValued, Le32};
+
+    fn memfd(size: u64) -> File {
+        let name = CString::new("guest-ram").unwrap();
+        // Safe because the name is NUL terminated and the returned fd is checked.
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        // Safe because the fd was just created and nothing else owns it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        file.set_len(size).unwrap();
+        file
+    }
+
+    fn region(guest_addr: u64, size: u64, user_addr: u64) -> VhostUserMemoryRegion {
+        VhostUserMemoryRegion::new(guest_addr, size, user_addr, 0)
+    }
+
+    #[test]
+    fn test_gpu_backend() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mut backend = GpuBackend::new(gpu);
+        backend.set_owner().unwrap();
+        assert!(backend.set_owner().is_err());
+
+        let features = backend.get_features().unwrap();
+        assert_ne!(features & 1 << VIRTIO_F_VERSION_1, 0);
+        assert!(backend.set_features(1 << 63).is_err());
+        backend.set_features(features).unwrap();
+        assert_eq!(backend.get_queue_num().unwrap(), NUM_QUEUES as u64);
+
+        // the rings are given in the frontend's mapping, translated once the table arrives
+        let flags = VhostUserVringAddrFlags::empty();
+        assert!(backend.set_vring_num(0, 100).is_err());
+        backend.set_vring_num(0, 256).unwrap();
+        backend.set_vring_addr(0, flags, 0x7f00_0000_1000, 0x7f00_0000_3000, 0x7f00_0000_2000, 0).unwrap();
+        let table = [region(0, 0x10000, 0x7f00_0000_0000), region(0x100000, 0x10000, 0x7f10_0000_0000)];
+        backend.set_mem_table(&table, vec![memfd(0x10000), memfd(0x10000)]).unwrap();
+        let vring = backend.vring(CONTROL_QUEUE);
+        assert_eq!(vring.desc_table, GuestAddress(0x1000));
+        assert_eq!(vring.avail_ring, GuestAddress(0x2000));
+        assert_eq!(vring.used_ring, GuestAddress(0x3000));
+        let memory = backend.memory().unwrap();
+        assert_eq!(memory.to_guest_addr(0x7f10_0000_0010), Some(GuestAddress(0x100010)));
+        assert_eq!(memory.to_guest_addr(0x7f00_0001_0000), None);
+        assert!(backend.set_vring_addr(1, flags, 0x1000, 0x2000, 0x3000, 0).is_err());
+
+        // a vring is enabled by the frontend once the protocol features are in use
+        assert!(!backend.vring(CONTROL_QUEUE).ready());
+        backend.set_vring_kick(0, Some(memfd(0))).unwrap();
+        assert!(!backend.vring(CONTROL_QUEUE).ready());
+        backend.set_vring_enable(0, true).unwrap();
+        assert!(backend.vring(CONTROL_QUEUE).ready());
+        backend.set_vring_base(0, 7).unwrap();
+        assert_eq!({ backend.get_vring_base(0).unwrap().num }, 7);
+        assert!(!backend.vring(CONTROL_QUEUE).ready());
+
+        // memory regions come and go one at a time
+        let single = VhostUserSingleMemoryRegion::new(0x200000, 0x10000, 0x7f20_0000_0000, 0);
+        backend.add_mem_region(&single, memfd(0x10000)).unwrap();
+        assert_eq!(backend.memory().unwrap().regions().count(), 3);
+        backend.remove_mem_region(&single).unwrap();
+        assert!(backend.remove_mem_region(&single).is_err());
+        assert_eq!(backend.memory().unwrap().regions().count(), 2);
+
+        assert_eq!(backend.get_config(0, 4, VhostUserConfigFlags::empty()).unwrap(), vec![0; 4]);
+    }
+
+    fn read_config(backend: &mut GpuBackend) -> virtio_gpu_config {
+        let flags = VhostUserConfigFlags::empty();
+        let buf = backend.get_config(0, size_of::<virtio_gpu_config>() as u32, flags).unwrap();
+        let mut config = virtio_gpu_config::default();
+        config.as_mut_slice().copy_from_slice(&buf);
+        config
+    }
+
+    #[test]
+    fn test_config_space() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mut backend = GpuBackend::new(gpu);
+        let flags = VhostUserConfigFlags::WRITABLE;
+        let config = read_config(&mut backend);
+        assert_eq!(config.events_read.to_native(), 0);
+        assert_eq!(config.num_scanouts.to_native(), 1);
+        assert_eq!(config.num_capsets.to_native(), backend.gpu().num_capsets());
+
+        // a second display plugged in by the VMM
+        backend.gpu().set_scanouts(&[(1024, 768), (800, 600)]).unwrap();
+        backend.notify_config_changes().unwrap();
+        let config = read_config(&mut backend);
+        assert_eq!(config.events_read.to_native(), VIRTIO_GPU_EVENT_DISPLAY);
+        assert_eq!(config.num_scanouts.to_native(), 2);
+        assert!(backend.get_config(12, 8, flags).unwrap().is_empty());
+
+        // only events_clear is writable
+        assert!(backend.set_config(8, &3u32.to_le_bytes(), flags).is_err());
+        assert!(backend.set_config(12, &[0; 8], flags).is_err());
+        backend
+            .set_config(4, &VIRTIO_GPU_EVENT_DISPLAY.to_le_bytes(), flags)
+            .unwrap();
+        assert_eq!(read_config(&mut backend).events_read.to_native(), 0);
+        // writing the whole config back as read is fine
+        let mut config = read_config(&mut backend);
+        config.events_clear = Le32::from(VIRTIO_GPU_EVENT_DISPLAY);
+        backend.set_config(0, config.as_slice(), flags).unwrap();
+
+        // a resize is signalled right away
+        backend.set_display_size(1280, 720).unwrap();
+        assert_eq!(read_config(&mut backend).events_read.to_native(), VIRTIO_GPU_EVENT_DISPLAY);
+        assert_eq!(backend.gpu().display_info(), &[(1280, 720), (800, 600)]);
+        assert!(backend.set_display_size(0, 0).is_err());
+    }
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        // Safe because the array holds the two fds written and the result is checked.
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        // Safe because the fds were just created and nothing else owns them.
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn test_device_state_transfer() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let gpu = VirtioGpu::with_display(gpu_parameter.clone(), GpuDisplay::open_stub).unwrap();
+        let mut source = GpuBackend::new(gpu);
+        assert!(source
+            .get_protocol_features()
+            .unwrap()
+            .contains(VhostUserProtocolFeatures::DEVICE_STATE));
+        assert!(source.check_device_state().is_err());
+
+        let (mut read_end, write_end) = pipe();
+        let (save, stopped) = (VhostTransferStateDirection::SAVE, VhostTransferStatePhase::STOPPED);
+        assert!(source.set_device_state_fd(save, stopped, write_end).unwrap().is_none());
+        let mut buf = Vec::new();
+        read_end.read_to_end(&mut buf).unwrap();
+        source.check_device_state().unwrap();
+        assert_eq!(DeviceState::from_bytes(&buf).unwrap().scanouts, vec![(1920, 1080)]);
+
+        // the state is restored once the memory table arrives
+        let gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mut destination = GpuBackend::new(gpu);
+        let (read_end, mut write_end) = pipe();
+        destination
+            .set_device_state_fd(VhostTransferStateDirection::LOAD, stopped, read_end)
+            .unwrap();
+        write_end.write_all(&buf).unwrap();
+        drop(write_end);
+        destination.check_device_state().unwrap();
+        assert!(destination.pending_state.is_some());
+        destination.set_mem_table(&[region(0, 0x10000, 0x7f00_0000_0000)], vec![memfd(0x10000)]).unwrap();
+        assert!(destination.pending_state.is_none());
+
+        // a state that isn't one is refused
+        let (read_end, write_end) = pipe();
+        destination
+            .set_device_state_fd(VhostTransferStateDirection::LOAD, stopped, read_end)
+            .unwrap();
+        drop(write_end);
+        assert!(destination.check_device_state().is_err());
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mut backend = GpuBackend::new(gpu);
+        let ram = memfd(0x10000);
+        backend.set_owner().unwrap();
+        let features = backend.get_features().unwrap();
+        backend.set_features(features).unwrap();
+        backend.set_mem_table(&[region(0, 0x10000, 0x7f00_0000_0000)], vec![ram.try_clone().unwrap()]).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        let memory = backend.memory().unwrap().memory();
+        let iovecs = sglist_to_rutabaga_iovecs(&[(GuestAddress(0x1000), 32)], memory).unwrap();
+        backend.gpu().cmd_resource_create_2d(create).unwrap();
+        backend.gpu().cmd_resource_attach_backing(attach, iovecs).unwrap();
+
+        // the next frontend starts from scratch, the backing stays where it was until its table
+        backend.disconnect();
+        assert!(backend.memory().is_none());
+        assert_eq!(backend.acked_features(), 0);
+        backend.set_owner().unwrap();
+        backend.set_features(features).unwrap();
+        backend.set_mem_table(&[region(0, 0x10000, 0x7f10_0000_0000)], vec![ram]).unwrap();
+
+        let host = backend.memory().unwrap().memory().get_host_address(GuestAddress(0)).unwrap() as usize;
+        assert_eq!(backend.gpu().resources_backed_by(host + 0x1000, 32), vec![1]);
+    }
+
+    #[test]
+    fn test_reset_device() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mut backend = GpuBackend::new(gpu);
+        assert!(backend.get_protocol_features().unwrap().contains(VhostUserProtocolFeatures::RESET_DEVICE));
+        backend.set_owner().unwrap();
+        let features = backend.get_features().unwrap();
+        backend.set_features(features).unwrap();
+        backend.set_mem_table(&[region(0, 0x10000, 0x7f00_0000_0000)], vec![memfd(0x10000)]).unwrap();
+        backend.set_vring_num(0, 4).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        let iovecs = sglist_to_rutabaga_iovecs(&[(GuestAddress(0x1000), 32)], backend.memory().unwrap().memory()).unwrap();
+        backend.gpu().cmd_resource_create_2d(create).unwrap();
+        backend.gpu().cmd_resource_attach_backing(attach, iovecs).unwrap();
+
+        // the driver starts over on the same frontend and memory
+        backend.reset_device().unwrap();
+        assert_eq!(backend.acked_features(), 0);
+        assert_eq!(backend.vring(0).size, 0);
+        let host = backend.memory().unwrap().memory().get_host_address(GuestAddress(0)).unwrap() as usize;
+        assert!(backend.gpu().resources_backed_by(host, 0x10000).is_empty());
+        backend.set_features(features).unwrap();
+        backend.gpu().cmd_resource_create_2d(create).unwrap();
+
+        // so does one the frontend gives up
+        backend.reset_owner().unwrap();
+        assert_eq!(backend.acked_features(), 0);
+        let offered = backend.gpu().supported_features();
+        assert_eq!(backend.gpu().acked_features(), offered);
+        backend.set_owner().unwrap();
+        backend.gpu().cmd_resource_create_2d(create).unwrap();
+    }
+
+    #[test]
+    fn test_inflight_resubmit() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let ram = memfd(0x10000);
+        let inflight = VhostUserInflight {
+            mmap_size:   0,
+            mmap_offset: 0,
+            num_queues:  NUM_QUEUES as u16,
+            queue_size:  4,
+        };
+        let start = |backend: &mut GpuBackend| {
+            // the region is only followed for split rings
+            let features = backend.get_features().unwrap() & !(1 << VIRTIO_F_RING_PACKED);
+            backend.set_features(features).unwrap();
+            let table = [region(0, 0x10000, 0x7f00_0000_0000)];
+            backend.set_mem_table(&table, vec![ram.try_clone().unwrap()]).unwrap();
+            backend.set_vring_num(0, 4).unwrap();
+            let flags = VhostUserVringAddrFlags::empty();
+            backend.set_vring_addr(0, flags, 0x7f00_0000_1000, 0x7f00_0000_3000, 0x7f00_0000_2000, 0).unwrap();
+            backend.set_vring_kick(0, Some(memfd(0))).unwrap();
+        };
+
+        // the first daemon pops a chain and dies
+        let gpu = VirtioGpu::with_display(gpu_parameter.clone(), GpuDisplay::open_stub).unwrap();
+        let mut backend = GpuBackend::new(gpu);
+        let (region, file) = backend.get_inflight_fd(&inflight).unwrap();
+        assert_eq!(region.mmap_size, 2 * 128);
+        start(&mut backend);
+        backend.vring_mut(0).inflight.as_mut().unwrap().get(2).unwrap();
+        drop(backend);
+
+        let gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mut backend = GpuBackend::new(gpu);
+        assert!(backend.set_inflight_fd(&inflight, file.try_clone().unwrap()).is_err());
+        backend.set_inflight_fd(&region, file).unwrap();
+        start(&mut backend);
+        assert_eq!(backend.vring(0).next_avail, 1);
+        let memory = backend.memory().unwrap().memory().clone();
+        // the driver made the chain available before the crash
+        memory.write_obj(Le16::from(1), GuestAddress(0x2002)).unwrap();
+        let (head, _) = pop_avail(&memory, backend.vring_mut(0)).unwrap().unwrap();
+        assert_eq!(head, 2);
+        assert!(pop_avail(&memory, backend.vring_mut(0)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_scanout_forwarding() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mut backend = GpuBackend::new(gpu).with_scanout_forwarding().unwrap();
+        let mut forwarder = FrontendScanoutForwarder::new(backend.gpu_socket.clone().unwrap()).unwrap();
+        let dmabuf = RutabagaHandle {
+            os_handle:   memfd(4096),
+            handle_type: RUTABAGA_MEM_HANDLE_TYPE_DMABUF,
+        };
+        let scanout = DmabufScanout {
+            width: 32,
+            height: 32,
+            stride: 128,
+            drm_fourcc: 0x3432_5258,
+            modifier: DRM_FORMAT_MOD_INVALID,
+        };
+        // nothing is forwarded before the frontend sent the socket
+        assert_eq!(forwarder.set_scanout(0, &scanout, &dmabuf).unwrap_err().kind(), io::ErrorKind::NotConnected);
+        assert_eq!(forwarder.flush(0, (0, 0, 32, 32)).unwrap_err().kind(), io::ErrorKind::NotConnected);
+
+        let (socket, mut frontend) = UnixStream::pair().unwrap();
+        backend.set_gpu_socket(GpuSocket::from_stream(socket)).unwrap();
+        forwarder.set_scanout(0, &scanout, &dmabuf).unwrap();
+        // request, flags and size, then the scanout
+        let mut msg = [0u8; 12 + size_of::<VhostUserGpuDMABUFScanout>()];
+        frontend.read_exact(&mut msg).unwrap();
+        assert_eq!(u32::from_le_bytes([msg[0], msg[1], msg[2], msg[3]]), 6);
+        assert_eq!(u32::from_le_bytes([msg[8], msg[9], msg[10], msg[11]]) as usize, msg.len() - 12);
+
+        // flushes don't wait for the frontend to present the last update, they're merged meanwhile
+        let mut update = [0u8; 12 + size_of::<VhostUserGpuUpdate>()];
+        forwarder.flush(0, (0, 0, 8, 8)).unwrap();
+        frontend.read_exact(&mut update).unwrap();
+        forwarder.flush(0, (16, 16, 8, 8)).unwrap();
+        forwarder.flush(0, (4, 4, 8, 8)).unwrap();
+        frontend.write_all(&[0u8; 12 + 8]).unwrap();
+        frontend.read_exact(&mut update).unwrap();
+        let fields: Vec<u32> = update[12..].chunks(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        assert_eq!(fields, vec![0, 4, 4, 20, 20]);
+        frontend.write_all(&[0u8; 12 + 8]).unwrap();
+
+        // tiled layouts stay with the device
+        let tiled = DmabufScanout { modifier: 1, ..scanout };
+        assert_eq!(forwarder.set_scanout(0, &tiled, &dmabuf).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        backend.disconnect();
+        assert_eq!(forwarder.disable_scanout(0).unwrap_err().kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn test_memory_hotplug() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mut backend = GpuBackend::new(gpu);
+        let (low, high) = (memfd(0x10000), memfd(0x10000));
+        let table = [region(0, 0x10000, 0x7f00_0000_0000), region(0x100000, 0x10000, 0x7f10_0000_0000)];
+        backend.set_mem_table(&table, vec![low.try_clone().unwrap(), high.try_clone().unwrap()]).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        for &(resource_id, addr) in &[(1, 0x1000), (2, 0x101000)] {
+            create.resource_id = Le32::from(resource_id);
+            attach.resource_id = Le32::from(resource_id);
+            let iovecs = sglist_to_rutabaga_iovecs(&[(GuestAddress(addr), 32)], backend.memory().unwrap().memory()).unwrap();
+            backend.gpu().cmd_resource_create_2d(create).unwrap();
+            backend.gpu().cmd_resource_attach_backing(attach, iovecs).unwrap();
+        }
+        let backed_at = |backend: &mut GpuBackend, addr: u64| {
+            let host = backend.memory().unwrap().memory().get_host_address(GuestAddress(addr)).unwrap() as usize;
+            backend.gpu().resources_backed_by(host, 32)
+        };
+
+        // plugging a region maps the table again, the backings follow
+        let plugged = VhostUserSingleMemoryRegion::new(0x200000, 0x10000, 0x7f20_0000_0000, 0);
+        backend.add_mem_region(&plugged, memfd(0x10000)).unwrap();
+        assert_eq!(backed_at(&mut backend, 0x1000), vec![1]);
+        assert_eq!(backed_at(&mut backend, 0x101000), vec![2]);
+
+        // unplugging the high region detaches what it held
+        let unplugged = VhostUserSingleMemoryRegion::new(0x100000, 0x10000, 0x7f10_0000_0000, 0);
+        backend.remove_mem_region(&unplugged).unwrap();
+        assert_eq!(backed_at(&mut backend, 0x1000), vec![1]);
+        assert_eq!(backend.gpu().resources_backed_by(0, usize::MAX), vec![1]);
+
+        // a whole new table
+        backend.set_mem_table(&[region(0, 0x10000, 0x7f30_0000_0000)], vec![low]).unwrap();
+        assert_eq!(backed_at(&mut backend, 0x1000), vec![1]);
+    }
+
+    #[test]
+    fn test_postcopy() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mut backend = GpuBackend::new(gpu);
+        assert!(backend.postcopy_listen().is_err());
+        assert!(backend.postcopy_end().is_err());
+        // userfaultfd may be disabled on this host
+        if backend.postcopy_advice().is_err() {
+            return;
+        }
+        backend.set_mem_table(&[region(0, 0x10000, 0x7f00_0000_0000)], vec![memfd(0x10000)]).unwrap();
+        backend.postcopy_listen().unwrap();
+        // tables sent while listening are registered too
+        backend.set_mem_table(&[region(0, 0x20000, 0x7f00_0000_0000)], vec![memfd(0x20000)]).unwrap();
+        backend.postcopy_end().unwrap();
+        assert!(backend.postcopy_end().is_err());
+    }
+}
@@ -1,14 +1,44 @@
 use std::num::NonZeroU32;
-use rutabaga_gfx::{Rutabaga, ResourceCreate3D, RUTABAGA_PIPE_TEXTURE_2D, RUTABAGA_PIPE_BIND_RENDER_TARGET, RutabagaIovec, Transfer3D, RutabagaBuilder, RutabagaFenceData, VirglRendererFlags, RutabagaComponentType, RutabagaError};
-use std::collections::BTreeMap;
-use vm_memory::{GuestMemoryMmap, GuestAddress, GuestMemory, VolatileSlice};
+use rutabaga_gfx::{Rutabaga, ResourceCreate3D, ResourceCreateBlob, RUTABAGA_PIPE_TEXTURE_2D, RUTABAGA_PIPE_BIND_RENDER_TARGET, RutabagaIovec, Transfer3D, RutabagaBuilder, RutabagaFenceData, VirglRendererFlags, RutabagaComponentType, RutabagaError, RutabagaResult, format_layout, RUTABAGA_MEM_HANDLE_TYPE_DMABUF, RUTABAGA_MEM_HANDLE_TYPE_OPAQUE_FD, RutabagaHandle};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use vm_memory::{GuestMemoryMmap, GuestAddress, GuestMemory, VolatileSlice, ByteValued, Bytes, Le32};
 use std::os::raw::c_void;
 use crate::protocol::*;
 use crate::protocol::VirtioGpuResponse::{OkNoData, OkCapsetInfo, OkCapset, ErrInvalidResourceId, OkDisplayInfo, OkResourceUuid, OkEdid, ErrUnspec};
 use std::fs::read_to_string;
-use std::cell::RefCell;
-use std::rc::Rc;
-use gpu_display::GpuDisplay;
+use gpu_display::{EventDevice, EventDeviceKind, GpuDisplay, GpuDisplayCapabilities, GpuDisplayError, GpuDisplayOutput};
+use crate::blob::{BlobMapping, BlobMemory, SharedMemoryMapper};
+use crate::capset::{apply_capset_masks, CapsetMask};
+use crate::damage::{bounding_box, DamageRect, DamageTracker};
+use crate::dmabuf::{DmabufScanout, ScanoutForwarder};
+use crate::display_thread::{start_display_thread, DisplayHandle, DisplayEvent, DisplayRequest, FlushRegion, InlineDisplay};
+use crate::edid::{EdidInfo, EdidError, load_edid_file, validate_edid};
+use crate::extension::ExtensionRegistry;
+use crate::input::InputReceiver;
+use crate::interceptor::CommandInterceptor;
+use crate::present::{PresentHook, PresentInfo};
+use crate::recorder::FrameRecorder;
+use crate::quirks::{make_opaque, rect_within, GuestProfile, Quirks};
+use crate::screenshot::Image;
+use crate::shm::ShmRegion;
+use crate::staging::{StagingBuffer, StagingPool};
+use crate::yuv::yuv_to_xrgb;
+use crate::fault_injection::Fault;
+use crate::migration::{DeviceState, MigrationError, ResourceState};
+#[cfg(feature = "fault-injection")]
+use crate::fault_injection::FaultInjector;
+use std::env;
+use std::path::Path;
+use std::cmp::{max, min};
+use std::convert::TryFrom;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::fmt::{self, Display};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use vmm_sys_util::timerfd::TimerFd;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum GpuMode {
@@ -16,7 +46,54 @@ pub enum GpuMode {
     Mode3D,
 }
 
-#[derive(Copy, Clone, Debug)]
+/// The display server `VirtioGpu::new` presents the scanouts on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DisplayBackend {
+    /// The X server `DISPLAY` names
+    X,
+    /// The Wayland compositor `WAYLAND_DISPLAY` names
+    Wayland,
+    /// No display at all, the scanouts are rendered but drawn nowhere
+    Headless,
+    /// A window on the desktop, X11 or Wayland alike, through winit.  Needs the `winit` feature
+    Winit,
+    /// Whichever of X, Wayland or headless the environment has, see `GpuParameter::resolve_display_backend`
+    Auto,
+}
+
+fn env_var_set(name: &str) -> bool {
+    env::var_os(name).map_or(false, |value| !value.is_empty())
+}
+
+impl DisplayBackend {
+    /// The backend `Auto` stands for, given which display servers the environment names.  Wayland
+    /// is preferred, unless the renderer needs X11 and Xwayland is there too.
+    fn detect(wayland: bool, x: bool, needs_x11: bool) -> DisplayBackend {
+        if wayland && !(needs_x11 && x) {
+            DisplayBackend::Wayland
+        } else if x {
+            DisplayBackend::X
+        } else {
+            DisplayBackend::Headless
+        }
+    }
+
+    /// Opens the display, `Auto` picks it from the environment.  Windows are titled `title`
+    /// where the backend has any.
+    pub fn open(self, title: &str) -> Result<GpuDisplay, GpuDisplayError> {
+        match self {
+            DisplayBackend::X => GpuDisplay::open_x::<String>(None),
+            DisplayBackend::Wayland => GpuDisplay::open_wayland::<String>(None),
+            DisplayBackend::Headless => GpuDisplay::open_headless(),
+            DisplayBackend::Winit => GpuDisplay::open_winit(title),
+            DisplayBackend::Auto => {
+                Self::detect(env_var_set("WAYLAND_DISPLAY"), env_var_set("DISPLAY"), false).open(title)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct GpuParameter {
     pub display_width:            u32,
     pub display_height:           u32,
@@ -24,14 +101,69 @@ pub struct GpuParameter {
     pub renderer_use_gles:        bool,
     pub renderer_use_glx:         bool,
     pub renderer_use_surfaceless: bool,
+    /// Have virglrenderer retire fences on its sync thread and report them from there as soon as
+    /// they signal.  They're handed to the `on_fence_complete` callback on the next
+    /// `process_fences`, which the `Fences` event source wakes up for.
+    pub renderer_async_fences:    bool,
     pub mode:                     GpuMode,
+    pub display_backend:          DisplayBackend,
+    /// Name of the VM, shown in the title of its window
+    pub vm_name:                  Option<String>,
+    /// EDID blob presented to the guest instead of the generated one
+    pub edid:                     Option<Vec<u8>>,
+    /// Mirror the host monitors on the guest scanouts, following hotplug
+    pub follow_host_outputs:      bool,
+    /// Interval of the frame pacing timer, cursor moves within a frame are coalesced into a
+    /// single display commit.  `None` commits every move right away.
+    pub frame_interval:           Option<Duration>,
+    /// Flip the scanout on the vertical blank of the display, the flushes within a frame only
+    /// show the last one.  Displays without `vsync_events` flip right away.
+    pub vsync:                    bool,
+    /// Number of unfenced TRANSFER_TO_HOST_* commands held back to run as a batch, 0 runs every
+    /// transfer as it comes.  The renderer still runs the batch synchronously on the control
+    /// queue, once it is full, before any other command and at the end of every drain.
+    pub transfer_queue_depth:     usize,
+    /// Run everything on the thread processing the queues, in the order the caller asks for it:
+    /// the display is driven inline and only polled by `process_display`, cursor moves and
+    /// transfers complete right away instead of waiting for timers or batches.
+    pub deterministic:            bool,
+    /// Offer VIRTIO_GPU_F_EDID
+    pub use_edid:                 bool,
+    /// Offer VIRTIO_GPU_F_RESOURCE_UUID
+    pub use_resource_uuid:        bool,
+    /// Offer VIRTIO_GPU_F_VIRGL in 3D mode, when built with virglrenderer.  Without it the device
+    /// only does 2D
+    pub use_virgl:                bool,
+    /// Offer VIRTIO_GPU_F_RESOURCE_BLOB in 3D mode, when virglrenderer does blob resources
+    pub use_resource_blob:        bool,
+    /// Offer VIRTIO_GPU_F_CONTEXT_INIT in 3D mode, letting the guest pick the capset of a context
+    pub use_context_init:         bool,
+    /// Refresh rate of the preferred mode in the generated EDIDs of the scanouts
+    pub refresh_rate:             u32,
+    /// `(min, max)` refresh rates of a host output with variable refresh rate support.  The
+    /// generated EDID advertises the range and the guest flush cadence is passed to the display.
+    pub adaptive_sync:            Option<(u32, u32)>,
+    /// Number of unreffed 2D resources kept by the renderer, to be reused when the guest creates
+    /// a resource with the same id and layout again.  0, the default, frees every resource right
+    /// away.
+    pub resource_pool_size:       usize,
+    /// QEMU behaviors to reproduce for guests that depend on them
+    pub quirks:                   Quirks,
+    /// Guest driver whose quirks are added to `quirks`
+    pub guest_profile:            GuestProfile,
+    /// Bits cleared from the capsets before the guest reads them
+    pub capset_masks:             Vec<CapsetMask>,
 }
 
 const DEFAULT_DSIPLAY_WIDTH: u32  = 1920;
 const DEFAULT_DISPLAY_HEIGHT: u32 = 1080;
-
-/// Warn: it's unsafe to used in thread, only be used with Mutex
-unsafe impl Send for VirtioGpu {}
+const DEFAULT_REFRESH_RATE: u32   = 60;
+const DEFAULT_RESOURCE_POOL_SIZE: usize = 0;
+const DEFAULT_WINDOW_TITLE: &str = "vhost-gpu-backend";
+/// How often the renderer is polled while waiting for fences
+const FENCE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// Changes of the guest flush interval smaller than this aren't passed to the display
+const FLUSH_CADENCE_TOLERANCE: Duration = Duration::from_millis(1);
 
 impl Default for GpuParameter {
     fn default() -> Self {
@@ -42,27 +174,372 @@ impl Default for GpuParameter {
             renderer_use_gles: true,
             renderer_use_glx: true,
             renderer_use_surfaceless: true,
-            mode: GpuMode::Mode3D
+            renderer_async_fences: false,
+            mode: GpuMode::Mode3D,
+            display_backend: DisplayBackend::Auto,
+            vm_name: None,
+            edid: None,
+            follow_host_outputs: false,
+            frame_interval: None,
+            vsync: false,
+            transfer_queue_depth: 0,
+            deterministic: false,
+            use_edid: true,
+            use_resource_uuid: true,
+            use_virgl: true,
+            use_resource_blob: true,
+            use_context_init: true,
+            refresh_rate: DEFAULT_REFRESH_RATE,
+            adaptive_sync: None,
+            resource_pool_size: DEFAULT_RESOURCE_POOL_SIZE,
+            quirks: Quirks::default(),
+            guest_profile: GuestProfile::default(),
+            capset_masks: Vec::new(),
+        }
+    }
+}
+
+impl GpuParameter {
+    /// Loads the EDID file presented to the guest, rejecting it if it isn't a valid EDID.
+    pub fn load_edid<P: AsRef<Path>>(&mut self, path: P) -> Result<(), EdidError> {
+        self.edid = Some(load_edid_file(path)?);
+        Ok(())
+    }
+
+    /// The virtio-gpu feature bits offered to the guest.
+    pub fn supported_features(&self) -> u64 {
+        let mut features = 0;
+        if self.mode == GpuMode::Mode3D && self.use_virgl && cfg!(feature = "virgl_renderer") {
+            features |= 1 << VIRTIO_GPU_F_VIRGL;
+        }
+        if self.use_edid {
+            features |= 1 << VIRTIO_GPU_F_EDID;
+        }
+        if self.use_resource_uuid {
+            features |= 1 << VIRTIO_GPU_F_RESOURCE_UUID;
+        }
+        if features & (1 << VIRTIO_GPU_F_VIRGL) != 0 && self.use_resource_blob && cfg!(feature = "virgl_renderer_next") {
+            features |= 1 << VIRTIO_GPU_F_RESOURCE_BLOB;
+        }
+        if features & (1 << VIRTIO_GPU_F_VIRGL) != 0 && self.use_context_init {
+            features |= 1 << VIRTIO_GPU_F_CONTEXT_INIT;
+        }
+        features
+    }
+
+    /// Checks the combinations of parameters that can't work, independently of the display.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        ConfigError::from_problems(self.problems())
+    }
+
+    fn problems(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        if self.display_width == 0 || self.display_height == 0 {
+            problems.push(ConfigProblem::ZeroSizedDisplay {
+                width: self.display_width,
+                height: self.display_height,
+            });
+        }
+        if self.uses_virgl() && !self.renderer_use_egl && !self.renderer_use_glx {
+            problems.push(ConfigProblem::NoGlBackend);
+        }
+        if let Some(ref edid) = self.edid {
+            if let Err(e) = validate_edid(edid) {
+                problems.push(ConfigProblem::InvalidEdid(e));
+            }
+        } else if self.use_edid && self.display_width != 0 && self.display_height != 0 {
+            // the display's EDID is generated
+            if let Err(e) = EdidInfo::new(self.display_width, self.display_height, self.refresh_rate) {
+                problems.push(ConfigProblem::InvalidEdid(e));
+            }
+        }
+        if self.refresh_rate == 0 {
+            problems.push(ConfigProblem::ZeroRefreshRate);
+        }
+        if let Some((min, max)) = self.adaptive_sync {
+            if min == 0 || min > max {
+                problems.push(ConfigProblem::InvalidRefreshRange { min, max });
+            }
+        }
+        if self.frame_interval == Some(Duration::from_secs(0)) {
+            problems.push(ConfigProblem::ZeroFrameInterval);
+        }
+        problems
+    }
+
+    /// The problems that depend on what the display supports.
+    fn display_problems(&self, capabilities: &GpuDisplayCapabilities) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+        if self.uses_virgl() && self.renderer_use_glx && !capabilities.x11 {
+            problems.push(ConfigProblem::GlxWithoutX11);
+        }
+        if let Some((max_width, max_height)) = capabilities.max_surface_size {
+            if self.display_width > max_width || self.display_height > max_height {
+                problems.push(ConfigProblem::DisplayTooLarge {
+                    width: self.display_width,
+                    height: self.display_height,
+                    max: (max_width, max_height),
+                });
+            }
+        }
+        problems
+    }
+
+    /// The display backend `VirtioGpu::new` opens, `Auto` resolved from the `WAYLAND_DISPLAY`
+    /// and `DISPLAY` environment variables.  GLX rendering keeps to the X server when both are
+    /// set.
+    pub fn resolve_display_backend(&self) -> DisplayBackend {
+        match self.display_backend {
+            DisplayBackend::Auto => DisplayBackend::detect(
+                env_var_set("WAYLAND_DISPLAY"),
+                env_var_set("DISPLAY"),
+                self.uses_virgl() && self.renderer_use_glx,
+            ),
+            display_backend => display_backend,
+        }
+    }
+
+    /// Opens the display `VirtioGpu::new` presents on, see `resolve_display_backend`.
+    pub fn display_opener(&self) -> impl FnMut() -> Result<GpuDisplay, GpuDisplayError> + Send + 'static {
+        let display_backend = self.resolve_display_backend();
+        let title = match self.vm_name {
+            Some(ref vm_name) => format!("{} - {}", vm_name, DEFAULT_WINDOW_TITLE),
+            None => DEFAULT_WINDOW_TITLE.to_string(),
+        };
+        move || display_backend.open(&title)
+    }
+
+    fn uses_virgl(&self) -> bool {
+        self.supported_features() & (1 << VIRTIO_GPU_F_VIRGL) != 0
+    }
+}
+
+/// A combination of `GpuParameter`s that can't work.
+#[derive(Debug)]
+pub enum ConfigProblem {
+    /// The display has no pixels.
+    ZeroSizedDisplay { width: u32, height: u32 },
+    /// The display is larger than the surfaces the display backend can create.
+    DisplayTooLarge { width: u32, height: u32, max: (u32, u32) },
+    /// virglrenderer is enabled with neither EGL nor GLX.
+    NoGlBackend,
+    /// GLX is enabled but the display isn't an X server.
+    GlxWithoutX11,
+    /// The EDID blob doesn't pass `validate_edid`, or none can be generated for the display.
+    InvalidEdid(EdidError),
+    /// The EDID would advertise a display that never refreshes.
+    ZeroRefreshRate,
+    /// The adaptive sync refresh rates aren't a non-empty range.
+    InvalidRefreshRange { min: u32, max: u32 },
+    /// A frame interval of zero would never fire.
+    ZeroFrameInterval,
+}
+
+impl Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ConfigProblem::*;
+
+        match self {
+            ZeroSizedDisplay { width, height } => {
+                write!(f, "display size {}x{} is empty", width, height)
+            }
+            DisplayTooLarge { width, height, max } => write!(
+                f,
+                "display size {}x{} exceeds the {}x{} the display supports",
+                width, height, max.0, max.1
+            ),
+            NoGlBackend => write!(f, "virgl needs EGL or GLX"),
+            GlxWithoutX11 => write!(f, "GLX needs an X11 display"),
+            InvalidEdid(e) => write!(f, "{}", e),
+            ZeroRefreshRate => write!(f, "the refresh rate is zero"),
+            InvalidRefreshRange { min, max } => {
+                write!(f, "invalid adaptive sync range {}-{}Hz", min, max)
+            }
+            ZeroFrameInterval => write!(f, "the frame interval is zero"),
+        }
+    }
+}
+
+/// Every problem found in a `GpuParameter`.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<ConfigProblem>);
+
+impl ConfigError {
+    fn from_problems(problems: Vec<ConfigProblem>) -> Result<(), ConfigError> {
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid GPU configuration: ")?;
+        for (index, problem) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// An error creating a `VirtioGpu`.
+#[derive(Debug)]
+pub enum VirtioGpuError {
+    /// The parameters can't work together.
+    Config(ConfigError),
+    /// The display couldn't be opened.
+    Display(io::Error),
+    /// The renderer couldn't be initialized.
+    Renderer(RutabagaError),
+    /// Neither the display nor the renderer could be initialized.
+    DisplayAndRenderer(io::Error, RutabagaError),
+    /// The timer pacing the frames couldn't be created.
+    FrameTimer(io::Error),
+}
+
+impl Display for VirtioGpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::VirtioGpuError::*;
+
+        match self {
+            Config(e) => write!(f, "{}", e),
+            Display(e) => write!(f, "failed to open the display: {}", e),
+            Renderer(e) => write!(f, "failed to initialize the renderer: {}", e),
+            DisplayAndRenderer(display, renderer) => write!(
+                f,
+                "failed to open the display: {}, and to initialize the renderer: {}",
+                display, renderer
+            ),
+            FrameTimer(e) => write!(f, "failed to create the frame timer: {}", e),
+        }
+    }
+}
+
+impl From<RutabagaError> for VirtioGpuError {
+    fn from(e: RutabagaError) -> Self {
+        VirtioGpuError::Renderer(e)
+    }
+}
+
+/// What an embedder's event loop waits on, see `VirtioGpu::event_sources`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum GpuEventSource {
+    /// Events from the display thread, handled by `process_display`
+    Display,
+    /// Fences completed by the renderer, handled by `process_fences`
+    Fences,
+    /// The frame pacing timer, handled by `handle_frame_timer`
+    FrameTimer,
+}
+
+/// A scanout as the VMM plugs it, see `VirtioGpu::set_scanout_topology`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScanoutConfig {
+    pub width:   u32,
+    pub height:  u32,
+    /// A monitor is connected, the guest sees the scanout disabled otherwise and the size is
+    /// ignored
+    pub enabled: bool,
+    /// The EDID of the monitor, one is generated from the size without it
+    pub edid:    Option<Vec<u8>>,
+}
+
+/// The renderer and display of a `VirtioGpu`, see `VirtioGpu::renderer_info`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RendererInfo {
+    /// virglrenderer renders the resources, rather than the 2D renderer
+    pub virgl:       bool,
+    pub num_capsets: u32,
+    /// Feature bits offered to the guest
+    pub features:    u64,
+    pub display:     GpuDisplayCapabilities,
+}
+
+/// Pixel layouts `VirtioGpu::blit_scanout` writes, named after their bytes in memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlitFormat {
+    /// B, G, R, 0xff, the layout of the displays
+    Bgrx,
+    /// R, G, B, 0xff
+    Rgbx,
+    /// R, G, B
+    Rgb,
+}
+
+impl BlitFormat {
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            BlitFormat::Bgrx | BlitFormat::Rgbx => 4,
+            BlitFormat::Rgb => 3,
+        }
+    }
+
+    fn write_pixel(self, [r, g, b]: [u8; 3], out: &mut [u8]) {
+        match self {
+            BlitFormat::Bgrx => out.copy_from_slice(&[b, g, r, 0xff]),
+            BlitFormat::Rgbx => out.copy_from_slice(&[r, g, b, 0xff]),
+            BlitFormat::Rgb => out.copy_from_slice(&[r, g, b]),
         }
     }
 }
 
+/// Byte offsets of the red, green and blue channels in the pixels of the display.
+const XRGB_CHANNELS: [usize; 3] = [2, 1, 0];
+
+/// Byte offsets of the red, green and blue channels in a pixel of a packed format.
+fn packed_channels(format: u32) -> Option<[usize; 3]> {
+    match format {
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM => Some(XRGB_CHANNELS),
+        VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM => Some([1, 2, 3]),
+        VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM | VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM => Some([0, 1, 2]),
+        VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM | VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM => Some([3, 2, 1]),
+        _ => None,
+    }
+}
+
+/// A TRANSFER_TO_HOST_* command queued through `VirtioGpu::queue_transfer`.
+#[derive(Copy, Clone, Debug)]
+pub enum PendingTransfer {
+    ToHost2d(virtio_gpu_transfer_to_host_2d),
+    ToHost3d(virtio_gpu_transfer_host_3d),
+}
+
 pub struct VirtioGpuResource {
     resource_id: u32,
     width: u32,
     height: u32,
+    format: u32,
     size: u64,
+    /// Attached to a context at some point, the renderer may still reference it from there
+    in_context: bool,
+    /// The guest detached the backing, the host copy holds the contents until it attaches another
+    backing_detached: bool,
+    /// Host ranges, address and length, of the guest memory the renderer references as the
+    /// backing of the resource, `None` when nothing is attached
+    backing: Option<Vec<(usize, usize)>>,
+    /// Size of a blob resource, 0 for the others
+    blob_size: u64,
 }
 
 impl VirtioGpuResource {
-    /// Creates a new VirtioGpuResource with the given metadata.  Width and height are used by the
-    /// display, while size is useful for hypervisor mapping.
-    pub fn new(resource_id: u32, width: u32, height: u32, size: u64) -> VirtioGpuResource {
+    /// Creates a new VirtioGpuResource with the given metadata.  Width, height and format are used
+    /// by the display, while size is useful for hypervisor mapping.
+    pub fn new(resource_id: u32, width: u32, height: u32, format: u32, size: u64) -> VirtioGpuResource {
         VirtioGpuResource {
             resource_id,
             width,
             height,
+            format,
             size,
+            in_context: false,
+            backing_detached: false,
+            backing: None,
+            blob_size: 0,
         }
     }
 
@@ -70,34 +547,159 @@ impl VirtioGpuResource {
     pub fn dimensions(&self) -> (u32, u32) {
         (self.width, self.height)
     }
+
+    /// Returns the format the VirtioGpuResource was created with.
+    pub fn format(&self) -> u32 {
+        self.format
+    }
+
+    /// Returns the size in bytes of the VirtioGpuResource, 0 when the renderer owns the layout.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the host ranges of the guest memory attached as the backing, if any.
+    pub fn backing(&self) -> Option<&[(usize, usize)]> {
+        self.backing.as_deref()
+    }
+
+    /// Whether the backing references host memory between `start` and `start + len`.
+    fn backing_overlaps(&self, start: usize, len: usize) -> bool {
+        self.backing().map_or(false, |backing| {
+            backing
+                .iter()
+                .any(|&(base, size)| base < start.saturating_add(len) && start < base.saturating_add(size))
+        })
+    }
 }
 
+fn iovec_ranges(iovecs: &[RutabagaIovec]) -> Vec<(usize, usize)> {
+    iovecs.iter().map(|iovec| (iovec.base as usize, iovec.len)).collect()
+}
+
+/// Byte size of a `width`x`height` 2D resource, `None` for unsupported formats or sizes not
+/// representable on the host.
+fn resource_2d_size(format: u32, width: u32, height: u32) -> Option<u64> {
+    let size = format_layout(format, width, height)?.size;
+    usize::try_from(size).ok()?;
+    Some(size)
+}
+
+/// The virtio-gpu device state.
+///
+/// The display is driven by its own thread through a `DisplayBus`, or inline in the deterministic
+/// mode.  The renderer isn't `Send`
+/// (virglrenderer keeps its GL context current on the thread that created it), so a `VirtioGpu`
+/// must be created on the thread processing the queues.
 pub struct VirtioGpu {
-    pub display:         Rc<RefCell<GpuDisplay>>,
+    display:             DisplayHandle,
+    close_requested:     bool,
     display_width:       u32,
     display_height:      u32,
+    /// Mode of every scanout exposed to the guest, `(0, 0)` for a disabled scanout.  Only the
+    /// first scanout is presented on the host.
+    scanouts:            Vec<(u32, u32)>,
+    /// EDIDs the VMM gave for the scanouts, by scanout
+    scanout_edids:       Vec<Option<Vec<u8>>>,
+    follow_host_outputs: bool,
+    events_read:         u32,
+    /// `(id, version, max size)` of the capsets, in the order the guest enumerates them
+    capsets:             Vec<(u32, u32, u32)>,
+    /// Feature bits offered to the guest
+    features:            u64,
+    /// Feature bits the driver acked, commands of the other features are rejected
+    acked_features:      u64,
     scanout_resource_id: Option<NonZeroU32>,
     scanout_surface_id:  Option<u32>,
+    /// The rect of the scanout resource the guest shows on the first scanout
+    scanout_rect:        DamageRect,
+    /// What the guest transferred to the scanout resource since the last flush
+    scanout_damage:      DamageTracker,
+    /// The resource and rect the guest shows on the other scanouts, only read by captures
+    other_scanouts:      BTreeMap<u32, (u32, DamageRect)>,
     cursor_resource_id:  Option<NonZeroU32>,
     cursor_surface_id:   Option<u32>,
+    /// Where the guest put the pointer
+    cursor_position:     (u32, u32),
+    /// The pixel of the cursor image pointing at `cursor_position`
+    cursor_hotspot:      (u32, u32),
+    cursor_pending:      bool,
+    /// Damage of the scanout flushes waiting for the next frame, when frame pacing is enabled
+    flush_pending:       Option<DamageRect>,
+    frame_interval:      Option<Duration>,
+    vsync:               bool,
+    /// Armed while cursor moves wait for the next frame, when frame pacing is enabled
+    frame_timer:         Option<TimerFd>,
+    last_frame:          Instant,
+    refresh_rate:        u32,
+    adaptive_sync:       Option<(u32, u32)>,
+    last_flush:          Option<Instant>,
+    /// Flush interval last passed to the display, with the surface it was given for
+    flush_interval:      Option<(u32, Duration)>,
+    transfer_queue_depth: usize,
+    /// Transfers waiting for the renderer, with the token their response is reported under
+    pending_transfers:   VecDeque<(u64, PendingTransfer)>,
+    #[cfg(feature = "fault-injection")]
+    faults:              FaultInjector,
     rutabaga:            Rutabaga,
+    /// Contexts the guest created and didn't destroy yet
+    contexts:            BTreeSet<u32>,
+    /// Names the guest gave its contexts, by context id
+    context_names:       BTreeMap<u32, String>,
     resources:           BTreeMap<u32, VirtioGpuResource>,
+    /// 2D resources the guest unreffed but the renderer still holds, oldest first
+    resource_pool:       VecDeque<VirtioGpuResource>,
+    resource_pool_size:  usize,
+    quirks:              Quirks,
+    capset_masks:        Vec<CapsetMask>,
+    /// Places mapped blobs in the host visible region, there is no region without one
+    shm_mapper:          Option<Box<dyn SharedMemoryMapper>>,
+    /// Where the mapped blobs lie in the host visible region
+    shm_region:          ShmRegion,
+    /// Blobs mapped in the host visible region, by resource id
+    blob_mappings:       BTreeMap<u32, BlobMapping>,
+    edid:                Option<Vec<u8>>,
+    extensions:          ExtensionRegistry,
+    interceptors:        Vec<Box<dyn CommandInterceptor>>,
+    present_hooks:       Vec<Box<dyn PresentHook>>,
+    /// Records the presented frames as a video
+    frame_recorder:      Option<FrameRecorder>,
+    /// Hands the scanout to the VMM when the renderer exports it as a dmabuf
+    scanout_forwarder:   Option<Box<dyn ScanoutForwarder>>,
+    /// The resource the VMM scans out, the device's surface is left alone meanwhile
+    forwarded_resource_id: Option<u32>,
+    /// Frames flipped on the scanout so far
+    frames_presented:    u64,
+    /// Buffers the copy flush paths read the scanout into, given back by the display thread
+    staging:             StagingPool,
+    fence_callback:      Option<Box<dyn FnMut(RutabagaFenceData)>>,
+    /// Told the new `virtio_gpu_config` when an event is raised, to interrupt the guest
+    config_callback:     Option<Box<dyn FnMut(virtio_gpu_config)>>,
+    /// Last fence signalled on each `(ctx_id, fence_ctx_idx)` ring, `(0, 0)` is the global one
+    signalled_fences:    BTreeMap<(u32, u32), u64>,
+    /// Fences created on each ring that haven't signalled yet
+    pending_fences:      BTreeMap<(u32, u32), BTreeSet<u64>>,
 }
 
-fn sglist_to_rutabaga_iovecs(vecs: &[(GuestAddress, usize)], mem: &GuestMemoryMmap) -> Result<Vec<RutabagaIovec>, VirtioGpuResponse> {
-    // validate sglist range
-    if vecs
-        .iter()
-        .any(|&(addr, len)| mem.get_slice(addr, len).is_err()) {
-        return Err(VirtioGpuResponse::InvalidSglistRegion());
+/// The `(ctx_id, fence_ctx_idx)` ring `fence` is on, `(0, 0)` for the global timeline.
+pub fn fence_ring(fence: &RutabagaFenceData) -> (u32, u32) {
+    if fence.flags & VIRTIO_GPU_FLAG_INFO_RING_IDX != 0 {
+        (fence.ctx_id, fence.fence_ctx_idx)
+    } else {
+        (0, 0)
     }
+}
 
-    let mut iovecs: Vec<RutabagaIovec> = Vec::new();
-    for &(addr, len) in vecs {
-        // it is safe to unwrap host address because we have already checked
-        let address = mem.get_host_address(addr).unwrap();
+/// Translates a guest scatter-gather list into host iovecs, rejecting entries outside guest memory
+/// with the index of the first one.
+pub fn sglist_to_rutabaga_iovecs(vecs: &[(GuestAddress, usize)], mem: &GuestMemoryMmap) -> Result<Vec<RutabagaIovec>, VirtioGpuResponse> {
+    let mut iovecs: Vec<RutabagaIovec> = Vec::with_capacity(vecs.len());
+    for (index, &(addr, len)) in vecs.iter().enumerate() {
+        let slice = mem
+            .get_slice(addr, len)
+            .map_err(|_| VirtioGpuResponse::InvalidSglistRegion(index))?;
         iovecs.push(RutabagaIovec {
-            base: address as *mut c_void,
+            base: slice.as_ptr() as *mut c_void,
             len,
         })
     }
@@ -105,6 +707,32 @@ fn sglist_to_rutabaga_iovecs(vecs: &[(GuestAddress, usize)], mem: &GuestMemoryMm
     Ok(iovecs)
 }
 
+/// `(id, version, max size)` of every capset the renderer supports.  Capsets are queried by
+/// index and the renderer reports the ones it lacks with version 0, so those are left out and
+/// the guest enumerates the rest with indexes of its own.
+fn query_capsets(rutabaga: &Rutabaga) -> Vec<(u32, u32, u32)> {
+    (0..)
+        .map(|index| rutabaga.get_capset_info(index))
+        .take_while(|info| info.is_ok())
+        .filter_map(|info| info.ok())
+        .filter(|&(_, version, _)| version != 0)
+        .collect()
+}
+
+/// The frame pacing timer, non-blocking so that handling it never waits for a frame.
+fn new_frame_timer() -> io::Result<TimerFd> {
+    let timer = TimerFd::new()?;
+    // Safe because the descriptor is owned by `timer` and only its flags are changed.
+    let ret = unsafe {
+        let flags = libc::fcntl(timer.as_raw_fd(), libc::F_GETFL);
+        libc::fcntl(timer.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(timer)
+}
+
 fn transfer_host_3d_to_transfer_3d(
     cmd: virtio_gpu_transfer_host_3d
 ) -> Transfer3D {
@@ -125,471 +753,4012 @@ fn transfer_host_3d_to_transfer_3d(
 impl VirtioGpu {
     pub fn new(
         gpu_parameter: GpuParameter,
-    ) -> Result<Self, RutabagaError> {
-        let display = GpuDisplay::open_x::<String>(None).unwrap();
-        let virtglrenderer_flags = VirglRendererFlags::new()
-            .use_egl(gpu_parameter.renderer_use_egl)
-            .use_gles(gpu_parameter.renderer_use_gles)
-            .use_glx(gpu_parameter.renderer_use_glx)
-            .use_surfaceless(gpu_parameter.renderer_use_surfaceless);
+    ) -> Result<Self, VirtioGpuError> {
+        let open_display = gpu_parameter.display_opener();
+        Self::with_display(gpu_parameter, open_display)
+    }
 
-        let component = match gpu_parameter.mode {
-            GpuMode::Mode2D => RutabagaComponentType::Rutabaga2D,
-            GpuMode::Mode3D => RutabagaComponentType::VirglRenderer,
+    /// Creates the device presenting its scanouts on the display opened by `open_display`
+    /// instead of the one `display_backend` names.  The display is opened on the display thread.
+    ///
+    /// A VMM presenting the scanouts itself, in its own GUI say, opens its `GpuDisplayBackend`
+    /// with `GpuDisplay::from_backend`.
+    ///
+    /// The display thread opens the display while the renderer is initialized on the calling
+    /// thread.  The parameters are checked against each other and against the display, all the
+    /// problems found are reported together and the renderer is only kept when there are none.
+    pub fn with_display<F>(
+        gpu_parameter: GpuParameter,
+        open_display: F,
+    ) -> Result<Self, VirtioGpuError>
+    where
+        F: FnMut() -> Result<GpuDisplay, GpuDisplayError> + Send + 'static,
+    {
+        let mut problems = gpu_parameter.problems();
+        let features = gpu_parameter.supported_features();
+        let (display, rutabaga) = if gpu_parameter.deterministic {
+            let display = InlineDisplay::open(open_display).map(DisplayHandle::Inline);
+            (display, None)
+        } else {
+            // the thread is stopped when the last `DisplayBus` is dropped
+            let pending = start_display_thread(open_display).map_err(VirtioGpuError::Display)?;
+            // a renderer that won't be used isn't worth the wait
+            let rutabaga = if problems.is_empty() {
+                Some(Self::build_renderer(&gpu_parameter, features))
+            } else {
+                None
+            };
+            (pending.wait().map(|(bus, _)| DisplayHandle::Thread(bus)), rutabaga)
+        };
+        let (display, rutabaga) = match (display, rutabaga) {
+            (Err(display), Some(Err(renderer))) => {
+                return Err(VirtioGpuError::DisplayAndRenderer(display, renderer))
+            }
+            (display, rutabaga) => (display.map_err(VirtioGpuError::Display)?, rutabaga),
+        };
+        problems.extend(gpu_parameter.display_problems(&display.capabilities()));
+        ConfigError::from_problems(problems).map_err(VirtioGpuError::Config)?;
+        let rutabaga = match rutabaga {
+            Some(rutabaga) => rutabaga?,
+            None => Self::build_renderer(&gpu_parameter, features)?,
         };
 
-        let rutabaga_builder = RutabagaBuilder::new(component)
-            .set_virglrenderer_flags(virtglrenderer_flags);
-
-        let rutabaga = rutabaga_builder.build()?;
+        // nothing is deferred to timers, batches or the vertical blank in the deterministic mode
+        let (frame_interval, transfer_queue_depth, vsync) = if gpu_parameter.deterministic {
+            (None, 0, false)
+        } else {
+            (gpu_parameter.frame_interval, gpu_parameter.transfer_queue_depth, gpu_parameter.vsync)
+        };
+        let capsets = query_capsets(&rutabaga);
+        let frame_timer = frame_interval
+            .map(|_| new_frame_timer())
+            .transpose()
+            .map_err(VirtioGpuError::FrameTimer)?;
 
         Ok(Self {
-            display: Rc::new(RefCell::new(display)),
+            display,
+            close_requested: false,
             display_width: gpu_parameter.display_width,
             display_height: gpu_parameter.display_height,
+            scanouts: vec![(gpu_parameter.display_width, gpu_parameter.display_height)],
+            scanout_edids: vec![None],
+            follow_host_outputs: gpu_parameter.follow_host_outputs,
+            events_read: 0,
+            capsets,
+            features,
+            acked_features: features,
             scanout_resource_id: None,
             scanout_surface_id: None,
+            scanout_rect: (0, 0, gpu_parameter.display_width, gpu_parameter.display_height),
+            other_scanouts: BTreeMap::new(),
+            scanout_damage: DamageTracker::new(),
             cursor_resource_id: None,
             cursor_surface_id: None,
+            cursor_position: (0, 0),
+            cursor_hotspot: (0, 0),
+            cursor_pending: false,
+            flush_pending: None,
+            frame_interval,
+            vsync,
+            frame_timer,
+            last_frame: Instant::now(),
+            refresh_rate: gpu_parameter.refresh_rate,
+            adaptive_sync: gpu_parameter.adaptive_sync,
+            last_flush: None,
+            flush_interval: None,
+            transfer_queue_depth,
+            pending_transfers: VecDeque::new(),
+            #[cfg(feature = "fault-injection")]
+            faults: FaultInjector::new(),
             rutabaga,
-            resources: Default::default()
+            contexts: BTreeSet::new(),
+            context_names: BTreeMap::new(),
+            resources: Default::default(),
+            resource_pool: VecDeque::new(),
+            resource_pool_size: gpu_parameter.resource_pool_size,
+            quirks: gpu_parameter.quirks.with(gpu_parameter.guest_profile.quirks()),
+            capset_masks: gpu_parameter.capset_masks,
+            shm_mapper: None,
+            shm_region: ShmRegion::default(),
+            blob_mappings: BTreeMap::new(),
+            edid: gpu_parameter.edid,
+            extensions: ExtensionRegistry::new(),
+            interceptors: Vec::new(),
+            present_hooks: Vec::new(),
+            frame_recorder: None,
+            scanout_forwarder: None,
+            forwarded_resource_id: None,
+            frames_presented: 0,
+            staging: StagingPool::new(),
+            fence_callback: None,
+            config_callback: None,
+            signalled_fences: BTreeMap::new(),
+            pending_fences: BTreeMap::new(),
         })
     }
 
-    pub fn display(&mut self) -> &Rc<RefCell<GpuDisplay>> { &self.display }
+    /// Initializes the renderer offering `features`, on the calling thread.
+    fn build_renderer(gpu_parameter: &GpuParameter, features: u64) -> RutabagaResult<Rutabaga> {
+        let virtglrenderer_flags = VirglRendererFlags::new()
+            .use_egl(gpu_parameter.renderer_use_egl)
+            .use_gles(gpu_parameter.renderer_use_gles)
+            .use_glx(gpu_parameter.renderer_use_glx)
+            .use_surfaceless(gpu_parameter.renderer_use_surfaceless)
+            .use_async_fence_cb(gpu_parameter.renderer_async_fences);
 
-    /// Gets the list of supported display resolutions as a slice of `(width, height)` tuples.
-    pub fn display_info(&self) -> [(u32, u32); 1] {
-        [(self.display_width, self.display_height)]
-    }
+        let component = if features & (1 << VIRTIO_GPU_F_VIRGL) != 0 {
+            RutabagaComponentType::VirglRenderer
+        } else {
+            RutabagaComponentType::Rutabaga2D
+        };
 
-    pub fn process_display(&mut self) -> bool {
-        let mut display = self.display.borrow_mut();
-        display.dispatch_events();
-        self.scanout_surface_id
-            .map(|s| display.close_requested(s))
-            .unwrap_or(false)
+        RutabagaBuilder::new(component)
+            .set_virglrenderer_flags(virtglrenderer_flags)
+            .build()
     }
 
-    fn resource_create_3d(&mut self, resource_id: u32, resource_create_3d: ResourceCreate3D) -> VirtioGpuResponseResult {
-        self.rutabaga
-            .resource_create_3d(resource_id, resource_create_3d)?;
-
-        let resource = VirtioGpuResource::new(
-            resource_id,
-            resource_create_3d.width,
-            resource_create_3d.height,
-            0,
-        );
+    pub fn display(&mut self) -> &DisplayHandle { &self.display }
 
-        self.resources.insert(resource_id, resource);
+    /// Handles the commands of `extensions` on top of the standard ones.
+    pub fn with_extensions(mut self, extensions: ExtensionRegistry) -> Self {
+        self.extensions = extensions;
+        self
+    }
 
-        match self.rutabaga.query(resource_id) {
-            Ok(_) => Ok(VirtioGpuResponse::ErrInvalidResourceId),
-            Err(_) => Ok(OkNoData)
-        }
+    /// Runs `interceptor` around every command passed to `intercept`.
+    pub fn with_interceptor<I: CommandInterceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
     }
 
-    pub fn cmd_get_display_info(&mut self, cmd: virtio_gpu_ctrl_hdr) -> VirtioGpuResponseResult {
-        Ok(OkDisplayInfo(Vec::from([(self.display_width, self.display_height)])))
+    /// Maps the blobs the guest asks for in the host visible region through `mapper`.
+    pub fn with_shm_mapper<M: SharedMemoryMapper + 'static>(mut self, mapper: M) -> Self {
+        self.shm_region = ShmRegion::new(mapper.region_size());
+        self.shm_mapper = Some(Box::new(mapper));
+        self
     }
 
-    pub fn cmd_resource_create_2d(&mut self, cmd: virtio_gpu_resource_create_2d) -> VirtioGpuResponseResult {
-        let resource_create_3d = ResourceCreate3D {
-            target: RUTABAGA_PIPE_TEXTURE_2D,
-            format: cmd.format.to_native(),
-            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
-            width: cmd.width.to_native(),
-            height: cmd.height.to_native(),
-            depth: 1,
-            array_size: 1,
-            last_level: 0,
-            nr_samples: 0,
-            flags: 0,
-        };
-        self.resource_create_3d(cmd.resource_id.to_native(), resource_create_3d)
+    /// The mappings of the host visible region.
+    pub fn shm_region(&self) -> &ShmRegion {
+        &self.shm_region
     }
 
-    pub fn cmd_resource_create_3d(&mut self, cmd: virtio_gpu_resource_create_3d) -> VirtioGpuResponseResult {
-        let resource_create_3d = ResourceCreate3D {
-            target: cmd.target.to_native(),
-            format: cmd.format.to_native(),
-            bind: cmd.bind.to_native(),
-            width: cmd.width.to_native(),
-            height: cmd.height.to_native(),
-            depth: cmd.depth.to_native(),
-            array_size: cmd.array_size.to_native(),
-            last_level: cmd.last_level.to_native(),
-            nr_samples: cmd.nr_samples.to_native(),
-            flags: cmd.flags.to_native(),
-        };
-        self.resource_create_3d(cmd.resource_id.to_native(), resource_create_3d)
+    /// Has `forwarder` hand the scanout resource to the VMM when it can be exported as a dmabuf.
+    pub fn with_scanout_forwarder<F: ScanoutForwarder + 'static>(mut self, forwarder: F) -> Self {
+        self.scanout_forwarder = Some(Box::new(forwarder));
+        self
     }
 
-    pub fn cmd_resource_unref(&mut self, cmd: virtio_gpu_resource_unref) -> VirtioGpuResponseResult {
-        self.rutabaga.unref_resource(cmd.resource_id.to_native())?;
-        self.resources
-            .remove(&cmd.resource_id.to_native())
-            .ok_or(ErrInvalidResourceId)?;
-        Ok(OkNoData)
+    /// Runs `hook` around every flip of the scanout.
+    pub fn with_present_hook<H: PresentHook + 'static>(mut self, hook: H) -> Self {
+        self.present_hooks.push(Box::new(hook));
+        self
     }
 
-    pub fn cmd_context_create(&mut self, cmd: virtio_gpu_ctx_create) -> VirtioGpuResponseResult {
-        self.rutabaga.create_context(cmd.hdr.ctx_id.to_native(), 0)?;
-        Ok(OkNoData)
+    /// Records the frames presented on the scanout with `recorder`.  Every frame recorded is read
+    /// back and converted to YUV as it is presented, on the queue presenting it.
+    pub fn with_frame_recorder(mut self, recorder: FrameRecorder) -> Self {
+        self.frame_recorder = Some(recorder);
+        self
     }
 
-    pub fn cmd_context_destroy(&mut self, cmd: virtio_gpu_ctx_destroy) -> VirtioGpuResponseResult {
-        self.rutabaga.destroy_context(cmd.hdr.ctx_id.to_native())?;
-        Ok(OkNoData)
+    /// Stops the recording, for the recorder to be finished.
+    pub fn take_frame_recorder(&mut self) -> Option<FrameRecorder> {
+        self.frame_recorder.take()
     }
 
-    pub fn cmd_get_edid(&mut self, cmd: virtio_gpu_cmd_get_edid) -> VirtioGpuResponseResult {
-        let mut edid = [0u8; 1024];
-        let edid_vec: Vec<u8> = vec![
-            // 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x09, 0xe5, 0xdf, 0x06, 0x00, 0x00, 0x00, 0x00,
-            // 0x01, 0x1a, 0x01, 0x04, 0xa5, 0x1f, 0x11, 0x78, 0x02, 0x86, 0x31, 0xa3, 0x54, 0x4e, 0x9b, 0x25,
-            // 0x0e, 0x50, 0x54, 0x00, 0x00, 0x00, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
-            // 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x3c, 0x37, 0x80, 0xde, 0x70, 0x38, 0x14, 0x40, 0x3c, 0x20,
-            // 0x36, 0x00, 0x35, 0xad, 0x10, 0x00, 0x00, 0x1a, 0x30, 0x2c, 0x80, 0xde, 0x70, 0x38, 0x14, 0x40,
-            // 0x30, 0x20, 0x36, 0x00, 0x35, 0xad, 0x10, 0x00, 0x00, 0x1a, 0x00, 0x00, 0x00, 0xfe, 0x00, 0x42,
-            // 0x4f, 0x45, 0x20, 0x43, 0x51, 0x0a, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x00, 0x00, 0x00, 0xfe,
-            // 0x00, 0x48, 0x56, 0x31, 0x34, 0x30, 0x46, 0x48, 0x4d, 0x2d, 0x4e, 0x36, 0x31, 0x0a, 0x00, 0x49,
-            0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x10, 0xac, 0xc0, 0xa0, 0x4c, 0x55, 0x36, 0x30,
-            0x2d, 0x18, 0x01, 0x03, 0x80, 0x35, 0x1e, 0x78, 0xea, 0xe2, 0x45, 0xa8, 0x55, 0x4d, 0xa3, 0x26,
-            0x0b, 0x50, 0x54, 0xa5, 0x4b, 0x00, 0x71, 0x4f, 0x81, 0x80, 0xa9, 0xc0, 0xa9, 0x40, 0xd1, 0xc0,
-            0xe1, 0x00, 0x01, 0x01, 0x01, 0x01, 0xa3, 0x66, 0x00, 0xa0, 0xf0, 0x70, 0x1f, 0x80, 0x30, 0x20,
-            0x35, 0x00, 0x0f, 0x28, 0x21, 0x00, 0x00, 0x1a, 0x00, 0x00, 0x00, 0xff, 0x00, 0x50, 0x32, 0x50,
-            0x43, 0x32, 0x34, 0x42, 0x34, 0x30, 0x36, 0x55, 0x4c, 0x0a, 0x00, 0x00, 0x00, 0xfc, 0x00, 0x44,
-            0x45, 0x4c, 0x4c, 0x20, 0x50, 0x32, 0x34, 0x31, 0x35, 0x51, 0x0a, 0x20, 0x00, 0x00, 0x00, 0xfd,
-            0x00, 0x1d, 0x4c, 0x1e, 0x8c, 0x1e, 0x00, 0x0a, 0x20, 0x20, 0x20, 0x20, 0x20, 0x20, 0x01, 0x96,
-            0x02, 0x03, 0x2a, 0xf1, 0x53, 0x90, 0x05, 0x04, 0x02, 0x07, 0x16, 0x01, 0x14, 0x1f, 0x12, 0x13,
-            0x27, 0x20, 0x21, 0x22, 0x03, 0x06, 0x11, 0x15, 0x23, 0x09, 0x07, 0x07, 0x6d, 0x03, 0x0c, 0x00,
-            0x10, 0x00, 0x30, 0x3c, 0x20, 0x00, 0x60, 0x03, 0x02, 0x01, 0x02, 0x3a, 0x80, 0x18, 0x71, 0x38,
-            0x2d, 0x40, 0x58, 0x2c, 0x25, 0x00, 0x0f, 0x28, 0x21, 0x00, 0x00, 0x1f, 0x01, 0x1d, 0x80, 0x18,
-            0x71, 0x1c, 0x16, 0x20, 0x58, 0x2c, 0x25, 0x00, 0x0f, 0x28, 0x21, 0x00, 0x00, 0x9e, 0x04, 0x74,
-            0x00, 0x30, 0xf2, 0x70, 0x5a, 0x80, 0xb0, 0x58, 0x8a, 0x00, 0x0f, 0x28, 0x21, 0x00, 0x00, 0x1e,
-            0x56, 0x5e, 0x00, 0xa0, 0xa0, 0xa0, 0x29, 0x50, 0x30, 0x20, 0x35, 0x00, 0x0f, 0x28, 0x21, 0x00,
-            0x00, 0x1a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xf9,
-        ];
-        for (pos, e) in edid_vec.iter().enumerate() {
-            edid[pos] = *e;
+    /// Flips the resource on the scanout surface through the registered present hooks.
+    fn present(&mut self, resource_id: u32, surface_id: u32, damage: DamageRect) -> VirtioGpuResponseResult {
+        let info = PresentInfo {
+            scanout_id: 0,
+            frame: self.frames_presented,
+            damage,
+        };
+        // the flip needs the device, so the hooks are moved out while it runs
+        let mut hooks = std::mem::take(&mut self.present_hooks);
+        for hook in hooks.iter_mut() {
+            hook.before_present(&info);
         }
-        Ok(OkEdid {
-            size: edid_vec.len() as u32,
-            edid
-        })
+        let result = self.flush_scanout(resource_id, surface_id, damage);
+        if result.is_ok() {
+            self.frames_presented += 1;
+            for hook in hooks.iter_mut().rev() {
+                hook.after_present(&info);
+            }
+            self.record_frame();
+        }
+        self.present_hooks = hooks;
+        result
     }
 
-    pub fn cmd_get_capset_info(&mut self, cmd: virtio_gpu_get_capset_info) -> VirtioGpuResponseResult {
-        let (capset_id, version, size) = self.rutabaga.get_capset_info(cmd.capset_index.to_native())?;
-        Ok(OkCapsetInfo {
-            capset_id,
-            version,
-            size
-        })
+    /// Hands the frame just presented to the recorder when it's one to record.
+    fn record_frame(&mut self) {
+        let mut recorder = match self.frame_recorder.take() {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        if recorder.next_frame() {
+            // a frame that can't be read back is left out of the recording
+            if let Ok(image) = self.capture_scanout(0) {
+                recorder.record(&image);
+            }
+        }
+        self.frame_recorder = Some(recorder);
     }
 
-    /// get rubataga capaset
-    pub fn cmd_get_capset(&mut self, cmd: virtio_gpu_get_capset) -> VirtioGpuResponseResult {
-        let capset = self.rutabaga.get_capset(cmd.capset_id.to_native(), cmd.capset_version.to_native())?;
-        Ok(OkCapset(capset))
+    /// Runs `handler`, the processing of `cmd`, through the registered interceptors.
+    pub fn intercept<F>(&mut self, cmd: &VirtioGpuCommand, handler: F) -> VirtioGpuResponseResult
+    where
+        F: FnOnce(&mut Self) -> VirtioGpuResponseResult,
+    {
+        // the handler gets the device, so the interceptors are moved out while it runs
+        let mut interceptors = std::mem::take(&mut self.interceptors);
+        let mut result = None;
+        let mut ran = 0;
+        for interceptor in interceptors.iter_mut() {
+            ran += 1;
+            result = interceptor.before(cmd);
+            if result.is_some() {
+                break;
+            }
+        }
+
+        let start = Instant::now();
+        let result = match result {
+            Some(result) => result,
+            None => handler(self),
+        };
+        let elapsed = start.elapsed();
+
+        for interceptor in interceptors[..ran].iter_mut().rev() {
+            interceptor.after(cmd, &result, elapsed);
+        }
+        self.interceptors = interceptors;
+        result
     }
 
-    /// Attempts to import the given resource into the display, otherwise falls back to rutabaga
-    /// copies.
-    pub fn flush_resource_to_surface(
+    /// Runs the extension command at `addr`, for commands `VirtioGpuCommand::decode` rejected.
+    /// Returns `None` if no extension handles its type.
+    pub fn process_extension(
         &mut self,
-        resource_id: u32,
-        surface_id: u32,
-    ) -> VirtioGpuResponseResult {
-        if let Some(import_id) = self.import_to_display(resource_id) {
-            self.display.borrow_mut().flip_to(surface_id, import_id);
-            return Ok(OkNoData);
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+    ) -> Option<VirtioGpuResponseResult> {
+        let hdr: virtio_gpu_ctrl_hdr = match mem.read_obj(addr) {
+            Ok(hdr) => hdr,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let type_ = hdr.type_.to_native();
+        let mut buf = vec![0u8; self.extensions.command_size(type_)?];
+        if let Err(e) = mem.read_slice(&mut buf, addr) {
+            return Some(Err(e.into()));
         }
 
-        if !self.resources.contains_key(&resource_id) {
-            return Err(ErrInvalidResourceId);
-        }
+        // the handlers get the device, so the registry is moved out while one runs
+        let mut extensions = std::mem::take(&mut self.extensions);
+        let result = extensions.handle(self, type_, &buf);
+        self.extensions = extensions;
+        result
+    }
 
-        // Import failed, fall back to a copy.
-        let mut display = self.display.borrow_mut();
-        // Prevent overwriting a buffer that is currently being used by the compositor.
-        if display.next_buffer_in_use(surface_id.clone()) {
-            return Ok(OkNoData);
+    /// Handles the command at `addr` that `VirtioGpuCommand::decode` rejected: runs its extension
+    /// if one is registered, otherwise the unknown command handler.
+    pub fn process_unknown_command(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+    ) -> VirtioGpuResponseResult {
+        if let Some(result) = self.process_extension(mem, addr) {
+            return result;
         }
+        let hdr: virtio_gpu_ctrl_hdr = mem.read_obj(addr)?;
+        self.extensions.handle_unknown(hdr, mem, addr)
+    }
 
-        let fb = display
-            .framebuffer_region(surface_id, 0, 0, self.display_width.clone(), self.display_height.clone())
-            .ok_or(ErrUnspec)?;
+    /// Bytes held by the resources whose size is known, i.e. the 2D resources.
+    pub fn resource_memory(&self) -> u64 {
+        self.resources.values().map(VirtioGpuResource::size).sum()
+    }
 
-        let mut transfer = Transfer3D::new_2d(0, 0, self.display_width.clone(), self.display_height.clone());
-        transfer.stride = fb.stride();
-        self.rutabaga
-            .transfer_read(0, resource_id, transfer, Some(fb.as_volatile_slice()))?;
-        display.flip(surface_id);
+    /// The virtio-gpu feature bits offered to the guest, see `GpuParameter::supported_features`.
+    pub fn supported_features(&self) -> u64 {
+        self.features
+    }
 
-        Ok(OkNoData)
+    /// Takes the features the driver acked, the ones the device didn't offer are left out.  Until
+    /// then, after creation as after a reset, the device behaves as if every feature offered was
+    /// acked.
+    pub fn ack_features(&mut self, features: u64) {
+        self.acked_features = features & self.features;
     }
 
-    /// flush resource screen
-    #[allow(unused_variables)]
-    pub fn cmd_flush_resource(&mut self, cmd: virtio_gpu_resource_flush) -> VirtioGpuResponseResult {
-        let resource_id = cmd.resource_id.to_native();
-        if resource_id == 0 {
-            return Ok(OkNoData);
-        }
+    pub fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
 
-        if let (Some(scanout_resource_id), Some(scanout_surface_id)) =
-            (self.scanout_resource_id, self.scanout_surface_id)
-        {
-            if scanout_resource_id.get() == cmd.resource_id.to_native() {
-                self.flush_resource_to_surface(resource_id, scanout_surface_id)?;
-            }
+    fn require_feature(&self, feature: u32) -> Result<(), VirtioGpuResponse> {
+        if self.acked_features & (1 << feature) != 0 {
+            Ok(())
+        } else {
+            Err(ErrUnspec)
         }
+    }
 
-        if let (Some(cursor_resource_id), Some(cursor_surface_id)) =
-            (self.cursor_resource_id, self.cursor_surface_id)
-        {
-            if cursor_resource_id.get() == resource_id {
-                self.flush_resource_to_surface(resource_id, cursor_surface_id)?;
-            }
-        }
+    /// Faults forced on the next commands, for testing error handling.
+    #[cfg(feature = "fault-injection")]
+    pub fn fault_injector(&mut self) -> &mut FaultInjector {
+        &mut self.faults
+    }
 
-        Ok(OkNoData)
+    #[cfg(feature = "fault-injection")]
+    fn inject_fault(&mut self, fault: Fault) -> bool {
+        self.faults.hit(fault)
     }
-    pub fn import_to_display(&mut self, resource_id: u32) -> Option<u32> { None }
 
+    #[cfg(not(feature = "fault-injection"))]
+    #[inline(always)]
+    fn inject_fault(&mut self, _fault: Fault) -> bool {
+        false
+    }
 
-    /// set the scanout surface
-    pub fn cmd_set_scanout(&mut self, cmd: virtio_gpu_set_scanout) -> VirtioGpuResponseResult {
-        let resource_id = cmd.resource_id.to_native();
-        let mut display = self.display.borrow_mut();
+    /// Gets the list of supported display resolutions as a slice of `(width, height)` tuples.
+    pub fn display_info(&self) -> &[(u32, u32)] {
+        &self.scanouts
+    }
 
-        if resource_id == 0 {
-            // TODO: if we implement the display protocol, try to use it
+    /// Pending `virtio_gpu_config` events, `VIRTIO_GPU_EVENT_DISPLAY` when the scanouts changed.
+    pub fn events_read(&self) -> u32 {
+        self.events_read
+    }
+
+    /// Acknowledges the events the guest wrote to `events_clear`.
+    pub fn clear_events(&mut self, events_clear: u32) {
+        self.events_read &= !events_clear;
+    }
+
+    /// Registers `callback`, called with the new config whenever an event is raised in
+    /// `events_read`.  The VMM sends the guest a configuration change notification from it.
+    pub fn on_config_change<F: FnMut(virtio_gpu_config) + 'static>(&mut self, callback: F) {
+        self.config_callback = Some(Box::new(callback));
+    }
+
+    /// Adds an input device of `kind` to the display window, whose events the embedder receives to
+    /// pass them on to the guest, see `crate::input`.
+    pub fn add_input_device(&mut self, kind: EventDeviceKind) -> io::Result<InputReceiver> {
+        let (display_end, embedder_end) = UnixStream::pair()?;
+        let receiver = InputReceiver::new(embedder_end)?;
+        self.display.send(DisplayRequest::AddInputDevice(EventDevice::new(kind, display_end)));
+        Ok(receiver)
+    }
+
+    fn raise_events(&mut self, events: u32) {
+        self.events_read |= events;
+        let config = self.config();
+        if let Some(ref mut callback) = self.config_callback {
+            callback(config);
+        }
+    }
+
+    /// Changes the scanouts to `scanouts`, `(0, 0)` for a disconnected one, for a resize or
+    /// hotplug decided by the VMM.  The scanouts keeping their size keep their EDID, the others
+    /// get generated ones.  The guest is told to re-query the display info through
+    /// `VIRTIO_GPU_EVENT_DISPLAY`.
+    pub fn set_scanouts(&mut self, scanouts: &[(u32, u32)]) -> Result<(), VirtioGpuResponse> {
+        let topology: Vec<ScanoutConfig> = scanouts
+            .iter()
+            .enumerate()
+            .map(|(index, &(width, height))| ScanoutConfig {
+                width,
+                height,
+                enabled: width != 0 && height != 0,
+                edid: match self.scanouts.get(index) {
+                    Some(&size) if size == (width, height) => self.scanout_edids.get(index).cloned().flatten(),
+                    _ => None,
+                },
+            })
+            .collect();
+        self.set_scanout_topology(&topology)
+    }
+
+    /// Plugs and unplugs scanouts while the guest runs, following the monitors of the VMM: the
+    /// scanouts become `topology`, the EDIDs given replacing the generated ones.  The guest is
+    /// told to re-query the display info and the EDIDs through `VIRTIO_GPU_EVENT_DISPLAY`.
+    pub fn set_scanout_topology(&mut self, topology: &[ScanoutConfig]) -> Result<(), VirtioGpuResponse> {
+        if topology.is_empty() || topology.len() > VIRTIO_GPU_MAX_SCANOUTS {
+            return Err(VirtioGpuResponse::TooManyScanout(topology.len()));
+        }
+        for scanout in topology {
+            if scanout.enabled && (scanout.width == 0 || scanout.height == 0) {
+                return Err(VirtioGpuResponse::ErrInvalidParameter);
+            }
+            if let Some(ref edid) = scanout.edid {
+                validate_edid(edid).map_err(|_| VirtioGpuResponse::ErrInvalidParameter)?;
+            }
+        }
+        let scanouts: Vec<(u32, u32)> = topology
+            .iter()
+            .map(|scanout| if scanout.enabled { (scanout.width, scanout.height) } else { (0, 0) })
+            .collect();
+        let edids: Vec<Option<Vec<u8>>> = topology.iter().map(|scanout| scanout.edid.clone()).collect();
+        if scanouts == self.scanouts && edids == self.scanout_edids {
+            return Ok(());
+        }
+        self.scanout_edids = edids;
+
+        if scanouts[0] != self.scanouts[0] {
+            // The host surfaces still have the old size, they are created again on the next
+            // SET_SCANOUT / UPDATE_CURSOR.
+            if let Some(surface_id) = self.cursor_surface_id.take() {
+                self.display.send(DisplayRequest::ReleaseSurface(surface_id));
+            }
             if let Some(surface_id) = self.scanout_surface_id.take() {
-                display.release_surface(surface_id);
+                self.display.send(DisplayRequest::ReleaseSurface(surface_id));
             }
-            self.scanout_resource_id = None;
-            return Ok(OkNoData);
+            self.display_width = scanouts[0].0;
+            self.display_height = scanouts[0].1;
         }
 
-         let resource = self
-             .resources
-             .get_mut(&resource_id)
-             .ok_or(ErrInvalidResourceId)?;
+        self.scanouts = scanouts;
+        self.raise_events(VIRTIO_GPU_EVENT_DISPLAY);
+        Ok(())
+    }
 
-        self.scanout_resource_id = NonZeroU32::new(resource_id);
-        if self.scanout_surface_id.is_none() {
-            let surface_id =
-                display.create_surface(None, self.display_width, self.display_height).map_err(VirtioGpuResponse::DisplayErr)?;
-            self.scanout_surface_id = Some(surface_id);
+    /// Resizes the first scanout to `width` x `height`, e.g. when the host window was resized,
+    /// the other scanouts are kept.  The guest re-queries the display info and sets a mode of the
+    /// new size.
+    pub fn set_display_size(&mut self, width: u32, height: u32) -> Result<(), VirtioGpuResponse> {
+        if width == 0 || height == 0 {
+            return Err(VirtioGpuResponse::ErrInvalidParameter);
         }
-        Ok(OkNoData)
+        if let Some((max_width, max_height)) = self.display.capabilities().max_surface_size {
+            if width > max_width || height > max_height {
+                return Err(VirtioGpuResponse::ErrInvalidParameter);
+            }
+        }
+        let mut scanouts = self.scanouts.clone();
+        scanouts[0] = (width, height);
+        self.set_scanouts(&scanouts)
     }
 
-    pub fn cmd_resource_attach_backing(
-        &mut self,
-        cmd: virtio_gpu_resource_attach_backing,
-        data: Vec<RutabagaIovec>
-    ) -> VirtioGpuResponseResult {
-        self.rutabaga.attach_backing(cmd.resource_id.to_native(), data)?;
-
-        Ok(OkNoData)
+    /// The current `virtio_gpu_config`.
+    pub fn config(&self) -> virtio_gpu_config {
+        virtio_gpu_config {
+            events_read:  Le32::from(self.events_read),
+            events_clear: Le32::from(0),
+            num_scanouts: Le32::from(self.scanouts.len() as u32),
+            num_capsets:  Le32::from(self.num_capsets()),
+        }
     }
 
-    pub fn cmd_resource_detach_backing(
-        &mut self,
-        cmd: virtio_gpu_resource_detach_backing
-    ) -> VirtioGpuResponseResult {
-        self.rutabaga.detach_backing(cmd.resource_id.to_native())?;
-        Ok(OkNoData)
+    /// Reads `size` bytes of the config space at `offset`, returns an empty vector when the
+    /// range is outside of `virtio_gpu_config`.
+    pub fn read_config(&self, offset: u32, size: u32) -> Vec<u8> {
+        let config = self.config();
+        let (offset, size) = (offset as usize, size as usize);
+        match offset.checked_add(size) {
+            Some(end) if end <= size_of::<virtio_gpu_config>() => config.as_slice()[offset..end].to_vec(),
+            _ => Vec::new(),
+        }
     }
 
-    pub fn cmd_ctx_attach_resource(
-        &mut self,
-        cmd: virtio_gpu_ctx_resource
-    ) -> VirtioGpuResponseResult {
-        self.rutabaga.context_attach_resource(cmd.hdr.ctx_id.to_native(), cmd.resource_id.to_native())?;
-        Ok(OkNoData)
+    /// Writes `data` to the config space at `offset`.  Only `events_clear` is writable, writing
+    /// it acknowledges the events set in `events_read`.
+    pub fn write_config(&mut self, offset: u32, data: &[u8]) -> io::Result<()> {
+        let mut config = self.config();
+        let offset = offset as usize;
+        let end = offset.checked_add(data.len())
+            .filter(|&end| end <= size_of::<virtio_gpu_config>())
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+        let original = config;
+        config.as_mut_slice()[offset..end].copy_from_slice(data);
+        if config.events_read.to_native() != original.events_read.to_native()
+            || config.num_scanouts.to_native() != original.num_scanouts.to_native()
+            || config.num_capsets.to_native() != original.num_capsets.to_native() {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+
+        self.clear_events(config.events_clear.to_native());
+        Ok(())
     }
 
-    pub fn cmd_ctx_detach_resource(
-        &mut self,
-        cmd: virtio_gpu_ctx_resource
-    ) -> VirtioGpuResponseResult {
-        self.rutabaga.context_detach_resource(cmd.hdr.ctx_id.to_native(), cmd.resource_id.to_native())?;
-        Ok(OkNoData)
+    /// Handles the events sent by the display thread, returns true once the user asked to close
+    /// the scanout window.
+    pub fn process_display(&mut self) -> bool {
+        for event in self.display.try_events() {
+            match event {
+                DisplayEvent::OutputsChanged(outputs) => {
+                    if self.follow_host_outputs {
+                        self.handle_host_outputs(&outputs);
+                    }
+                }
+                DisplayEvent::CloseRequested(surface_id) => {
+                    if self.scanout_surface_id == Some(surface_id) {
+                        self.close_requested = true;
+                    }
+                }
+                DisplayEvent::ConnectionLost => {
+                    // the surfaces went away with the display server
+                    self.scanout_surface_id = None;
+                    self.cursor_surface_id = None;
+                    self.flush_interval = None;
+                }
+                DisplayEvent::Reconnected(_) => self.restore_surfaces(),
+            }
+        }
+        self.close_requested
     }
 
-    pub fn cmd_submit_3d(
-        &mut self,
-        cmd: virtio_gpu_cmd_submit,
-        data: &mut [u8]
-    ) -> VirtioGpuResponseResult {
-        self.rutabaga.submit_command(cmd.hdr.ctx_id.to_native(), data)?;
-        Ok(OkNoData)
+    /// Creates the scanout and cursor surfaces again from the device state and shows the current
+    /// contents on them, after the display was reopened.
+    fn restore_surfaces(&mut self) {
+        if let Some(resource_id) = self.scanout_resource_id {
+            let (x, y, width, height) = self.scanout_rect;
+            let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+            set_scanout.resource_id = Le32::from(resource_id.get());
+            set_scanout.r.x = Le32::from(x);
+            set_scanout.r.y = Le32::from(y);
+            set_scanout.r.width = Le32::from(width);
+            set_scanout.r.height = Le32::from(height);
+            if self.cmd_set_scanout(set_scanout).is_ok() {
+                if let Some(surface_id) = self.scanout_surface_id {
+                    // the new surface has none of the old contents
+                    let _ = self.present(resource_id.get(), surface_id, (x, y, width, height));
+                }
+            }
+        }
+        if let Some(resource_id) = self.cursor_resource_id {
+            let (x, y) = self.cursor_position;
+            let (hot_x, hot_y) = self.cursor_hotspot;
+            let mut update_cursor: virtio_gpu_update_cursor = Default::default();
+            update_cursor.resource_id = Le32::from(resource_id.get());
+            update_cursor.pos.x = Le32::from(x);
+            update_cursor.pos.y = Le32::from(y);
+            update_cursor.hot_x = Le32::from(hot_x);
+            update_cursor.hot_y = Le32::from(hot_y);
+            let _ = self.cmd_update_cursor(update_cursor);
+        }
     }
 
-    pub fn cmd_transfer_to_host_2d(
-        &mut self,
-        cmd: virtio_gpu_transfer_to_host_2d
-    ) -> VirtioGpuResponseResult {
-        let resource_id = cmd.resource_id.to_native();
-        let transfer = Transfer3D::new_2d(
-            cmd.r.x.to_native(),
-            cmd.r.y.to_native(),
-            cmd.r.width.to_native(),
-            cmd.r.height.to_native()
-        );
+    /// Deadline of the next frame, if there is anything waiting for it.
+    pub fn next_frame(&self) -> Option<Instant> {
+        let frame_interval = self.frame_interval?;
+        if self.cursor_pending || self.flush_pending.is_some() {
+            Some(self.last_frame + frame_interval)
+        } else {
+            None
+        }
+    }
 
-        self.rutabaga.transfer_write(cmd.hdr.ctx_id.to_native(), resource_id, transfer)?;
-        Ok(OkNoData)
+    /// The descriptors to wait on for the device to make progress without the caller polling,
+    /// each readable descriptor is handled with `handle_event`.  The renderer only has a fence
+    /// descriptor when it runs its own sync thread.
+    pub fn event_sources(&self) -> Vec<(GpuEventSource, RawFd)> {
+        let mut sources = vec![(GpuEventSource::Display, self.display.events_fd())];
+        if let Some(fd) = self.rutabaga.poll_descriptor() {
+            sources.push((GpuEventSource::Fences, fd));
+        }
+        if let Some(ref frame_timer) = self.frame_timer {
+            sources.push((GpuEventSource::FrameTimer, frame_timer.as_raw_fd()));
+        }
+        sources
     }
 
+    /// Handles the readable descriptor of `source`, without blocking.
+    pub fn handle_event(&mut self, source: GpuEventSource) {
+        match source {
+            GpuEventSource::Display => {
+                self.process_display();
+            }
+            GpuEventSource::Fences => {
+                self.process_fences();
+            }
+            GpuEventSource::FrameTimer => self.handle_frame_timer(),
+        }
+    }
 
-    pub fn cmd_transfer_to_host_3d(
-        &mut self,
-        cmd: virtio_gpu_transfer_host_3d
-    ) -> VirtioGpuResponseResult {
-        let resource_id = cmd.resource_id.to_native();
-        let transfer = transfer_host_3d_to_transfer_3d(cmd);
-        self.rutabaga.transfer_write(cmd.hdr.ctx_id.to_native(), resource_id, transfer)?;
-        Ok(OkNoData)
+    /// Acknowledges the expired frame timer and runs the frame.
+    pub fn handle_frame_timer(&mut self) {
+        if let Some(ref mut frame_timer) = self.frame_timer {
+            // fails with EAGAIN when the timer didn't expire
+            let _ = frame_timer.wait();
+        }
+        self.process_frame();
     }
 
-    pub fn cmd_resource_assign_uuid(&self, cmd: virtio_gpu_resource_assign_uuid) -> VirtioGpuResponseResult {
-        let resource_id = cmd.resource_id.to_native();
-        if !self.resources.contains_key(&resource_id) {
-            return Err(ErrInvalidResourceId);
+    fn arm_frame_timer(&mut self) {
+        if let (Some(deadline), Some(frame_timer)) = (self.next_frame(), self.frame_timer.as_mut()) {
+            // a zero duration would disarm the timer
+            let timeout = max(deadline.saturating_duration_since(Instant::now()), Duration::from_nanos(1));
+            let _ = frame_timer.reset(timeout, None);
         }
+    }
 
-        let mut uuid: [u8; 16] = [0; 16];
-        for (idx, byte) in resource_id.to_be_bytes().iter().enumerate() {
-            uuid[12 + idx] = *byte;
+    /// Called by the frame pacing timer: presents the scanout flushed since the last frame and
+    /// commits the cursor moves batched meanwhile.
+    pub fn process_frame(&mut self) {
+        if let Some(damage) = self.flush_pending.take() {
+            if let (Some(resource_id), Some(surface_id)) = (self.scanout_resource_id, self.scanout_surface_id) {
+                // nobody is left to report a failure to
+                let _ = self.present(resource_id.get(), surface_id, damage);
+            }
         }
-        Ok(OkResourceUuid { uuid })
+        if self.cursor_pending {
+            self.cursor_pending = false;
+            self.commit_cursor_position();
+        }
+        self.last_frame = Instant::now();
     }
 
-    #[allow(unused_variablesb)]
-    pub fn cmd_transfer_from_host_3d(
-        &mut self,
-        cmd: virtio_gpu_transfer_host_3d,
-        buf: Option<VolatileSlice>
-    ) -> VirtioGpuResponseResult {
-        let resource_id = cmd.resource_id.to_native();
-        let transfer = transfer_host_3d_to_transfer_3d(cmd);
-        self.rutabaga.transfer_read(cmd.hdr.ctx_id.to_native(), resource_id, transfer, None)?;
-        Ok(OkNoData)
+    /// Where the cursor surface goes on the scanout for its hotspot to be at the pointer.  The
+    /// displays place surfaces from the top left corner of the scanout, a hotspot hanging over
+    /// it pins the image there.
+    fn cursor_surface_position(&self) -> (u32, u32) {
+        let (x, y) = self.cursor_position;
+        let (hot_x, hot_y) = self.cursor_hotspot;
+        (x.saturating_sub(hot_x), y.saturating_sub(hot_y))
     }
 
-    /// TODO: not implement, just return OkNoData
-    pub fn cmd_move_curosr(
-        &mut self,
-        cmd: virtio_gpu_update_cursor
-    ) -> VirtioGpuResponseResult {
-        let x = cmd.pos.x.to_native();
-        let y = cmd.pos.y.to_native();
-        if let Some(cursor_surface_id) = self.cursor_surface_id {
+    fn commit_cursor_position(&mut self) {
+        let (x, y) = self.cursor_surface_position();
+        let cursor_surface_id = match self.cursor_surface_id {
+            Some(surface_id) => surface_id,
+            None => return,
+        };
+        if self.display.capabilities().cursor_plane {
+            self.display.send(DisplayRequest::MoveSurface {
+                surface_id: cursor_surface_id,
+                parent: self.scanout_surface_id,
+                x,
+                y,
+            });
+        } else {
+            // the new position shows up with the next commit of the scanout
+            self.display.send(DisplayRequest::SetPosition { surface_id: cursor_surface_id, x, y });
             if let Some(scanout_surface_id) = self.scanout_surface_id {
-                let mut display = self.display.borrow_mut();
-                display.set_position(cursor_surface_id, x, y);
-                display.commit(scanout_surface_id);
+                self.display.send(DisplayRequest::Commit(scanout_surface_id));
             }
         }
-        Ok(OkNoData)
     }
 
-    #[allow(unused_variables)]
-    pub fn cmd_update_cursor(
-        &mut self,
-        cmd: virtio_gpu_update_cursor
-    ) -> VirtioGpuResponseResult {
-        let resource_id = cmd.resource_id.to_native();
-        let y = cmd.pos.y.to_native();
-        let x = cmd.pos.x.to_native();
-        if resource_id == 0 {
-            if let Some(surface_id) = self.cursor_surface_id.take() {
-                self.display.borrow_mut().release_surface(surface_id);
-            }
-            self.cursor_resource_id = None;
-            return Ok(OkNoData);
+    /// Maps the host monitors on the scanouts: the n-th host output drives the n-th scanout and
+    /// scanouts whose monitor went away are disabled.  The guest is told to re-query the display
+    /// info through `VIRTIO_GPU_EVENT_DISPLAY`.
+    fn handle_host_outputs(&mut self, outputs: &[GpuDisplayOutput]) {
+        let num_scanouts = max(self.scanouts.len(), outputs.len()).min(VIRTIO_GPU_MAX_SCANOUTS);
+        let mut scanouts = vec![(0, 0); num_scanouts];
+        for (scanout, output) in scanouts.iter_mut().zip(outputs) {
+            *scanout = (output.width, output.height);
         }
+        // the count is within bounds
+        let _ = self.set_scanouts(&scanouts);
+    }
 
-        let (resource_width, resource_height) = self
-            .resources
-            .get_mut(&resource_id)
-            .ok_or(ErrInvalidResourceId)?
-            .dimensions();
-
-        self.cursor_resource_id = NonZeroU32::new(resource_id);
-
-        if self.cursor_surface_id.is_none() {
-            self.cursor_surface_id = Some(self.display.borrow_mut().create_surface(
-                self.scanout_surface_id,
-                resource_width,
-                resource_height,
-            ).map_err(VirtioGpuResponse::DisplayErr)?);
+    fn resource_create_3d(&mut self, resource_id: u32, resource_create_3d: ResourceCreate3D, size: u64) -> VirtioGpuResponseResult {
+        if self.inject_fault(Fault::RendererOom) {
+            return Err(VirtioGpuResponse::ErrOutOfMemory);
+        }
+        // the id is taken again with another layout
+        if self.take_pooled_resource(resource_id).is_some() {
+            let _ = self.rutabaga.unref_resource(resource_id);
         }
+        self.rutabaga
+            .resource_create_3d(resource_id, resource_create_3d)?;
 
-        let cursor_surface_id = self.cursor_surface_id.unwrap();
-        self.display
-            .borrow_mut()
-            .set_position(cursor_surface_id, x, y);
+        let resource = VirtioGpuResource::new(
+            resource_id,
+            resource_create_3d.width,
+            resource_create_3d.height,
+            resource_create_3d.format,
+            size,
+        );
 
-        // Gets the resource's pixels into the display by importing the buffer.
-        if let Some(import_id) = self.import_to_display(resource_id) {
-            self.display
-                .borrow_mut()
-                .flip_to(cursor_surface_id, import_id);
-            return Ok(OkNoData);
-        }
+        self.resources.insert(resource_id, resource);
 
-        // Importing failed, so try copying the pixels into the surface's slower shared memory
-        // framebuffer.
-        if let Some(fb) = self.display.borrow_mut().framebuffer(cursor_surface_id) {
-            let mut transfer = Transfer3D::new_2d(0, 0, resource_width, resource_height);
-            transfer.stride = fb.stride();
-            self.rutabaga
-                .transfer_read(0, resource_id, transfer, Some(fb.as_volatile_slice()))?;
+        match self.rutabaga.query(resource_id) {
+            Ok(_) => Ok(VirtioGpuResponse::ErrInvalidResourceId),
+            Err(_) => Ok(OkNoData)
         }
-        self.display.borrow_mut().flip(cursor_surface_id);
-        Ok(OkNoData)
     }
 
-    /// poll the fenced data
-    #[allow(unused_variables)]
-    pub fn fence_poll(&mut self) -> Vec<RutabagaFenceData> {
-        self.rutabaga.poll()
+    pub fn cmd_get_display_info(&mut self, cmd: virtio_gpu_ctrl_hdr) -> VirtioGpuResponseResult {
+        Ok(OkDisplayInfo(self.scanouts.clone()))
     }
 
-    pub fn force_ctx_0(&mut self) {
-        self.rutabaga.force_ctx_0()
+    fn take_pooled_resource(&mut self, resource_id: u32) -> Option<VirtioGpuResource> {
+        let index = self
+            .resource_pool
+            .iter()
+            .position(|resource| resource.resource_id == resource_id)?;
+        self.resource_pool.remove(index)
     }
 
-    /// create fence for ctx
-    pub fn create_fence(&mut self, request_fence_data: RutabagaFenceData) -> VirtioGpuResponseResult {
-        self.rutabaga.create_fence(request_fence_data)?;
+    /// Fails for the id of a resource the guest unreffed into the pool.  The renderer still has
+    /// it, but to the guest it is gone.
+    fn check_not_pooled(&self, resource_id: u32) -> Result<(), VirtioGpuResponse> {
+        if self.resource_pool.iter().any(|resource| resource.resource_id == resource_id) {
+            return Err(ErrInvalidResourceId);
+        }
+        Ok(())
+    }
+
+    /// Zeroes the contents the renderer keeps for 2D resource `resource`.
+    fn clear_resource(&mut self, resource: &VirtioGpuResource) -> VirtioGpuResponseResult {
+        let (width, height) = resource.dimensions();
+        let mut zeroes = vec![0u8; resource.size() as usize];
+        let iovec = RutabagaIovec {
+            base: zeroes.as_mut_ptr() as *mut c_void,
+            len:  zeroes.len(),
+        };
+        self.rutabaga.attach_backing(resource.resource_id, vec![iovec])?;
+        let written = self.rutabaga.transfer_write(0, resource.resource_id, Transfer3D::new_2d(0, 0, width, height));
+        self.rutabaga.detach_backing(resource.resource_id)?;
+        written?;
         Ok(OkNoData)
     }
-}
 
+    pub fn cmd_resource_create_2d(&mut self, cmd: virtio_gpu_resource_create_2d) -> VirtioGpuResponseResult {
+        let size = resource_2d_size(cmd.format.to_native(), cmd.width.to_native(), cmd.height.to_native())
+            .ok_or(VirtioGpuResponse::ErrInvalidParameter)?;
+        // Guests hand out the lowest free id, so a mode change destroys and creates resources of
+        // the same id and layout.
+        let resource_id = cmd.resource_id.to_native();
+        if self.quirks.check_resource_ids && (resource_id == 0 || self.resources.contains_key(&resource_id)) {
+            return Err(ErrInvalidResourceId);
+        }
+        let reusable = self.resource_pool.iter().any(|resource| {
+            resource.resource_id == resource_id
+                && resource.format() == cmd.format.to_native()
+                && resource.dimensions() == (cmd.width.to_native(), cmd.height.to_native())
+        });
+        if reusable {
+            let mut resource = self.take_pooled_resource(resource_id).unwrap();
+            // a new resource doesn't inherit the contents of the unreffed one
+            resource.backing_detached = false;
+            resource.backing = None;
+            if let Err(e) = self.clear_resource(&resource) {
+                let _ = self.rutabaga.unref_resource(resource_id);
+                return Err(e);
+            }
+            self.resources.insert(resource_id, resource);
+            return Ok(OkNoData);
+        }
 
-#[cfg(test)]
-pub(crate) mod tests {
-    use crate::virtio_gpu::GpuParameter;
-    use crate::VirtioGpu;
-    use gpu_display::GpuDisplay;
+        let resource_create_3d = ResourceCreate3D {
+            target: RUTABAGA_PIPE_TEXTURE_2D,
+            format: cmd.format.to_native(),
+            bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+            width: cmd.width.to_native(),
+            height: cmd.height.to_native(),
+            depth: 1,
+            array_size: 1,
+            last_level: 0,
+            nr_samples: 0,
+            flags: 0,
+        };
+        self.resource_create_3d(cmd.resource_id.to_native(), resource_create_3d, size)
+    }
 
-    #[test]
-    fn test_new_virtio_gpu() {
-        let gpu_parameter: GpuParameter = Default::default();
-        let virtio_gpu = VirtioGpu::new(gpu_parameter).map_err(|e| {
-                panic!("Gpu: create new virtio gpu failed, err: {:?}", e);
-                e
-            }).unwrap();
+    pub fn cmd_resource_create_3d(&mut self, cmd: virtio_gpu_resource_create_3d) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_VIRGL)?;
+        let resource_create_3d = ResourceCreate3D {
+            target: cmd.target.to_native(),
+            format: cmd.format.to_native(),
+            bind: cmd.bind.to_native(),
+            width: cmd.width.to_native(),
+            height: cmd.height.to_native(),
+            depth: cmd.depth.to_native(),
+            array_size: cmd.array_size.to_native(),
+            last_level: cmd.last_level.to_native(),
+            nr_samples: cmd.nr_samples.to_native(),
+            flags: cmd.flags.to_native(),
+        };
+        // the layout of 3D resources is chosen by the renderer
+        self.resource_create_3d(cmd.resource_id.to_native(), resource_create_3d, 0)
     }
-}
 
+    /// Creates a blob resource.  `iovecs` are the `nr_entries` memory entries following the
+    /// command, the guest backing of BLOB_MEM_GUEST and BLOB_MEM_HOST3D_GUEST blobs.
+    pub fn cmd_resource_create_blob(
+        &mut self,
+        cmd: virtio_gpu_resource_create_blob,
+        iovecs: Vec<RutabagaIovec>,
+    ) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_RESOURCE_BLOB)?;
+        let resource_id = cmd.resource_id.to_native();
+        if resource_id == 0 {
+            return Err(ErrInvalidResourceId);
+        }
+        match cmd.blob_mem.to_native() {
+            VIRTIO_GPU_BLOB_MEM_GUEST | VIRTIO_GPU_BLOB_MEM_HOST3D_GUEST => {}
+            // only lives on the host
+            VIRTIO_GPU_BLOB_MEM_HOST3D if iovecs.is_empty() => {}
+            _ => return Err(VirtioGpuResponse::ErrInvalidParameter),
+        }
 
+        let resource_create_blob = ResourceCreateBlob {
+            blob_mem: cmd.blob_mem.to_native(),
+            blob_flags: cmd.blob_flags.to_native(),
+            blob_id: cmd.blob_id.to_native(),
+            size: cmd.size.to_native(),
+        };
+        let backing = iovec_ranges(&iovecs);
+        self.rutabaga
+            .resource_create_blob(cmd.hdr.ctx_id.to_native(), resource_id, resource_create_blob, iovecs)?;
+        // blobs have no format, and no layout that could be pooled
+        let mut resource = VirtioGpuResource::new(resource_id, 0, 0, 0, 0);
+        resource.blob_size = cmd.size.to_native();
+        if !backing.is_empty() {
+            resource.backing = Some(backing);
+        }
+        self.resources.insert(resource_id, resource);
+        Ok(OkNoData)
+    }
+
+    /// Maps a blob at `cmd.offset` of the host visible region and answers with the caching the
+    /// guest has to map it with.  The renderer's export of the blob
+    /// is handed to the VMM when it can be mmapped, its own mapping of the blob otherwise.
+    pub fn cmd_resource_map_blob(&mut self, cmd: virtio_gpu_resource_map_blob) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_RESOURCE_BLOB)?;
+        let resource_id = cmd.resource_id.to_native();
+        let offset = cmd.offset.to_native();
+        let size = self
+            .resources
+            .get(&resource_id)
+            .map(|resource| resource.blob_size)
+            .ok_or(ErrInvalidResourceId)?;
+        if self.blob_mappings.contains_key(&resource_id) {
+            return Err(VirtioGpuResponse::ErrInvalidParameter);
+        }
+        if self.shm_mapper.is_none() {
+            return Err(ErrUnspec);
+        }
+        self.shm_region
+            .reserve(offset, size)
+            .map_err(|_| VirtioGpuResponse::ErrInvalidParameter)?;
+
+        let result = self.map_blob_at(resource_id, offset, size);
+        if result.is_err() {
+            self.shm_region.release(offset);
+        }
+        result
+    }
+
+    fn map_blob_at(&mut self, resource_id: u32, offset: u64, size: u64) -> VirtioGpuResponseResult {
+        let map_info = self.rutabaga.map_info(resource_id)?;
+        let memory = match self.rutabaga.export_blob(resource_id) {
+            // opaque fds can't be mmapped
+            Ok(handle) if handle.handle_type != RUTABAGA_MEM_HANDLE_TYPE_OPAQUE_FD => BlobMemory::Handle(handle),
+            _ => BlobMemory::Mapping(self.rutabaga.map(resource_id)?),
+        };
+        if let Some(ref mut mapper) = self.shm_mapper {
+            mapper.add_mapping(&memory, offset, size).map_err(|_| ErrUnspec)?;
+        }
+        self.blob_mappings.insert(resource_id, BlobMapping { offset, memory });
+        Ok(VirtioGpuResponse::OkMapInfo { map_info })
+    }
+
+    pub fn cmd_resource_unmap_blob(&mut self, cmd: virtio_gpu_resource_unmap_blob) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_RESOURCE_BLOB)?;
+        self.unmap_blob(cmd.resource_id.to_native())
+    }
+
+    /// Removes the mapping of the blob from the host visible region.  The blob stays mapped, and
+    /// its range reserved, when the mapper fails to remove it.
+    fn unmap_blob(&mut self, resource_id: u32) -> VirtioGpuResponseResult {
+        let offset = self
+            .blob_mappings
+            .get(&resource_id)
+            .ok_or(VirtioGpuResponse::ErrInvalidParameter)?
+            .offset;
+        if let Some(ref mut mapper) = self.shm_mapper {
+            mapper.remove_mapping(offset).map_err(|_| ErrUnspec)?;
+        }
+        self.blob_mappings.remove(&resource_id);
+        self.shm_region.release(offset);
+        Ok(OkNoData)
+    }
+
+    pub fn cmd_resource_unref(&mut self, cmd: virtio_gpu_resource_unref) -> VirtioGpuResponseResult {
+        let resource_id = cmd.resource_id.to_native();
+        // the guest should have unmapped it, the renderer can't free a mapped blob
+        if self.blob_mappings.contains_key(&resource_id) {
+            self.unmap_blob(resource_id)?;
+        }
+        if !self.resources.contains_key(&resource_id) {
+            return Err(ErrInvalidResourceId);
+        }
+        if self.forwarded_resource_id == Some(resource_id) {
+            self.stop_forwarding();
+        }
+        let resource = &self.resources[&resource_id];
+        // only 2D resources have a layout that can be matched on create, and contexts may hold on
+        // to the resources attached to them.  The resource is only forgotten once the renderer
+        // let go of its backing.
+        if self.resource_pool_size == 0 || resource.size() == 0 || resource.in_context {
+            self.rutabaga.unref_resource(resource_id)?;
+            self.resources.remove(&resource_id);
+            return Ok(OkNoData);
+        }
+
+        self.rutabaga.detach_backing(resource_id)?;
+        let mut resource = self.resources.remove(&resource_id).unwrap();
+        resource.backing = None;
+        self.resource_pool.push_back(resource);
+        if self.resource_pool.len() > self.resource_pool_size {
+            if let Some(evicted) = self.resource_pool.pop_front() {
+                self.rutabaga.unref_resource(evicted.resource_id)?;
+            }
+        }
+        Ok(OkNoData)
+    }
+
+    pub fn cmd_context_create(&mut self, cmd: virtio_gpu_ctx_create) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_VIRGL)?;
+        let ctx_id = cmd.hdr.ctx_id.to_native();
+        let name = cmd.debug_name();
+        // the renderer's default context type without context init
+        let context_init = cmd.context_init.to_native();
+        if context_init != 0 {
+            self.require_feature(VIRTIO_GPU_F_CONTEXT_INIT)?;
+            let capset_id = context_init & VIRTIO_GPU_CONTEXT_INIT_CAPSET_ID_MASK;
+            if !self.capsets.iter().any(|&(id, _, _)| id == capset_id) {
+                return Err(VirtioGpuResponse::ErrInvalidParameter);
+            }
+        }
+        self.rutabaga.create_context(ctx_id, context_init, name)?;
+        self.contexts.insert(ctx_id);
+        if let Some(name) = name {
+            self.context_names.insert(ctx_id, name.to_string());
+        }
+        Ok(OkNoData)
+    }
+
+    pub fn cmd_context_destroy(&mut self, cmd: virtio_gpu_ctx_destroy) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_VIRGL)?;
+        let ctx_id = cmd.hdr.ctx_id.to_native();
+        self.rutabaga.destroy_context(ctx_id)?;
+        self.contexts.remove(&ctx_id);
+        self.context_names.remove(&ctx_id);
+        // the rings go with the context, its fences will never signal
+        self.signalled_fences.retain(|&(ring_ctx_id, _), _| ring_ctx_id != ctx_id);
+        self.pending_fences.retain(|&(ring_ctx_id, _), _| ring_ctx_id != ctx_id);
+        Ok(OkNoData)
+    }
+
+    /// The name the guest gave context `ctx_id`, usually the process it renders for.
+    pub fn context_name(&self, ctx_id: u32) -> Option<&str> {
+        self.context_names.get(&ctx_id).map(String::as_str)
+    }
+
+    pub fn cmd_get_edid(&mut self, cmd: virtio_gpu_cmd_get_edid) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_EDID)?;
+        let scanout = cmd.scanout.to_native() as usize;
+        let (width, height) = match self.scanouts.get(scanout) {
+            // a disabled scanout offers the mode of the primary one, to be enabled with
+            Some(&(0, 0)) => (self.display_width, self.display_height),
+            Some(&size) => size,
+            None => return Err(VirtioGpuResponse::ErrInvalidScanoutId),
+        };
+        let edid_vec = match (self.scanout_edids.get(scanout), &self.edid) {
+            (Some(Some(edid)), _) => edid.clone(),
+            // the configured EDID is the one of the primary scanout
+            (_, Some(edid)) if scanout == 0 => edid.clone(),
+            _ => {
+                let info = EdidInfo::new(width, height, self.refresh_rate)
+                    .map_err(|_| VirtioGpuResponse::ErrInvalidParameter)?
+                    .with_serial(scanout as u32 + 1);
+                match self.adaptive_sync {
+                    Some((min, max)) => info.with_adaptive_sync(min, max).generate(),
+                    None => info.generate(),
+                }
+            }
+        };
+        let mut edid = [0u8; 1024];
+        edid[..edid_vec.len()].copy_from_slice(&edid_vec);
+        Ok(OkEdid {
+            size: edid_vec.len() as u32,
+            edid
+        })
+    }
+
+    pub fn cmd_get_capset_info(&mut self, cmd: virtio_gpu_get_capset_info) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_VIRGL)?;
+        let (capset_id, version, size) = *self
+            .capsets
+            .get(cmd.capset_index.to_native() as usize)
+            .ok_or(VirtioGpuResponse::ErrInvalidParameter)?;
+        Ok(OkCapsetInfo {
+            capset_id,
+            version,
+            size
+        })
+    }
+
+    /// get rubataga capaset
+    pub fn cmd_get_capset(&mut self, cmd: virtio_gpu_get_capset) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_VIRGL)?;
+        let (capset_id, version) = (cmd.capset_id.to_native(), cmd.capset_version.to_native());
+        match self.capsets.iter().find(|&&(id, _, _)| id == capset_id) {
+            Some(&(_, max_version, _)) if version <= max_version => {}
+            _ => return Err(VirtioGpuResponse::ErrInvalidParameter),
+        }
+        let mut capset = self.rutabaga.get_capset(capset_id, version)?;
+        apply_capset_masks(capset_id, &mut capset, &self.capset_masks);
+        Ok(OkCapset(capset))
+    }
+
+    /// Attempts to import the given resource into the display, otherwise falls back to rutabaga
+    /// copies.
+    pub fn flush_resource_to_surface(
+        &mut self,
+        resource_id: u32,
+        surface_id: u32,
+    ) -> VirtioGpuResponseResult {
+        if self.inject_fault(Fault::DisplayFlip) {
+            return Err(ErrUnspec);
+        }
+        if self.display.capabilities().dmabuf_import {
+            if let Some(import_id) = self.import_to_display(resource_id) {
+                self.display.send(DisplayRequest::FlipTo { surface_id, import_id });
+                return Ok(OkNoData);
+            }
+        }
+
+        if !self.resources.contains_key(&resource_id) {
+            return Err(ErrInvalidResourceId);
+        }
+
+        // Import failed, fall back to a copy.
+        let alpha = self.scanout_alpha(resource_id);
+        self.copy_to_surface(resource_id, surface_id, self.display_width, self.display_height, alpha)
+    }
+
+    /// The alpha byte to make opaque in the pixels of `resource_id` when it is scanned out, see
+    /// `Quirks::opaque_formats`.
+    fn scanout_alpha(&self, resource_id: u32) -> Option<usize> {
+        let resource = self.resources.get(&resource_id)?;
+        self.quirks.scanout_alpha(resource.format())
+    }
+
+    /// Has the VMM scan `resource_id` out through the scanout forwarder, returns whether it does.
+    /// The resource is exported on its first flush, the VMM is only told of `damage` on the next
+    /// ones.  Nothing is forwarded when the renderer can't export the resource as a dmabuf or
+    /// the VMM fails to take it, the device's surface takes over again.
+    fn forward_scanout(&mut self, resource_id: u32, damage: DamageRect) -> bool {
+        if self.scanout_forwarder.is_none() || !self.resources.contains_key(&resource_id) {
+            return false;
+        }
+        let exported = if self.forwarded_resource_id != Some(resource_id) {
+            let exported = self.rutabaga.query(resource_id).and_then(|metadata| {
+                let dmabuf = self.rutabaga.export_blob(resource_id)?;
+                Ok((metadata, dmabuf))
+            });
+            match exported {
+                Ok((metadata, dmabuf)) if dmabuf.handle_type == RUTABAGA_MEM_HANDLE_TYPE_DMABUF => {
+                    let scanout = DmabufScanout {
+                        width:      metadata.width,
+                        height:     metadata.height,
+                        stride:     metadata.strides[0],
+                        drm_fourcc: metadata.drm_fourcc,
+                        modifier:   metadata.modifier,
+                    };
+                    Some((scanout, dmabuf))
+                }
+                _ => {
+                    self.stop_forwarding();
+                    return false;
+                }
+            }
+        } else {
+            None
+        };
+        self.forward_exported(resource_id, exported, damage)
+    }
+
+    /// Has the forwarder scan out the dmabuf `resource_id` was `exported` as, if it isn't already,
+    /// then flushes `damage` of it.
+    fn forward_exported(
+        &mut self,
+        resource_id: u32,
+        exported: Option<(DmabufScanout, RutabagaHandle)>,
+        damage: DamageRect,
+    ) -> bool {
+        let forwarder = match self.scanout_forwarder {
+            Some(ref mut forwarder) => forwarder,
+            None => return false,
+        };
+        if let Some((scanout, dmabuf)) = exported {
+            if forwarder.set_scanout(0, &scanout, &dmabuf).is_err() {
+                self.stop_forwarding();
+                return false;
+            }
+            self.forwarded_resource_id = Some(resource_id);
+        }
+        if forwarder.flush(0, damage).is_err() {
+            self.stop_forwarding();
+            return false;
+        }
+        true
+    }
+
+    /// Takes the scanout back from the VMM, if it was forwarded.
+    fn stop_forwarding(&mut self) {
+        if self.forwarded_resource_id.take().is_some() {
+            if let Some(ref mut forwarder) = self.scanout_forwarder {
+                // the device presents the scanout again either way
+                let _ = forwarder.disable_scanout(0);
+            }
+        }
+    }
+
+    /// Flushes the scanout resource to its surface.  2D resources of the 2D renderer only change
+    /// through transfers, so only the areas transferred since the last flush are read back and
+    /// sent to the display, and nothing is when there are none.
+    fn flush_scanout(&mut self, resource_id: u32, surface_id: u32, damage: DamageRect) -> VirtioGpuResponseResult {
+        if self.forward_scanout(resource_id, damage) {
+            return Ok(OkNoData);
+        }
+        // nobody would look at the pixels, the frame counts as presented all the same
+        if self.display.capabilities().headless {
+            self.scanout_damage.take();
+            return Ok(OkNoData);
+        }
+        let partial = self.acked_features & (1 << VIRTIO_GPU_F_VIRGL) == 0
+            && !self.display.capabilities().dmabuf_import
+            && match self.resources.get(&resource_id) {
+                Some(resource) => {
+                    let (width, height) = resource.dimensions();
+                    width >= self.display_width
+                        && height >= self.display_height
+                        && format_layout(resource.format(), 1, 1)
+                            .map_or(false, |layout| !layout.is_multi_planar())
+                }
+                None => false,
+            };
+        let rects = match self.scanout_damage.take() {
+            Some(rects) if partial => rects,
+            _ => return self.flush_resource_to_surface(resource_id, surface_id),
+        };
+        if self.inject_fault(Fault::DisplayFlip) {
+            return Err(ErrUnspec);
+        }
+        if rects.is_empty() {
+            return Ok(OkNoData);
+        }
+
+        let alpha = self.scanout_alpha(resource_id);
+        let mut regions = Vec::with_capacity(rects.len());
+        for (x, y, width, height) in rects {
+            // The rect is read at its position in rows of its own width, only the part of the
+            // buffer from its first pixel on is written.
+            let stride = width * 4;
+            let start = y as usize * stride as usize + x as usize * 4;
+            let mut pixels = self.staging.take(start + stride as usize * height as usize);
+            let mut transfer = Transfer3D::new_2d(x, y, width, height);
+            transfer.stride = stride;
+            self.rutabaga.transfer_read(
+                0,
+                resource_id,
+                transfer,
+                Some(data_model::VolatileSlice::new(&mut pixels)),
+            )?;
+            let mut pixels = pixels.skip(start);
+            if let Some(alpha) = alpha {
+                make_opaque(&mut pixels, alpha);
+            }
+            regions.push(FlushRegion {
+                x,
+                y,
+                width,
+                height,
+                pixels,
+            });
+        }
+
+        self.display.send(DisplayRequest::FlushRegions {
+            surface_id,
+            width: self.display_width,
+            height: self.display_height,
+            regions,
+        });
+        Ok(OkNoData)
+    }
+
+    /// Reads the top left `width`x`height` pixels of the resource and sends them to the display
+    /// thread, which copies them into the surface and flips it, with the byte at `alpha` of every
+    /// pixel made opaque.  Nothing is read for a headless display, the scanout's frames are
+    /// still counted by `present`.
+    fn copy_to_surface(
+        &mut self,
+        resource_id: u32,
+        surface_id: u32,
+        width: u32,
+        height: u32,
+        alpha: Option<usize>,
+    ) -> VirtioGpuResponseResult {
+        // nobody would look at the pixels
+        if self.display.capabilities().headless {
+            return Ok(OkNoData);
+        }
+        // The display takes 4 bytes per pixel, like all the packed virtio formats.
+        let stride = width.checked_mul(4).ok_or(ErrUnspec)?;
+        let mut pixels = self.read_pixels(resource_id, width, height, stride)?;
+        if let Some(alpha) = alpha {
+            make_opaque(&mut pixels, alpha);
+        }
+
+        self.display.send(DisplayRequest::Flush {
+            surface_id,
+            stride,
+            height,
+            pixels,
+        });
+        Ok(OkNoData)
+    }
+
+    /// Reads the top left `width`x`height` pixels of the resource into rows of `stride` bytes,
+    /// packed formats as they are and YUV ones converted to XRGB8888.
+    fn read_pixels(
+        &mut self,
+        resource_id: u32,
+        width: u32,
+        height: u32,
+        stride: u32,
+    ) -> Result<StagingBuffer, VirtioGpuResponse> {
+        let size = (stride as usize).checked_mul(height as usize).ok_or(ErrUnspec)?;
+        let pixels = match self.read_yuv_resource(resource_id, width, height, stride)? {
+            Some(pixels) => pixels,
+            None => {
+                match self.resources.get(&resource_id) {
+                    // don't read past the end of a resource whose layout is known
+                    Some(resource) if resource.size() != 0 && resource.size() < size as u64 => {
+                        return Err(VirtioGpuResponse::ErrInvalidParameter);
+                    }
+                    _ => {}
+                }
+                let mut pixels = self.staging.take(size);
+
+                let mut transfer = Transfer3D::new_2d(0, 0, width, height);
+                transfer.stride = stride;
+                self.rutabaga.transfer_read(
+                    0,
+                    resource_id,
+                    transfer,
+                    Some(data_model::VolatileSlice::new(&mut pixels)),
+                )?;
+                pixels
+            }
+        };
+        Ok(pixels)
+    }
+
+    /// The resource shown on `scanout_id` and the rect of it shown, an enabled scanout the guest
+    /// set one on.
+    fn scanout_source(&self, scanout_id: u32) -> Result<(u32, DamageRect), VirtioGpuResponse> {
+        match self.scanouts.get(scanout_id as usize) {
+            Some(&(width, height)) if width != 0 && height != 0 => {}
+            _ => return Err(VirtioGpuResponse::ErrInvalidScanoutId),
+        }
+        let source = if scanout_id == 0 {
+            self.scanout_resource_id.map(|resource_id| (resource_id.get(), self.scanout_rect))
+        } else {
+            self.other_scanouts.get(&scanout_id).copied()
+        };
+        source.ok_or(VirtioGpuResponse::ErrInvalidScanoutId)
+    }
+
+    /// Copies the contents of the presented scanout into `dst`, rows of `dst_stride` bytes of
+    /// `format` pixels, without going through the display.  Returns the dimensions copied.
+    pub fn blit_scanout(
+        &mut self,
+        dst: VolatileSlice,
+        dst_stride: u32,
+        format: BlitFormat,
+    ) -> Result<(u32, u32), VirtioGpuResponse> {
+        let (resource_id, rect) = self.scanout_source(0)?;
+        self.blit_resource(resource_id, rect, dst, dst_stride, format)
+    }
+
+    /// Copies `rect` of the resource into `dst` like `blit_scanout`.
+    fn blit_resource(
+        &mut self,
+        resource_id: u32,
+        (x, y, width, height): DamageRect,
+        dst: VolatileSlice,
+        dst_stride: u32,
+        format: BlitFormat,
+    ) -> Result<(u32, u32), VirtioGpuResponse> {
+        let resource_format = self
+            .resources
+            .get(&resource_id)
+            .ok_or(ErrInvalidResourceId)?
+            .format();
+        // YUV resources are read back converted
+        let channels = match format_layout(resource_format, 1, 1) {
+            Some(layout) if layout.is_multi_planar() => XRGB_CHANNELS,
+            _ => packed_channels(resource_format).ok_or(VirtioGpuResponse::ErrInvalidParameter)?,
+        };
+        let row_size = width.checked_mul(format.bytes_per_pixel()).ok_or(ErrUnspec)?;
+        if row_size > dst_stride
+            || (dst_stride as usize).checked_mul(height as usize).map_or(true, |size| size > dst.len())
+        {
+            return Err(VirtioGpuResponse::ErrInvalidParameter);
+        }
+
+        // the pixels are read from the top left corner, the rect is then picked out of them
+        let read_width = x.checked_add(width).ok_or(VirtioGpuResponse::ErrInvalidParameter)?;
+        let read_height = y.checked_add(height).ok_or(VirtioGpuResponse::ErrInvalidParameter)?;
+        let stride = read_width.checked_mul(4).ok_or(ErrUnspec)?;
+        let pixels = self.read_pixels(resource_id, read_width, read_height, stride)?;
+        let mut row = vec![0u8; row_size as usize];
+        for (dst_y, line) in pixels.chunks_exact(stride as usize).skip(y as usize).enumerate() {
+            let line = &line[x as usize * 4..];
+            for (src, out) in line.chunks_exact(4).zip(row.chunks_exact_mut(format.bytes_per_pixel() as usize)) {
+                format.write_pixel([src[channels[0]], src[channels[1]], src[channels[2]]], out);
+            }
+            dst.subslice(dst_y * dst_stride as usize, row.len())
+                .map_err(|_| VirtioGpuResponse::ErrInvalidParameter)?
+                .copy_from(&row);
+        }
+        Ok((width, height))
+    }
+
+    /// Reads the rect of its resource the guest shows on `scanout_id` back into an RGBA image,
+    /// whether the host presents the scanout or not.  Disabled scanouts have nothing to capture.
+    pub fn capture_scanout(&mut self, scanout_id: u32) -> Result<Image, VirtioGpuResponse> {
+        let (resource_id, rect) = self.scanout_source(scanout_id)?;
+        let (width, height) = (rect.2, rect.3);
+        let stride = width.checked_mul(4).ok_or(ErrUnspec)?;
+        let size = (stride as usize).checked_mul(height as usize).ok_or(ErrUnspec)?;
+        let mut pixels = vec![0u8; size];
+        self.blit_resource(resource_id, rect, VolatileSlice::from(&mut pixels[..]), stride, BlitFormat::Rgbx)?;
+        Ok(Image { width, height, pixels })
+    }
+
+    /// Reads the planes of a YUV resource and converts its top left `width`x`height` pixels for
+    /// the display, `None` when the resource isn't YUV.
+    fn read_yuv_resource(
+        &mut self,
+        resource_id: u32,
+        width: u32,
+        height: u32,
+        stride: u32,
+    ) -> Result<Option<StagingBuffer>, VirtioGpuResponse> {
+        let resource = match self.resources.get(&resource_id) {
+            Some(resource) => resource,
+            None => return Ok(None),
+        };
+        let format = resource.format();
+        let (resource_width, resource_height) = resource.dimensions();
+        let layout = match format_layout(format, resource_width, resource_height) {
+            Some(layout) if layout.is_multi_planar() => layout,
+            _ => return Ok(None),
+        };
+        if width > resource_width || height > resource_height {
+            return Err(VirtioGpuResponse::ErrInvalidParameter);
+        }
+
+        let mut planes = self.staging.take(layout.size as usize);
+        self.rutabaga.transfer_read(
+            0,
+            resource_id,
+            Transfer3D::new_2d(0, 0, resource_width, resource_height),
+            Some(data_model::VolatileSlice::new(&mut planes)),
+        )?;
+
+        let mut pixels = self.staging.take(stride as usize * height as usize);
+        yuv_to_xrgb(format, &layout, &planes, width, height, &mut pixels, stride)
+            .ok_or(VirtioGpuResponse::ErrInvalidParameter)?;
+        Ok(Some(pixels))
+    }
+
+    /// flush resource screen
+    #[allow(unused_variables)]
+    pub fn cmd_flush_resource(&mut self, cmd: virtio_gpu_resource_flush) -> VirtioGpuResponseResult {
+        let resource_id = cmd.resource_id.to_native();
+        if resource_id == 0 {
+            return Ok(OkNoData);
+        }
+
+        if let (Some(scanout_resource_id), Some(scanout_surface_id)) =
+            (self.scanout_resource_id, self.scanout_surface_id)
+        {
+            if scanout_resource_id.get() == cmd.resource_id.to_native() {
+                let mut damage = (cmd.r.x.to_native(), cmd.r.y.to_native(), cmd.r.width.to_native(), cmd.r.height.to_native());
+                if let Some(resource) = self.resources.get(&resource_id) {
+                    let (width, height) = resource.dimensions();
+                    damage = self.quirks.fit_rect(damage, width, height)?;
+                }
+                if self.frame_interval.is_some() {
+                    // presented once per frame with the damage of all the flushes meanwhile
+                    if !self.resources.contains_key(&resource_id) {
+                        return Err(ErrInvalidResourceId);
+                    }
+                    let pending = self.flush_pending.is_some();
+                    self.flush_pending = Some(match self.flush_pending {
+                        Some(pending) => bounding_box(pending, damage),
+                        None => damage,
+                    });
+                    if !pending && !self.cursor_pending {
+                        self.arm_frame_timer();
+                    }
+                } else {
+                    self.present(resource_id, scanout_surface_id, damage)?;
+                }
+                self.track_flush_cadence(Instant::now());
+            }
+        }
+
+        if let (Some(cursor_resource_id), Some(cursor_surface_id)) =
+            (self.cursor_resource_id, self.cursor_surface_id)
+        {
+            if cursor_resource_id.get() == resource_id {
+                self.flush_resource_to_surface(resource_id, cursor_surface_id)?;
+            }
+        }
+
+        Ok(OkNoData)
+    }
+
+    /// Follows the interval between the guest's scanout flushes and passes it to the display when
+    /// the host output refreshes at a variable rate.
+    fn track_flush_cadence(&mut self, now: Instant) {
+        let (surface_id, (min_rate, max_rate)) = match (self.scanout_surface_id, self.adaptive_sync) {
+            (Some(surface_id), Some(range)) => (surface_id, range),
+            _ => return,
+        };
+        let interval = match self.last_flush.replace(now) {
+            Some(last_flush) => now.saturating_duration_since(last_flush),
+            None => return,
+        };
+        // a guest flushing slower than the lowest refresh rate is idle rather than pacing frames
+        if interval > Duration::from_secs(1) / min_rate.max(1) {
+            return;
+        }
+        let interval = max(interval, Duration::from_secs(1) / max_rate.max(1));
+
+        let changed = match self.flush_interval {
+            Some((last_surface_id, last_interval)) => {
+                last_surface_id != surface_id
+                    || max(interval, last_interval) - min(interval, last_interval) > FLUSH_CADENCE_TOLERANCE
+            }
+            None => true,
+        };
+        if changed {
+            self.flush_interval = Some((surface_id, interval));
+            self.display.send(DisplayRequest::SetFrameInterval {
+                surface_id,
+                interval: Some(interval),
+            });
+        }
+    }
+
+    /// What the device ended up running on, for diagnostics.
+    pub fn renderer_info(&self) -> RendererInfo {
+        RendererInfo {
+            virgl: self.features & (1 << VIRTIO_GPU_F_VIRGL) != 0,
+            num_capsets: self.num_capsets(),
+            features: self.features,
+            display: self.display.capabilities(),
+        }
+    }
+
+    /// `(id, version, max size)` of the capsets the renderer offers, in the order the guest
+    /// enumerates them.
+    pub fn capsets(&self) -> Vec<(u32, u32, u32)> {
+        self.capsets.clone()
+    }
+
+    /// Number of capsets, `num_capsets` of the config space.
+    pub fn num_capsets(&self) -> u32 {
+        self.capsets.len() as u32
+    }
+
+    /// The guest flush interval the display follows, when adaptive sync is enabled.
+    pub fn flush_interval(&self) -> Option<Duration> {
+        self.flush_interval.map(|(_, interval)| interval)
+    }
+
+    pub fn import_to_display(&mut self, resource_id: u32) -> Option<u32> { None }
+
+
+    /// set the scanout surface
+    pub fn cmd_set_scanout(&mut self, cmd: virtio_gpu_set_scanout) -> VirtioGpuResponseResult {
+        let resource_id = cmd.resource_id.to_native();
+        let scanout_id = cmd.scanout_id.to_native() as usize;
+        match self.scanouts.get(scanout_id) {
+            None => return Err(VirtioGpuResponse::ErrInvalidScanoutId),
+            // only the first scanout is presented on the host
+            Some(_) if scanout_id != 0 => {
+                let rect = (cmd.r.x.to_native(), cmd.r.y.to_native(), cmd.r.width.to_native(), cmd.r.height.to_native());
+                if resource_id == 0 {
+                    self.other_scanouts.remove(&(scanout_id as u32));
+                } else {
+                    self.other_scanouts.insert(scanout_id as u32, (resource_id, rect));
+                }
+                return Ok(OkNoData);
+            }
+            Some(_) => {}
+        }
+        if resource_id == 0 {
+            self.stop_forwarding();
+            if let Some(surface_id) = self.scanout_surface_id.take() {
+                self.display.send(DisplayRequest::ReleaseSurface(surface_id));
+            }
+            self.scanout_resource_id = None;
+            return Ok(OkNoData);
+        }
+
+         let resource = self
+             .resources
+             .get_mut(&resource_id)
+             .ok_or(ErrInvalidResourceId)?;
+        let (width, height) = resource.dimensions();
+        let rect = (cmd.r.x.to_native(), cmd.r.y.to_native(), cmd.r.width.to_native(), cmd.r.height.to_native());
+        if self.quirks.invalid_rect_parameter && !rect_within(rect, width, height) {
+            return Err(VirtioGpuResponse::ErrInvalidParameter);
+        }
+
+        self.scanout_resource_id = NonZeroU32::new(resource_id);
+        self.scanout_rect = rect;
+        self.scanout_damage.damage_all();
+        if self.scanout_surface_id.is_none() {
+            let surface_id =
+                self.display.create_surface(None, self.display_width, self.display_height).map_err(VirtioGpuResponse::DisplayErr)?;
+            if self.vsync {
+                self.display.send(DisplayRequest::SetVsync { surface_id, enabled: true });
+            }
+            self.scanout_surface_id = Some(surface_id);
+            self.close_requested = false;
+        }
+        Ok(OkNoData)
+    }
+
+    pub fn cmd_resource_attach_backing(
+        &mut self,
+        cmd: virtio_gpu_resource_attach_backing,
+        data: Vec<RutabagaIovec>
+    ) -> VirtioGpuResponseResult {
+        if self.inject_fault(Fault::RendererOom) {
+            return Err(VirtioGpuResponse::ErrOutOfMemory);
+        }
+        let resource_id = cmd.resource_id.to_native();
+        self.check_not_pooled(resource_id)?;
+        if self.quirks.reject_double_attach
+            && self.resources.get(&resource_id).map_or(false, |resource| resource.backing.is_some())
+        {
+            return Err(ErrUnspec);
+        }
+        let backing = iovec_ranges(&data);
+        self.rutabaga.attach_backing(resource_id, data)?;
+
+        // Guests detach and reattach the backings across suspend/resume and expect the contents
+        // to survive like they would in video memory.
+        if let Some(resource) = self.resources.get_mut(&resource_id) {
+            resource.backing = Some(backing);
+            if resource.backing_detached {
+                resource.backing_detached = false;
+                self.rutabaga.restore_backing(resource_id)?;
+            }
+        }
+        Ok(OkNoData)
+    }
+
+    /// Detaches the backing of a resource.  Once it returns the renderer doesn't reference the
+    /// guest memory of the backing anymore, the guest is free to reuse it.
+    pub fn cmd_resource_detach_backing(
+        &mut self,
+        cmd: virtio_gpu_resource_detach_backing
+    ) -> VirtioGpuResponseResult {
+        self.check_not_pooled(cmd.resource_id.to_native())?;
+        self.detach_backing(cmd.resource_id.to_native())
+    }
+
+    fn detach_backing(&mut self, resource_id: u32) -> VirtioGpuResponseResult {
+        self.rutabaga.detach_backing(resource_id)?;
+        if let Some(resource) = self.resources.get_mut(&resource_id) {
+            resource.backing = None;
+            // only the 2D renderer keeps a host copy of the contents
+            if self.acked_features & (1 << VIRTIO_GPU_F_VIRGL) == 0 {
+                resource.backing_detached = resource.size() != 0;
+            }
+        }
+        Ok(OkNoData)
+    }
+
+    /// Resources whose backing references host memory between `start` and `start + len`.
+    pub fn resources_backed_by(&self, start: usize, len: usize) -> Vec<u32> {
+        self.resources
+            .values()
+            .filter(|resource| resource.backing_overlaps(start, len))
+            .map(|resource| resource.resource_id)
+            .collect()
+    }
+
+    /// Detaches every backing in the host mapping of guest memory between `start` and
+    /// `start + len`, for the VMM to call before that memory is unplugged or the memory table
+    /// otherwise stops covering it.  2D resources keep their contents like on DETACH_BACKING, the
+    /// guest reattaches a backing before using them again.
+    pub fn detach_backings_in(&mut self, start: usize, len: usize) -> VirtioGpuResponseResult {
+        for resource_id in self.resources_backed_by(start, len) {
+            self.detach_backing(resource_id)?;
+        }
+        Ok(OkNoData)
+    }
+
+    /// Moves the backings from a host mapping of guest memory about to go away to the same guest
+    /// ranges in `mem`, when the frontend changed the memory table or a reconnected one sent its
+    /// own.  `to_guest` translates the host addresses of the old mapping, the backings it or `mem`
+    /// doesn't cover are detached like by `detach_backings_in`.  Stops at the first backing the
+    /// renderer fails to detach: that one and those not moved yet stay in the old mapping, which
+    /// must then be kept until `resources_backed_by` finds nothing in it.
+    pub fn rebind_backings<F>(&mut self, to_guest: F, mem: &GuestMemoryMmap) -> VirtioGpuResponseResult
+    where
+        F: Fn(usize) -> Option<GuestAddress>,
+    {
+        let backed: Vec<(u32, Vec<(usize, usize)>)> = self
+            .resources
+            .values()
+            .filter_map(|resource| Some((resource.resource_id, resource.backing.clone()?)))
+            .collect();
+        for (resource_id, backing) in backed {
+            let iovecs = backing
+                .iter()
+                .map(|&(base, len)| to_guest(base).map(|addr| (addr, len)))
+                .collect::<Option<Vec<_>>>()
+                .and_then(|sglist| sglist_to_rutabaga_iovecs(&sglist, mem).ok());
+            // regions kept mapped at the same place
+            if iovecs.as_ref().map(|iovecs| iovec_ranges(iovecs)) == Some(backing) {
+                continue;
+            }
+            self.detach_backing(resource_id)?;
+            if let Some(iovecs) = iovecs {
+                let ranges = iovec_ranges(&iovecs);
+                // left detached on failure, the old mapping is about to go
+                if self.rutabaga.attach_backing(resource_id, iovecs).is_err() {
+                    continue;
+                }
+                if let Some(resource) = self.resources.get_mut(&resource_id) {
+                    resource.backing = Some(ranges);
+                    resource.backing_detached = false;
+                }
+            }
+        }
+        Ok(OkNoData)
+    }
+
+    pub fn cmd_ctx_attach_resource(
+        &mut self,
+        cmd: virtio_gpu_ctx_resource
+    ) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_VIRGL)?;
+        self.check_not_pooled(cmd.resource_id.to_native())?;
+        self.rutabaga.context_attach_resource(cmd.hdr.ctx_id.to_native(), cmd.resource_id.to_native())?;
+        if let Some(resource) = self.resources.get_mut(&cmd.resource_id.to_native()) {
+            resource.in_context = true;
+        }
+        Ok(OkNoData)
+    }
+
+    pub fn cmd_ctx_detach_resource(
+        &mut self,
+        cmd: virtio_gpu_ctx_resource
+    ) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_VIRGL)?;
+        self.check_not_pooled(cmd.resource_id.to_native())?;
+        self.rutabaga.context_detach_resource(cmd.hdr.ctx_id.to_native(), cmd.resource_id.to_native())?;
+        Ok(OkNoData)
+    }
+
+    pub fn cmd_submit_3d(
+        &mut self,
+        cmd: virtio_gpu_cmd_submit,
+        data: data_model::VolatileSlice
+    ) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_VIRGL)?;
+        if self.inject_fault(Fault::RendererOom) {
+            return Err(VirtioGpuResponse::ErrOutOfMemory);
+        }
+        self.rutabaga.submit_command(cmd.hdr.ctx_id.to_native(), data)?;
+        Ok(OkNoData)
+    }
+
+    /// Submits a command stream read straight from guest memory.  `sglist` holds the readable
+    /// descriptors following the `virtio_gpu_cmd_submit` header and the stream is their first
+    /// `cmd.size` bytes.
+    ///
+    /// A stream within a single descriptor is handed to the renderer in place, as a volatile slice
+    /// since the guest can write to it meanwhile.  Only streams split across descriptors are
+    /// gathered into a bounce buffer.
+    pub fn cmd_submit_3d_from_guest(
+        &mut self,
+        cmd: virtio_gpu_cmd_submit,
+        mem: &GuestMemoryMmap,
+        sglist: &[(GuestAddress, usize)]
+    ) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_VIRGL)?;
+        if cmd.size.to_native() > VIRTIO_GPU_MAX_SUBMIT_SIZE {
+            return Err(VirtioGpuCommandDecodeError::SubmitTooLarge(cmd.size.to_native()).response());
+        }
+        let size = cmd.size.to_native() as usize;
+        if sglist.iter().map(|&(_, len)| len).sum::<usize>() < size {
+            return Err(VirtioGpuResponse::InvalidSglistRegion(sglist.len()));
+        }
+
+        match sglist.first() {
+            Some(&(addr, len)) if len >= size => {
+                let slice = mem
+                    .get_slice(addr, size)
+                    .map_err(|_| VirtioGpuResponse::InvalidSglistRegion(0))?;
+                // Safe because the slice was validated against the guest memory map above, which
+                // outlives this call, and the stream is only accessed as volatile memory.
+                let data = unsafe { data_model::VolatileSlice::from_raw_parts(slice.as_ptr(), slice.len()) };
+                self.cmd_submit_3d(cmd, data)
+            }
+            _ => {
+                let mut data = vec![0u8; size];
+                let mut offset = 0;
+                for (index, &(addr, len)) in sglist.iter().enumerate() {
+                    if offset == size {
+                        break;
+                    }
+                    let len = len.min(size - offset);
+                    let slice = mem
+                        .get_slice(addr, len)
+                        .map_err(|_| VirtioGpuResponse::InvalidSglistRegion(index))?;
+                    slice.copy_to(&mut data[offset..offset + len]);
+                    offset += len;
+                }
+                self.cmd_submit_3d(cmd, data_model::VolatileSlice::new(&mut data))
+            }
+        }
+    }
+
+    pub fn cmd_transfer_to_host_2d(
+        &mut self,
+        cmd: virtio_gpu_transfer_to_host_2d
+    ) -> VirtioGpuResponseResult {
+        let resource_id = cmd.resource_id.to_native();
+        self.check_not_pooled(resource_id)?;
+        let mut rect = (cmd.r.x.to_native(), cmd.r.y.to_native(), cmd.r.width.to_native(), cmd.r.height.to_native());
+        if let Some(resource) = self.resources.get(&resource_id) {
+            let (width, height) = resource.dimensions();
+            rect = self.quirks.fit_rect(rect, width, height)?;
+            // clipped away entirely
+            if rect.2 == 0 || rect.3 == 0 {
+                return Ok(OkNoData);
+            }
+        }
+        let mut transfer = Transfer3D::new_2d(rect.0, rect.1, rect.2, rect.3);
+        // where the rect starts in the backing, whose rows are width * bpp bytes apart.  Clipping
+        // only shrinks the far edges, so the rect still starts there.
+        transfer.offset = cmd.offset.to_native();
+
+        self.rutabaga.transfer_write(cmd.hdr.ctx_id.to_native(), resource_id, transfer)?;
+        if self.scanout_resource_id.map(NonZeroU32::get) == Some(resource_id) {
+            self.scanout_damage.add(rect, self.display_width, self.display_height);
+        }
+        Ok(OkNoData)
+    }
+
+
+    pub fn cmd_transfer_to_host_3d(
+        &mut self,
+        cmd: virtio_gpu_transfer_host_3d
+    ) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_VIRGL)?;
+        let resource_id = cmd.resource_id.to_native();
+        self.check_not_pooled(resource_id)?;
+        let transfer = transfer_host_3d_to_transfer_3d(cmd);
+        self.rutabaga.transfer_write(cmd.hdr.ctx_id.to_native(), resource_id, transfer)?;
+        Ok(OkNoData)
+    }
+
+    /// Queues a transfer to run later in a batch, with the other transfers queued.  Its response
+    /// is held back until then.  `token` identifies the command to the caller, typically its
+    /// descriptor index.
+    ///
+    /// Returns the transfers run by this call: nothing while the queue has room, the whole queue
+    /// once it reaches `transfer_queue_depth`, synchronously like any other command.  Queued
+    /// transfers must be run with `complete_transfers` before any other command touching their
+    /// resources is processed.
+    pub fn queue_transfer(
+        &mut self,
+        token: u64,
+        transfer: PendingTransfer,
+    ) -> Vec<(u64, VirtioGpuResponseResult)> {
+        self.pending_transfers.push_back((token, transfer));
+        if self.pending_transfers.len() >= max(self.transfer_queue_depth, 1) {
+            self.complete_transfers()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Runs every queued transfer and returns their responses in submission order.
+    pub fn complete_transfers(&mut self) -> Vec<(u64, VirtioGpuResponseResult)> {
+        let mut completed = Vec::with_capacity(self.pending_transfers.len());
+        while let Some((token, transfer)) = self.pending_transfers.pop_front() {
+            let result = match transfer {
+                PendingTransfer::ToHost2d(cmd) => self.cmd_transfer_to_host_2d(cmd),
+                PendingTransfer::ToHost3d(cmd) => self.cmd_transfer_to_host_3d(cmd),
+            };
+            completed.push((token, result));
+        }
+        completed
+    }
+
+    pub fn has_pending_transfers(&self) -> bool {
+        !self.pending_transfers.is_empty()
+    }
+
+    /// Polls the renderer until every fence created signalled, for at most `timeout`.  Returns
+    /// whether they all did.
+    pub fn wait_fences(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        self.process_fences();
+        while !self.pending_fences.is_empty() && Instant::now() < deadline {
+            thread::sleep(FENCE_POLL_INTERVAL);
+            self.process_fences();
+        }
+        self.pending_fences.is_empty()
+    }
+
+    /// Releases everything the guest created, for a VMM tearing the device down: the fences in
+    /// flight get `timeout` to signal, then the blobs are unmapped, the contexts destroyed, the
+    /// resources detached from their backings and unreffed and the surfaces released.  Returns
+    /// whether every fence signalled.
+    pub fn shutdown(&mut self, timeout: Duration) -> bool {
+        // nobody is left to answer the queued transfers
+        let _ = self.complete_transfers();
+        let signalled = self.wait_fences(timeout);
+        self.pending_fences.clear();
+        self.signalled_fences.clear();
+
+        // failures are of the renderer, which goes down with the device anyway
+        let mapped: Vec<u32> = self.blob_mappings.keys().copied().collect();
+        for resource_id in mapped {
+            let _ = self.unmap_blob(resource_id);
+        }
+        for ctx_id in std::mem::take(&mut self.contexts) {
+            let _ = self.rutabaga.destroy_context(ctx_id);
+        }
+        self.context_names.clear();
+        for (resource_id, resource) in std::mem::take(&mut self.resources) {
+            if resource.backing.is_some() {
+                let _ = self.rutabaga.detach_backing(resource_id);
+            }
+            let _ = self.rutabaga.unref_resource(resource_id);
+        }
+        for resource in self.resource_pool.drain(..) {
+            let _ = self.rutabaga.unref_resource(resource.resource_id);
+        }
+
+        self.stop_forwarding();
+        self.scanout_resource_id = None;
+        self.other_scanouts.clear();
+        self.cursor_resource_id = None;
+        self.flush_pending = None;
+        self.cursor_pending = false;
+        if let Some(surface_id) = self.cursor_surface_id.take() {
+            self.display.send(DisplayRequest::ReleaseSurface(surface_id));
+        }
+        if let Some(surface_id) = self.scanout_surface_id.take() {
+            self.display.send(DisplayRequest::ReleaseSurface(surface_id));
+        }
+        signalled
+    }
+
+    /// Returns the device to its state right after creation, for a driver that reset it: what the
+    /// guest created is released as on `shutdown`, without waiting for fences the driver gave up
+    /// on, the acked features go back to every feature offered until the next driver negotiates,
+    /// and the raised events and the cursor start over.  The renderer, the display, the scanouts the VMM set up and what was
+    /// installed with the builders are kept.
+    pub fn reset(&mut self) {
+        self.shutdown(Duration::from_secs(0));
+        self.acked_features = self.features;
+        self.events_read = 0;
+        self.scanout_damage = DamageTracker::new();
+        self.cursor_position = (0, 0);
+        self.cursor_hotspot = (0, 0);
+        self.last_flush = None;
+        self.flush_interval = None;
+        self.frames_presented = 0;
+        self.close_requested = false;
+    }
+
+    pub fn cmd_resource_assign_uuid(&self, cmd: virtio_gpu_resource_assign_uuid) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_RESOURCE_UUID)?;
+        let resource_id = cmd.resource_id.to_native();
+        if !self.resources.contains_key(&resource_id) {
+            return Err(ErrInvalidResourceId);
+        }
+
+        let mut uuid: [u8; 16] = [0; 16];
+        for (idx, byte) in resource_id.to_be_bytes().iter().enumerate() {
+            uuid[12 + idx] = *byte;
+        }
+        Ok(OkResourceUuid { uuid })
+    }
+
+    #[allow(unused_variablesb)]
+    pub fn cmd_transfer_from_host_3d(
+        &mut self,
+        cmd: virtio_gpu_transfer_host_3d,
+        buf: Option<VolatileSlice>
+    ) -> VirtioGpuResponseResult {
+        self.require_feature(VIRTIO_GPU_F_VIRGL)?;
+        let resource_id = cmd.resource_id.to_native();
+        self.check_not_pooled(resource_id)?;
+        let transfer = transfer_host_3d_to_transfer_3d(cmd);
+        self.rutabaga.transfer_read(cmd.hdr.ctx_id.to_native(), resource_id, transfer, None)?;
+        Ok(OkNoData)
+    }
+
+    /// Moves the cursor without touching its image.  Guests send one of these for every pointer
+    /// motion, so the scanout is only committed when the display can't move the cursor surface
+    /// on its own, and at most once per frame when frame pacing is enabled.
+    pub fn cmd_move_curosr(
+        &mut self,
+        cmd: virtio_gpu_update_cursor
+    ) -> VirtioGpuResponseResult {
+        let (x, y) = self.quirks.cursor_position(
+            cmd.pos.x.to_native(),
+            cmd.pos.y.to_native(),
+            self.scanout_rect.2,
+            self.scanout_rect.3,
+        );
+        if self.cursor_position == (x, y) {
+            return Ok(OkNoData);
+        }
+        self.cursor_position = (x, y);
+
+        if self.frame_interval.is_some() {
+            let armed = self.cursor_pending || self.flush_pending.is_some();
+            self.cursor_pending = true;
+            if !armed {
+                self.arm_frame_timer();
+            }
+        } else {
+            self.commit_cursor_position();
+        }
+        Ok(OkNoData)
+    }
+
+    /// Runs a command of the cursor queue.  Cursor commands only talk to the display: they never
+    /// complete the queued transfers or wait for the renderer, so a VMM can run them between the
+    /// control queue's commands to keep the pointer responsive during long 3D batches.  Commands
+    /// of the control queue are refused with ERR_UNSPEC.
+    pub fn process_cursor(&mut self, cmd: &VirtioGpuCommand) -> VirtioGpuResponseResult {
+        match *cmd {
+            VirtioGpuCommand::CmdMoveCursor(cursor) => self.intercept(cmd, |gpu| gpu.cmd_move_curosr(cursor)),
+            VirtioGpuCommand::CmdUpdateCursor(cursor) => self.intercept(cmd, |gpu| gpu.cmd_update_cursor(cursor)),
+            _ => Err(ErrUnspec),
+        }
+    }
+
+    #[allow(unused_variables)]
+    pub fn cmd_update_cursor(
+        &mut self,
+        cmd: virtio_gpu_update_cursor
+    ) -> VirtioGpuResponseResult {
+        let resource_id = cmd.resource_id.to_native();
+        let (x, y) = self.quirks.cursor_position(
+            cmd.pos.x.to_native(),
+            cmd.pos.y.to_native(),
+            self.scanout_rect.2,
+            self.scanout_rect.3,
+        );
+        let hide = self.quirks.tolerant_cursor && !self.resources.contains_key(&resource_id);
+        if resource_id == 0 || hide {
+            if let Some(surface_id) = self.cursor_surface_id.take() {
+                self.display.send(DisplayRequest::ReleaseSurface(surface_id));
+            }
+            self.cursor_resource_id = None;
+            return Ok(OkNoData);
+        }
+
+        let (resource_width, resource_height) = self
+            .resources
+            .get_mut(&resource_id)
+            .ok_or(ErrInvalidResourceId)?
+            .dimensions();
+
+        self.cursor_resource_id = NonZeroU32::new(resource_id);
+
+        if self.cursor_surface_id.is_none() {
+            self.cursor_surface_id = Some(self.display.create_surface(
+                self.scanout_surface_id,
+                resource_width,
+                resource_height,
+            ).map_err(VirtioGpuResponse::DisplayErr)?);
+        }
+
+        let cursor_surface_id = self.cursor_surface_id.unwrap();
+        self.cursor_position = (x, y);
+        self.cursor_hotspot = (cmd.hot_x.to_native(), cmd.hot_y.to_native());
+        self.cursor_pending = false;
+        let (x, y) = self.cursor_surface_position();
+        self.display.send(DisplayRequest::SetPosition {
+            surface_id: cursor_surface_id,
+            x,
+            y,
+        });
+
+        // Gets the resource's pixels into the display by importing the buffer.
+        if let Some(import_id) = self.import_to_display(resource_id) {
+            self.display.send(DisplayRequest::FlipTo {
+                surface_id: cursor_surface_id,
+                import_id,
+            });
+            return Ok(OkNoData);
+        }
+
+        // Importing failed, so try copying the pixels into the surface's slower shared memory
+        // framebuffer.
+        self.copy_to_surface(resource_id, cursor_surface_id, resource_width, resource_height, None)
+    }
+
+    /// poll the fenced data
+    #[allow(unused_variables)]
+    pub fn fence_poll(&mut self) -> Vec<RutabagaFenceData> {
+        self.rutabaga.poll()
+    }
+
+    /// Registers `callback`, called by `process_fences` with every newly completed fence.
+    pub fn on_fence_complete<F: FnMut(RutabagaFenceData) + 'static>(&mut self, callback: F) {
+        self.fence_callback = Some(Box::new(callback));
+    }
+
+    /// Polls the renderer and reports the fences completed since the last call to the
+    /// `on_fence_complete` callback, returns how many were reported.  The renderer only reports
+    /// the latest fence of each ring, the fences before it on the same ring are implicitly done.
+    pub fn process_fences(&mut self) -> usize {
+        let mut reported = 0;
+        for fence in self.rutabaga.poll() {
+            let ring = fence_ring(&fence);
+            let signalled = self.signalled_fences.entry(ring).or_insert(0);
+            if fence.fence_id <= *signalled {
+                continue;
+            }
+            *signalled = fence.fence_id;
+            if let Some(pending) = self.pending_fences.get_mut(&ring) {
+                *pending = pending.split_off(&(fence.fence_id + 1));
+                if pending.is_empty() {
+                    self.pending_fences.remove(&ring);
+                }
+            }
+
+            if let Some(ref mut callback) = self.fence_callback {
+                callback(fence);
+            }
+            reported += 1;
+        }
+        reported
+    }
+
+    pub fn force_ctx_0(&mut self) {
+        self.rutabaga.force_ctx_0()
+    }
+
+    /// Id of the last fence signalled on the `(ctx_id, ring_idx)` ring, 0 before the first one.
+    pub fn signalled_fence(&self, ring: (u32, u32)) -> u64 {
+        self.signalled_fences.get(&ring).copied().unwrap_or(0)
+    }
+
+    /// Number of fences created on the `(ctx_id, ring_idx)` ring that haven't signalled yet.
+    pub fn pending_fences(&self, ring: (u32, u32)) -> usize {
+        self.pending_fences.get(&ring).map_or(0, BTreeSet::len)
+    }
+
+    /// Whether a fence created on the `(ctx_id, ring_idx)` ring before `fence_id` is still pending.
+    pub fn fences_pending_before(&self, ring: (u32, u32), fence_id: u64) -> bool {
+        self.pending_fences
+            .get(&ring)
+            .map_or(false, |pending| pending.range(..fence_id).next().is_some())
+    }
+
+    /// create fence for ctx, on one of its rings with VIRTIO_GPU_FLAG_INFO_RING_IDX
+    pub fn create_fence(&mut self, request_fence_data: RutabagaFenceData) -> VirtioGpuResponseResult {
+        if request_fence_data.flags & VIRTIO_GPU_FLAG_INFO_RING_IDX != 0 {
+            self.require_feature(VIRTIO_GPU_F_CONTEXT_INIT)?;
+            if request_fence_data.fence_ctx_idx >= VIRTIO_GPU_MAX_RINGS {
+                return Err(VirtioGpuResponse::ErrInvalidParameter);
+            }
+        }
+        let ring = fence_ring(&request_fence_data);
+        let fence_id = request_fence_data.fence_id;
+        if self.inject_fault(Fault::FenceTimeout) {
+            // accepted, but never handed to the renderer so it never signals
+            self.pending_fences.entry(ring).or_default().insert(fence_id);
+            return Ok(OkNoData);
+        }
+        self.rutabaga.create_fence(request_fence_data)?;
+        self.pending_fences.entry(ring).or_default().insert(fence_id);
+        Ok(OkNoData)
+    }
+
+    /// Saves what the destination of a live migration needs to carry on, see `migration`.  The
+    /// queues must be stopped, the transfers still queued are run so the host copies are current.
+    /// `to_guest` translates host addresses of guest memory back to guest addresses.
+    pub fn save_state<F>(&mut self, to_guest: F) -> Result<DeviceState, MigrationError>
+    where
+        F: Fn(usize) -> Option<GuestAddress>,
+    {
+        if self.acked_features & (1 << VIRTIO_GPU_F_VIRGL) != 0 {
+            return Err(MigrationError::Unsupported);
+        }
+        // the chains of the transfers are gone with the stopped queues, nobody to answer
+        let _ = self.complete_transfers();
+
+        let mut resources = Vec::with_capacity(self.resources.len());
+        for resource in self.resources.values() {
+            let resource_id = resource.resource_id;
+            let (width, height) = resource.dimensions();
+            // blobs have no host copy, only the renderer knows where their contents are
+            let layout = match format_layout(resource.format(), width, height) {
+                Some(layout) if resource.size() != 0 => layout,
+                _ => return Err(MigrationError::Unsupported),
+            };
+            let mut contents = vec![0u8; resource.size() as usize];
+            let mut transfer = Transfer3D::new_2d(0, 0, width, height);
+            transfer.stride = layout.planes[0].stride;
+            self.rutabaga
+                .transfer_read(0, resource_id, transfer, Some(data_model::VolatileSlice::new(&mut contents)))
+                .map_err(|e| MigrationError::Renderer(resource_id, e))?;
+
+            let backing = match resource.backing() {
+                Some(backing) => Some(
+                    backing
+                        .iter()
+                        .map(|&(base, len)| to_guest(base).map(|addr| (addr, len)))
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or(MigrationError::InvalidBacking(resource_id))?,
+                ),
+                None => None,
+            };
+            resources.push(ResourceState {
+                resource_id,
+                width,
+                height,
+                format: resource.format(),
+                backing,
+                backing_detached: resource.backing_detached,
+                contents,
+            });
+        }
+
+        Ok(DeviceState {
+            scanouts: self.scanouts.clone(),
+            events_read: self.events_read,
+            scanout_resource_id: self.scanout_resource_id.map_or(0, NonZeroU32::get),
+            cursor_resource_id: self.cursor_resource_id.map_or(0, NonZeroU32::get),
+            cursor_position: self.cursor_position,
+            cursor_hotspot: self.cursor_hotspot,
+            signalled_fences: self.signalled_fences.iter().map(|(&ring, &fence_id)| (ring, fence_id)).collect(),
+            resources,
+        })
+    }
+
+    /// Restores the state `save_state` saved on the source, on a device that didn't process any
+    /// command yet, and shows the scanout and cursor again.  The backings are attached in `mem`.
+    pub fn restore_state(&mut self, state: DeviceState, mem: &GuestMemoryMmap) -> Result<(), MigrationError> {
+        if self.acked_features & (1 << VIRTIO_GPU_F_VIRGL) != 0 {
+            return Err(MigrationError::Unsupported);
+        }
+
+        for resource in state.resources {
+            let resource_id = resource.resource_id;
+            let (width, height, format) = (resource.width, resource.height, resource.format);
+            let renderer_error = |e| MigrationError::Renderer(resource_id, e);
+            let layout = format_layout(format, width, height).ok_or(MigrationError::InvalidState("resource format"))?;
+            let size = resource_2d_size(format, width, height).ok_or(MigrationError::InvalidState("resource size"))?;
+            if resource.contents.len() as u64 != size {
+                return Err(MigrationError::InvalidState("resource contents"));
+            }
+
+            let resource_create_3d = ResourceCreate3D {
+                target: RUTABAGA_PIPE_TEXTURE_2D,
+                format,
+                bind: RUTABAGA_PIPE_BIND_RENDER_TARGET,
+                width,
+                height,
+                depth: 1,
+                array_size: 1,
+                last_level: 0,
+                nr_samples: 0,
+                flags: 0,
+            };
+            self.rutabaga
+                .resource_create_3d(resource_id, resource_create_3d)
+                .map_err(renderer_error)?;
+
+            // The contents go through a backing of their own: the guest's may already hold
+            // pixels the guest didn't transfer yet.
+            let mut contents = resource.contents;
+            let iovec = RutabagaIovec {
+                base: contents.as_mut_ptr() as *mut c_void,
+                len:  contents.len(),
+            };
+            let mut transfer = Transfer3D::new_2d(0, 0, width, height);
+            transfer.stride = layout.planes[0].stride;
+            self.rutabaga.attach_backing(resource_id, vec![iovec]).map_err(renderer_error)?;
+            let written = self.rutabaga.transfer_write(0, resource_id, transfer);
+            self.rutabaga.detach_backing(resource_id).map_err(renderer_error)?;
+            written.map_err(renderer_error)?;
+
+            let mut restored = VirtioGpuResource::new(resource_id, width, height, format, size);
+            restored.backing_detached = resource.backing_detached;
+            if let Some(backing) = resource.backing {
+                let iovecs =
+                    sglist_to_rutabaga_iovecs(&backing, mem).map_err(|_| MigrationError::InvalidBacking(resource_id))?;
+                restored.backing = Some(iovec_ranges(&iovecs));
+                self.rutabaga.attach_backing(resource_id, iovecs).map_err(renderer_error)?;
+            }
+            self.resources.insert(resource_id, restored);
+        }
+
+        if !state.scanouts.is_empty() {
+            self.set_scanouts(&state.scanouts)
+                .map_err(|_| MigrationError::InvalidState("scanouts"))?;
+        }
+        self.events_read = state.events_read;
+        let resource = |resource_id: u32| match NonZeroU32::new(resource_id) {
+            Some(_) if !self.resources.contains_key(&resource_id) => Err(MigrationError::InvalidState("unknown resource")),
+            id => Ok(id),
+        };
+        let scanout_resource_id = resource(state.scanout_resource_id)?;
+        let cursor_resource_id = resource(state.cursor_resource_id)?;
+        self.scanout_resource_id = scanout_resource_id;
+        self.cursor_resource_id = cursor_resource_id;
+        self.cursor_position = state.cursor_position;
+        self.cursor_hotspot = state.cursor_hotspot;
+        self.signalled_fences = state.signalled_fences.into_iter().collect();
+        self.restore_surfaces();
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::protocol::*;
+    use crate::virtio_gpu::{fence_ring, resource_2d_size, sglist_to_rutabaga_iovecs, BlitFormat, ConfigError, ConfigProblem, DisplayBackend, GpuEventSource, GpuMode, GpuParameter, ScanoutConfig, VirtioGpuError};
+    use crate::edid::{EdidError, EdidInfo};
+    use crate::extension::ExtensionRegistry;
+    use crate::interceptor::{CommandFilter, CommandInterceptor};
+    use crate::migration::MigrationError;
+    use crate::present::{PresentHook, PresentInfo};
+    use crate::recorder::tests::SharedBuffer;
+    use crate::recorder::FrameRecorder;
+    use crate::quirks::{GuestProfile, Quirks};
+    use crate::damage::DamageRect;
+    use crate::dmabuf::{DmabufScanout, ScanoutForwarder};
+    use crate::{RutabagaFenceData, RutabagaIovec, VirtioGpu, VirtioGpuCommand, VirtioGpuResponseResult};
+    use rutabaga_gfx::{RutabagaHandle, Transfer3D, RUTABAGA_MEM_HANDLE_TYPE_DMABUF};
+    use std::fs::File;
+    use std::io;
+    use std::os::raw::c_void;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+    use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, Le32, Le64, VolatileSlice};
+    use std::num::NonZeroU32;
+    use gpu_display::{
+        crc32, EventDeviceKind, FrameChecksums, GpuDisplay, GpuDisplayBackend, GpuDisplayError, GpuDisplayFramebuffer,
+        GpuDisplayOutput,
+    };
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    /// A 2D device on the display `open` opens, showing resource 1 on the scanout: a BGRX
+    /// resource as large as the display, the contents of `backing` transferred to it, flushed
+    /// once.  The device keeps `backing` attached, it has to outlive the device.
+    pub(crate) fn scanout_fixture<F>(gpu_parameter: GpuParameter, open: F, backing: &mut [u8]) -> VirtioGpu
+    where
+        F: FnMut() -> Result<GpuDisplay, GpuDisplayError> + Send + 'static,
+    {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..gpu_parameter
+        };
+        let (width, height) = (gpu_parameter.display_width, gpu_parameter.display_height);
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, open).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(width);
+        create.height = Le32::from(height);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        let mut transfer: virtio_gpu_transfer_to_host_2d = Default::default();
+        transfer.resource_id = Le32::from(1);
+        transfer.r.width = Le32::from(width);
+        transfer.r.height = Le32::from(height);
+        virtio_gpu.cmd_transfer_to_host_2d(transfer).unwrap();
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        set_scanout.r.width = Le32::from(width);
+        set_scanout.r.height = Le32::from(height);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+        let mut flush: virtio_gpu_resource_flush = Default::default();
+        flush.resource_id = Le32::from(1);
+        flush.r.width = Le32::from(width);
+        flush.r.height = Le32::from(height);
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        virtio_gpu
+    }
+
+    #[test]
+    fn test_new_virtio_gpu() {
+        let gpu_parameter: GpuParameter = Default::default();
+        let virtio_gpu = VirtioGpu::new(gpu_parameter).map_err(|e| {
+                panic!("Gpu: create new virtio gpu failed, err: {:?}", e);
+                e
+            }).unwrap();
+    }
+
+    #[test]
+    fn test_sglist_to_rutabaga_iovecs() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x2000)]).unwrap();
+
+        let iovecs = sglist_to_rutabaga_iovecs(&[(GuestAddress(0), 0x1000), (GuestAddress(0x1000), 0x800)], &mem).unwrap();
+        assert_eq!(iovecs.len(), 2);
+        assert_eq!(iovecs[1].len, 0x800);
+        assert_eq!(iovecs[1].base as usize - iovecs[0].base as usize, 0x1000);
+
+        let sglist = [(GuestAddress(0), 0x1000), (GuestAddress(0x1800), 0x1000), (GuestAddress(0x4000), 1)];
+        match sglist_to_rutabaga_iovecs(&sglist, &mem) {
+            Err(VirtioGpuResponse::InvalidSglistRegion(index)) => assert_eq!(index, 1),
+            _ => panic!("sglist crossing the end of guest memory was accepted"),
+        }
+    }
+
+    #[test]
+    fn test_resource_2d_size() {
+        assert_eq!(resource_2d_size(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, 1920, 1080), Some(1920 * 1080 * 4));
+        assert_eq!(resource_2d_size(VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM, 3, 2), Some(24));
+        assert_eq!(resource_2d_size(0, 64, 64), None);
+        // 4 luma rows padded to 8 bytes, then 2 rows of every 2x2 subsampled chroma plane
+        assert_eq!(resource_2d_size(VIRTIO_GPU_FORMAT_NV12, 6, 4), Some(32 + 16));
+        assert_eq!(resource_2d_size(VIRTIO_GPU_FORMAT_YV12, 6, 4), Some(32 + 8 + 8));
+        // the stride times the height overflows
+        assert_eq!(resource_2d_size(VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM, u32::MAX, u32::MAX), None);
+    }
+
+    #[test]
+    fn test_extension_command() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut extensions = ExtensionRegistry::new();
+        extensions
+            .register(0x0f00, |gpu: &mut VirtioGpu, cmd: virtio_gpu_resource_unref| {
+                Ok(VirtioGpuResponse::OkDisplayInfo(vec![(cmd.resource_id.to_native(), gpu.events_read())]))
+            })
+            .unwrap();
+        assert!(extensions.register(VIRTIO_GPU_CMD_GET_EDID, |_, _: virtio_gpu_ctrl_hdr| Ok(VirtioGpuResponse::OkNoData)).is_err());
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub)
+            .unwrap()
+            .with_extensions(extensions);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut cmd = virtio_gpu_resource_unref::default();
+        cmd.hdr.type_ = Le32::from(0x0f00);
+        cmd.resource_id = Le32::from(7);
+        mem.write_obj(cmd, GuestAddress(0)).unwrap();
+        match virtio_gpu.process_extension(&mem, GuestAddress(0)) {
+            Some(Ok(VirtioGpuResponse::OkDisplayInfo(info))) => assert_eq!(info, vec![(7, 0)]),
+            other => panic!("unexpected response {:?}", other),
+        }
+
+        cmd.hdr.type_ = Le32::from(0x0f01);
+        mem.write_obj(cmd, GuestAddress(0)).unwrap();
+        assert!(virtio_gpu.process_extension(&mem, GuestAddress(0)).is_none());
+        assert!(matches!(virtio_gpu.process_unknown_command(&mem, GuestAddress(0)), Err(VirtioGpuResponse::ErrUnspec)));
+    }
+
+    #[test]
+    fn test_unknown_command_handler() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut extensions = ExtensionRegistry::new();
+        extensions.set_unknown_command_handler(|hdr, mem, addr| {
+            let payload: Le32 = mem.read_obj(GuestAddress(addr.0 + 24))?;
+            Ok(VirtioGpuResponse::OkDisplayInfo(vec![(hdr.type_.to_native(), payload.to_native())]))
+        });
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub)
+            .unwrap()
+            .with_extensions(extensions);
+
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut cmd = virtio_gpu_resource_unref::default();
+        cmd.hdr.type_ = Le32::from(0x0f02);
+        cmd.resource_id = Le32::from(3);
+        mem.write_obj(cmd, GuestAddress(0x100)).unwrap();
+        match virtio_gpu.process_unknown_command(&mem, GuestAddress(0x100)) {
+            Ok(VirtioGpuResponse::OkDisplayInfo(info)) => assert_eq!(info, vec![(0x0f02, 3)]),
+            other => panic!("unexpected response {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_interceptors() {
+        struct Counter(Rc<Cell<u32>>);
+        impl CommandInterceptor for Counter {
+            fn after(&mut self, _cmd: &VirtioGpuCommand, _result: &VirtioGpuResponseResult, _elapsed: Duration) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let count = Rc::new(Cell::new(0));
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub)
+            .unwrap()
+            .with_interceptor(Counter(count.clone()))
+            .with_interceptor(CommandFilter::new().deny(VIRTIO_GPU_CMD_GET_EDID));
+
+        let mut get_edid: virtio_gpu_cmd_get_edid = Default::default();
+        get_edid.hdr.type_ = Le32::from(VIRTIO_GPU_CMD_GET_EDID);
+        let cmd = VirtioGpuCommand::CmdGetEdid(get_edid);
+        let result = virtio_gpu.intercept(&cmd, |gpu| gpu.cmd_get_edid(get_edid));
+        assert!(matches!(result, Err(VirtioGpuResponse::ErrUnspec)));
+
+        let mut hdr: virtio_gpu_ctrl_hdr = Default::default();
+        hdr.type_ = Le32::from(VIRTIO_GPU_CMD_GET_DISPLAY_INFO);
+        let cmd = VirtioGpuCommand::CmdGetDisplayInfo(hdr);
+        let result = virtio_gpu.intercept(&cmd, |gpu| gpu.cmd_get_display_info(hdr));
+        assert!(matches!(result, Ok(VirtioGpuResponse::OkDisplayInfo(_))));
+        assert_eq!(count.get(), 2);
+    }
+
+    #[test]
+    fn test_fence_callback() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let completed = Rc::new(Cell::new(0));
+        let completed_by_callback = completed.clone();
+        virtio_gpu.on_fence_complete(move |fence| completed_by_callback.set(fence.fence_id));
+
+        let fence = RutabagaFenceData {
+            flags: VIRTIO_GPU_FLAG_FENCE,
+            fence_id: 5,
+            ctx_id: 0,
+            fence_ctx_idx: 0,
+        };
+        virtio_gpu.create_fence(fence).unwrap();
+        assert_eq!(virtio_gpu.pending_fences((0, 0)), 1);
+        assert_eq!(virtio_gpu.process_fences(), 1);
+        assert_eq!(completed.get(), 5);
+        assert_eq!(virtio_gpu.pending_fences((0, 0)), 0);
+        // nothing new completed
+        assert_eq!(virtio_gpu.process_fences(), 0);
+
+        // rings of contexts need VIRTIO_GPU_F_CONTEXT_INIT
+        let ring_fence = RutabagaFenceData {
+            flags: VIRTIO_GPU_FLAG_FENCE | VIRTIO_GPU_FLAG_INFO_RING_IDX,
+            fence_id: 6,
+            ctx_id: 1,
+            fence_ctx_idx: 2,
+        };
+        assert_eq!(fence_ring(&ring_fence), (1, 2));
+        assert!(matches!(virtio_gpu.create_fence(ring_fence), Err(VirtioGpuResponse::ErrUnspec)));
+        assert_eq!(virtio_gpu.pending_fences((1, 2)), 0);
+    }
+
+    #[test]
+    fn test_cursor_queue() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let mut cursor: virtio_gpu_update_cursor = Default::default();
+        cursor.hdr.type_ = Le32::from(VIRTIO_GPU_CMD_MOVE_CURSOR);
+        cursor.pos.x = Le32::from(12);
+        cursor.pos.y = Le32::from(34);
+        let cmd = VirtioGpuCommand::decode_cursor_from_slice(cursor.as_slice()).unwrap();
+        virtio_gpu.process_cursor(&cmd).unwrap();
+        assert_eq!(virtio_gpu.cursor_position, (12, 34));
+
+        // the control queue's commands don't belong here
+        let mut hdr: virtio_gpu_ctrl_hdr = Default::default();
+        hdr.type_ = Le32::from(VIRTIO_GPU_CMD_GET_DISPLAY_INFO);
+        assert!(matches!(
+            VirtioGpuCommand::decode_cursor_from_slice(hdr.as_slice()),
+            Err(VirtioGpuCommandDecodeError::InvalidCommand(VIRTIO_GPU_CMD_GET_DISPLAY_INFO))
+        ));
+        let cmd = VirtioGpuCommand::CmdGetDisplayInfo(hdr);
+        assert!(matches!(virtio_gpu.process_cursor(&cmd), Err(VirtioGpuResponse::ErrUnspec)));
+    }
+
+    #[test]
+    fn test_headless_display() {
+        struct Frames(Rc<Cell<u64>>);
+        impl PresentHook for Frames {
+            fn after_present(&mut self, _: &PresentInfo) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            display_width: 64,
+            display_height: 64,
+            ..Default::default()
+        };
+        let frames = Rc::new(Cell::new(0));
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_headless)
+            .unwrap()
+            .with_present_hook(Frames(frames.clone()));
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.resource_id = Le32::from(1);
+        create.width = Le32::from(64);
+        create.height = Le32::from(64);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        set_scanout.r.width = Le32::from(64);
+        set_scanout.r.height = Le32::from(64);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+        let mut flush: virtio_gpu_resource_flush = Default::default();
+        flush.resource_id = Le32::from(1);
+        flush.r.width = Le32::from(64);
+        flush.r.height = Le32::from(64);
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+
+        // the cursor is a subsurface of the scanout, drawn nowhere either
+        let mut cursor: virtio_gpu_update_cursor = Default::default();
+        cursor.resource_id = Le32::from(1);
+        cursor.pos.x = Le32::from(8);
+        virtio_gpu.cmd_update_cursor(cursor).unwrap();
+        assert!(virtio_gpu.cursor_surface_id.is_some());
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+
+        // the frames drawn nowhere are presented all the same
+        assert_eq!(virtio_gpu.frames_presented, 2);
+        assert_eq!(frames.get(), 2);
+    }
+
+    #[test]
+    fn test_cursor_hotspot() {
+        let gpu_parameter = GpuParameter {
+            display_width: 64,
+            display_height: 64,
+            ..Default::default()
+        };
+        let mut backing = vec![0u8; 64 * 64 * 4];
+        let mut virtio_gpu = scanout_fixture(gpu_parameter, GpuDisplay::open_headless, &mut backing);
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM);
+        create.resource_id = Le32::from(2);
+        create.width = Le32::from(16);
+        create.height = Le32::from(16);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+
+        // the hotspot lands on the pointer
+        let mut cursor: virtio_gpu_update_cursor = Default::default();
+        cursor.resource_id = Le32::from(2);
+        cursor.pos.x = Le32::from(20);
+        cursor.pos.y = Le32::from(30);
+        cursor.hot_x = Le32::from(4);
+        cursor.hot_y = Le32::from(8);
+        virtio_gpu.cmd_update_cursor(cursor).unwrap();
+        assert_eq!(virtio_gpu.cursor_position, (20, 30));
+        assert_eq!(virtio_gpu.cursor_surface_position(), (16, 22));
+
+        // moves keep it, up to the top left corner
+        cursor.pos.x = Le32::from(2);
+        virtio_gpu.cmd_move_curosr(cursor).unwrap();
+        assert_eq!(virtio_gpu.cursor_surface_position(), (0, 22));
+    }
+
+    #[test]
+    fn test_display_backend() {
+        assert_eq!(DisplayBackend::detect(true, false, false), DisplayBackend::Wayland);
+        assert_eq!(DisplayBackend::detect(true, true, false), DisplayBackend::Wayland);
+        assert_eq!(DisplayBackend::detect(false, true, false), DisplayBackend::X);
+        assert_eq!(DisplayBackend::detect(false, false, true), DisplayBackend::Headless);
+        // GLX goes through Xwayland, and fails without it
+        assert_eq!(DisplayBackend::detect(true, true, true), DisplayBackend::X);
+        assert_eq!(DisplayBackend::detect(true, false, true), DisplayBackend::Wayland);
+
+        let gpu_parameter = GpuParameter::default();
+        assert_eq!(gpu_parameter.display_backend, DisplayBackend::Auto);
+        assert_ne!(gpu_parameter.resolve_display_backend(), DisplayBackend::Auto);
+        let gpu_parameter = GpuParameter {
+            display_backend: DisplayBackend::Headless,
+            ..Default::default()
+        };
+        assert_eq!(gpu_parameter.resolve_display_backend(), DisplayBackend::Headless);
+    }
+
+    #[test]
+    fn test_event_sources() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            frame_interval: Some(Duration::from_millis(1)),
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let sources: Vec<GpuEventSource> = virtio_gpu.event_sources().into_iter().map(|(source, _)| source).collect();
+        assert_eq!(sources, vec![GpuEventSource::Display, GpuEventSource::FrameTimer]);
+
+        let mut cursor: virtio_gpu_update_cursor = Default::default();
+        cursor.pos.x = Le32::from(10);
+        virtio_gpu.cmd_move_curosr(cursor).unwrap();
+        assert!(virtio_gpu.next_frame().is_some());
+
+        // handling the timer before it expires doesn't block
+        virtio_gpu.handle_event(GpuEventSource::FrameTimer);
+        assert!(virtio_gpu.next_frame().is_none());
+    }
+
+    #[test]
+    fn test_flush_coalescing() {
+        struct Damage(Rc<RefCell<Vec<DamageRect>>>);
+        impl PresentHook for Damage {
+            fn after_present(&mut self, info: &PresentInfo) {
+                self.0.borrow_mut().push(info.damage);
+            }
+        }
+
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            display_width: 64,
+            display_height: 64,
+            frame_interval: Some(Duration::from_millis(16)),
+            ..Default::default()
+        };
+        let presented = Rc::new(RefCell::new(Vec::new()));
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub)
+            .unwrap()
+            .with_present_hook(Damage(presented.clone()));
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(64);
+        create.height = Le32::from(64);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+
+        // a compositor flushing two tiles
+        let mut flush: virtio_gpu_resource_flush = Default::default();
+        flush.resource_id = Le32::from(1);
+        flush.r.width = Le32::from(32);
+        flush.r.height = Le32::from(32);
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        flush.r.x = Le32::from(32);
+        flush.r.y = Le32::from(32);
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        assert!(presented.borrow().is_empty());
+        assert!(virtio_gpu.next_frame().is_some());
+
+        virtio_gpu.process_frame();
+        assert_eq!(*presented.borrow(), vec![(0, 0, 64, 64)]);
+        assert!(virtio_gpu.next_frame().is_none());
+
+        // the scanout is disabled before the frame
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        set_scanout.resource_id = Le32::from(0);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+        virtio_gpu.process_frame();
+        assert_eq!(presented.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_transfer_with_backing_stride() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+
+        // rows of 6 pixels in the guest for a 4 pixels wide resource
+        let mut backing: Vec<u8> = (0..48).collect();
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+
+        let mut transfer = Transfer3D::new_2d(1, 1, 2, 1);
+        transfer.stride = 24;
+        transfer.offset = 24 + 4;
+        virtio_gpu.rutabaga.transfer_write(0, 1, transfer).unwrap();
+
+        let mut pixels = vec![0u8; 32];
+        let mut read = Transfer3D::new_2d(0, 0, 4, 2);
+        read.stride = 16;
+        virtio_gpu.rutabaga.transfer_read(0, 1, read, Some(data_model::VolatileSlice::new(&mut pixels))).unwrap();
+        assert_eq!(&pixels[..20], &[0u8; 20][..]);
+        assert_eq!(&pixels[20..28], &backing[28..36]);
+        assert_eq!(&pixels[28..], &[0u8; 4][..]);
+    }
+
+    #[test]
+    fn test_transfer_nv12() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_NV12);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        assert_eq!(virtio_gpu.resources.get(&1).unwrap().size(), 12);
+
+        let mut backing: Vec<u8> = (0..12).collect();
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+
+        // the right half of the luma plane and the chroma sample covering it
+        virtio_gpu.rutabaga.transfer_write(0, 1, Transfer3D::new_2d(2, 0, 2, 2)).unwrap();
+
+        let mut pixels = vec![0u8; 12];
+        virtio_gpu.rutabaga.transfer_read(0, 1, Transfer3D::new_2d(0, 0, 4, 2), Some(data_model::VolatileSlice::new(&mut pixels))).unwrap();
+        assert_eq!(pixels, vec![0, 0, 2, 3, 0, 0, 6, 7, 0, 0, 10, 11]);
+    }
+
+    #[test]
+    fn test_read_yuv_resource() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_NV12);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        create.resource_id = Le32::from(2);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+
+        // white luma, neutral chroma
+        let mut backing = vec![235u8, 235, 235, 235, 235, 235, 235, 235, 128, 128, 128, 128];
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        virtio_gpu.rutabaga.transfer_write(0, 1, Transfer3D::new_2d(0, 0, 4, 2)).unwrap();
+
+        let pixels = virtio_gpu.read_yuv_resource(1, 4, 2, 16).unwrap().unwrap();
+        assert_eq!(pixels[..], [255, 255, 255, 0xff].repeat(8)[..]);
+        assert!(matches!(virtio_gpu.read_yuv_resource(1, 8, 2, 32), Err(VirtioGpuResponse::ErrInvalidParameter)));
+        assert!(virtio_gpu.read_yuv_resource(2, 4, 2, 16).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_blit_scanout() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            display_width: 2,
+            display_height: 2,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mut dst = [0u8; 16];
+        assert!(matches!(
+            virtio_gpu.blit_scanout(VolatileSlice::from(&mut dst[..]), 8, BlitFormat::Rgbx),
+            Err(VirtioGpuResponse::ErrInvalidScanoutId)
+        ));
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(2);
+        create.height = Le32::from(2);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        let mut backing = [1u8, 2, 3, 0].repeat(4);
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        virtio_gpu.rutabaga.transfer_write(0, 1, Transfer3D::new_2d(0, 0, 2, 2)).unwrap();
+        virtio_gpu.scanout_resource_id = NonZeroU32::new(1);
+
+        // blue 1, green 2, red 3, into padded rows
+        let mut dst = [0u8; 16];
+        assert_eq!(virtio_gpu.blit_scanout(VolatileSlice::from(&mut dst[..]), 8, BlitFormat::Rgb).unwrap(), (2, 2));
+        assert_eq!(dst, [3, 2, 1, 3, 2, 1, 0, 0, 3, 2, 1, 3, 2, 1, 0, 0]);
+        virtio_gpu.blit_scanout(VolatileSlice::from(&mut dst[..]), 8, BlitFormat::Bgrx).unwrap();
+        assert_eq!(dst[..], [1, 2, 3, 0xff].repeat(4)[..]);
+        // the rows don't fit
+        assert!(matches!(
+            virtio_gpu.blit_scanout(VolatileSlice::from(&mut dst[..]), 4, BlitFormat::Rgbx),
+            Err(VirtioGpuResponse::ErrInvalidParameter)
+        ));
+
+        let image = virtio_gpu.capture_scanout(0).unwrap();
+        assert_eq!((image.width, image.height), (2, 2));
+        assert_eq!(image.pixels, [3, 2, 1, 0xff].repeat(4));
+        assert!(matches!(virtio_gpu.capture_scanout(1), Err(VirtioGpuResponse::ErrInvalidScanoutId)));
+
+        // a scanout the host doesn't present is captured at the rect the guest shows on it
+        backing[12..].copy_from_slice(&[4, 5, 6, 0]);
+        virtio_gpu.rutabaga.transfer_write(0, 1, Transfer3D::new_2d(0, 0, 2, 2)).unwrap();
+        virtio_gpu.set_scanouts(&[(2, 2), (1, 1)]).unwrap();
+        assert!(matches!(virtio_gpu.capture_scanout(1), Err(VirtioGpuResponse::ErrInvalidScanoutId)));
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.scanout_id = Le32::from(1);
+        set_scanout.resource_id = Le32::from(1);
+        set_scanout.r.x = Le32::from(1);
+        set_scanout.r.y = Le32::from(1);
+        set_scanout.r.width = Le32::from(1);
+        set_scanout.r.height = Le32::from(1);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+        let image = virtio_gpu.capture_scanout(1).unwrap();
+        assert_eq!((image.width, image.height), (1, 1));
+        assert_eq!(image.pixels, [6, 5, 4, 0xff]);
+
+        // an unplugged one has nothing left to capture
+        virtio_gpu.set_scanouts(&[(2, 2), (0, 0)]).unwrap();
+        assert!(matches!(virtio_gpu.capture_scanout(1), Err(VirtioGpuResponse::ErrInvalidScanoutId)));
+    }
+
+    #[test]
+    fn test_frame_recording() {
+        let gpu_parameter = GpuParameter {
+            display_width: 2,
+            display_height: 2,
+            ..Default::default()
+        };
+        // red
+        let mut backing = [0u8, 0, 255, 0].repeat(4);
+        let buffer = SharedBuffer::default();
+        let recorder = FrameRecorder::new(buffer.clone()).every(NonZeroU32::new(2).unwrap());
+        let mut virtio_gpu =
+            scanout_fixture(gpu_parameter, GpuDisplay::open_stub, &mut backing).with_frame_recorder(recorder);
+
+        let mut flush: virtio_gpu_resource_flush = Default::default();
+        flush.resource_id = Le32::from(1);
+        flush.r.width = Le32::from(2);
+        flush.r.height = Le32::from(2);
+        for _ in 0..3 {
+            virtio_gpu.cmd_flush_resource(flush).unwrap();
+        }
+
+        // the first and third frames
+        let recorder = virtio_gpu.take_frame_recorder().unwrap();
+        assert_eq!(recorder.frames_recorded(), 2);
+        recorder.finish().unwrap();
+        let stream = buffer.0.lock().unwrap();
+        assert!(stream.starts_with(b"YUV4MPEG2 W2 H2 "));
+        assert!(stream.ends_with(b"FRAME\n\x52\x52\x52\x52\x5a\xf0"));
+
+        // presenting goes on without it
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+    }
+
+    #[test]
+    fn test_present_hooks() {
+        struct Recorder(Rc<RefCell<Vec<(&'static str, PresentInfo)>>>);
+        impl PresentHook for Recorder {
+            fn before_present(&mut self, info: &PresentInfo) {
+                self.0.borrow_mut().push(("before", *info));
+            }
+            fn after_present(&mut self, info: &PresentInfo) {
+                self.0.borrow_mut().push(("after", *info));
+            }
+        }
+
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            display_width: 64,
+            display_height: 64,
+            ..Default::default()
+        };
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub)
+            .unwrap()
+            .with_present_hook(Recorder(calls.clone()));
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.resource_id = Le32::from(1);
+        create.width = Le32::from(64);
+        create.height = Le32::from(64);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        // too small for the display, its flips fail
+        create.resource_id = Le32::from(2);
+        create.width = Le32::from(32);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+        let mut flush: virtio_gpu_resource_flush = Default::default();
+        flush.resource_id = Le32::from(1);
+        flush.r.width = Le32::from(8);
+        flush.r.height = Le32::from(4);
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        set_scanout.resource_id = Le32::from(2);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+        flush.resource_id = Le32::from(2);
+        assert!(virtio_gpu.cmd_flush_resource(flush).is_err());
+
+        let calls = calls.borrow();
+        let frames: Vec<(&str, u64)> = calls.iter().map(|(hook, info)| (*hook, info.frame)).collect();
+        assert_eq!(frames, vec![("before", 0), ("after", 0), ("before", 1), ("after", 1), ("before", 2)]);
+        assert_eq!(calls[0].1, PresentInfo { scanout_id: 0, frame: 0, damage: (0, 0, 8, 4) });
+    }
+
+    #[test]
+    fn test_scanout_forwarder() {
+        struct Forwarder(Rc<RefCell<Vec<String>>>);
+        impl ScanoutForwarder for Forwarder {
+            fn set_scanout(&mut self, scanout_id: u32, scanout: &DmabufScanout, _: &RutabagaHandle) -> io::Result<()> {
+                self.0.borrow_mut().push(format!("set {} {}x{}", scanout_id, scanout.width, scanout.height));
+                Ok(())
+            }
+            fn disable_scanout(&mut self, scanout_id: u32) -> io::Result<()> {
+                self.0.borrow_mut().push(format!("disable {}", scanout_id));
+                Ok(())
+            }
+            fn flush(&mut self, scanout_id: u32, rect: DamageRect) -> io::Result<()> {
+                self.0.borrow_mut().push(format!("flush {} {:?}", scanout_id, rect));
+                Ok(())
+            }
+        }
+
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            display_width: 64,
+            display_height: 64,
+            ..Default::default()
+        };
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub)
+            .unwrap()
+            .with_scanout_forwarder(Forwarder(calls.clone()));
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.resource_id = Le32::from(1);
+        create.width = Le32::from(64);
+        create.height = Le32::from(64);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+        let mut flush: virtio_gpu_resource_flush = Default::default();
+        flush.resource_id = Le32::from(1);
+        flush.r.width = Le32::from(64);
+        flush.r.height = Le32::from(64);
+
+        // the 2D renderer exports nothing, the scanout is copied
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        assert!(calls.borrow().is_empty());
+        assert_eq!(virtio_gpu.forwarded_resource_id, None);
+
+        // once the VMM holds the dmabuf, flushes only say what the guest flushed
+        let scanout = DmabufScanout {
+            width: 64,
+            height: 64,
+            stride: 256,
+            ..Default::default()
+        };
+        let dmabuf = RutabagaHandle {
+            os_handle:   File::open("/dev/null").unwrap(),
+            handle_type: RUTABAGA_MEM_HANDLE_TYPE_DMABUF,
+        };
+        assert!(virtio_gpu.forward_exported(1, Some((scanout, dmabuf)), (0, 0, 64, 64)));
+        flush.r.x = Le32::from(8);
+        flush.r.y = Le32::from(4);
+        flush.r.width = Le32::from(16);
+        flush.r.height = Le32::from(2);
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        set_scanout.resource_id = Le32::from(0);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+        assert_eq!(
+            *calls.borrow(),
+            vec!["set 0 64x64", "flush 0 (0, 0, 64, 64)", "flush 0 (8, 4, 16, 2)", "disable 0"]
+        );
+    }
+
+    #[test]
+    fn test_restore_surfaces() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            display_width: 64,
+            display_height: 64,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        // nothing shown, nothing to restore
+        virtio_gpu.restore_surfaces();
+        assert_eq!(virtio_gpu.scanout_surface_id, None);
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(64);
+        create.height = Le32::from(64);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        set_scanout.r.width = Le32::from(64);
+        set_scanout.r.height = Le32::from(64);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+
+        // what a lost connection leaves behind, shown again as a frame of its own
+        virtio_gpu.scanout_surface_id = None;
+        virtio_gpu.restore_surfaces();
+        assert!(virtio_gpu.scanout_surface_id.is_some());
+        assert_eq!(virtio_gpu.scanout_resource_id, NonZeroU32::new(1));
+        assert_eq!(virtio_gpu.scanout_rect, (0, 0, 64, 64));
+        assert_eq!(virtio_gpu.frames_presented, 1);
+    }
+
+    #[test]
+    fn test_input_device() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            deterministic: true,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        // the stub has no input to send, it drops the device
+        let mut receiver = virtio_gpu.add_input_device(EventDeviceKind::Keyboard).unwrap();
+        assert_eq!(receiver.try_recv().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    /// Keeps the frames flipped, as an embedder drawing them in its own window would.
+    struct RecordingBackend {
+        next_surface_id: u32,
+        /// The width and the pixels of every surface
+        surfaces:        BTreeMap<u32, (u32, Vec<u8>)>,
+        frames:          Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl RecordingBackend {
+        fn new(frames: Arc<Mutex<Vec<Vec<u8>>>>) -> Self {
+            RecordingBackend {
+                next_surface_id: 1,
+                surfaces: BTreeMap::new(),
+                frames,
+            }
+        }
+    }
+
+    impl GpuDisplayBackend for RecordingBackend {
+        fn dispatch_events(&mut self) {}
+
+        fn create_surface(&mut self, _parent_surface_id: Option<u32>, width: u32, height: u32) -> Result<u32, GpuDisplayError> {
+            let surface_id = self.next_surface_id;
+            self.next_surface_id += 1;
+            self.surfaces.insert(surface_id, (width, vec![0; width as usize * height as usize * 4]));
+            Ok(surface_id)
+        }
+
+        fn release_surface(&mut self, surface_id: u32) {
+            self.surfaces.remove(&surface_id);
+        }
+
+        fn framebuffer(&mut self, surface_id: u32) -> Option<GpuDisplayFramebuffer<'_>> {
+            let (width, buffer) = self.surfaces.get_mut(&surface_id)?;
+            Some(GpuDisplayFramebuffer::new(data_model::VolatileSlice::new(buffer), *width * 4, 4))
+        }
+
+        fn flip(&mut self, surface_id: u32) {
+            if let Some((_, buffer)) = self.surfaces.get(&surface_id) {
+                self.frames.lock().unwrap().push(buffer.clone());
+            }
+        }
+    }
+
+    #[test]
+    fn test_custom_display_backend() {
+        let gpu_parameter = GpuParameter {
+            display_width: 4,
+            display_height: 2,
+            deterministic: true,
+            ..Default::default()
+        };
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let display_frames = frames.clone();
+        let mut backing = vec![0u8; 32];
+        backing[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        let _virtio_gpu = scanout_fixture(
+            gpu_parameter,
+            move || {
+                Ok(GpuDisplay::from_backend(Box::new(RecordingBackend::new(display_frames.clone()))))
+            },
+            &mut backing,
+        );
+
+        // the backend was handed the frame the guest drew
+        let frames = frames.lock().unwrap();
+        assert_eq!(&frames.last().unwrap()[4..8], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_flush_damage() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            display_width: 4,
+            display_height: 2,
+            deterministic: true,
+            ..Default::default()
+        };
+        let checksums = FrameChecksums::default();
+        let display_checksums = checksums.clone();
+        let mut virtio_gpu =
+            VirtioGpu::with_display(gpu_parameter, move || GpuDisplay::open_crc(display_checksums.clone())).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        let mut backing = vec![0u8; 32];
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+        let surface_id = virtio_gpu.scanout_surface_id.unwrap();
+
+        let mut flush: virtio_gpu_resource_flush = Default::default();
+        flush.resource_id = Le32::from(1);
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        assert_eq!(checksums.latest(surface_id), Some(crc32(&[0; 32])));
+
+        // only the transferred pixel is read back, the rest of the frame is kept
+        backing[20..24].copy_from_slice(&[1, 2, 3, 4]);
+        let mut transfer: virtio_gpu_transfer_to_host_2d = Default::default();
+        transfer.resource_id = Le32::from(1);
+        transfer.r.x = Le32::from(1);
+        transfer.r.y = Le32::from(1);
+        transfer.r.width = Le32::from(1);
+        transfer.r.height = Le32::from(1);
+        transfer.offset = Le64::from(20);
+        virtio_gpu.cmd_transfer_to_host_2d(transfer).unwrap();
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        let mut frame = [0u8; 32];
+        frame[20..24].copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(checksums.latest(surface_id), Some(crc32(&frame)));
+
+        // nothing changed, nothing is presented
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        assert_eq!(checksums.history(surface_id).len(), 2);
+    }
+
+    #[test]
+    fn test_flush_cadence() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            adaptive_sync: Some((48, 120)),
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(64);
+        create.height = Le32::from(64);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+
+        let start = Instant::now();
+        virtio_gpu.track_flush_cadence(start);
+        assert_eq!(virtio_gpu.flush_interval(), None);
+        virtio_gpu.track_flush_cadence(start + Duration::from_millis(16));
+        assert_eq!(virtio_gpu.flush_interval(), Some(Duration::from_millis(16)));
+        // faster than the highest refresh rate
+        virtio_gpu.track_flush_cadence(start + Duration::from_millis(20));
+        assert_eq!(virtio_gpu.flush_interval(), Some(Duration::from_secs(1) / 120));
+        // an idle guest keeps the last cadence
+        virtio_gpu.track_flush_cadence(start + Duration::from_secs(1));
+        assert_eq!(virtio_gpu.flush_interval(), Some(Duration::from_secs(1) / 120));
+
+        let get_edid: virtio_gpu_cmd_get_edid = Default::default();
+        match virtio_gpu.cmd_get_edid(get_edid) {
+            Ok(VirtioGpuResponse::OkEdid { edid, .. }) => assert_eq!(edid[24] & 0x01, 0x01),
+            _ => panic!("no EDID"),
+        }
+    }
+
+    #[test]
+    fn test_scanout_edid() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            display_width: 1280,
+            display_height: 800,
+            refresh_rate: 75,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let output = |width, height| GpuDisplayOutput { x: 0, y: 0, width, height };
+        virtio_gpu.handle_host_outputs(&[output(1280, 800), output(2560, 1440), output(0, 0)]);
+
+        let get_edid = |virtio_gpu: &mut VirtioGpu, scanout: u32| {
+            let mut cmd: virtio_gpu_cmd_get_edid = Default::default();
+            cmd.scanout = Le32::from(scanout);
+            match virtio_gpu.cmd_get_edid(cmd) {
+                Ok(VirtioGpuResponse::OkEdid { size, edid }) => Some(edid[..size as usize].to_vec()),
+                Err(VirtioGpuResponse::ErrInvalidScanoutId) => None,
+                _ => panic!("unexpected EDID response"),
+            }
+        };
+        assert_eq!(get_edid(&mut virtio_gpu, 0).unwrap(), EdidInfo::new(1280, 800, 75).unwrap().with_serial(1).generate());
+        assert_eq!(get_edid(&mut virtio_gpu, 1).unwrap(), EdidInfo::new(2560, 1440, 75).unwrap().with_serial(2).generate());
+        // a disabled scanout offers the primary mode
+        assert_eq!(get_edid(&mut virtio_gpu, 2).unwrap(), EdidInfo::new(1280, 800, 75).unwrap().with_serial(3).generate());
+        assert!(get_edid(&mut virtio_gpu, 3).is_none());
+    }
+
+    #[test]
+    fn test_scanout_topology() {
+        let gpu_parameter = GpuParameter {
+            display_width: 1280,
+            display_height: 800,
+            refresh_rate: 60,
+            ..Default::default()
+        };
+        let mut backing = vec![0u8; 1280 * 800 * 4];
+        let mut virtio_gpu = scanout_fixture(gpu_parameter, GpuDisplay::open_stub, &mut backing);
+        let get_edid = |virtio_gpu: &mut VirtioGpu, scanout: u32| {
+            let mut cmd: virtio_gpu_cmd_get_edid = Default::default();
+            cmd.scanout = Le32::from(scanout);
+            match virtio_gpu.cmd_get_edid(cmd) {
+                Ok(VirtioGpuResponse::OkEdid { size, edid }) => edid[..size as usize].to_vec(),
+                _ => panic!("unexpected EDID response"),
+            }
+        };
+        let primary = ScanoutConfig { width: 1280, height: 800, enabled: true, edid: None };
+        let monitor_edid = EdidInfo::new(1920, 1080, 60).unwrap().with_serial(42).generate();
+        let monitor = ScanoutConfig {
+            width: 1920,
+            height: 1080,
+            enabled: true,
+            edid: Some(monitor_edid.clone()),
+        };
+
+        // a monitor plugged in, with its own EDID
+        virtio_gpu.set_scanout_topology(&[primary.clone(), monitor.clone()]).unwrap();
+        assert_eq!(virtio_gpu.events_read(), VIRTIO_GPU_EVENT_DISPLAY);
+        assert_eq!(virtio_gpu.display_info(), &[(1280, 800), (1920, 1080)]);
+        assert_eq!(get_edid(&mut virtio_gpu, 0), EdidInfo::new(1280, 800, 60).unwrap().with_serial(1).generate());
+        assert_eq!(get_edid(&mut virtio_gpu, 1), monitor_edid);
+
+        // resizing another scanout leaves the monitor's EDID alone
+        virtio_gpu.set_scanouts(&[(1024, 768), (1920, 1080)]).unwrap();
+        assert_eq!(get_edid(&mut virtio_gpu, 0), EdidInfo::new(1024, 768, 60).unwrap().with_serial(1).generate());
+        assert_eq!(get_edid(&mut virtio_gpu, 1), monitor_edid);
+        virtio_gpu.set_scanout_topology(&[primary.clone(), monitor.clone()]).unwrap();
+
+        // a new EDID alone is an event as well
+        virtio_gpu.clear_events(VIRTIO_GPU_EVENT_DISPLAY);
+        let other_edid = EdidInfo::new(1920, 1080, 60).unwrap().with_serial(43).generate();
+        let other = ScanoutConfig { edid: Some(other_edid.clone()), ..monitor.clone() };
+        virtio_gpu.set_scanout_topology(&[primary.clone(), other.clone()]).unwrap();
+        assert_eq!(virtio_gpu.events_read(), VIRTIO_GPU_EVENT_DISPLAY);
+        assert_eq!(get_edid(&mut virtio_gpu, 1), other_edid);
+
+        // unplugged, the scanout is disabled
+        virtio_gpu.clear_events(VIRTIO_GPU_EVENT_DISPLAY);
+        let unplugged = ScanoutConfig { enabled: false, edid: None, ..monitor.clone() };
+        virtio_gpu.set_scanout_topology(&[primary.clone(), unplugged]).unwrap();
+        assert_eq!(virtio_gpu.events_read(), VIRTIO_GPU_EVENT_DISPLAY);
+        assert_eq!(virtio_gpu.display_info(), &[(1280, 800), (0, 0)]);
+        assert_eq!(get_edid(&mut virtio_gpu, 1), EdidInfo::new(1280, 800, 60).unwrap().with_serial(2).generate());
+
+        // an enabled scanout needs a size and EDIDs have to be valid
+        let zero_sized = ScanoutConfig { width: 0, ..monitor.clone() };
+        assert!(virtio_gpu.set_scanout_topology(&[primary.clone(), zero_sized]).is_err());
+        let bad_edid = ScanoutConfig { edid: Some(vec![0; 128]), ..monitor };
+        assert!(virtio_gpu.set_scanout_topology(&[primary, bad_edid]).is_err());
+        assert!(virtio_gpu.set_scanout_topology(&[]).is_err());
+        assert_eq!(virtio_gpu.display_info(), &[(1280, 800), (0, 0)]);
+
+        // the monitors stay plugged in for the next driver
+        let primary = ScanoutConfig { width: 1280, height: 800, enabled: true, edid: None };
+        virtio_gpu.set_scanout_topology(&[primary, other]).unwrap();
+        virtio_gpu.reset();
+        virtio_gpu.ack_features(virtio_gpu.supported_features());
+        assert_eq!(virtio_gpu.display_info(), &[(1280, 800), (1920, 1080)]);
+        assert_eq!(get_edid(&mut virtio_gpu, 1), other_edid);
+    }
+
+    #[test]
+    fn test_renderer_info() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let info = virtio_gpu.renderer_info();
+        assert!(!info.virgl);
+        assert_eq!(info.num_capsets, 0);
+        assert_eq!(info.features, 1 << VIRTIO_GPU_F_EDID | 1 << VIRTIO_GPU_F_RESOURCE_UUID);
+        // the stub shows nothing, so it takes the copy paths
+        assert_eq!(info.display, Default::default());
+    }
+
+    #[test]
+    fn test_capsets() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        // the 2D renderer has none
+        assert!(virtio_gpu.capsets.is_empty());
+
+        // the guest sees the capsets the renderer was queried for, by their own index
+        virtio_gpu.capsets = vec![(VIRTIO_GPU_CAPSET_VIRGL2, 2, 1024)];
+        virtio_gpu.acked_features |= 1 << VIRTIO_GPU_F_VIRGL;
+        assert_eq!(virtio_gpu.num_capsets(), 1);
+        let mut info: virtio_gpu_get_capset_info = Default::default();
+        assert!(matches!(
+            virtio_gpu.cmd_get_capset_info(info),
+            Ok(VirtioGpuResponse::OkCapsetInfo { capset_id: VIRTIO_GPU_CAPSET_VIRGL2, version: 2, size: 1024 })
+        ));
+        info.capset_index = Le32::from(1);
+        assert!(matches!(virtio_gpu.cmd_get_capset_info(info), Err(VirtioGpuResponse::ErrInvalidParameter)));
+
+        // capsets missing from the list or newer than it says are refused before the renderer
+        let mut capset: virtio_gpu_get_capset = Default::default();
+        capset.capset_id = Le32::from(VIRTIO_GPU_CAPSET_VIRGL);
+        capset.capset_version = Le32::from(1);
+        assert!(matches!(virtio_gpu.cmd_get_capset(capset), Err(VirtioGpuResponse::ErrInvalidParameter)));
+        capset.capset_id = Le32::from(VIRTIO_GPU_CAPSET_VIRGL2);
+        capset.capset_version = Le32::from(3);
+        assert!(matches!(virtio_gpu.cmd_get_capset(capset), Err(VirtioGpuResponse::ErrInvalidParameter)));
+    }
+
+    #[test]
+    fn test_validate_parameters() {
+        assert!(GpuParameter::default().validate().is_ok());
+
+        let gpu_parameter = GpuParameter {
+            display_width: 0,
+            renderer_use_egl: false,
+            renderer_use_glx: false,
+            edid: Some(vec![0; 100]),
+            adaptive_sync: Some((144, 48)),
+            ..Default::default()
+        };
+        let ConfigError(problems) = gpu_parameter.validate().unwrap_err();
+        let mut problems = problems.iter();
+        assert!(matches!(problems.next(), Some(ConfigProblem::ZeroSizedDisplay { width: 0, height: 1080 })));
+        // the GL backends only matter to virglrenderer
+        if cfg!(feature = "virgl_renderer") {
+            assert!(matches!(problems.next(), Some(ConfigProblem::NoGlBackend)));
+        }
+        assert!(matches!(problems.next(), Some(ConfigProblem::InvalidEdid(EdidError::InvalidLength(100)))));
+        assert!(matches!(problems.next(), Some(ConfigProblem::InvalidRefreshRange { min: 144, max: 48 })));
+        assert!(problems.next().is_none());
+
+        // no EDID describes a display that large
+        let gpu_parameter = GpuParameter {
+            display_width: 4096,
+            ..Default::default()
+        };
+        let ConfigError(problems) = gpu_parameter.validate().unwrap_err();
+        assert!(matches!(problems[..], [ConfigProblem::InvalidEdid(EdidError::UnsupportedMode { width: 4096, .. })]));
+        assert!(GpuParameter { use_edid: false, ..gpu_parameter }.validate().is_ok());
+
+        // GLX can't render for the stub, and the display problems are reported with the others
+        let gpu_parameter = GpuParameter {
+            frame_interval: Some(Duration::from_secs(0)),
+            ..Default::default()
+        };
+        match VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub) {
+            Err(VirtioGpuError::Config(ConfigError(problems))) => {
+                let mut problems = problems.iter();
+                assert!(matches!(problems.next(), Some(ConfigProblem::ZeroFrameInterval)));
+                if cfg!(feature = "virgl_renderer") {
+                    assert!(matches!(problems.next(), Some(ConfigProblem::GlxWithoutX11)));
+                }
+                assert!(problems.next().is_none());
+            }
+            _ => panic!("invalid parameters were accepted"),
+        }
+    }
+
+    #[test]
+    fn test_startup_errors() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        // the renderer is up, only the display is reported
+        match VirtioGpu::with_display(gpu_parameter, || Err(GpuDisplayError::Connect)) {
+            Err(VirtioGpuError::Display(_)) => {}
+            _ => panic!("the display error wasn't reported"),
+        }
+    }
+
+    #[test]
+    fn test_resource_pool() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            resource_pool_size: 2,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(64);
+        create.height = Le32::from(64);
+        let mut unref: virtio_gpu_resource_unref = Default::default();
+        for resource_id in 1..=3 {
+            create.resource_id = Le32::from(resource_id);
+            virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        }
+        let mut backing = vec![0xffu8; 64 * 64 * 4];
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(2);
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        let mut transfer: virtio_gpu_transfer_to_host_2d = Default::default();
+        transfer.resource_id = Le32::from(2);
+        transfer.r.width = Le32::from(64);
+        transfer.r.height = Le32::from(64);
+        virtio_gpu.cmd_transfer_to_host_2d(transfer).unwrap();
+        for resource_id in 1..=3 {
+            unref.resource_id = Le32::from(resource_id);
+            virtio_gpu.cmd_resource_unref(unref).unwrap();
+        }
+        // the oldest one was freed
+        let pooled: Vec<u32> = virtio_gpu.resource_pool.iter().map(|r| r.resource_id).collect();
+        assert_eq!(pooled, vec![2, 3]);
+        assert!(virtio_gpu.rutabaga.unref_resource(1).is_err());
+
+        // a pooled resource is gone for the guest
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        assert!(matches!(
+            virtio_gpu.cmd_resource_attach_backing(attach, iovecs),
+            Err(VirtioGpuResponse::ErrInvalidResourceId)
+        ));
+        assert!(matches!(
+            virtio_gpu.cmd_transfer_to_host_2d(transfer),
+            Err(VirtioGpuResponse::ErrInvalidResourceId)
+        ));
+
+        // same id and layout, the renderer's resource is taken back, without its contents
+        create.resource_id = Le32::from(2);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        assert!(virtio_gpu.resources.contains_key(&2));
+        assert_eq!(virtio_gpu.resource_pool.len(), 1);
+        let mut pixels = vec![0xffu8; 64 * 64 * 4];
+        let mut read = Transfer3D::new_2d(0, 0, 64, 64);
+        read.stride = 64 * 4;
+        virtio_gpu.rutabaga.transfer_read(0, 2, read, Some(data_model::VolatileSlice::new(&mut pixels))).unwrap();
+        assert!(pixels.iter().all(|&byte| byte == 0));
+
+        // another layout replaces the pooled resource
+        create.resource_id = Le32::from(3);
+        create.width = Le32::from(32);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        assert_eq!(virtio_gpu.resources.get(&3).unwrap().dimensions(), (32, 64));
+        assert!(virtio_gpu.resource_pool.is_empty());
+
+        // a freed resource stays invisible to the guest
+        unref.resource_id = Le32::from(3);
+        virtio_gpu.cmd_resource_unref(unref).unwrap();
+        assert!(matches!(virtio_gpu.cmd_resource_unref(unref), Err(VirtioGpuResponse::ErrInvalidResourceId)));
+    }
+
+    #[test]
+    fn test_reattach_backing() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        let mut detach: virtio_gpu_resource_detach_backing = Default::default();
+        detach.resource_id = Le32::from(1);
+
+        let mut backing: Vec<u8> = (0..32).collect();
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        virtio_gpu.rutabaga.transfer_write(0, 1, Transfer3D::new_2d(0, 0, 4, 2)).unwrap();
+        virtio_gpu.cmd_resource_detach_backing(detach).unwrap();
+
+        // the new backing is split and gets the contents of the old one
+        let mut first = vec![0xffu8; 20];
+        let mut second = vec![0xffu8; 12];
+        let iovecs = vec![
+            RutabagaIovec { base: first.as_mut_ptr() as *mut c_void, len: first.len() },
+            RutabagaIovec { base: second.as_mut_ptr() as *mut c_void, len: second.len() },
+        ];
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        assert_eq!(first, &backing[..20]);
+        assert_eq!(second, &backing[20..]);
+
+        // a backing attached without detaching one first is left alone
+        let mut other = vec![0xffu8; 32];
+        let iovecs = vec![RutabagaIovec { base: other.as_mut_ptr() as *mut c_void, len: other.len() }];
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        assert_eq!(other, vec![0xffu8; 32]);
+    }
+
+    #[test]
+    fn test_detach_backings_in() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        let mut memory = vec![0u8; 64];
+        let base = memory.as_mut_ptr() as usize;
+        for resource_id in 1..=2 {
+            create.resource_id = Le32::from(resource_id);
+            virtio_gpu.cmd_resource_create_2d(create).unwrap();
+            attach.resource_id = Le32::from(resource_id);
+            let half = &mut memory[(resource_id as usize - 1) * 32..];
+            let iovecs = vec![RutabagaIovec { base: half.as_mut_ptr() as *mut c_void, len: 32 }];
+            virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        }
+        assert_eq!(virtio_gpu.resources[&2].backing(), Some(&[(base + 32, 32)][..]));
+        assert_eq!(virtio_gpu.resources_backed_by(base, 64), vec![1, 2]);
+        assert_eq!(virtio_gpu.resources_backed_by(base + 40, 4), vec![2]);
+        assert!(virtio_gpu.resources_backed_by(base + 64, 4096).is_empty());
+
+        // only the resource in the unplugged range loses its backing
+        virtio_gpu.detach_backings_in(base, 16).unwrap();
+        assert!(virtio_gpu.resources[&1].backing().is_none());
+        assert!(virtio_gpu.resources[&2].backing().is_some());
+
+        let mut detach: virtio_gpu_resource_detach_backing = Default::default();
+        detach.resource_id = Le32::from(2);
+        virtio_gpu.cmd_resource_detach_backing(detach).unwrap();
+        assert!(virtio_gpu.resources_backed_by(base, 64).is_empty());
+
+        // the resource is forgotten once the renderer let go of it
+        let mut unref: virtio_gpu_resource_unref = Default::default();
+        unref.resource_id = Le32::from(3);
+        assert!(virtio_gpu.cmd_resource_unref(unref).is_err());
+        unref.resource_id = Le32::from(1);
+        virtio_gpu.cmd_resource_unref(unref).unwrap();
+        assert!(!virtio_gpu.resources.contains_key(&1));
+    }
+
+    #[test]
+    fn test_rebind_backings_failure() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        let mut memory = vec![0u8; 64];
+        let base = memory.as_mut_ptr() as usize;
+        for resource_id in 1..=2 {
+            create.resource_id = Le32::from(resource_id);
+            virtio_gpu.cmd_resource_create_2d(create).unwrap();
+            attach.resource_id = Le32::from(resource_id);
+            let half = &mut memory[(resource_id as usize - 1) * 32..];
+            let iovecs = vec![RutabagaIovec { base: half.as_mut_ptr() as *mut c_void, len: 32 }];
+            virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        }
+
+        // the renderer can't detach the first backing, the second one stays in the old mapping
+        virtio_gpu.rutabaga.unref_resource(1).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let to_guest = |host_addr: usize| Some(GuestAddress((host_addr - base) as u64));
+        assert!(virtio_gpu.rebind_backings(to_guest, &mem).is_err());
+        assert_eq!(virtio_gpu.resources[&2].backing(), Some(&[(base + 32, 32)][..]));
+        assert_eq!(virtio_gpu.resources_backed_by(base, 64), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_qemu_quirks() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            display_width: 4,
+            display_height: 2,
+            quirks: Quirks::qemu(),
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        assert!(matches!(virtio_gpu.cmd_resource_create_2d(create), Err(VirtioGpuResponse::ErrInvalidResourceId)));
+        create.resource_id = Le32::from(1);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        assert!(matches!(virtio_gpu.cmd_resource_create_2d(create), Err(VirtioGpuResponse::ErrInvalidResourceId)));
+
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        let mut backing: Vec<u8> = (0..32).collect();
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        assert!(matches!(virtio_gpu.cmd_resource_attach_backing(attach, iovecs), Err(VirtioGpuResponse::ErrUnspec)));
+
+        // the transfer is clipped to the resource instead of failing
+        let mut transfer: virtio_gpu_transfer_to_host_2d = Default::default();
+        transfer.resource_id = Le32::from(1);
+        transfer.r.x = Le32::from(2);
+        transfer.r.width = Le32::from(8);
+        transfer.r.height = Le32::from(8);
+        transfer.offset = Le64::from(8);
+        virtio_gpu.cmd_transfer_to_host_2d(transfer).unwrap();
+        transfer.r.x = Le32::from(16);
+        virtio_gpu.cmd_transfer_to_host_2d(transfer).unwrap();
+
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        set_scanout.r.width = Le32::from(8);
+        set_scanout.r.height = Le32::from(2);
+        assert!(matches!(virtio_gpu.cmd_set_scanout(set_scanout), Err(VirtioGpuResponse::ErrInvalidParameter)));
+        set_scanout.r.width = Le32::from(4);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+
+        let mut flush: virtio_gpu_resource_flush = Default::default();
+        flush.resource_id = Le32::from(1);
+        flush.r.width = Le32::from(64);
+        flush.r.height = Le32::from(64);
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+    }
+
+    #[test]
+    fn test_windows_guest_profile() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            display_width: 4,
+            display_height: 2,
+            guest_profile: GuestProfile::Windows,
+            deterministic: true,
+            ..Default::default()
+        };
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let display_frames = frames.clone();
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, move || {
+            Ok(GpuDisplay::from_backend(Box::new(RecordingBackend::new(display_frames.clone()))))
+        })
+        .unwrap();
+
+        // translucent pixels, in the scanout and in the cursor
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        let mut backing = vec![0x80u8; 32];
+        for resource_id in 1..=2 {
+            create.resource_id = Le32::from(resource_id);
+            virtio_gpu.cmd_resource_create_2d(create).unwrap();
+            let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+            let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+            attach.resource_id = Le32::from(resource_id);
+            virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+            let mut transfer: virtio_gpu_transfer_to_host_2d = Default::default();
+            transfer.resource_id = Le32::from(resource_id);
+            transfer.r.width = Le32::from(4);
+            transfer.r.height = Le32::from(2);
+            virtio_gpu.cmd_transfer_to_host_2d(transfer).unwrap();
+        }
+        assert_eq!(virtio_gpu.resources[&1].format(), VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM);
+
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        set_scanout.r.width = Le32::from(2);
+        set_scanout.r.height = Le32::from(2);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+        let mut flush: virtio_gpu_resource_flush = Default::default();
+        flush.resource_id = Le32::from(1);
+        flush.r.width = Le32::from(4);
+        flush.r.height = Le32::from(2);
+        virtio_gpu.cmd_flush_resource(flush).unwrap();
+        // the scanout is shown opaque
+        assert_eq!(&frames.lock().unwrap().last().unwrap()[..4], &[0x80, 0x80, 0x80, 0xff]);
+
+        // a cursor the driver hasn't created yet hides the cursor
+        let mut cursor: virtio_gpu_update_cursor = Default::default();
+        cursor.resource_id = Le32::from(3);
+        virtio_gpu.cmd_update_cursor(cursor).unwrap();
+        assert_eq!(virtio_gpu.cursor_resource_id, None);
+
+        // the cursor keeps its alpha and stays within the rect the scanout shows
+        cursor.resource_id = Le32::from(2);
+        cursor.pos.x = Le32::from(-8i32 as u32);
+        cursor.pos.y = Le32::from(4);
+        virtio_gpu.cmd_update_cursor(cursor).unwrap();
+        assert_eq!(virtio_gpu.cursor_position, (0, 1));
+        assert_eq!(&frames.lock().unwrap().last().unwrap()[..4], &[0x80, 0x80, 0x80, 0x80]);
+    }
+
+    #[test]
+    fn test_config_space() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        // num_scanouts
+        assert_eq!(virtio_gpu.read_config(8, 4), 1u32.to_le_bytes());
+        assert!(virtio_gpu.read_config(12, 8).is_empty());
+
+        // events_read is read only, events_clear is writable
+        assert!(virtio_gpu.write_config(0, &1u32.to_le_bytes()).is_err());
+        assert!(virtio_gpu.write_config(4, &1u32.to_le_bytes()).is_ok());
+        assert!(virtio_gpu.write_config(16, &[0]).is_err());
+
+        // a resize raises the display event, until the guest clears it
+        let notified = Rc::new(Cell::new(0));
+        let notified_by_callback = notified.clone();
+        virtio_gpu.on_config_change(move |config| notified_by_callback.set(config.events_read.to_native()));
+        virtio_gpu.set_scanouts(&[(1280, 800), (0, 0)]).unwrap();
+        assert_eq!(notified.get(), VIRTIO_GPU_EVENT_DISPLAY);
+        assert_eq!(virtio_gpu.display_info(), &[(1280, 800), (0, 0)]);
+        assert_eq!(virtio_gpu.read_config(0, 4), VIRTIO_GPU_EVENT_DISPLAY.to_le_bytes());
+        assert_eq!(virtio_gpu.read_config(8, 4), 2u32.to_le_bytes());
+        virtio_gpu.write_config(4, &VIRTIO_GPU_EVENT_DISPLAY.to_le_bytes()).unwrap();
+        assert_eq!(virtio_gpu.events_read(), 0);
+
+        // the same scanouts again change nothing
+        notified.set(0);
+        virtio_gpu.set_scanouts(&[(1280, 800), (0, 0)]).unwrap();
+        assert_eq!(notified.get(), 0);
+        assert!(virtio_gpu.set_scanouts(&[]).is_err());
+        assert!(virtio_gpu.set_scanouts(&[(64, 64); VIRTIO_GPU_MAX_SCANOUTS + 1]).is_err());
+
+        // a host window resize only changes the first scanout
+        virtio_gpu.set_display_size(1920, 1080).unwrap();
+        assert_eq!(notified.get(), VIRTIO_GPU_EVENT_DISPLAY);
+        assert_eq!(virtio_gpu.display_info(), &[(1920, 1080), (0, 0)]);
+        assert!(virtio_gpu.set_display_size(0, 1080).is_err());
+        assert_eq!(virtio_gpu.display_info(), &[(1920, 1080), (0, 0)]);
+    }
+
+    #[test]
+    fn test_feature_toggles() {
+        let gpu_parameter: GpuParameter = Default::default();
+        // 3D needs virglrenderer in the build
+        let virgl = if cfg!(feature = "virgl_renderer") {
+            1 << VIRTIO_GPU_F_VIRGL | 1 << VIRTIO_GPU_F_CONTEXT_INIT
+        } else {
+            0
+        };
+        assert_eq!(
+            gpu_parameter.supported_features(),
+            virgl | 1 << VIRTIO_GPU_F_EDID | 1 << VIRTIO_GPU_F_RESOURCE_UUID
+        );
+
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            use_edid: false,
+            ..Default::default()
+        };
+        assert_eq!(gpu_parameter.supported_features(), 1 << VIRTIO_GPU_F_RESOURCE_UUID);
+
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let get_edid: virtio_gpu_cmd_get_edid = Default::default();
+        assert!(matches!(virtio_gpu.cmd_get_edid(get_edid), Err(VirtioGpuResponse::ErrUnspec)));
+        let get_capset_info: virtio_gpu_get_capset_info = Default::default();
+        assert!(matches!(
+            virtio_gpu.cmd_get_capset_info(get_capset_info),
+            Err(VirtioGpuResponse::ErrUnspec)
+        ));
+        let mut create_blob: virtio_gpu_resource_create_blob = Default::default();
+        create_blob.resource_id = Le32::from(1);
+        create_blob.blob_mem = Le32::from(VIRTIO_GPU_BLOB_MEM_HOST3D);
+        assert!(matches!(
+            virtio_gpu.cmd_resource_create_blob(create_blob, Vec::new()),
+            Err(VirtioGpuResponse::ErrUnspec)
+        ));
+        let map_blob: virtio_gpu_resource_map_blob = Default::default();
+        assert!(matches!(virtio_gpu.cmd_resource_map_blob(map_blob), Err(VirtioGpuResponse::ErrUnspec)));
+        // rejected before its descriptors are looked at, let alone gathered
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut submit: virtio_gpu_cmd_submit = Default::default();
+        submit.size = Le32::from(VIRTIO_GPU_MAX_SUBMIT_SIZE);
+        let sglist = [(GuestAddress(0x10000), 8), (GuestAddress(0x20000), VIRTIO_GPU_MAX_SUBMIT_SIZE as usize)];
+        assert!(matches!(
+            virtio_gpu.cmd_submit_3d_from_guest(submit, &mem, &sglist),
+            Err(VirtioGpuResponse::ErrUnspec)
+        ));
+    }
+
+    #[test]
+    fn test_feature_negotiation() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let offered = virtio_gpu.supported_features();
+        assert_eq!(virtio_gpu.acked_features(), offered);
+        let get_edid: virtio_gpu_cmd_get_edid = Default::default();
+        assert!(virtio_gpu.cmd_get_edid(get_edid).is_ok());
+
+        // a driver that doesn't know EDIDs, and bits never offered are dropped
+        virtio_gpu.ack_features(1 << VIRTIO_GPU_F_RESOURCE_UUID | 1 << VIRTIO_GPU_F_VIRGL);
+        assert_eq!(virtio_gpu.acked_features(), 1 << VIRTIO_GPU_F_RESOURCE_UUID);
+        assert!(matches!(virtio_gpu.cmd_get_edid(get_edid), Err(VirtioGpuResponse::ErrUnspec)));
+        let assign_uuid: virtio_gpu_resource_assign_uuid = Default::default();
+        assert!(!matches!(virtio_gpu.cmd_resource_assign_uuid(assign_uuid), Err(VirtioGpuResponse::ErrUnspec)));
+
+        // back to the state before negotiation, until the next driver negotiates again
+        virtio_gpu.reset();
+        assert_eq!(virtio_gpu.acked_features(), offered);
+        assert!(virtio_gpu.cmd_get_edid(get_edid).is_ok());
+        virtio_gpu.ack_features(1 << VIRTIO_GPU_F_EDID);
+        assert!(matches!(virtio_gpu.cmd_resource_assign_uuid(assign_uuid), Err(VirtioGpuResponse::ErrUnspec)));
+    }
+
+    #[test]
+    fn test_migrate_state() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut source = VirtioGpu::with_display(gpu_parameter.clone(), GpuDisplay::open_stub).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let base = mem.get_host_address(GuestAddress(0)).unwrap() as usize;
+        let to_guest = |host: usize| match host.checked_sub(base) {
+            Some(offset) if offset < 0x10000 => Some(GuestAddress(offset as u64)),
+            _ => None,
+        };
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.resource_id = Le32::from(1);
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        source.cmd_resource_create_2d(create).unwrap();
+        let pixels: Vec<u8> = (0..32).collect();
+        mem.write_slice(&pixels, GuestAddress(0x1000)).unwrap();
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        let iovecs = sglist_to_rutabaga_iovecs(&[(GuestAddress(0x1000), 32)], &mem).unwrap();
+        source.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        source.rutabaga.transfer_write(0, 1, Transfer3D::new_2d(0, 0, 4, 2)).unwrap();
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        source.cmd_set_scanout(set_scanout).unwrap();
+
+        // the guest wrote pixels it didn't transfer yet, the host copy is what moves
+        mem.write_slice(&[0xffu8; 32], GuestAddress(0x1000)).unwrap();
+        let state = source.save_state(to_guest).unwrap();
+        assert_eq!(state.scanout_resource_id, 1);
+        assert_eq!(state.resources.len(), 1);
+        assert_eq!(state.resources[0].backing, Some(vec![(GuestAddress(0x1000), 32)]));
+        assert_eq!(state.resources[0].contents, pixels);
+
+        let mut destination = VirtioGpu::with_display(gpu_parameter.clone(), GpuDisplay::open_stub).unwrap();
+        destination.restore_state(state.clone(), &mem).unwrap();
+        assert_eq!(destination.save_state(to_guest).unwrap(), state);
+        let mut contents = vec![0u8; 32];
+        let mut transfer = Transfer3D::new_2d(0, 0, 4, 2);
+        transfer.stride = 16;
+        destination
+            .rutabaga
+            .transfer_read(0, 1, transfer, Some(data_model::VolatileSlice::new(&mut contents)))
+            .unwrap();
+        assert_eq!(contents, pixels);
+
+        // backings outside of guest memory can't be saved, nor states that don't add up restored
+        assert!(matches!(source.save_state(|_| None), Err(MigrationError::InvalidBacking(1))));
+        let mut truncated = state;
+        truncated.resources[0].contents.pop();
+        let mut destination = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        assert!(matches!(
+            destination.restore_state(truncated, &mem),
+            Err(MigrationError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            resource_pool_size: 4,
+            ..Default::default()
+        };
+        let mut virtio_gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+
+        let mut create: virtio_gpu_resource_create_2d = Default::default();
+        create.format = Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM);
+        create.width = Le32::from(4);
+        create.height = Le32::from(2);
+        let mut backing = vec![0u8; 32];
+        for resource_id in 1..=2 {
+            create.resource_id = Le32::from(resource_id);
+            virtio_gpu.cmd_resource_create_2d(create).unwrap();
+        }
+        let mut attach: virtio_gpu_resource_attach_backing = Default::default();
+        attach.resource_id = Le32::from(1);
+        let iovecs = vec![RutabagaIovec { base: backing.as_mut_ptr() as *mut c_void, len: backing.len() }];
+        virtio_gpu.cmd_resource_attach_backing(attach, iovecs).unwrap();
+        let mut set_scanout: virtio_gpu_set_scanout = Default::default();
+        set_scanout.resource_id = Le32::from(1);
+        virtio_gpu.cmd_set_scanout(set_scanout).unwrap();
+        let mut unref: virtio_gpu_resource_unref = Default::default();
+        unref.resource_id = Le32::from(2);
+        virtio_gpu.cmd_resource_unref(unref).unwrap();
+        let fence = RutabagaFenceData {
+            flags: VIRTIO_GPU_FLAG_FENCE,
+            fence_id: 1,
+            ctx_id: 0,
+            fence_ctx_idx: 0,
+        };
+        virtio_gpu.create_fence(fence).unwrap();
+
+        // the 2D fences signal on the next poll
+        assert!(virtio_gpu.shutdown(Duration::from_millis(100)));
+        assert_eq!(virtio_gpu.pending_fences((0, 0)), 0);
+        assert!(virtio_gpu.resources.is_empty());
+        assert!(virtio_gpu.resource_pool.is_empty());
+        assert!(virtio_gpu.scanout_resource_id.is_none());
+        assert!(virtio_gpu.scanout_surface_id.is_none());
+        // nothing is left in the renderer, the ids can be created again
+        create.resource_id = Le32::from(1);
+        virtio_gpu.cmd_resource_create_2d(create).unwrap();
+    }
+}
@@ -1,5 +1,345 @@
-use crate::protocol::{virtio_gpu_ctrl_hdr, VIRTIO_GPU_FLAG_FENCE};
+use std::mem::size_of;
+
+use vm_memory::{Address, ByteValued, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
+
+use crate::protocol::{
+    command_size, virtio_gpu_ctrl_hdr, virtio_gpu_mem_entry, VirtioGpuCommand, VirtioGpuCommandDecodeError,
+    VIRTIO_GPU_FLAG_FENCE, VIRTIO_GPU_MAX_MEM_ENTRIES, VIRTIO_GPU_MAX_SUBMIT_SIZE,
+};
 
 pub fn is_fence(hdr: virtio_gpu_ctrl_hdr) -> bool {
     hdr.flags.to_native() & VIRTIO_GPU_FLAG_FENCE != 0
 }
+
+/// One descriptor of a chain.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChainDescriptor {
+    pub addr:     GuestAddress,
+    pub len:      usize,
+    /// written by the device
+    pub writable: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChainError {
+    /// a device-readable descriptor follows a device-writable one
+    ReadableAfterWritable(usize),
+    /// the readable descriptors hold fewer bytes than the request
+    RequestTooShort(usize),
+    /// the writable descriptors can't hold a response header
+    ResponseTooShort(usize),
+    /// the descriptor of this index wraps around the address space
+    AddressOverflow(usize),
+}
+
+/// A virtio-gpu descriptor chain split in its regions, each a list of `(address, length)`.
+#[derive(Debug, Default, PartialEq)]
+pub struct GpuChain {
+    /// the request, exactly as many bytes as it was split for
+    pub request:  Vec<(GuestAddress, usize)>,
+    /// the readable bytes after the request: memory entries, command streams, ...
+    pub data:     Vec<(GuestAddress, usize)>,
+    /// where the response is written
+    pub response: Vec<(GuestAddress, usize)>,
+}
+
+impl GpuChain {
+    pub fn data_len(&self) -> usize {
+        self.data.iter().map(|&(_, len)| len).sum()
+    }
+
+    pub fn response_len(&self) -> usize {
+        self.response.iter().map(|&(_, len)| len).sum()
+    }
+}
+
+/// Splits a descriptor chain whose request is `request_size` bytes long.  The device-readable
+/// descriptors come first and hold the request followed by its data, which may start in the same
+/// descriptor; the device-writable ones follow and must fit at least a response header.
+pub fn split_chain(descriptors: &[ChainDescriptor], request_size: usize) -> Result<GpuChain, ChainError> {
+    let mut chain = GpuChain::default();
+    let mut request_left = request_size;
+    let mut readable = 0;
+
+    for (index, desc) in descriptors.iter().enumerate() {
+        if desc.writable {
+            chain.response.push((desc.addr, desc.len));
+            continue;
+        }
+        if !chain.response.is_empty() {
+            return Err(ChainError::ReadableAfterWritable(index));
+        }
+
+        readable += desc.len;
+        let in_request = desc.len.min(request_left);
+        if in_request > 0 {
+            chain.request.push((desc.addr, in_request));
+            request_left -= in_request;
+        }
+        if in_request < desc.len {
+            let addr = desc
+                .addr
+                .checked_add(in_request as u64)
+                .ok_or(ChainError::AddressOverflow(index))?;
+            chain.data.push((addr, desc.len - in_request));
+        }
+    }
+
+    if request_left > 0 {
+        return Err(ChainError::RequestTooShort(readable));
+    }
+    let response_len = chain.response_len();
+    if response_len < size_of::<virtio_gpu_ctrl_hdr>() {
+        return Err(ChainError::ResponseTooShort(response_len));
+    }
+    Ok(chain)
+}
+
+/// The regions holding the first `len` bytes of `regions`, which have to hold that many, after
+/// checking that each lies within guest memory.
+fn guest_regions(
+    mem: &GuestMemoryMmap,
+    regions: &[(GuestAddress, usize)],
+    len: usize,
+) -> Result<Vec<(GuestAddress, usize)>, GuestMemoryError> {
+    let mut checked = Vec::new();
+    let mut left = len;
+    for &(addr, region_len) in regions {
+        if left == 0 {
+            break;
+        }
+        let n = region_len.min(left);
+        mem.get_slice(addr, n)?;
+        checked.push((addr, n));
+        left -= n;
+    }
+    Ok(checked)
+}
+
+/// Reads the first `len` bytes of `regions`, which have to hold that many.
+fn read_regions(mem: &GuestMemoryMmap, regions: &[(GuestAddress, usize)], len: usize) -> Result<Vec<u8>, GuestMemoryError> {
+    let mut buf = vec![0; len];
+    let mut filled = 0;
+    for &(addr, region_len) in regions {
+        if filled == len {
+            break;
+        }
+        let n = region_len.min(len - filled);
+        mem.read_slice(&mut buf[filled..filled + n], addr)?;
+        filled += n;
+    }
+    Ok(buf)
+}
+
+#[derive(Debug)]
+pub enum DecodeChainError {
+    /// the descriptors aren't laid out as a request and its response
+    Chain(ChainError),
+    /// the request isn't a command
+    Command(VirtioGpuCommandDecodeError),
+    /// a descriptor lies outside of guest memory
+    Memory(GuestMemoryError),
+    /// the readable descriptors hold fewer bytes than the data of the command
+    DataTooShort(usize),
+}
+
+impl From<ChainError> for DecodeChainError {
+    fn from(e: ChainError) -> Self {
+        DecodeChainError::Chain(e)
+    }
+}
+
+impl From<VirtioGpuCommandDecodeError> for DecodeChainError {
+    fn from(e: VirtioGpuCommandDecodeError) -> Self {
+        DecodeChainError::Command(e)
+    }
+}
+
+impl From<GuestMemoryError> for DecodeChainError {
+    fn from(e: GuestMemoryError) -> Self {
+        DecodeChainError::Memory(e)
+    }
+}
+
+/// A command decoded from its descriptor chain, with the buffers around it.
+#[derive(Debug)]
+pub struct DecodedChain {
+    pub command:     VirtioGpuCommand,
+    /// the memory entries of RESOURCE_ATTACH_BACKING and RESOURCE_CREATE_BLOB
+    pub mem_entries: Vec<(GuestAddress, usize)>,
+    /// the readable descriptors holding the command stream of SUBMIT_3D, which is read in place
+    pub cmd_regions: Vec<(GuestAddress, usize)>,
+    /// where the response is written
+    pub response:    Vec<(GuestAddress, usize)>,
+}
+
+/// Decodes the command of a descriptor chain of the control or cursor queue along with the data
+/// following it.  A virtio queue implementation's chain maps onto `descriptors` one to one:
+/// address, length and the write flag of each descriptor, in chain order.
+pub fn decode_chain(mem: &GuestMemoryMmap, descriptors: &[ChainDescriptor]) -> Result<DecodedChain, DecodeChainError> {
+    // the header tells how long the request is
+    let chain = split_chain(descriptors, size_of::<virtio_gpu_ctrl_hdr>())?;
+    let mut hdr = virtio_gpu_ctrl_hdr::default();
+    hdr.as_mut_slice()
+        .copy_from_slice(&read_regions(mem, &chain.request, size_of::<virtio_gpu_ctrl_hdr>())?);
+    let type_ = hdr.type_.to_native();
+    let request_size = command_size(type_).ok_or(VirtioGpuCommandDecodeError::InvalidCommand(type_))?;
+
+    let chain = split_chain(descriptors, request_size)?;
+    let mut request = read_regions(mem, &chain.request, request_size)?;
+    let command = VirtioGpuCommand::decode_from_slice(&request)?;
+
+    let mut decoded = DecodedChain {
+        command,
+        mem_entries: Vec::new(),
+        cmd_regions: Vec::new(),
+        response:    chain.response.clone(),
+    };
+    let entries_size = |nr_entries: u32| {
+        if nr_entries > VIRTIO_GPU_MAX_MEM_ENTRIES {
+            return Err(VirtioGpuCommandDecodeError::TooManyMemEntries(nr_entries));
+        }
+        Ok(nr_entries as usize * size_of::<virtio_gpu_mem_entry>())
+    };
+    let data_size = match command {
+        VirtioGpuCommand::CmdResourceAttachBacking(cmd) => entries_size(cmd.nr_entries.to_native())?,
+        VirtioGpuCommand::CmdResourceCreateBlob(cmd) => entries_size(cmd.nr_entries.to_native())?,
+        VirtioGpuCommand::CmdSubmit3D(cmd) => {
+            let size = cmd.size.to_native();
+            if size > VIRTIO_GPU_MAX_SUBMIT_SIZE {
+                return Err(VirtioGpuCommandDecodeError::SubmitTooLarge(size).into());
+            }
+            size as usize
+        }
+        _ => return Ok(decoded),
+    };
+    if chain.data_len() < data_size {
+        return Err(DecodeChainError::DataTooShort(chain.data_len()));
+    }
+    // nothing is allocated for the data before its descriptors are known to be in guest memory
+    let data = guest_regions(mem, &chain.data, data_size)?;
+
+    match command {
+        VirtioGpuCommand::CmdSubmit3D(_) => decoded.cmd_regions = data,
+        _ => {
+            request.extend_from_slice(&read_regions(mem, &data, data_size)?);
+            decoded.mem_entries = command.decode_mem_entries_from_slice(&request)?;
+        }
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::protocol::*;
+    use crate::virtio_utils::{decode_chain, split_chain, ChainDescriptor, ChainError, DecodeChainError};
+    use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryMmap, Le32, Le64};
+
+    fn desc(addr: u64, len: usize, writable: bool) -> ChainDescriptor {
+        ChainDescriptor {
+            addr: GuestAddress(addr),
+            len,
+            writable,
+        }
+    }
+
+    #[test]
+    fn test_split_chain() {
+        // request and its data in one descriptor, the rest of the data in another
+        let chain = split_chain(&[desc(0x1000, 48, false), desc(0x2000, 64, false), desc(0x3000, 24, true)], 32).unwrap();
+        assert_eq!(chain.request, vec![(GuestAddress(0x1000), 32)]);
+        assert_eq!(chain.data, vec![(GuestAddress(0x1020), 16), (GuestAddress(0x2000), 64)]);
+        assert_eq!(chain.data_len(), 80);
+        assert_eq!(chain.response, vec![(GuestAddress(0x3000), 24)]);
+
+        // request split across descriptors
+        let chain = split_chain(&[desc(0x1000, 16, false), desc(0x2000, 16, false), desc(0x3000, 24, true)], 32).unwrap();
+        assert_eq!(chain.request, vec![(GuestAddress(0x1000), 16), (GuestAddress(0x2000), 16)]);
+        assert!(chain.data.is_empty());
+
+        assert_eq!(
+            split_chain(&[desc(0x1000, 16, false), desc(0x3000, 24, true)], 32),
+            Err(ChainError::RequestTooShort(16))
+        );
+        assert_eq!(
+            split_chain(&[desc(0x1000, 32, false), desc(0x3000, 8, true)], 32),
+            Err(ChainError::ResponseTooShort(8))
+        );
+        assert_eq!(
+            split_chain(&[desc(0x1000, 32, false), desc(0x3000, 24, true), desc(0x4000, 8, false)], 32),
+            Err(ChainError::ReadableAfterWritable(2))
+        );
+        assert_eq!(
+            split_chain(&[desc(u64::MAX - 8, 48, false), desc(0x3000, 24, true)], 32),
+            Err(ChainError::AddressOverflow(0))
+        );
+    }
+
+    #[test]
+    fn test_decode_chain() {
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let cmd = virtio_gpu_resource_attach_backing {
+            hdr: virtio_gpu_ctrl_hdr {
+                type_: Le32::from(VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING),
+                ..Default::default()
+            },
+            resource_id: Le32::from(1),
+            nr_entries: Le32::from(2),
+        };
+        let entry = |addr: u64, length: u32| virtio_gpu_mem_entry {
+            addr: Le64::from(addr),
+            length: Le32::from(length),
+            ..Default::default()
+        };
+        // the command with its first entry, then the second entry on its own
+        mem.write_obj(cmd, GuestAddress(0x1000)).unwrap();
+        mem.write_obj(entry(0x8000, 4096), GuestAddress(0x1020)).unwrap();
+        mem.write_obj(entry(0xa000, 512), GuestAddress(0x2000)).unwrap();
+        let descriptors = [desc(0x1000, 48, false), desc(0x2000, 16, false), desc(0x3000, 24, true)];
+        let decoded = decode_chain(&mem, &descriptors).unwrap();
+        assert!(matches!(decoded.command, VirtioGpuCommand::CmdResourceAttachBacking(_)));
+        assert_eq!(decoded.mem_entries, vec![(GuestAddress(0x8000), 4096), (GuestAddress(0xa000), 512)]);
+        assert_eq!(decoded.response, vec![(GuestAddress(0x3000), 24)]);
+
+        // an entry missing
+        assert!(matches!(
+            decode_chain(&mem, &[desc(0x1000, 48, false), desc(0x3000, 24, true)]),
+            Err(DecodeChainError::DataTooShort(16))
+        ));
+
+        // the command stream of SUBMIT_3D, split across descriptors
+        let submit = virtio_gpu_cmd_submit {
+            hdr: virtio_gpu_ctrl_hdr {
+                type_: Le32::from(VIRTIO_GPU_CMD_SUBMIT_3D),
+                ..Default::default()
+            },
+            size: Le32::from(8),
+            ..Default::default()
+        };
+        mem.write_obj(submit, GuestAddress(0x4000)).unwrap();
+        mem.write_slice(&[1, 2, 3, 4], GuestAddress(0x4000 + submit.as_slice().len() as u64)).unwrap();
+        mem.write_slice(&[5, 6, 7, 8], GuestAddress(0x5000)).unwrap();
+        let descriptors = [desc(0x4000, 36, false), desc(0x5000, 4, false), desc(0x3000, 24, true)];
+        let decoded = decode_chain(&mem, &descriptors).unwrap();
+        assert_eq!(decoded.cmd_regions, vec![(GuestAddress(0x4020), 4), (GuestAddress(0x5000), 4)]);
+        assert!(decoded.mem_entries.is_empty());
+
+        // a stream outside of guest memory, and one too large to take
+        let descriptors = [desc(0x4000, 36, false), desc(0x20000, 4, false), desc(0x3000, 24, true)];
+        assert!(matches!(decode_chain(&mem, &descriptors), Err(DecodeChainError::Memory(_))));
+        let mut huge = submit;
+        huge.size = Le32::from(VIRTIO_GPU_MAX_SUBMIT_SIZE + 4);
+        mem.write_obj(huge, GuestAddress(0x4000)).unwrap();
+        let descriptors = [desc(0x4000, 32, false), desc(0x8000, u32::MAX as usize, false), desc(0x3000, 24, true)];
+        assert!(matches!(
+            decode_chain(&mem, &descriptors),
+            Err(DecodeChainError::Command(VirtioGpuCommandDecodeError::SubmitTooLarge(_)))
+        ));
+
+        mem.write_obj(0xdeadu32, GuestAddress(0x6000)).unwrap();
+        assert!(matches!(
+            decode_chain(&mem, &[desc(0x6000, 24, false), desc(0x3000, 24, true)]),
+            Err(DecodeChainError::Command(VirtioGpuCommandDecodeError::InvalidCommand(0xdead)))
+        ));
+    }
+}
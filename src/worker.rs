@@ -0,0 +1,492 @@
+// The queue workers: drain the chains the driver made available, run their commands on the
+// device and return the responses on the used ring.  Responses to fenced commands are held back
+// until the renderer signals their fence, responses to queued transfers until the transfers ran.
+//
+// The cursorq has a worker of its own, run before the controlq's on every wakeup.  Both run on the
+// renderer's thread, since the device can't leave it, but the cursor worker never waits on the
+// renderer: its commands only talk to the display and their fences are retired without a renderer
+// fence, so the pointer keeps moving while a 3D submission holds the control queue's responses.
+
+use std::collections::BTreeMap;
+use std::mem::size_of;
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use crate::protocol::{
+    virtio_gpu_ctrl_hdr, virtio_gpu_update_cursor, VirtioGpuCommand, VirtioGpuCommandDecodeError, VirtioGpuResponse,
+};
+use crate::queue::{add_used, pop_avail, signal_used, QueueError};
+use crate::vhost::Vring;
+use crate::virtio_gpu::{fence_ring, sglist_to_rutabaga_iovecs, PendingTransfer, VirtioGpu};
+use crate::virtio_utils::{decode_chain, ChainDescriptor, DecodeChainError, DecodedChain};
+use crate::{VirtioGpuCommandResult, VirtioGpuResponseResult};
+
+/// A response waiting for the fence of its command.
+struct FencedResponse {
+    ring:     (u32, u32),
+    fence_id: u64,
+    head:     u16,
+    hdr:      virtio_gpu_ctrl_hdr,
+    result:   VirtioGpuResponseResult,
+    response: Vec<(GuestAddress, usize)>,
+}
+
+/// Runs `decoded`'s command on `gpu`, the match from the commands to the device's handlers.
+pub fn dispatch(gpu: &mut VirtioGpu, mem: &GuestMemoryMmap, decoded: &DecodedChain) -> VirtioGpuResponseResult {
+    use crate::protocol::VirtioGpuCommand::*;
+
+    let command = decoded.command;
+    gpu.intercept(&command, |gpu| match command {
+        CmdGetDisplayInfo(hdr) => gpu.cmd_get_display_info(hdr),
+        CmdResourceCreate2D(cmd) => gpu.cmd_resource_create_2d(cmd),
+        CmdResourceUnref(cmd) => gpu.cmd_resource_unref(cmd),
+        CmdSetScanout(cmd) => gpu.cmd_set_scanout(cmd),
+        CmdResourceFlush(cmd) => gpu.cmd_flush_resource(cmd),
+        CmdTransferToHost2D(cmd) => gpu.cmd_transfer_to_host_2d(cmd),
+        CmdResourceAttachBacking(cmd) => {
+            let iovecs = sglist_to_rutabaga_iovecs(&decoded.mem_entries, mem)?;
+            gpu.cmd_resource_attach_backing(cmd, iovecs)
+        }
+        CmdResourceDetachBacking(cmd) => gpu.cmd_resource_detach_backing(cmd),
+        CmdGetCapsetInfo(cmd) => gpu.cmd_get_capset_info(cmd),
+        CmdGetCapset(cmd) => gpu.cmd_get_capset(cmd),
+        CmdGetEdid(cmd) => gpu.cmd_get_edid(cmd),
+        CmdResourceAssignUuid(cmd) => gpu.cmd_resource_assign_uuid(cmd),
+        CmdResourceCreateBlob(cmd) => {
+            let iovecs = sglist_to_rutabaga_iovecs(&decoded.mem_entries, mem)?;
+            gpu.cmd_resource_create_blob(cmd, iovecs)
+        }
+        CmdCtxCreate(cmd) => gpu.cmd_context_create(cmd),
+        CmdCtxDestroy(cmd) => gpu.cmd_context_destroy(cmd),
+        CmdCtxAttachResource(cmd) => gpu.cmd_ctx_attach_resource(cmd),
+        CmdCtxDetachResource(cmd) => gpu.cmd_ctx_detach_resource(cmd),
+        CmdResourceCreate3D(cmd) => gpu.cmd_resource_create_3d(cmd),
+        CmdTransferToHost3D(cmd) => gpu.cmd_transfer_to_host_3d(cmd),
+        CmdTransferFromHost3D(cmd) => gpu.cmd_transfer_from_host_3d(cmd, None),
+        CmdSubmit3D(cmd) => gpu.cmd_submit_3d_from_guest(cmd, mem, &decoded.cmd_regions),
+        CmdResourceMapBlob(cmd) => gpu.cmd_resource_map_blob(cmd),
+        CmdResourceUnmapBlob(cmd) => gpu.cmd_resource_unmap_blob(cmd),
+        CmdUpdateCursor(cmd) => gpu.cmd_update_cursor(cmd),
+        CmdMoveCursor(cmd) => gpu.cmd_move_curosr(cmd),
+    })
+}
+
+/// Writes the response to the command of header `hdr` across the writable descriptors, returns
+/// how many bytes the device wrote.  A response that doesn't fit is replaced with ERR_UNSPEC,
+/// nothing is written if the driver didn't leave room for a header.
+fn write_response(
+    mem: &GuestMemoryMmap,
+    response: &[(GuestAddress, usize)],
+    hdr: &virtio_gpu_ctrl_hdr,
+    result: VirtioGpuResponseResult,
+) -> u32 {
+    match result.unwrap_or_else(|e| e).encode_into(hdr, mem, response) {
+        Ok(written) => written as u32,
+        Err(_) => VirtioGpuResponse::ErrUnspec.encode_into(hdr, mem, response).unwrap_or(0) as u32,
+    }
+}
+
+/// The header the response of a chain that failed to decode echoes: whatever the request's
+/// first bytes hold, zeroes if they can't be read.
+fn chain_hdr(mem: &GuestMemoryMmap, addr: GuestAddress) -> virtio_gpu_ctrl_hdr {
+    mem.read_obj(addr).unwrap_or_default()
+}
+
+/// Drains the control queue of a `VirtioGpu`.
+#[derive(Default)]
+pub struct ControlWorker {
+    fenced:    Vec<FencedResponse>,
+    /// transfers queued on the device by the head of their chain, with where they're answered
+    transfers: BTreeMap<u64, (virtio_gpu_ctrl_hdr, Vec<(GuestAddress, usize)>)>,
+}
+
+impl ControlWorker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Number of chains the worker holds on to until a fence signals or a transfer runs.
+    pub fn pending(&self) -> usize {
+        self.fenced.len() + self.transfers.len()
+    }
+
+    /// Runs every chain available on `vring` and signals the driver if any was returned.
+    pub fn process_queue(&mut self, gpu: &mut VirtioGpu, mem: &GuestMemoryMmap, vring: &mut Vring) -> Result<(), QueueError> {
+        let mut used = false;
+        while let Some((head, descs)) = pop_avail(mem, vring)? {
+            let decoded = match decode_chain(mem, &descs) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    self.complete_transfers(gpu, mem, vring)?;
+                    let response = response_regions(&descs);
+                    let hdr = descs.first().map_or_else(Default::default, |desc| chain_hdr(mem, desc.addr));
+                    let result = match e {
+                        DecodeChainError::Command(VirtioGpuCommandDecodeError::InvalidCommand(_)) => {
+                            gpu.process_unknown_command(mem, descs[0].addr)
+                        }
+                        DecodeChainError::Command(e) => Err(e.response()),
+                        _ => Err(VirtioGpuResponse::ErrUnspec),
+                    };
+                    let len = write_response(mem, &response, &hdr, result);
+                    add_used(mem, vring, head, len)?;
+                    used = true;
+                    continue;
+                }
+            };
+
+            let hdr = *decoded.command.hdr();
+            let fence = hdr.fence();
+            // unfenced transfers are the ones worth batching, their responses carry nothing
+            let transfer = match decoded.command {
+                VirtioGpuCommand::CmdTransferToHost2D(cmd) if fence.is_none() => Some(PendingTransfer::ToHost2d(cmd)),
+                VirtioGpuCommand::CmdTransferToHost3D(cmd) if fence.is_none() => Some(PendingTransfer::ToHost3d(cmd)),
+                _ => None,
+            };
+            if let Some(transfer) = transfer {
+                self.transfers.insert(head as u64, (hdr, decoded.response));
+                let completed = gpu.queue_transfer(head as u64, transfer);
+                used |= self.return_transfers(mem, vring, completed)?;
+                continue;
+            }
+            // queued transfers run before anything that could touch their resources
+            used |= self.complete_transfers(gpu, mem, vring)?;
+
+            let mut result = dispatch(gpu, mem, &decoded);
+            if let (Some(fence), true) = (fence, result.is_ok()) {
+                let (ring, fence_id) = (fence_ring(&fence), fence.fence_id);
+                result = gpu.create_fence(fence).and(result);
+                if result.is_ok() {
+                    self.fenced.push(FencedResponse {
+                        ring,
+                        fence_id,
+                        head,
+                        hdr,
+                        result,
+                        response: decoded.response,
+                    });
+                    continue;
+                }
+            }
+            let len = write_response(mem, &decoded.response, &hdr, result);
+            add_used(mem, vring, head, len)?;
+            used = true;
+        }
+        used |= self.complete_transfers(gpu, mem, vring)?;
+
+        if used {
+            signal_used(vring);
+        }
+        Ok(())
+    }
+
+    /// Polls the renderer's fences and returns the chains of the fenced commands that signalled.
+    pub fn process_fences(&mut self, gpu: &mut VirtioGpu, mem: &GuestMemoryMmap, vring: &mut Vring) -> Result<(), QueueError> {
+        gpu.process_fences();
+        let (signalled, fenced) = std::mem::take(&mut self.fenced)
+            .into_iter()
+            .partition::<Vec<_>, _>(|fenced| fenced.fence_id <= gpu.signalled_fence(fenced.ring));
+        self.fenced = fenced;
+
+        let used = !signalled.is_empty();
+        for fenced in signalled {
+            let len = write_response(mem, &fenced.response, &fenced.hdr, fenced.result);
+            add_used(mem, vring, fenced.head, len)?;
+        }
+        if used {
+            signal_used(vring);
+        }
+        Ok(())
+    }
+
+    fn complete_transfers(&mut self, gpu: &mut VirtioGpu, mem: &GuestMemoryMmap, vring: &mut Vring) -> Result<bool, QueueError> {
+        if !gpu.has_pending_transfers() {
+            return Ok(false);
+        }
+        let completed = gpu.complete_transfers();
+        self.return_transfers(mem, vring, completed)
+    }
+
+    fn return_transfers(
+        &mut self,
+        mem: &GuestMemoryMmap,
+        vring: &mut Vring,
+        completed: Vec<(u64, VirtioGpuResponseResult)>,
+    ) -> Result<bool, QueueError> {
+        let used = !completed.is_empty();
+        for (token, result) in completed {
+            if let Some((hdr, response)) = self.transfers.remove(&token) {
+                let len = write_response(mem, &response, &hdr, result);
+                add_used(mem, vring, token as u16, len)?;
+            }
+        }
+        Ok(used)
+    }
+}
+
+/// The writable descriptors of a chain, where its response goes.
+fn response_regions(descs: &[ChainDescriptor]) -> Vec<(GuestAddress, usize)> {
+    descs
+        .iter()
+        .filter(|desc| desc.writable)
+        .map(|desc| (desc.addr, desc.len))
+        .collect()
+}
+
+/// Drains the cursor queue of a `VirtioGpu`.  Cursor chains usually carry no response, the driver
+/// only waits for them to be used.
+#[derive(Default)]
+pub struct CursorWorker {
+    /// fenced commands already run, waiting for the earlier fences of their ring
+    fenced: Vec<FencedResponse>,
+}
+
+impl CursorWorker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Number of chains waiting for the control queue's fences.
+    pub fn pending(&self) -> usize {
+        self.fenced.len()
+    }
+
+    /// Runs every chain available on `vring` and signals the driver if any was returned.
+    pub fn process_queue(&mut self, gpu: &mut VirtioGpu, mem: &GuestMemoryMmap, vring: &mut Vring) -> Result<(), QueueError> {
+        let mut used = false;
+        while let Some((head, descs)) = pop_avail(mem, vring)? {
+            let response = response_regions(&descs);
+            let (hdr, result) = match read_cursor_command(mem, &descs) {
+                Ok(cmd) => (*cmd.hdr(), gpu.process_cursor(&cmd)),
+                Err(e) => {
+                    let hdr = descs.first().map_or_else(Default::default, |desc| chain_hdr(mem, desc.addr));
+                    (hdr, Err(e.response()))
+                }
+            };
+
+            // the command already ran, only its response waits for the fences the guest
+            // ordered it after
+            if let (Some(fence), true) = (hdr.fence(), result.is_ok()) {
+                let (ring, fence_id) = (fence_ring(&fence), fence.fence_id);
+                if gpu.fences_pending_before(ring, fence_id) {
+                    self.fenced.push(FencedResponse {
+                        ring,
+                        fence_id,
+                        head,
+                        hdr,
+                        result,
+                        response,
+                    });
+                    continue;
+                }
+            }
+            let len = write_response(mem, &response, &hdr, result);
+            add_used(mem, vring, head, len)?;
+            used = true;
+        }
+
+        if used {
+            signal_used(vring);
+        }
+        Ok(())
+    }
+
+    /// Returns the fenced chains whose ring caught up, after the control queue's worker polled the
+    /// renderer.
+    pub fn process_fences(&mut self, gpu: &VirtioGpu, mem: &GuestMemoryMmap, vring: &mut Vring) -> Result<(), QueueError> {
+        let (ready, fenced) = std::mem::take(&mut self.fenced)
+            .into_iter()
+            .partition::<Vec<_>, _>(|fenced| !gpu.fences_pending_before(fenced.ring, fenced.fence_id));
+        self.fenced = fenced;
+
+        let used = !ready.is_empty();
+        for fenced in ready {
+            let len = write_response(mem, &fenced.response, &fenced.hdr, fenced.result);
+            add_used(mem, vring, fenced.head, len)?;
+        }
+        if used {
+            signal_used(vring);
+        }
+        Ok(())
+    }
+}
+
+/// Reads the cursor command at the start of a chain's readable descriptors.
+fn read_cursor_command(mem: &GuestMemoryMmap, descs: &[ChainDescriptor]) -> VirtioGpuCommandResult {
+    let mut buf = vec![0u8; size_of::<virtio_gpu_update_cursor>()];
+    let mut filled = 0;
+    for desc in descs.iter().take_while(|desc| !desc.writable) {
+        if filled == buf.len() {
+            break;
+        }
+        let n = desc.len.min(buf.len() - filled);
+        mem.read_slice(&mut buf[filled..filled + n], desc.addr)?;
+        filled += n;
+    }
+    if filled < buf.len() {
+        return Err(VirtioGpuCommandDecodeError::DescriptorTooShort(filled));
+    }
+    VirtioGpuCommand::decode_cursor_from_slice(&buf)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::protocol::*;
+    use crate::queue::{virtq_desc, virtq_used_elem, VIRTQ_DESC_F_NEXT, VIRTQ_DESC_F_WRITE};
+    use crate::virtio_gpu::{GpuMode, GpuParameter};
+    use crate::worker::*;
+    use crate::RutabagaFenceData;
+    use gpu_display::GpuDisplay;
+    use std::mem::size_of;
+    use vm_memory::{Address, Le16, Le32, Le64};
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+
+    /// Makes the request at `request` available as the chain of descriptor `head`, followed by
+    /// descriptor `head + 1` for the response if there is one.
+    fn add_chain(mem: &GuestMemoryMmap, slot: u16, head: u16, request: (u64, u32), response: Option<(u64, u32)>) {
+        let desc = |(addr, len): (u64, u32), flags: u16, next: u16| virtq_desc {
+            addr: Le64::from(addr),
+            len: Le32::from(len),
+            flags: Le16::from(flags),
+            next: Le16::from(next),
+        };
+        let table = GuestAddress(DESC_TABLE);
+        let flags = if response.is_some() { VIRTQ_DESC_F_NEXT } else { 0 };
+        mem.write_obj(desc(request, flags, head + 1), table.unchecked_add(head as u64 * 16))
+            .unwrap();
+        if let Some(response) = response {
+            mem.write_obj(desc(response, VIRTQ_DESC_F_WRITE, 0), table.unchecked_add((head as u64 + 1) * 16))
+                .unwrap();
+        }
+        mem.write_obj(Le16::from(head), GuestAddress(AVAIL_RING + 4 + 2 * slot as u64)).unwrap();
+        mem.write_obj(Le16::from(slot + 1), GuestAddress(AVAIL_RING + 2)).unwrap();
+    }
+
+    fn vring() -> Vring {
+        let mut vring = Vring::default();
+        vring.size = 8;
+        vring.desc_table = GuestAddress(DESC_TABLE);
+        vring.avail_ring = GuestAddress(AVAIL_RING);
+        vring.used_ring = GuestAddress(USED_RING);
+        vring
+    }
+
+    fn used_idx(mem: &GuestMemoryMmap) -> u16 {
+        let used_idx: Le16 = mem.read_obj(GuestAddress(USED_RING + 2)).unwrap();
+        used_idx.to_native()
+    }
+
+    fn used_elem(mem: &GuestMemoryMmap, slot: u64) -> (u32, u32) {
+        let elem: virtq_used_elem = mem.read_obj(GuestAddress(USED_RING + 4 + 8 * slot)).unwrap();
+        (elem.id.to_native(), elem.len.to_native())
+    }
+
+    fn hdr(type_: u32) -> virtio_gpu_ctrl_hdr {
+        virtio_gpu_ctrl_hdr {
+            type_: Le32::from(type_),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_control_worker() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vring = vring();
+
+        let display_info_len = size_of::<virtio_gpu_resp_display_info>() as u32;
+        mem.write_obj(hdr(VIRTIO_GPU_CMD_GET_DISPLAY_INFO), GuestAddress(0x4000)).unwrap();
+        add_chain(&mem, 0, 0, (0x4000, 24), Some((0x5000, display_info_len)));
+        let create = virtio_gpu_resource_create_2d {
+            hdr: hdr(VIRTIO_GPU_CMD_RESOURCE_CREATE_2D),
+            resource_id: Le32::from(1),
+            format: Le32::from(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM),
+            width: Le32::from(64),
+            height: Le32::from(64),
+        };
+        mem.write_obj(create, GuestAddress(0x4100)).unwrap();
+        add_chain(&mem, 1, 2, (0x4100, size_of::<virtio_gpu_resource_create_2d>() as u32), Some((0x5400, 24)));
+        // no handler for the type, and a response too small for the display info
+        mem.write_obj(hdr(0x0fff), GuestAddress(0x4200)).unwrap();
+        add_chain(&mem, 2, 4, (0x4200, 24), Some((0x5500, 24)));
+        add_chain(&mem, 3, 6, (0x4000, 24), Some((0x5600, 24)));
+
+        let mut worker = ControlWorker::new();
+        worker.process_queue(&mut gpu, &mem, &mut vring).unwrap();
+        assert_eq!(vring.next_avail, 4);
+        assert_eq!(worker.pending(), 0);
+
+        assert_eq!(used_idx(&mem), 4);
+        let expected = [
+            (0, display_info_len, 0x5000, VIRTIO_GPU_RESP_OK_DISPLAY_INFO),
+            (2, 24, 0x5400, VIRTIO_GPU_RESP_OK_NODATA),
+            (4, 24, 0x5500, VIRTIO_GPU_RESP_ERR_UNSPEC),
+            (6, 24, 0x5600, VIRTIO_GPU_RESP_ERR_UNSPEC),
+        ];
+        for (slot, &(head, len, response, type_)) in expected.iter().enumerate() {
+            assert_eq!(used_elem(&mem, slot as u64), (head, len));
+            let hdr: virtio_gpu_ctrl_hdr = mem.read_obj(GuestAddress(response)).unwrap();
+            assert_eq!(hdr.type_.to_native(), type_);
+        }
+
+        // nothing left to run
+        worker.process_queue(&mut gpu, &mem, &mut vring).unwrap();
+        assert_eq!(used_idx(&mem), 4);
+    }
+
+    #[test]
+    fn test_cursor_worker() {
+        let gpu_parameter = GpuParameter {
+            mode: GpuMode::Mode2D,
+            ..Default::default()
+        };
+        let mut gpu = VirtioGpu::with_display(gpu_parameter, GpuDisplay::open_stub).unwrap();
+        let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vring = vring();
+        let cursor_len = size_of::<virtio_gpu_update_cursor>() as u32;
+        let mut cursor = virtio_gpu_update_cursor {
+            hdr: hdr(VIRTIO_GPU_CMD_MOVE_CURSOR),
+            ..Default::default()
+        };
+        cursor.pos.x = Le32::from(10);
+        mem.write_obj(cursor, GuestAddress(0x4000)).unwrap();
+        // fenced after a fence of the control queue that hasn't signalled yet
+        cursor.hdr.flags = Le32::from(VIRTIO_GPU_FLAG_FENCE);
+        cursor.hdr.fence_id = Le64::from(6);
+        mem.write_obj(cursor, GuestAddress(0x4100)).unwrap();
+        gpu.create_fence(RutabagaFenceData {
+            flags: VIRTIO_GPU_FLAG_FENCE,
+            fence_id: 5,
+            ctx_id: 0,
+            fence_ctx_idx: 0,
+        })
+        .unwrap();
+
+        add_chain(&mem, 0, 0, (0x4000, cursor_len), None);
+        add_chain(&mem, 1, 1, (0x4100, cursor_len), Some((0x5000, 24)));
+        // too short for a cursor command
+        add_chain(&mem, 2, 3, (0x4000, 24), None);
+        let mut worker = CursorWorker::new();
+        worker.process_queue(&mut gpu, &mem, &mut vring).unwrap();
+        assert_eq!(used_idx(&mem), 2);
+        assert_eq!(used_elem(&mem, 0), (0, 0));
+        assert_eq!(used_elem(&mem, 1), (3, 0));
+        assert_eq!(worker.pending(), 1);
+
+        worker.process_fences(&gpu, &mem, &mut vring).unwrap();
+        assert_eq!(worker.pending(), 1);
+        gpu.process_fences();
+        worker.process_fences(&gpu, &mem, &mut vring).unwrap();
+        assert_eq!(worker.pending(), 0);
+        assert_eq!(used_elem(&mem, 2), (1, 24));
+        let hdr: virtio_gpu_ctrl_hdr = mem.read_obj(GuestAddress(0x5000)).unwrap();
+        assert_eq!(hdr.type_.to_native(), VIRTIO_GPU_RESP_OK_NODATA);
+        assert_eq!(hdr.fence_id.to_native(), 6);
+    }
+}
@@ -0,0 +1,118 @@
+// Software conversion of multi-planar YUV resources to the XRGB8888 pixels the displays take,
+// used when a guest scans out a video frame and the display can't present it natively, and the
+// other way around for the frame recordings.
+
+use rutabaga_gfx::{RutabagaFormatLayout, RutabagaPlane};
+
+use crate::protocol::{VIRTIO_GPU_FORMAT_NV12, VIRTIO_GPU_FORMAT_YV12};
+
+/// Byte `byte` of the sample of `plane` covering the resource pixel (`x`, `y`).
+fn sample(src: &[u8], plane: &RutabagaPlane, x: u32, y: u32, byte: u32) -> Option<u8> {
+    let offset = plane.offset
+        + (y >> plane.v_shift) as u64 * plane.stride as u64
+        + ((x >> plane.h_shift) * plane.bytes_per_pixel + byte) as u64;
+    src.get(offset as usize).copied()
+}
+
+fn clamp(value: i32) -> u8 {
+    value.max(0).min(255) as u8
+}
+
+/// Converts a limited range BT.601 sample to little endian XRGB8888.
+fn yuv_to_xrgb_pixel(y: u8, u: u8, v: u8) -> [u8; 4] {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let r = clamp((c + 409 * e + 128) >> 8);
+    let g = clamp((c - 100 * d - 208 * e + 128) >> 8);
+    let b = clamp((c + 516 * d + 128) >> 8);
+    [b, g, r, 0xff]
+}
+
+/// Converts red, green and blue to a limited range BT.601 sample, the inverse of
+/// `yuv_to_xrgb_pixel`.
+pub(crate) fn rgb_to_yuv_pixel(r: u8, g: u8, b: u8) -> [u8; 3] {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+    let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+    [clamp(y), clamp(u), clamp(v)]
+}
+
+/// Converts the top left `width`x`height` pixels of `src`, a `format` image laid out as `layout`,
+/// into `dst` rows of `dst_stride` bytes.  Returns `None` for formats that aren't YUV or buffers
+/// too small for the rect.
+pub fn yuv_to_xrgb(
+    format: u32,
+    layout: &RutabagaFormatLayout,
+    src: &[u8],
+    width: u32,
+    height: u32,
+    dst: &mut [u8],
+    dst_stride: u32,
+) -> Option<()> {
+    // (plane, byte within the sample) of the Cb and Cr samples
+    let (cb, cr) = match format {
+        VIRTIO_GPU_FORMAT_NV12 => ((1, 0), (1, 1)),
+        VIRTIO_GPU_FORMAT_YV12 => ((2, 0), (1, 0)),
+        _ => return None,
+    };
+    let luma = layout.planes.get(0)?;
+    let cb_plane = layout.planes.get(cb.0)?;
+    let cr_plane = layout.planes.get(cr.0)?;
+
+    for y in 0..height {
+        let row = y as usize * dst_stride as usize;
+        for x in 0..width {
+            let pixel = yuv_to_xrgb_pixel(
+                sample(src, luma, x, y, 0)?,
+                sample(src, cb_plane, x, y, cb.1)?,
+                sample(src, cr_plane, x, y, cr.1)?,
+            );
+            let offset = row + x as usize * 4;
+            dst.get_mut(offset..offset + 4)?.copy_from_slice(&pixel);
+        }
+    }
+    Some(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::protocol::{VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, VIRTIO_GPU_FORMAT_NV12, VIRTIO_GPU_FORMAT_YV12};
+    use crate::yuv::*;
+    use rutabaga_gfx::format_layout;
+
+    #[test]
+    fn test_yuv_to_xrgb_pixel() {
+        assert_eq!(yuv_to_xrgb_pixel(16, 128, 128), [0, 0, 0, 0xff]);
+        assert_eq!(yuv_to_xrgb_pixel(235, 128, 128), [255, 255, 255, 0xff]);
+        // pure red
+        assert_eq!(yuv_to_xrgb_pixel(81, 90, 240), [0, 0, 255, 0xff]);
+        assert_eq!(rgb_to_yuv_pixel(0, 0, 0), [16, 128, 128]);
+        assert_eq!(rgb_to_yuv_pixel(255, 255, 255), [235, 128, 128]);
+        assert_eq!(rgb_to_yuv_pixel(255, 0, 0), [82, 90, 240]);
+    }
+
+    #[test]
+    fn test_yuv_to_xrgb() {
+        // 2x2 image: black on the left, white on the right, neutral chroma
+        let layout = format_layout(VIRTIO_GPU_FORMAT_NV12, 2, 2).unwrap();
+        let src = [16, 235, 0, 0, 16, 235, 0, 0, 128, 128, 0, 0];
+        let mut dst = [0u8; 16];
+        yuv_to_xrgb(VIRTIO_GPU_FORMAT_NV12, &layout, &src, 2, 2, &mut dst, 8).unwrap();
+        assert_eq!(dst, [0, 0, 0, 0xff, 255, 255, 255, 0xff, 0, 0, 0, 0xff, 255, 255, 255, 0xff]);
+
+        // Cr comes before Cb in YV12
+        let layout = format_layout(VIRTIO_GPU_FORMAT_YV12, 2, 2).unwrap();
+        let src = [81, 81, 0, 0, 81, 81, 0, 0, 240, 0, 0, 0, 90, 0, 0, 0];
+        let mut dst = [0u8; 4];
+        yuv_to_xrgb(VIRTIO_GPU_FORMAT_YV12, &layout, &src, 1, 1, &mut dst, 4).unwrap();
+        assert_eq!(dst, [0, 0, 255, 0xff]);
+
+        let layout = format_layout(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, 2, 2).unwrap();
+        assert!(yuv_to_xrgb(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM, &layout, &[0; 16], 2, 2, &mut [0; 16], 8).is_none());
+        // the destination is too small
+        let layout = format_layout(VIRTIO_GPU_FORMAT_NV12, 2, 2).unwrap();
+        assert!(yuv_to_xrgb(VIRTIO_GPU_FORMAT_NV12, &layout, &[0; 12], 2, 2, &mut [0; 8], 8).is_none());
+    }
+}
@@ -73,6 +73,11 @@ fn compile_protocol<P: AsRef<Path>>(name: &str, out: P) -> PathBuf {
 }
 
 fn main() {
+    // The helpers are only used by the Wayland display.
+    if env::var_os("CARGO_FEATURE_WAYLAND").is_none() {
+        return;
+    }
+
     println!("cargo:rerun-if-env-changed=WAYLAND_PROTOCOLS_PATH");
     let out_dir = env::var("OUT_DIR").unwrap();
 
@@ -87,11 +87,21 @@ struct dwl_surface {
 	uint32_t height;
 	double scale;
 	bool close_requested;
+	// Pending until the compositor shows the last flip.
+	struct wl_callback *frame_callback;
 	size_t buffer_count;
 	uint64_t buffer_use_bit_mask;
 	struct wl_buffer *buffers[0];
 };
 
+// A damaged area of a surface, in buffer pixels.
+struct dwl_rect {
+	uint32_t x;
+	uint32_t y;
+	uint32_t width;
+	uint32_t height;
+};
+
 static_assert(sizeof(((struct dwl_surface *)0)->buffer_use_bit_mask) * 8 >=
 		  MAX_BUFFER_COUNT,
 	      "not enough bits in buffer_use_bit_mask");
@@ -552,6 +562,30 @@ static void surface_buffer_release(void *data, struct wl_buffer *buffer)
 static const struct wl_buffer_listener surface_buffer_listener = {
     .release = surface_buffer_release};
 
+static void surface_frame_done(void *data, struct wl_callback *callback,
+			       uint32_t time)
+{
+	struct dwl_surface *surface = (struct dwl_surface *)data;
+	(void)time;
+
+	wl_callback_destroy(callback);
+	surface->frame_callback = NULL;
+}
+
+static const struct wl_callback_listener surface_frame_listener = {
+    .done = surface_frame_done};
+
+// Asks the compositor to tell when the next commit is shown, which happens
+// on the vertical blank of the output.
+static void surface_request_frame(struct dwl_surface *self)
+{
+	if (self->frame_callback)
+		return;
+	self->frame_callback = wl_surface_frame(self->surface);
+	wl_callback_add_listener(self->frame_callback, &surface_frame_listener,
+				 self);
+}
+
 struct dwl_surface *dwl_context_surface_new(struct dwl_context *self,
 					    struct dwl_surface *parent,
 					    int shm_fd, size_t shm_size,
@@ -731,6 +765,8 @@ fail:
 void dwl_surface_destroy(struct dwl_surface **self)
 {
 	size_t i;
+	if ((*self)->frame_callback)
+		wl_callback_destroy((*self)->frame_callback);
 	if ((*self)->viewport)
 		wp_viewport_destroy((*self)->viewport);
 	if ((*self)->subsurface)
@@ -773,6 +809,24 @@ void dwl_surface_flip(struct dwl_surface *self, size_t buffer_index)
 		return;
 	wl_surface_attach(self->surface, self->buffers[buffer_index], 0, 0);
 	wl_surface_damage(self->surface, 0, 0, self->width, self->height);
+	surface_request_frame(self);
+	dwl_surface_commit(self);
+	self->buffer_use_bit_mask |= 1 << buffer_index;
+}
+
+void dwl_surface_flip_damage(struct dwl_surface *self, size_t buffer_index,
+			     const struct dwl_rect *rects, size_t rect_count)
+{
+	size_t i;
+	if (buffer_index >= self->buffer_count)
+		return;
+	// The buffers live in the shm pool shared with the compositor, which
+	// only has to upload the damaged rects of it.
+	wl_surface_attach(self->surface, self->buffers[buffer_index], 0, 0);
+	for (i = 0; i < rect_count; i++)
+		wl_surface_damage(self->surface, rects[i].x, rects[i].y,
+				  rects[i].width, rects[i].height);
+	surface_request_frame(self);
 	dwl_surface_commit(self);
 	self->buffer_use_bit_mask |= 1 << buffer_index;
 }
@@ -783,10 +837,16 @@ void dwl_surface_flip_to(struct dwl_surface *self, struct dwl_dmabuf *dmabuf)
 		return;
 	wl_surface_attach(self->surface, dmabuf->buffer, 0, 0);
 	wl_surface_damage(self->surface, 0, 0, self->width, self->height);
+	surface_request_frame(self);
 	dwl_surface_commit(self);
 	dmabuf->in_use = true;
 }
 
+bool dwl_surface_frame_pending(const struct dwl_surface *self)
+{
+	return self->frame_callback != NULL;
+}
+
 bool dwl_surface_close_requested(const struct dwl_surface *self)
 {
 	return self->close_requested;
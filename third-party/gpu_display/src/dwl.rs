@@ -57,6 +57,14 @@ pub struct dwl_dmabuf {
 pub struct dwl_surface {
     pub _bindgen_opaque_blob: [u64; 12usize],
 }
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct dwl_rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
 extern "C" {
     pub fn dwl_context_new() -> *mut dwl_context;
 }
@@ -114,9 +122,20 @@ extern "C" {
 extern "C" {
     pub fn dwl_surface_flip(self_: *mut dwl_surface, buffer_index: usize);
 }
+extern "C" {
+    pub fn dwl_surface_flip_damage(
+        self_: *mut dwl_surface,
+        buffer_index: usize,
+        rects: *const dwl_rect,
+        rect_count: usize,
+    );
+}
 extern "C" {
     pub fn dwl_surface_flip_to(self_: *mut dwl_surface, dmabuf: *mut dwl_dmabuf);
 }
+extern "C" {
+    pub fn dwl_surface_frame_pending(self_: *const dwl_surface) -> bool;
+}
 extern "C" {
     pub fn dwl_surface_close_requested(self_: *const dwl_surface) -> bool;
 }
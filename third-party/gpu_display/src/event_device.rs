@@ -26,7 +26,7 @@ const EVENT_BUFFER_LEN_MAX: usize = 16 * EVENT_SIZE;
 //     }
 // }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum EventDeviceKind {
     /// Produces relative mouse motions, wheel, and button clicks while the real mouse is captured.
     Mouse,
@@ -37,6 +37,7 @@ pub enum EventDeviceKind {
 }
 
 /// Encapsulates a virtual event device, such as a mouse or keyboard
+#[derive(Debug)]
 pub struct EventDevice {
     kind: EventDeviceKind,
     event_buffer: VecDeque<u8>,
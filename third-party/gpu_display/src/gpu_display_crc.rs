@@ -0,0 +1,231 @@
+// A display backend that shows nothing but records a CRC32 of every presented frame, so tests
+// can check what the guest drew without keeping golden images around.
+
+use std::collections::BTreeMap;
+use std::num::NonZeroU32;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+use crate::{EventDevice, GpuDisplayBackend, GpuDisplayError, GpuDisplayFramebuffer};
+
+use data_model::VolatileSlice;
+
+type SurfaceId = NonZeroU32;
+
+// XRGB8888
+const BYTES_PER_PIXEL: u32 = 4;
+
+/// Number of frame checksums kept per surface, older ones are dropped.
+const MAX_FRAMES_PER_SURFACE: usize = 256;
+
+/// CRC-32 (IEEE 802.3), as computed by zlib.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// The checksums of the frames presented on a CRC display, shared with whoever inspects them.
+#[derive(Clone, Default)]
+pub struct FrameChecksums {
+    frames: Arc<Mutex<BTreeMap<u32, Vec<u32>>>>,
+}
+
+impl FrameChecksums {
+    /// CRC32 of the last frame presented on the surface.
+    pub fn latest(&self, surface_id: u32) -> Option<u32> {
+        self.frames
+            .lock()
+            .unwrap()
+            .get(&surface_id)
+            .and_then(|crcs| crcs.last().cloned())
+    }
+
+    /// CRC32 of the most recent frames presented on the surface, oldest first.
+    pub fn history(&self, surface_id: u32) -> Vec<u32> {
+        self.frames
+            .lock()
+            .unwrap()
+            .get(&surface_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn record(&self, surface_id: u32, crc: u32) {
+        let mut frames = self.frames.lock().unwrap();
+        let crcs = frames.entry(surface_id).or_insert_with(Vec::new);
+        if crcs.len() == MAX_FRAMES_PER_SURFACE {
+            crcs.remove(0);
+        }
+        crcs.push(crc);
+    }
+}
+
+struct Surface {
+    width: u32,
+    buffer: Vec<u8>,
+}
+
+pub struct DisplayCrc {
+    next_surface_id: SurfaceId,
+    surfaces: BTreeMap<SurfaceId, Surface>,
+    checksums: FrameChecksums,
+}
+
+impl DisplayCrc {
+    pub fn new(checksums: FrameChecksums) -> Result<DisplayCrc, GpuDisplayError> {
+        Ok(DisplayCrc {
+            next_surface_id: SurfaceId::new(1).unwrap(),
+            surfaces: Default::default(),
+            checksums,
+        })
+    }
+
+    fn get_surface(&mut self, surface_id: u32) -> Option<&mut Surface> {
+        SurfaceId::new(surface_id).and_then(move |id| self.surfaces.get_mut(&id))
+    }
+}
+
+impl GpuDisplayBackend for DisplayCrc {
+    fn dispatch_events(&mut self) {}
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        width: u32,
+        height: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        if parent_surface_id.is_some() {
+            return Err(GpuDisplayError::Unsupported);
+        }
+        let size = (width as usize) * (height as usize) * (BYTES_PER_PIXEL as usize);
+        let new_surface_id = self.next_surface_id;
+        self.surfaces.insert(
+            new_surface_id,
+            Surface {
+                width,
+                buffer: vec![0; size],
+            },
+        );
+        self.next_surface_id = SurfaceId::new(self.next_surface_id.get() + 1).unwrap();
+
+        Ok(new_surface_id.get())
+    }
+
+    fn release_surface(&mut self, surface_id: u32) {
+        SurfaceId::new(surface_id).and_then(|id| self.surfaces.remove(&id));
+    }
+
+    fn framebuffer(&mut self, surface_id: u32) -> Option<GpuDisplayFramebuffer> {
+        self.get_surface(surface_id).map(|s| {
+            GpuDisplayFramebuffer::new(
+                VolatileSlice::new(s.buffer.as_mut_slice()),
+                s.width * BYTES_PER_PIXEL,
+                BYTES_PER_PIXEL,
+            )
+        })
+    }
+
+    fn next_buffer_in_use(&self, _surface_id: u32) -> bool {
+        false
+    }
+
+    fn flip(&mut self, surface_id: u32) {
+        let crc = match self.get_surface(surface_id) {
+            Some(surface) => crc32(&surface.buffer),
+            None => return,
+        };
+        self.checksums.record(surface_id, crc);
+    }
+
+    fn close_requested(&self, _surface_id: u32) -> bool {
+        false
+    }
+
+    fn import_dmabuf(
+        &mut self,
+        _fd: RawFd,
+        _offset: u32,
+        _stride: u32,
+        _modifiers: u64,
+        _width: u32,
+        _height: u32,
+        _fourcc: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+
+    fn release_import(&mut self, _import_id: u32) {
+        // unsupported
+    }
+
+    fn commit(&mut self, _surface_id: u32) {
+        // unsupported
+    }
+
+    fn flip_to(&mut self, _surface_id: u32, _import_id: u32) {
+        // unsupported
+    }
+
+    fn set_position(&mut self, _surface_id: u32, _x: u32, _y: u32) {
+        // unsupported
+    }
+
+    fn move_surface(&mut self, _surface_id: u32, _x: u32, _y: u32) -> Result<(), GpuDisplayError> {
+        Ok(())
+    }
+
+    fn import_event_device(&mut self, _event_device: EventDevice) -> Result<u32, GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+
+    fn release_event_device(&mut self, _event_device_id: u32) {
+        // unsupported
+    }
+
+    fn attach_event_device(&mut self, _surface_id: u32, _event_device_id: u32) {
+        // unsupported
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn records_frame_checksums() {
+        let checksums = FrameChecksums::default();
+        let mut display = DisplayCrc::new(checksums.clone()).unwrap();
+        let surface_id = display.create_surface(None, 4, 4).unwrap();
+        assert_eq!(checksums.latest(surface_id), None);
+
+        display.flip(surface_id);
+        let black = crc32(&[0; 4 * 4 * 4]);
+        assert_eq!(checksums.latest(surface_id), Some(black));
+
+        display
+            .framebuffer(surface_id)
+            .unwrap()
+            .as_volatile_slice()
+            .write_bytes(0xff);
+        display.flip(surface_id);
+        assert_eq!(
+            checksums.history(surface_id),
+            vec![black, crc32(&[0xff; 4 * 4 * 4])]
+        );
+    }
+}
@@ -0,0 +1,280 @@
+// A display backend writing every presented frame to numbered image files, for checking the
+// guest output without a display server.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::num::NonZeroU32;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+use crate::{EventDevice, GpuDisplayBackend, GpuDisplayError, GpuDisplayFramebuffer};
+
+use data_model::VolatileSlice;
+
+type SurfaceId = NonZeroU32;
+
+// XRGB8888
+const BYTES_PER_PIXEL: u32 = 4;
+
+/// The file format of the dumped frames.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FrameDumpFormat {
+    /// Binary portable pixmap, needs no extra dependency.
+    Ppm,
+    /// Only available with the `png` feature.
+    #[cfg(feature = "png")]
+    Png,
+}
+
+impl FrameDumpFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            FrameDumpFormat::Ppm => "ppm",
+            #[cfg(feature = "png")]
+            FrameDumpFormat::Png => "png",
+        }
+    }
+}
+
+struct Surface {
+    width: u32,
+    height: u32,
+    buffer: Vec<u8>,
+    /// Contents of the last dumped frame, kept to skip frames without damage.
+    last_frame: Option<Vec<u8>>,
+}
+
+impl Surface {
+    fn new(width: u32, height: u32) -> Surface {
+        let size = (width as usize) * (height as usize) * (BYTES_PER_PIXEL as usize);
+        Surface {
+            width,
+            height,
+            buffer: vec![0; size],
+            last_frame: None,
+        }
+    }
+
+    fn framebuffer(&mut self) -> GpuDisplayFramebuffer {
+        GpuDisplayFramebuffer::new(
+            VolatileSlice::new(self.buffer.as_mut_slice()),
+            self.width * BYTES_PER_PIXEL,
+            BYTES_PER_PIXEL,
+        )
+    }
+
+    /// The frame as packed RGB triplets.
+    fn rgb(&self) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity((self.width as usize) * (self.height as usize) * 3);
+        for bgrx in self.buffer.chunks(BYTES_PER_PIXEL as usize) {
+            rgb.extend_from_slice(&[bgrx[2], bgrx[1], bgrx[0]]);
+        }
+        rgb
+    }
+}
+
+pub struct DisplayDump {
+    directory: PathBuf,
+    format: FrameDumpFormat,
+    only_on_damage: bool,
+    next_surface_id: SurfaceId,
+    surfaces: BTreeMap<SurfaceId, Surface>,
+    frame_count: u64,
+    /// The first frame that couldn't be written, dumping stops with it.
+    error: Option<io::Error>,
+}
+
+impl DisplayDump {
+    pub fn new<P: AsRef<Path>>(
+        directory: P,
+        format: FrameDumpFormat,
+        only_on_damage: bool,
+    ) -> Result<DisplayDump, GpuDisplayError> {
+        let directory = directory.as_ref().to_path_buf();
+        if !directory.is_dir() {
+            return Err(GpuDisplayError::InvalidPath);
+        }
+        Ok(DisplayDump {
+            directory,
+            format,
+            only_on_damage,
+            next_surface_id: SurfaceId::new(1).unwrap(),
+            surfaces: Default::default(),
+            frame_count: 0,
+            error: None,
+        })
+    }
+
+    fn get_surface(&mut self, surface_id: u32) -> Option<&mut Surface> {
+        SurfaceId::new(surface_id).and_then(move |id| self.surfaces.get_mut(&id))
+    }
+}
+
+fn write_frame(format: FrameDumpFormat, path: &Path, surface: &Surface) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    match format {
+        FrameDumpFormat::Ppm => {
+            write!(writer, "P6\n{} {}\n255\n", surface.width, surface.height)?;
+            writer.write_all(&surface.rgb())?;
+        }
+        #[cfg(feature = "png")]
+        FrameDumpFormat::Png => {
+            let mut encoder = png_encoder::Encoder::new(&mut writer, surface.width, surface.height);
+            encoder.set_color(png_encoder::ColorType::RGB);
+            encoder.set_depth(png_encoder::BitDepth::Eight);
+            encoder
+                .write_header()
+                .and_then(|mut png_writer| png_writer.write_image_data(&surface.rgb()))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        }
+    }
+    writer.flush()
+}
+
+impl GpuDisplayBackend for DisplayDump {
+    fn dispatch_events(&mut self) {}
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        width: u32,
+        height: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        if parent_surface_id.is_some() {
+            return Err(GpuDisplayError::Unsupported);
+        }
+        let new_surface_id = self.next_surface_id;
+        self.surfaces.insert(new_surface_id, Surface::new(width, height));
+        self.next_surface_id = SurfaceId::new(self.next_surface_id.get() + 1).unwrap();
+
+        Ok(new_surface_id.get())
+    }
+
+    fn release_surface(&mut self, surface_id: u32) {
+        SurfaceId::new(surface_id).and_then(|id| self.surfaces.remove(&id));
+    }
+
+    fn framebuffer(&mut self, surface_id: u32) -> Option<GpuDisplayFramebuffer> {
+        self.get_surface(surface_id).map(|s| s.framebuffer())
+    }
+
+    fn next_buffer_in_use(&self, _surface_id: u32) -> bool {
+        false
+    }
+
+    fn flip(&mut self, surface_id: u32) {
+        if self.error.is_some() {
+            return;
+        }
+        let format = self.format;
+        let only_on_damage = self.only_on_damage;
+        let path = self.directory.join(format!(
+            "surface{}-frame{:06}.{}",
+            surface_id,
+            self.frame_count,
+            format.extension()
+        ));
+
+        let surface = match self.get_surface(surface_id) {
+            Some(surface) => surface,
+            None => return,
+        };
+        if only_on_damage && surface.last_frame.as_ref() == Some(&surface.buffer) {
+            return;
+        }
+        if let Err(e) = write_frame(format, &path, surface) {
+            self.error = Some(e);
+            return;
+        }
+        if only_on_damage {
+            surface.last_frame = Some(surface.buffer.clone());
+        }
+        self.frame_count += 1;
+    }
+
+    fn close_requested(&self, _surface_id: u32) -> bool {
+        false
+    }
+
+    fn import_dmabuf(
+        &mut self,
+        _fd: RawFd,
+        _offset: u32,
+        _stride: u32,
+        _modifiers: u64,
+        _width: u32,
+        _height: u32,
+        _fourcc: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+
+    fn release_import(&mut self, _import_id: u32) {
+        // unsupported
+    }
+
+    fn commit(&mut self, _surface_id: u32) {
+        // unsupported
+    }
+
+    fn flip_to(&mut self, _surface_id: u32, _import_id: u32) {
+        // unsupported
+    }
+
+    fn set_position(&mut self, _surface_id: u32, _x: u32, _y: u32) {
+        // unsupported
+    }
+
+    fn import_event_device(&mut self, _event_device: EventDevice) -> Result<u32, GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+
+    fn release_event_device(&mut self, _event_device_id: u32) {
+        // unsupported
+    }
+
+    fn attach_event_device(&mut self, _surface_id: u32, _event_device_id: u32) {
+        // unsupported
+    }
+
+    /// A dump that can't write its frames anymore, e.g. to a removed directory or a full disk,
+    /// is as good as a lost display.
+    fn connection_lost(&self) -> bool {
+        self.error.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn dump_only_damaged_frames() {
+        let directory = std::env::temp_dir().join(format!("gpu_display_dump_{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        let mut display = DisplayDump::new(&directory, FrameDumpFormat::Ppm, true).unwrap();
+        let surface_id = display.create_surface(None, 2, 1).unwrap();
+        display
+            .framebuffer(surface_id)
+            .unwrap()
+            .as_volatile_slice()
+            .copy_from(&[0x01u8, 0x02, 0x03, 0x00, 0x04, 0x05, 0x06, 0x00]);
+        display.flip(surface_id);
+        // nothing changed, so nothing is written
+        display.flip(surface_id);
+
+        let frame = fs::read(directory.join(format!("surface{}-frame000000.ppm", surface_id))).unwrap();
+        assert_eq!(frame, b"P6\n2 1\n255\n\x03\x02\x01\x06\x05\x04");
+        assert!(!directory.join(format!("surface{}-frame000001.ppm", surface_id)).exists());
+
+        fs::remove_dir_all(&directory).unwrap();
+        // the frames have nowhere to go anymore
+        display.framebuffer(surface_id).unwrap().as_volatile_slice().write_bytes(0);
+        display.flip(surface_id);
+        assert_eq!(display.error.as_ref().unwrap().kind(), io::ErrorKind::NotFound);
+        assert!(display.connection_lost());
+    }
+}
@@ -0,0 +1,136 @@
+// A display backend for hosts without any display: surfaces and subsurfaces are only tracked so
+// their ids stay valid, they have no framebuffer and flipping them draws nowhere.
+
+use std::collections::BTreeSet;
+use std::os::unix::io::RawFd;
+
+use crate::{
+    EventDevice, GpuDisplayBackend, GpuDisplayCapabilities, GpuDisplayError, GpuDisplayFramebuffer,
+};
+
+pub struct DisplayHeadless {
+    next_surface_id: u32,
+    surfaces: BTreeSet<u32>,
+}
+
+impl DisplayHeadless {
+    pub fn new() -> Result<DisplayHeadless, GpuDisplayError> {
+        Ok(DisplayHeadless {
+            next_surface_id: 1,
+            surfaces: BTreeSet::new(),
+        })
+    }
+}
+
+impl GpuDisplayBackend for DisplayHeadless {
+    fn dispatch_events(&mut self) {}
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        _width: u32,
+        _height: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        if let Some(parent_surface_id) = parent_surface_id {
+            if !self.surfaces.contains(&parent_surface_id) {
+                return Err(GpuDisplayError::InvalidSurfaceId);
+            }
+        }
+        let surface_id = self.next_surface_id;
+        self.next_surface_id = self
+            .next_surface_id
+            .checked_add(1)
+            .ok_or(GpuDisplayError::Allocate)?;
+        self.surfaces.insert(surface_id);
+        Ok(surface_id)
+    }
+
+    fn release_surface(&mut self, surface_id: u32) {
+        self.surfaces.remove(&surface_id);
+    }
+
+    fn framebuffer(&mut self, _surface_id: u32) -> Option<GpuDisplayFramebuffer> {
+        // there is nothing to draw into
+        None
+    }
+
+    fn next_buffer_in_use(&self, _surface_id: u32) -> bool {
+        false
+    }
+
+    fn flip(&mut self, _surface_id: u32) {}
+
+    fn close_requested(&self, _surface_id: u32) -> bool {
+        false
+    }
+
+    fn import_dmabuf(
+        &mut self,
+        _fd: RawFd,
+        _offset: u32,
+        _stride: u32,
+        _modifiers: u64,
+        _width: u32,
+        _height: u32,
+        _fourcc: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+
+    fn release_import(&mut self, _import_id: u32) {
+        // unsupported
+    }
+
+    fn commit(&mut self, _surface_id: u32) {}
+
+    fn flip_to(&mut self, _surface_id: u32, _import_id: u32) {
+        // unsupported
+    }
+
+    fn set_position(&mut self, _surface_id: u32, _x: u32, _y: u32) {}
+
+    fn move_surface(&mut self, _surface_id: u32, _x: u32, _y: u32) -> Result<(), GpuDisplayError> {
+        Ok(())
+    }
+
+    fn import_event_device(&mut self, _event_device: EventDevice) -> Result<u32, GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+
+    fn release_event_device(&mut self, _event_device_id: u32) {
+        // unsupported
+    }
+
+    fn attach_event_device(&mut self, _surface_id: u32, _event_device_id: u32) {
+        // unsupported
+    }
+
+    fn capabilities(&self) -> GpuDisplayCapabilities {
+        GpuDisplayCapabilities {
+            headless: true,
+            // moving a subsurface costs nothing
+            cursor_plane: true,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn surfaces_draw_nowhere() {
+        let mut display = DisplayHeadless::new().unwrap();
+        let surface_id = display.create_surface(None, 1920, 1080).unwrap();
+        let cursor_id = display.create_surface(Some(surface_id), 64, 64).unwrap();
+        assert_ne!(surface_id, cursor_id);
+        assert!(display.framebuffer(surface_id).is_none());
+        display.flip(surface_id);
+        assert!(display.move_surface(cursor_id, 10, 10).is_ok());
+        assert!(display.capabilities().headless);
+
+        display.release_surface(surface_id);
+        assert!(display.create_surface(Some(surface_id), 64, 64).is_err());
+    }
+}
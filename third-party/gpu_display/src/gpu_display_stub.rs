@@ -6,7 +6,7 @@ use std::collections::BTreeMap;
 use std::num::NonZeroU32;
 use std::os::unix::io::RawFd;
 
-use crate::{DisplayT, EventDevice, GpuDisplayError, GpuDisplayFramebuffer};
+use crate::{EventDevice, GpuDisplayBackend, GpuDisplayError, GpuDisplayFramebuffer};
 
 use data_model::VolatileSlice;
 
@@ -141,7 +141,7 @@ impl DisplayStub {
     }
 }
 
-impl DisplayT for DisplayStub {
+impl GpuDisplayBackend for DisplayStub {
     fn dispatch_events(&mut self) {}
 
     fn create_surface(
@@ -207,6 +207,11 @@ impl DisplayT for DisplayStub {
         // unsupported
     }
 
+    fn move_surface(&mut self, _surface_id: u32, _x: u32, _y: u32) -> Result<(), GpuDisplayError> {
+        // nothing is displayed, so there is nothing to recomposite either
+        Ok(())
+    }
+
     fn import_event_device(&mut self, _event_device: EventDevice) -> Result<u32, GpuDisplayError> {
         Err(GpuDisplayError::Unsupported)
     }
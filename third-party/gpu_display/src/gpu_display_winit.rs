@@ -0,0 +1,354 @@
+// A windowed display backend on winit, which talks to whichever of Wayland or X11 the desktop
+// runs, with softbuffer putting the frames in the windows.  winit only has top level windows, so
+// subsurfaces like the cursor are blended on their parent when it is presented.
+//
+// winit allows a single event loop per process, so the display can only be opened once: a lost
+// connection isn't reported, the device couldn't open the display again, it just stops drawing.
+
+use std::collections::BTreeMap;
+use std::num::NonZeroU32;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::time::Duration;
+
+use data_model::VolatileSlice;
+use softbuffer::{Context, Surface as SoftSurface};
+use winit_window::dpi::PhysicalSize;
+use winit_window::event::{Event, WindowEvent};
+use winit_window::event_loop::{EventLoop, EventLoopBuilder};
+use winit_window::platform::pump_events::{EventLoopExtPumpEvents, PumpStatus};
+use winit_window::platform::wayland::EventLoopBuilderExtWayland;
+use winit_window::window::{Window, WindowBuilder, WindowId};
+
+use crate::{EventDevice, GpuDisplayBackend, GpuDisplayError, GpuDisplayFramebuffer};
+
+// XRGB8888, ARGB8888 for subsurfaces
+const BYTES_PER_PIXEL: u32 = 4;
+
+struct TopLevel {
+    window: Rc<Window>,
+    buffer: SoftSurface<Rc<Window>, Rc<Window>>,
+    close_requested: bool,
+}
+
+struct Surface {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+    parent: Option<u32>,
+    /// relative to the parent, applied on its next presentation
+    position: (u32, u32),
+    top_level: Option<TopLevel>,
+}
+
+impl Surface {
+    fn pixel(&self, x: u32, y: u32) -> u32 {
+        let offset = ((y * self.width + x) * BYTES_PER_PIXEL) as usize;
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&self.pixels[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    }
+}
+
+/// Blends the ARGB8888 `src` over the XRGB8888 `dst`.
+fn blend(dst: u32, src: u32) -> u32 {
+    let alpha = src >> 24;
+    match alpha {
+        0 => dst,
+        0xff => src & 0x00ff_ffff,
+        _ => {
+            let mut out = 0;
+            for shift in &[0, 8, 16] {
+                let s = (src >> shift) & 0xff;
+                let d = (dst >> shift) & 0xff;
+                out |= ((s * alpha + d * (0xff - alpha)) / 0xff) << shift;
+            }
+            out
+        }
+    }
+}
+
+pub struct DisplayWinit {
+    event_loop: EventLoop<()>,
+    title: String,
+    next_surface_id: u32,
+    surfaces: BTreeMap<u32, Surface>,
+    exited: bool,
+}
+
+impl DisplayWinit {
+    /// Connects to the desktop, the windows created afterwards are titled `title`.
+    pub fn new(title: &str) -> Result<DisplayWinit, GpuDisplayError> {
+        let mut builder = EventLoopBuilder::new();
+        // the display lives on a thread of its own, the X11 builder has the same setting
+        EventLoopBuilderExtWayland::with_any_thread(&mut builder, true);
+        let event_loop = builder.build().map_err(|_| GpuDisplayError::Connect)?;
+        Ok(DisplayWinit {
+            event_loop,
+            title: title.to_string(),
+            next_surface_id: 1,
+            surfaces: BTreeMap::new(),
+            exited: false,
+        })
+    }
+
+    fn top_level_id(&self, surface_id: u32) -> Option<u32> {
+        let surface = self.surfaces.get(&surface_id)?;
+        Some(surface.parent.unwrap_or(surface_id))
+    }
+
+    /// Puts the contents of the top level surface and of its subsurfaces in its window.
+    fn present(&mut self, surface_id: u32) {
+        let top_level_id = match self.top_level_id(surface_id) {
+            Some(id) => id,
+            None => return,
+        };
+        let frame = {
+            let surface = &self.surfaces[&top_level_id];
+            let children: Vec<&Surface> = self
+                .surfaces
+                .values()
+                .filter(|child| child.parent == Some(top_level_id))
+                .collect();
+            let mut frame: Vec<u32> = surface
+                .pixels
+                .chunks_exact(BYTES_PER_PIXEL as usize)
+                .map(|pixel| u32::from_le_bytes([pixel[0], pixel[1], pixel[2], 0]))
+                .collect();
+            for child in children {
+                let (x0, y0) = child.position;
+                for y in 0..child.height {
+                    for x in 0..child.width {
+                        let (x1, y1) = (x0.saturating_add(x), y0.saturating_add(y));
+                        if x1 >= surface.width || y1 >= surface.height {
+                            continue;
+                        }
+                        let index = (y1 * surface.width + x1) as usize;
+                        frame[index] = blend(frame[index], child.pixel(x, y));
+                    }
+                }
+            }
+            frame
+        };
+
+        let top_level = match self
+            .surfaces
+            .get_mut(&top_level_id)
+            .and_then(|surface| surface.top_level.as_mut())
+        {
+            Some(top_level) => top_level,
+            None => return,
+        };
+        // the frame is dropped if the window can't take it, the next one is tried anyway
+        if let Ok(mut buffer) = top_level.buffer.buffer_mut() {
+            if buffer.len() == frame.len() {
+                buffer.copy_from_slice(&frame);
+                let _ = buffer.present();
+            }
+        }
+    }
+
+    fn create_window(&self, width: u32, height: u32) -> Result<TopLevel, GpuDisplayError> {
+        let window = WindowBuilder::new()
+            .with_title(self.title.as_str())
+            .with_inner_size(PhysicalSize::new(width, height))
+            .with_resizable(false)
+            .build(&self.event_loop)
+            .map_err(|_| GpuDisplayError::CreateSurface)?;
+        let window = Rc::new(window);
+        let context = Context::new(window.clone()).map_err(|_| GpuDisplayError::CreateSurface)?;
+        let mut buffer = SoftSurface::new(&context, window.clone())
+            .map_err(|_| GpuDisplayError::CreateSurface)?;
+        let (width, height) = match (NonZeroU32::new(width), NonZeroU32::new(height)) {
+            (Some(width), Some(height)) => (width, height),
+            _ => return Err(GpuDisplayError::CreateSurface),
+        };
+        buffer
+            .resize(width, height)
+            .map_err(|_| GpuDisplayError::CreateSurface)?;
+        Ok(TopLevel {
+            window,
+            buffer,
+            close_requested: false,
+        })
+    }
+}
+
+impl GpuDisplayBackend for DisplayWinit {
+    fn dispatch_events(&mut self) {
+        if self.exited {
+            return;
+        }
+        let mut closed: Vec<WindowId> = Vec::new();
+        let mut exposed: Vec<WindowId> = Vec::new();
+        let status = self
+            .event_loop
+            .pump_events(Some(Duration::ZERO), |event, _| match event {
+                Event::WindowEvent {
+                    window_id,
+                    event: WindowEvent::CloseRequested,
+                } => closed.push(window_id),
+                Event::WindowEvent {
+                    window_id,
+                    event: WindowEvent::RedrawRequested,
+                } => exposed.push(window_id),
+                _ => {}
+            });
+        if let PumpStatus::Exit(_) = status {
+            self.exited = true;
+        }
+
+        let mut redraw = Vec::new();
+        for (surface_id, surface) in self.surfaces.iter_mut() {
+            if let Some(ref mut top_level) = surface.top_level {
+                let window_id = top_level.window.id();
+                if closed.contains(&window_id) {
+                    top_level.close_requested = true;
+                }
+                if exposed.contains(&window_id) {
+                    redraw.push(*surface_id);
+                }
+            }
+        }
+        for surface_id in redraw {
+            self.present(surface_id);
+        }
+    }
+
+    fn event_fd(&self) -> Option<RawFd> {
+        // the event loop's connection stays readable once it's gone
+        if self.exited {
+            return None;
+        }
+        Some(self.event_loop.as_raw_fd())
+    }
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        width: u32,
+        height: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        let top_level = match parent_surface_id {
+            Some(parent_surface_id) => {
+                // a single level of subsurfaces, which is what the cursor needs
+                match self.surfaces.get(&parent_surface_id) {
+                    Some(parent) if parent.parent.is_none() => None,
+                    _ => return Err(GpuDisplayError::InvalidSurfaceId),
+                }
+            }
+            None => Some(self.create_window(width, height)?),
+        };
+        let size = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|pixels| pixels.checked_mul(BYTES_PER_PIXEL as usize))
+            .ok_or(GpuDisplayError::Allocate)?;
+
+        let surface_id = self.next_surface_id;
+        self.next_surface_id += 1;
+        self.surfaces.insert(
+            surface_id,
+            Surface {
+                width,
+                height,
+                pixels: vec![0; size],
+                parent: parent_surface_id,
+                position: (0, 0),
+                top_level,
+            },
+        );
+        Ok(surface_id)
+    }
+
+    fn release_surface(&mut self, surface_id: u32) {
+        // a subsurface goes away with the next presentation of its parent, a window along with
+        // its subsurfaces
+        if let Some(surface) = self.surfaces.remove(&surface_id) {
+            if surface.parent.is_none() {
+                self.surfaces
+                    .retain(|_, child| child.parent != Some(surface_id));
+            }
+        }
+    }
+
+    fn framebuffer(&mut self, surface_id: u32) -> Option<GpuDisplayFramebuffer> {
+        let surface = self.surfaces.get_mut(&surface_id)?;
+        let stride = surface.width * BYTES_PER_PIXEL;
+        Some(GpuDisplayFramebuffer::new(
+            VolatileSlice::new(surface.pixels.as_mut_slice()),
+            stride,
+            BYTES_PER_PIXEL,
+        ))
+    }
+
+    fn next_buffer_in_use(&self, _surface_id: u32) -> bool {
+        // softbuffer copies the frame out on present
+        false
+    }
+
+    fn flip(&mut self, surface_id: u32) {
+        self.present(surface_id);
+    }
+
+    fn close_requested(&self, surface_id: u32) -> bool {
+        self.surfaces
+            .get(&surface_id)
+            .and_then(|surface| surface.top_level.as_ref())
+            .map_or(false, |top_level| top_level.close_requested)
+    }
+
+    fn import_dmabuf(
+        &mut self,
+        _fd: RawFd,
+        _offset: u32,
+        _stride: u32,
+        _modifiers: u64,
+        _width: u32,
+        _height: u32,
+        _fourcc: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+
+    fn release_import(&mut self, _import_id: u32) {
+        // unsupported
+    }
+
+    fn commit(&mut self, surface_id: u32) {
+        self.present(surface_id);
+    }
+
+    fn flip_to(&mut self, _surface_id: u32, _import_id: u32) {
+        // unsupported
+    }
+
+    fn set_position(&mut self, surface_id: u32, x: u32, y: u32) {
+        if let Some(surface) = self.surfaces.get_mut(&surface_id) {
+            surface.position = (x, y);
+        }
+    }
+
+    fn import_event_device(&mut self, _event_device: EventDevice) -> Result<u32, GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+
+    fn release_event_device(&mut self, _event_device_id: u32) {
+        // unsupported
+    }
+
+    fn attach_event_device(&mut self, _surface_id: u32, _event_device_id: u32) {
+        // unsupported
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blend_cursor_pixels() {
+        assert_eq!(blend(0x0011_2233, 0x00ff_ffff), 0x0011_2233);
+        assert_eq!(blend(0x0011_2233, 0xff44_5566), 0x0044_5566);
+        assert_eq!(blend(0x0000_0000, 0x80ff_ffff), 0x0080_8080);
+    }
+}
@@ -0,0 +1,443 @@
+// Copyright 2018 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A display backend presenting surfaces on a Wayland compositor, through the helpers of
+//! `display_wl.c`.  Surfaces are double buffered in a wl_shm pool backed by a memfd, imported
+//! dmabufs are shown with zwp_linux_dmabuf_v1 without a copy.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::ptr::{null, null_mut};
+
+use base::{pagesize, MemoryMapping};
+use data_model::VolatileMemory;
+
+use crate::dwl::*;
+use crate::{
+    EventDevice, GpuDisplayBackend, GpuDisplayCapabilities, GpuDisplayError, GpuDisplayFramebuffer,
+    GpuDisplayRect,
+};
+
+const BUFFER_COUNT: usize = 2;
+const BYTES_PER_PIXEL: u32 = 4;
+
+struct DwlContext(*mut dwl_context);
+impl Drop for DwlContext {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            // Safe given that we checked the pointer for non-null and it should always be of the
+            // correct type.
+            unsafe {
+                dwl_context_destroy(&mut self.0);
+            }
+        }
+    }
+}
+
+struct DwlDmabuf(*mut dwl_dmabuf);
+impl Drop for DwlDmabuf {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            // Safe given that we checked the pointer for non-null and it should always be of the
+            // correct type.
+            unsafe {
+                dwl_dmabuf_destroy(&mut self.0);
+            }
+        }
+    }
+}
+
+struct DwlSurface(*mut dwl_surface);
+impl Drop for DwlSurface {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            // Safe given that we checked the pointer for non-null and it should always be of the
+            // correct type.
+            unsafe {
+                dwl_surface_destroy(&mut self.0);
+            }
+        }
+    }
+}
+
+struct WaylandSurface {
+    surface: DwlSurface,
+    row_size: u32,
+    buffer_size: usize,
+    /// The buffer last flipped, the one after it is handed out by `framebuffer`.
+    buffer_index: usize,
+    buffer_mem: MemoryMapping,
+}
+
+impl WaylandSurface {
+    fn surface(&self) -> *mut dwl_surface {
+        self.surface.0
+    }
+
+    fn next_buffer_index(&self) -> usize {
+        (self.buffer_index + 1) % BUFFER_COUNT
+    }
+}
+
+fn round_up_to_page_size(size: usize) -> usize {
+    let page_size = pagesize();
+    (size + page_size - 1) & !(page_size - 1)
+}
+
+/// Creates the memfd the buffers of a surface are shared with the compositor in.
+fn create_shm(size: usize) -> Result<File, GpuDisplayError> {
+    let name = CString::new("gpu_display_wl").unwrap();
+    // Safe because the name is NUL terminated and the returned fd is checked.
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(GpuDisplayError::CreateShm);
+    }
+    // Safe because the fd was just created and nothing else owns it.
+    let file = unsafe { File::from_raw_fd(fd) };
+    file.set_len(size as u64)
+        .map_err(|_| GpuDisplayError::CreateShm)?;
+    Ok(file)
+}
+
+/// A connection to the compositor and associated collection of state.
+pub struct DisplayWl {
+    dmabufs: HashMap<u32, DwlDmabuf>,
+    dmabuf_next_id: u32,
+    surfaces: HashMap<u32, WaylandSurface>,
+    surface_next_id: u32,
+    connection_lost: bool,
+    ctx: DwlContext,
+}
+
+impl DisplayWl {
+    /// Opens a fresh connection to the compositor, on the socket at `wayland_path` or the one
+    /// `WAYLAND_DISPLAY` names if `None`.
+    pub fn new(wayland_path: Option<&Path>) -> Result<DisplayWl, GpuDisplayError> {
+        // The dwl_context_new call should always be safe to call, and we check its result.
+        let ctx = DwlContext(unsafe { dwl_context_new() });
+        if ctx.0.is_null() {
+            return Err(GpuDisplayError::Allocate);
+        }
+
+        let cstr_path = match wayland_path.map(|p| p.as_os_str().to_str()) {
+            Some(Some(s)) => match CString::new(s) {
+                Ok(cstr) => Some(cstr),
+                Err(_) => return Err(GpuDisplayError::InvalidPath),
+            },
+            Some(None) => return Err(GpuDisplayError::InvalidPath),
+            None => None,
+        };
+        // This grabs a pointer to cstr_path without moving the CString into the .map closure
+        // accidentally, which triggers a really hard to catch use after free in
+        // dwl_context_setup.
+        let cstr_path_ptr = cstr_path
+            .as_ref()
+            .map(|s: &CString| s.as_ptr())
+            .unwrap_or(null());
+        // Safe because the context was checked for non-null and the path is NUL terminated.
+        let setup_success = unsafe { dwl_context_setup(ctx.0, cstr_path_ptr) };
+        if !setup_success {
+            return Err(GpuDisplayError::Connect);
+        }
+
+        Ok(DisplayWl {
+            dmabufs: Default::default(),
+            dmabuf_next_id: 0,
+            surfaces: Default::default(),
+            surface_next_id: 0,
+            connection_lost: false,
+            ctx,
+        })
+    }
+
+    fn ctx(&self) -> *mut dwl_context {
+        self.ctx.0
+    }
+
+    fn get_surface(&self, surface_id: u32) -> Option<&WaylandSurface> {
+        self.surfaces.get(&surface_id)
+    }
+}
+
+impl GpuDisplayBackend for DisplayWl {
+    fn import_dmabuf(
+        &mut self,
+        fd: RawFd,
+        offset: u32,
+        stride: u32,
+        modifiers: u64,
+        width: u32,
+        height: u32,
+        fourcc: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        // Safe given that the context pointer is valid. Any other invalid parameters would be
+        // rejected by dwl_context_dmabuf_new safely. We check that the resulting dmabuf is valid
+        // before filing it away.
+        let dmabuf = DwlDmabuf(unsafe {
+            dwl_context_dmabuf_new(
+                self.ctx(),
+                fd,
+                offset,
+                stride,
+                modifiers,
+                width,
+                height,
+                fourcc,
+            )
+        });
+        if dmabuf.0.is_null() {
+            return Err(GpuDisplayError::FailedImport);
+        }
+
+        let next_id = self.dmabuf_next_id;
+        self.dmabufs.insert(next_id, dmabuf);
+        self.dmabuf_next_id += 1;
+        Ok(next_id)
+    }
+
+    fn release_import(&mut self, import_id: u32) {
+        self.dmabufs.remove(&import_id);
+    }
+
+    fn dispatch_events(&mut self) {
+        if self.connection_lost {
+            return;
+        }
+        // dwl_context_dispatch blocks until the compositor sends something, only call it when
+        // there is something to read.
+        let mut pollfd = libc::pollfd {
+            // Safe given that the context pointer is valid.
+            fd: unsafe { dwl_context_fd(self.ctx()) },
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safe because a single valid pollfd is passed and the result is checked.
+        if unsafe { libc::poll(&mut pollfd, 1, 0) } <= 0 {
+            return;
+        }
+        if pollfd.revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+            self.connection_lost = true;
+            return;
+        }
+        // Safe given that the context pointer is valid.
+        unsafe {
+            dwl_context_dispatch(self.ctx());
+        }
+    }
+
+    fn event_fd(&self) -> Option<RawFd> {
+        // Safe given that the context pointer is valid.
+        Some(unsafe { dwl_context_fd(self.ctx()) })
+    }
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        width: u32,
+        height: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        let parent_ptr = match parent_surface_id {
+            Some(id) => match self.get_surface(id).map(|p| p.surface()) {
+                Some(ptr) => ptr,
+                None => return Err(GpuDisplayError::InvalidSurfaceId),
+            },
+            None => null_mut(),
+        };
+        let row_size = width
+            .checked_mul(BYTES_PER_PIXEL)
+            .ok_or(GpuDisplayError::CreateSurface)?;
+        let fb_size = (row_size as usize)
+            .checked_mul(height as usize)
+            .ok_or(GpuDisplayError::CreateSurface)?;
+        let buffer_size = round_up_to_page_size(fb_size * BUFFER_COUNT);
+        let buffer_shm = create_shm(buffer_size)?;
+        let buffer_mem = MemoryMapping::from_fd(&buffer_shm, buffer_size)
+            .map_err(|_| GpuDisplayError::CreateShm)?;
+
+        // Safe because only a valid context, parent pointer (if not None), and buffer FD are used.
+        // The returned surface is checked for validity before being filed away.
+        let surface = DwlSurface(unsafe {
+            dwl_context_surface_new(
+                self.ctx(),
+                parent_ptr,
+                buffer_shm.as_raw_fd(),
+                buffer_size,
+                fb_size,
+                width,
+                height,
+                row_size,
+            )
+        });
+        if surface.0.is_null() {
+            return Err(GpuDisplayError::CreateSurface);
+        }
+
+        let next_id = self.surface_next_id;
+        self.surfaces.insert(
+            next_id,
+            WaylandSurface {
+                surface,
+                row_size,
+                buffer_size: fb_size,
+                buffer_index: 0,
+                buffer_mem,
+            },
+        );
+        self.surface_next_id += 1;
+        Ok(next_id)
+    }
+
+    fn release_surface(&mut self, surface_id: u32) {
+        self.surfaces.remove(&surface_id);
+    }
+
+    fn framebuffer(&mut self, surface_id: u32) -> Option<GpuDisplayFramebuffer> {
+        let surface = self.get_surface(surface_id)?;
+        let buffer_index = surface.next_buffer_index();
+        let framebuffer = surface
+            .buffer_mem
+            .get_slice(buffer_index * surface.buffer_size, surface.buffer_size)
+            .ok()?;
+        Some(GpuDisplayFramebuffer::new(
+            framebuffer,
+            surface.row_size,
+            BYTES_PER_PIXEL,
+        ))
+    }
+
+    fn commit(&mut self, surface_id: u32) {
+        if let Some(surface) = self.get_surface(surface_id) {
+            // Safe because only a valid surface is used.
+            unsafe {
+                dwl_surface_commit(surface.surface());
+            }
+        }
+    }
+
+    fn next_buffer_in_use(&self, surface_id: u32) -> bool {
+        match self.get_surface(surface_id) {
+            // Safe because only a valid surface and buffer index is used.
+            Some(surface) => unsafe {
+                dwl_surface_buffer_in_use(surface.surface(), surface.next_buffer_index())
+            },
+            None => false,
+        }
+    }
+
+    fn flip(&mut self, surface_id: u32) {
+        if let Some(surface) = self.surfaces.get_mut(&surface_id) {
+            surface.buffer_index = surface.next_buffer_index();
+            // Safe because only a valid surface and buffer index is used.
+            unsafe {
+                dwl_surface_flip(surface.surface(), surface.buffer_index);
+            }
+        }
+    }
+
+    fn flip_damage(&mut self, surface_id: u32, damage: &[GpuDisplayRect]) {
+        if let Some(surface) = self.surfaces.get_mut(&surface_id) {
+            surface.buffer_index = surface.next_buffer_index();
+            let rects: Vec<dwl_rect> = damage
+                .iter()
+                .map(|rect| dwl_rect {
+                    x: rect.x,
+                    y: rect.y,
+                    width: rect.width,
+                    height: rect.height,
+                })
+                .collect();
+            // Safe because only a valid surface and buffer index is used, and the rects outlive
+            // the call.
+            unsafe {
+                dwl_surface_flip_damage(
+                    surface.surface(),
+                    surface.buffer_index,
+                    rects.as_ptr(),
+                    rects.len(),
+                );
+            }
+        }
+    }
+
+    fn flip_to(&mut self, surface_id: u32, import_id: u32) {
+        if let Some(surface) = self.get_surface(surface_id) {
+            if let Some(dmabuf) = self.dmabufs.get(&import_id) {
+                // Safe because only a valid surface and dmabuf is used.
+                unsafe { dwl_surface_flip_to(surface.surface(), dmabuf.0) }
+            }
+        }
+    }
+
+    fn frame_pending(&self, surface_id: u32) -> bool {
+        match self.get_surface(surface_id) {
+            // Safe because only a valid surface is used.
+            Some(surface) => unsafe { dwl_surface_frame_pending(surface.surface()) },
+            None => false,
+        }
+    }
+
+    fn close_requested(&self, surface_id: u32) -> bool {
+        match self.get_surface(surface_id) {
+            // Safe because only a valid surface is used.
+            Some(surface) => unsafe { dwl_surface_close_requested(surface.surface()) },
+            None => false,
+        }
+    }
+
+    fn set_position(&mut self, surface_id: u32, x: u32, y: u32) {
+        if let Some(surface) = self.get_surface(surface_id) {
+            // Safe because only a valid surface is used.
+            unsafe {
+                dwl_surface_set_position(surface.surface(), x, y);
+            }
+        }
+    }
+
+    fn import_event_device(&mut self, _event_device: EventDevice) -> Result<u32, GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+
+    fn release_event_device(&mut self, _event_device_id: u32) {
+        // unsupported
+    }
+
+    fn attach_event_device(&mut self, _surface_id: u32, _event_device_id: u32) {
+        // unsupported
+    }
+
+    fn capabilities(&self) -> GpuDisplayCapabilities {
+        GpuDisplayCapabilities {
+            // zwp_linux_buffer_params_v1 takes the modifier along with the planes
+            dmabuf_import: true,
+            modifiers: true,
+            // flips ask for a frame callback
+            vsync_events: true,
+            ..Default::default()
+        }
+    }
+
+    fn connection_lost(&self) -> bool {
+        self.connection_lost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connect_to_missing_compositor() {
+        let path = std::env::temp_dir().join(format!("gpu_display_wl_{}", std::process::id()));
+        match DisplayWl::new(Some(&path)) {
+            Err(GpuDisplayError::Connect) => {}
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("connected to a socket nobody listens on"),
+        }
+        assert_eq!(round_up_to_page_size(1), pagesize());
+        assert_eq!(round_up_to_page_size(pagesize()), pagesize());
+    }
+}
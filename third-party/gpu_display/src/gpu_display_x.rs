@@ -13,11 +13,12 @@ mod xlib;
 
 use linux_input_sys::virtio_input_event;
 use std::cmp::max;
+use std::cell::Cell;
 use std::collections::BTreeMap;
 use std::ffi::{c_void, CStr, CString};
 use std::mem::{transmute_copy, zeroed};
 use std::num::NonZeroU32;
-use std::os::raw::c_ulong;
+use std::os::raw::{c_int, c_long, c_ulong};
 use std::ptr::{null, null_mut, NonNull};
 use std::rc::Rc;
 use std::time::Duration;
@@ -26,14 +27,134 @@ use std::os::unix::prelude::RawFd;
 use libc::{shmat, shmctl, shmdt, shmget, IPC_CREAT, IPC_PRIVATE, IPC_RMID};
 
 use crate::{
-    keycode_converter::KeycodeTranslator, keycode_converter::KeycodeTypes, DisplayT, EventDevice,
-    EventDeviceKind, GpuDisplayError, GpuDisplayFramebuffer,
+    keycode_converter::KeycodeTranslator, keycode_converter::KeycodeTypes, EventDevice,
+    EventDeviceKind, GpuDisplayBackend, GpuDisplayCapabilities, GpuDisplayError,
+    GpuDisplayFramebuffer, GpuDisplayOutput,
 };
 
 use data_model::VolatileSlice;
 
 const BUFFER_COUNT: usize = 2;
 
+/// The few XRandR entry points used to track host monitors.
+#[allow(non_snake_case, non_camel_case_types, non_upper_case_globals)]
+mod xrandr {
+    use super::xlib;
+    use std::os::raw::{c_int, c_uint, c_ushort};
+
+    pub type RRCrtc = xlib::XID;
+    pub type RROutput = xlib::XID;
+    pub type RRMode = xlib::XID;
+
+    pub const RRScreenChangeNotify: c_int = 0;
+    pub const RRNotify: c_int = 1;
+    pub const RRScreenChangeNotifyMask: c_int = 1 << 0;
+    pub const RRCrtcChangeNotifyMask: c_int = 1 << 1;
+    pub const RROutputChangeNotifyMask: c_int = 1 << 2;
+
+    #[repr(C)]
+    pub struct XRRScreenResources {
+        pub timestamp: xlib::Time,
+        pub configTimestamp: xlib::Time,
+        pub ncrtc: c_int,
+        pub crtcs: *mut RRCrtc,
+        pub noutput: c_int,
+        pub outputs: *mut RROutput,
+        pub nmode: c_int,
+        pub modes: *mut std::ffi::c_void,
+    }
+
+    #[repr(C)]
+    pub struct XRRCrtcInfo {
+        pub timestamp: xlib::Time,
+        pub x: c_int,
+        pub y: c_int,
+        pub width: c_uint,
+        pub height: c_uint,
+        pub mode: RRMode,
+        pub rotation: c_ushort,
+        pub noutput: c_int,
+        pub outputs: *mut RROutput,
+        pub rotations: c_ushort,
+        pub npossible: c_int,
+        pub possible: *mut RROutput,
+    }
+
+    #[link(name = "Xrandr")]
+    extern "C" {
+        pub fn XRRQueryExtension(
+            dpy: *mut xlib::Display,
+            event_base: *mut c_int,
+            error_base: *mut c_int,
+        ) -> c_int;
+        pub fn XRRSelectInput(dpy: *mut xlib::Display, window: xlib::Window, mask: c_int);
+        pub fn XRRUpdateConfiguration(event: *mut xlib::XEvent) -> c_int;
+        pub fn XRRGetScreenResourcesCurrent(
+            dpy: *mut xlib::Display,
+            window: xlib::Window,
+        ) -> *mut XRRScreenResources;
+        pub fn XRRFreeScreenResources(resources: *mut XRRScreenResources);
+        pub fn XRRGetCrtcInfo(
+            dpy: *mut xlib::Display,
+            resources: *mut XRRScreenResources,
+            crtc: RRCrtc,
+        ) -> *mut XRRCrtcInfo;
+        pub fn XRRFreeCrtcInfo(crtc_info: *mut XRRCrtcInfo);
+    }
+}
+
+/// Window properties, used for the hints given to the compositor.
+#[allow(non_snake_case, non_upper_case_globals)]
+mod xproperty {
+    use super::xlib;
+    use std::os::raw::{c_int, c_uchar};
+
+    pub const XA_CARDINAL: xlib::Atom = 6;
+    pub const PropModeReplace: c_int = 0;
+
+    #[link(name = "X11")]
+    extern "C" {
+        pub fn XChangeProperty(
+            dpy: *mut xlib::Display,
+            window: xlib::Window,
+            property: xlib::Atom,
+            type_: xlib::Atom,
+            format: c_int,
+            mode: c_int,
+            data: *const c_uchar,
+            nelements: c_int,
+        ) -> c_int;
+        pub fn XDeleteProperty(
+            dpy: *mut xlib::Display,
+            window: xlib::Window,
+            property: xlib::Atom,
+        ) -> c_int;
+    }
+}
+
+/// Surviving an I/O error, which Xlib otherwise answers by exiting the process.  The exit
+/// handler needs libX11 1.7 or later.
+#[allow(non_snake_case)]
+mod xioerror {
+    use super::xlib;
+    use std::ffi::c_void;
+    use std::os::raw::c_int;
+
+    pub type XIOErrorHandler = Option<unsafe extern "C" fn(dpy: *mut xlib::Display) -> c_int>;
+    pub type XIOErrorExitHandler =
+        Option<unsafe extern "C" fn(dpy: *mut xlib::Display, user_data: *mut c_void)>;
+
+    #[link(name = "X11")]
+    extern "C" {
+        pub fn XSetIOErrorHandler(handler: XIOErrorHandler) -> XIOErrorHandler;
+        pub fn XSetIOErrorExitHandler(
+            dpy: *mut xlib::Display,
+            handler: XIOErrorExitHandler,
+            user_data: *mut c_void,
+        );
+    }
+}
+
 type ObjectId = NonZeroU32;
 
 /// A wrapper for XFree that takes any type.
@@ -41,23 +162,83 @@ unsafe fn x_free<T>(t: *mut T) {
     xlib::XFree(t as *mut c_void);
 }
 
+/// The connection to the X server, and whether the server hung up on it.
 #[derive(Clone)]
-struct XDisplay(Rc<NonNull<xlib::Display>>);
+struct XDisplay(Rc<NonNull<xlib::Display>>, Rc<Cell<bool>>);
 impl Drop for XDisplay {
     fn drop(&mut self) {
-        if Rc::strong_count(&self.0) == 1 {
-            unsafe {
+        if Rc::strong_count(&self.0) != 1 {
+            return;
+        }
+        unsafe {
+            if self.is_lost() {
+                // Closing a lost connection is an I/O error, which Xlib survives only if both the
+                // error handler and the display's exit handler return.  The error handler is
+                // process wide, so it is only swapped while closing, on the one thread using Xlib.
+                let previous = xioerror::XSetIOErrorHandler(Some(ignore_io_error));
+                xioerror::XSetIOErrorExitHandler(
+                    self.as_ptr(),
+                    Some(ignore_io_error_exit),
+                    null_mut(),
+                );
+                xlib::XCloseDisplay(self.as_ptr());
+                xioerror::XSetIOErrorHandler(previous);
+            } else {
                 xlib::XCloseDisplay(self.as_ptr());
             }
         }
     }
 }
 
+unsafe extern "C" fn ignore_io_error(_dpy: *mut xlib::Display) -> c_int {
+    0
+}
+
+unsafe extern "C" fn ignore_io_error_exit(_dpy: *mut xlib::Display, _user_data: *mut c_void) {}
+
 impl XDisplay {
     fn as_ptr(&self) -> *mut xlib::Display {
         self.0.as_ptr()
     }
 
+    /// Returns true once the server closed the connection.  Any request made through Xlib after
+    /// that makes it exit the process, so nothing is sent to the server anymore.
+    fn is_lost(&self) -> bool {
+        self.1.get()
+    }
+
+    /// Checks whether the server hung up, peeking at the socket without going through Xlib.
+    /// Returns false once the connection is lost.
+    fn check_connection(&self) -> bool {
+        if self.is_lost() {
+            return false;
+        }
+        let mut byte = 0u8;
+        // Safe because XConnectionNumber only reads the Display, and recv writes at most one byte
+        // to `byte` without consuming it from the socket.
+        let ret = unsafe {
+            let fd = xlib::XConnectionNumber(self.as_ptr());
+            libc::recv(
+                fd,
+                &mut byte as *mut u8 as *mut c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        let lost = match ret {
+            0 => true,
+            ret if ret < 0 => !matches!(
+                std::io::Error::last_os_error().raw_os_error(),
+                Some(libc::EAGAIN) | Some(libc::EINTR)
+            ),
+            _ => false,
+        };
+        if lost {
+            self.1.set(true);
+        }
+        !lost
+    }
+
     /// Returns true of the XShm extension is supported on this display.
     fn supports_shm(&self) -> bool {
         unsafe { xlib::XShmQueryExtension(self.as_ptr()) != 0 }
@@ -185,7 +366,9 @@ struct Buffer {
 impl Drop for Buffer {
     fn drop(&mut self) {
         unsafe {
-            xlib::XShmDetach(self.display.as_ptr(), self.segment_info.as_mut());
+            if !self.display.is_lost() {
+                xlib::XShmDetach(self.display.as_ptr(), self.segment_info.as_mut());
+            }
             xlib::XDestroyImage(self.image);
             shmdt(self.segment_info.shmaddr as *const _);
             shmctl(self.segment_info.shmid, IPC_RMID, null_mut());
@@ -228,6 +411,9 @@ struct Surface {
     // Fields for handling window close requests
     delete_window_atom: c_ulong,
     close_requested: bool,
+
+    /// The window asked the compositor for variable refresh rate presentation.
+    variable_refresh: bool,
 }
 
 impl Surface {
@@ -314,6 +500,7 @@ impl Surface {
                 buffer_completion_type,
                 delete_window_atom,
                 close_requested: false,
+                variable_refresh: false,
             })
         }
     }
@@ -516,10 +703,50 @@ impl Surface {
         self.buffer_next = (self.buffer_next + 1) % self.buffers.len();
         self.draw_buffer(current_buffer_index);
     }
+
+    /// Sets the `_VARIABLE_REFRESH` window property, which VRR capable X drivers honor for the
+    /// window being flipped full screen.
+    fn set_variable_refresh(&mut self, enabled: bool) {
+        if self.variable_refresh == enabled {
+            return;
+        }
+        self.variable_refresh = enabled;
+        // Safe because the window belongs to the display, and the property data is a single
+        // format 32 item, which Xlib takes as a long.
+        unsafe {
+            let atom = xlib::XInternAtom(
+                self.display.as_ptr(),
+                CStr::from_bytes_with_nul(b"_VARIABLE_REFRESH\0")
+                    .unwrap()
+                    .as_ptr(),
+                0,
+            );
+            if enabled {
+                let value: c_long = 1;
+                xproperty::XChangeProperty(
+                    self.display.as_ptr(),
+                    self.window,
+                    atom,
+                    xproperty::XA_CARDINAL,
+                    32,
+                    xproperty::PropModeReplace,
+                    &value as *const c_long as *const u8,
+                    1,
+                );
+            } else {
+                xproperty::XDeleteProperty(self.display.as_ptr(), self.window, atom);
+            }
+        }
+        self.display.flush();
+    }
 }
 
 impl Drop for Surface {
     fn drop(&mut self) {
+        // The window and GC are gone with a lost connection.
+        if self.display.is_lost() {
+            return;
+        }
         // Safe given it should always be of the correct type.
         unsafe {
             xlib::XFreeGC(self.display.as_ptr(), self.gc);
@@ -540,6 +767,9 @@ pub struct DisplayX {
     next_id: ObjectId,
     surfaces: BTreeMap<ObjectId, Surface>,
     event_devices: BTreeMap<ObjectId, EventDevice>,
+    randr_event_base: Option<c_int>,
+    outputs: Vec<GpuDisplayOutput>,
+    outputs_dirty: bool,
 }
 
 impl DisplayX {
@@ -559,7 +789,7 @@ impl DisplayX {
                     .map(|s| CStr::as_ptr(s))
                     .unwrap_or(null()),
             )) {
-                Some(display_ptr) => XDisplay(Rc::new(display_ptr)),
+                Some(display_ptr) => XDisplay(Rc::new(display_ptr), Rc::new(Cell::new(false))),
                 None => return Err(GpuDisplayError::Connect),
             };
 
@@ -607,7 +837,24 @@ impl DisplayX {
             let visual = (*visual_info).visual;
             x_free(visual_info);
 
-            Ok(DisplayX {
+            // Track the host monitors if the server supports XRandR.
+            let mut event_base = 0;
+            let mut error_base = 0;
+            let randr_event_base =
+                if xrandr::XRRQueryExtension(display.as_ptr(), &mut event_base, &mut error_base) != 0 {
+                    xrandr::XRRSelectInput(
+                        display.as_ptr(),
+                        xlib::XRootWindowOfScreen(screen.as_ptr()),
+                        xrandr::RRScreenChangeNotifyMask
+                            | xrandr::RRCrtcChangeNotifyMask
+                            | xrandr::RROutputChangeNotifyMask,
+                    );
+                    Some(event_base)
+                } else {
+                    None
+                };
+
+            let mut display_x = DisplayX {
                 // wait_ctx,
                 display,
                 screen,
@@ -615,10 +862,52 @@ impl DisplayX {
                 next_id: ObjectId::new(1).unwrap(),
                 surfaces: Default::default(),
                 event_devices: Default::default(),
-            })
+                randr_event_base,
+                outputs: Vec::new(),
+                outputs_dirty: false,
+            };
+            display_x.outputs = display_x.query_outputs();
+            Ok(display_x)
         }
     }
 
+    /// Lists the monitors currently driven by the X server, ordered by position.
+    fn query_outputs(&self) -> Vec<GpuDisplayOutput> {
+        let mut outputs = Vec::new();
+        if self.randr_event_base.is_none() {
+            return outputs;
+        }
+
+        unsafe {
+            let root = xlib::XRootWindowOfScreen(self.screen.as_ptr());
+            let resources = xrandr::XRRGetScreenResourcesCurrent(self.display.as_ptr(), root);
+            if resources.is_null() {
+                return outputs;
+            }
+            for i in 0..(*resources).ncrtc as usize {
+                let crtc = *(*resources).crtcs.add(i);
+                let info = xrandr::XRRGetCrtcInfo(self.display.as_ptr(), resources, crtc);
+                if info.is_null() {
+                    continue;
+                }
+                // A CRTC without a mode or output isn't lighting up any monitor.
+                if (*info).mode != 0 && (*info).noutput > 0 {
+                    outputs.push(GpuDisplayOutput {
+                        x: (*info).x,
+                        y: (*info).y,
+                        width: (*info).width,
+                        height: (*info).height,
+                    });
+                }
+                xrandr::XRRFreeCrtcInfo(info);
+            }
+            xrandr::XRRFreeScreenResources(resources);
+        }
+
+        outputs.sort_by_key(|o| (o.x, o.y));
+        outputs
+    }
+
     fn surface_ref(&self, surface_id: u32) -> Option<&Surface> {
         ObjectId::new(surface_id).and_then(move |id| self.surfaces.get(&id))
     }
@@ -635,7 +924,19 @@ impl DisplayX {
         ObjectId::new(event_device_id).and_then(move |id| self.event_devices.get_mut(&id))
     }
 
-    fn handle_event(&mut self, ev: XEvent) {
+    fn handle_event(&mut self, mut ev: XEvent) {
+        if let Some(event_base) = self.randr_event_base {
+            let type_ = ev.type_() as c_int;
+            if type_ == event_base + xrandr::RRScreenChangeNotify
+                || type_ == event_base + xrandr::RRNotify
+            {
+                // Keeps Xlib's idea of the screen size up to date.
+                unsafe { xrandr::XRRUpdateConfiguration(&mut ev.0) };
+                self.outputs_dirty = true;
+                return;
+            }
+        }
+
         let window = ev.window();
         for surface in self.surfaces.values_mut() {
             if surface.window != window {
@@ -647,6 +948,9 @@ impl DisplayX {
     }
 
     fn dispatch_display_events(&mut self) {
+        if !self.display.check_connection() {
+            return;
+        }
         loop {
             self.display.flush();
             if !self.display.pending_events() {
@@ -698,13 +1002,19 @@ impl DisplayX {
     // }
 }
 
-impl DisplayT for DisplayX {
+impl GpuDisplayBackend for DisplayX {
     fn dispatch_events(&mut self) {
+        self.dispatch_display_events();
         // if let Err(e) = self.handle_poll_ctx() {
         //     // error!("failed to dispatch events: {}", e);
         // }
     }
 
+    fn event_fd(&self) -> Option<RawFd> {
+        // Safe because XConnectionNumber only reads the Display.
+        Some(unsafe { xlib::XConnectionNumber(self.display.as_ptr()) })
+    }
+
     fn create_surface(
         &mut self,
         parent_surface_id: Option<u32>,
@@ -753,6 +1063,25 @@ impl DisplayT for DisplayX {
         }
     }
 
+    fn capabilities(&self) -> GpuDisplayCapabilities {
+        GpuDisplayCapabilities {
+            // window sizes are 16 bits in the X protocol
+            max_surface_size: Some((u16::MAX as u32, u16::MAX as u32)),
+            x11: true,
+            ..Default::default()
+        }
+    }
+
+    fn connection_lost(&self) -> bool {
+        !self.display.check_connection()
+    }
+
+    fn set_frame_interval(&mut self, surface_id: u32, interval: Option<Duration>) {
+        if let Some(surface) = self.surface_mut(surface_id) {
+            surface.set_variable_refresh(interval.is_some());
+        }
+    }
+
     fn close_requested(&self, surface_id: u32) -> bool {
         self.surface_ref(surface_id)
             .map(|s| s.close_requested)
@@ -824,5 +1153,19 @@ impl DisplayT for DisplayX {
         let event_device = self.event_devices.remove(&event_device_id).unwrap();
         surface.event_devices.insert(event_device_id, event_device);
     }
+
+    fn take_output_changes(&mut self) -> Option<Vec<GpuDisplayOutput>> {
+        if !self.outputs_dirty {
+            return None;
+        }
+        self.outputs_dirty = false;
+
+        let outputs = self.query_outputs();
+        if outputs == self.outputs {
+            return None;
+        }
+        self.outputs = outputs.clone();
+        Some(outputs)
+    }
 }
 
@@ -6,16 +6,29 @@
 
 use std::fmt::{self, Display};
 use std::path::Path;
+use std::time::Duration;
 
 use data_model::VolatileSlice;
 
+#[cfg(feature = "wayland")]
+#[allow(dead_code, non_camel_case_types)]
+mod dwl;
 mod event_device;
+mod gpu_display_crc;
+mod gpu_display_dump;
+mod gpu_display_headless;
 mod gpu_display_stub;
+#[cfg(feature = "wayland")]
+mod gpu_display_wl;
+#[cfg(feature = "winit")]
+mod gpu_display_winit;
 #[cfg(feature = "x")]
 mod gpu_display_x;
 mod keycode_converter;
 
 pub use event_device::{EventDevice, EventDeviceKind};
+pub use gpu_display_crc::{crc32, FrameChecksums};
+pub use gpu_display_dump::FrameDumpFormat;
 use std::os::unix::io::RawFd;
 
 /// An error generated by `GpuDisplay`.
@@ -62,6 +75,45 @@ impl Display for GpuDisplayError {
     }
 }
 
+/// What a display backend supports, for its users to choose how to present.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GpuDisplayCapabilities {
+    /// Buffers can be shown with `import_dmabuf` and `flip_to` instead of being copied.
+    pub dmabuf_import: bool,
+    /// Imported buffers may use format modifiers other than linear.
+    pub modifiers: bool,
+    /// Subsurfaces, like the cursor, move on their own with `move_surface`, without a commit of
+    /// their parent.
+    pub cursor_plane: bool,
+    /// Flips complete on the vertical blank of the output, `next_buffer_in_use` and
+    /// `frame_pending` follow it.
+    pub vsync_events: bool,
+    /// Largest surface the backend can create, `None` when only memory limits it.
+    pub max_surface_size: Option<(u32, u32)>,
+    /// The display is an X server connection, which GLX rendering needs.
+    pub x11: bool,
+    /// Nothing is shown anywhere, the frames flipped on the surfaces are never looked at.
+    pub headless: bool,
+}
+
+/// A host output (monitor) as reported by the display server.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GpuDisplayOutput {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A rect of a surface, in pixels.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GpuDisplayRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
 #[derive(Clone)]
 pub struct GpuDisplayFramebuffer<'a> {
     framebuffer: VolatileSlice<'a>,
@@ -71,7 +123,9 @@ pub struct GpuDisplayFramebuffer<'a> {
 }
 
 impl<'a> GpuDisplayFramebuffer<'a> {
-    fn new(
+    /// A framebuffer of `stride` bytes per line and `bytes_per_pixel` bytes per pixel in
+    /// `framebuffer`, for backends to return from `GpuDisplayBackend::framebuffer`.
+    pub fn new(
         framebuffer: VolatileSlice<'a>,
         stride: u32,
         bytes_per_pixel: u32,
@@ -117,27 +171,33 @@ impl<'a> GpuDisplayFramebuffer<'a> {
     }
 }
 
-trait DisplayT {
-    fn import_dmabuf(
-        &mut self,
-        fd: RawFd,
-        offset: u32,
-        stride: u32,
-        modifiers: u64,
-        width: u32,
-        height: u32,
-        fourcc: u32,
-    ) -> Result<u32, GpuDisplayError>;
-    fn release_import(&mut self, import_id: u32);
+/// A display backend, what `GpuDisplay` drives.
+///
+/// The backends of this crate implement it, and so can users presenting the frames themselves,
+/// e.g. in the GUI of their VMM, with `GpuDisplay::from_backend`.  Only surfaces with a shared
+/// memory framebuffer are required, what a backend doesn't support is left to the defaults: no
+/// imports, event devices or subsurface positions.
+pub trait GpuDisplayBackend {
+    /// Handles what the display server sent since the last call, without blocking.
     fn dispatch_events(&mut self);
+    /// The connection to the display server, readable when `dispatch_events` has something to
+    /// handle.  `None` for backends without a server, which never have.
+    fn event_fd(&self) -> Option<RawFd> {
+        None
+    }
+    /// Creates a top level surface, or a subsurface of `parent_surface_id`, of `width`x`height`
+    /// pixels and returns its id, which must not be one of a surface still alive.
     fn create_surface(
         &mut self,
         parent_surface_id: Option<u32>,
         width: u32,
         height: u32,
     ) -> Result<u32, GpuDisplayError>;
+    /// Forgets the surface, the id may be handed out again.
     fn release_surface(&mut self, surface_id: u32);
-    fn framebuffer(&mut self, surface_id: u32) -> Option<GpuDisplayFramebuffer>;
+    /// The framebuffer the next frame of the surface is drawn in, shown on the next `flip`.
+    fn framebuffer(&mut self, surface_id: u32) -> Option<GpuDisplayFramebuffer<'_>>;
+    /// A rect of `framebuffer`, for backends that can hand out less than the whole of it.
     fn framebuffer_region(
         &mut self,
         surface_id: u32,
@@ -145,27 +205,98 @@ trait DisplayT {
         y: u32,
         width: u32,
         height: u32,
-    ) -> Option<GpuDisplayFramebuffer> {
+    ) -> Option<GpuDisplayFramebuffer<'_>> {
         let framebuffer = self.framebuffer(surface_id)?;
         framebuffer.sub_region(x, y, width, height)
     }
-    fn commit(&mut self, surface_id: u32);
-    fn next_buffer_in_use(&self, surface_id: u32) -> bool;
+    /// Shows the framebuffer last returned for the surface.
     fn flip(&mut self, surface_id: u32);
-    fn flip_to(&mut self, surface_id: u32, import_id: u32);
-    fn close_requested(&self, surface_id: u32) -> bool;
-    fn set_position(&mut self, surface_id: u32, x: u32, y: u32);
-    fn import_event_device(&mut self, event_device: EventDevice) -> Result<u32, GpuDisplayError>;
-    fn release_event_device(&mut self, event_device_id: u32);
-    fn attach_event_device(&mut self, surface_id: u32, event_device_id: u32);
+    /// Like `flip`, when only `damage` changed since the previous flip.  Backends that can't use
+    /// the damage show the whole framebuffer.
+    #[allow(unused_variables)]
+    fn flip_damage(&mut self, surface_id: u32, damage: &[GpuDisplayRect]) {
+        self.flip(surface_id)
+    }
+    /// Applies the pending state of the surface, like the positions of its subsurfaces.
+    #[allow(unused_variables)]
+    fn commit(&mut self, surface_id: u32) {}
+    /// Whether the display server still reads the framebuffer `framebuffer` would return next.
+    #[allow(unused_variables)]
+    fn next_buffer_in_use(&self, surface_id: u32) -> bool {
+        false
+    }
+    /// Imports a dmabuf laid out as described and returns an id for `flip_to`.
+    #[allow(unused_variables)]
+    fn import_dmabuf(
+        &mut self,
+        fd: RawFd,
+        offset: u32,
+        stride: u32,
+        modifiers: u64,
+        width: u32,
+        height: u32,
+        fourcc: u32,
+    ) -> Result<u32, GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+    /// Forgets an import of `import_dmabuf`.
+    #[allow(unused_variables)]
+    fn release_import(&mut self, import_id: u32) {}
+    /// Shows the imported buffer on the surface in place of its framebuffer.
+    #[allow(unused_variables)]
+    fn flip_to(&mut self, surface_id: u32, import_id: u32) {}
+    /// Whether the user asked to close the top level surface.
+    #[allow(unused_variables)]
+    fn close_requested(&self, surface_id: u32) -> bool {
+        false
+    }
+    /// Whether the last frame flipped on the surface still waits for the vertical blank.
+    #[allow(unused_variables)]
+    fn frame_pending(&self, surface_id: u32) -> bool {
+        false
+    }
+    /// Places the subsurface relative to its parent on the parent's next `commit`.
+    #[allow(unused_variables)]
+    fn set_position(&mut self, surface_id: u32, x: u32, y: u32) {}
+    /// Places the subsurface right away, `Unsupported` sends the caller to `set_position`.
+    #[allow(unused_variables)]
+    fn move_surface(&mut self, surface_id: u32, x: u32, y: u32) -> Result<(), GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+    /// Takes the event device the input of the surfaces it's attached to is written to.
+    #[allow(unused_variables)]
+    fn import_event_device(&mut self, event_device: EventDevice) -> Result<u32, GpuDisplayError> {
+        Err(GpuDisplayError::Unsupported)
+    }
+    /// Drops an event device of `import_event_device`.
+    #[allow(unused_variables)]
+    fn release_event_device(&mut self, event_device_id: u32) {}
+    /// Sends the input of the top level surface to the event device.
+    #[allow(unused_variables)]
+    fn attach_event_device(&mut self, surface_id: u32, event_device_id: u32) {}
+    /// The host outputs, when they changed since the last call.
+    fn take_output_changes(&mut self) -> Option<Vec<GpuDisplayOutput>> {
+        None
+    }
+    /// How often the surface is flipped, a hint for variable refresh rate outputs.
+    #[allow(unused_variables)]
+    fn set_frame_interval(&mut self, surface_id: u32, interval: Option<Duration>) {}
+    /// What the backend supports.
+    fn capabilities(&self) -> GpuDisplayCapabilities {
+        Default::default()
+    }
+    /// Whether the connection to the display server is gone for good.
+    fn connection_lost(&self) -> bool {
+        false
+    }
 }
 
 /// A connection to the compositor and associated collection of state.
 ///
-/// The user of `GpuDisplay` can use `AsRawDescriptor` to poll on the compositor connection's file
+/// The user of `GpuDisplay` can use `event_fd` to poll on the compositor connection's file
 /// descriptor. When the connection is readable, `dispatch_events` can be called to process it.
 pub struct GpuDisplay {
-    inner: Box<dyn DisplayT>,
+    inner: Box<dyn GpuDisplayBackend>,
     is_x: bool,
 }
 
@@ -185,12 +316,82 @@ impl GpuDisplay {
         Err(GpuDisplayError::Unsupported)
     }
 
+    /// Opens a fresh connection to the Wayland compositor listening at `wayland_path`, or the one
+    /// `WAYLAND_DISPLAY` names if `None`.
+    pub fn open_wayland<P: AsRef<Path>>(
+        wayland_path: Option<P>,
+    ) -> Result<GpuDisplay, GpuDisplayError> {
+        let _ = wayland_path;
+        #[cfg(feature = "wayland")]
+        {
+            let display = match wayland_path {
+                Some(s) => gpu_display_wl::DisplayWl::new(Some(s.as_ref()))?,
+                None => gpu_display_wl::DisplayWl::new(None)?,
+            };
+            let inner = Box::new(display);
+            Ok(GpuDisplay { inner, is_x: false })
+        }
+        #[cfg(not(feature = "wayland"))]
+        Err(GpuDisplayError::Unsupported)
+    }
+
+    /// Opens a window titled `title` on the desktop, X11 or Wayland alike.  This can only be
+    /// done once per process.
+    pub fn open_winit(title: &str) -> Result<GpuDisplay, GpuDisplayError> {
+        let _ = title;
+        #[cfg(feature = "winit")]
+        {
+            let display = gpu_display_winit::DisplayWinit::new(title)?;
+            let inner = Box::new(display);
+            Ok(GpuDisplay { inner, is_x: false })
+        }
+        #[cfg(not(feature = "winit"))]
+        Err(GpuDisplayError::Unsupported)
+    }
+
+    /// Wraps a backend implemented outside of this crate.
+    pub fn from_backend(backend: Box<dyn GpuDisplayBackend>) -> GpuDisplay {
+        let is_x = backend.capabilities().x11;
+        GpuDisplay {
+            inner: backend,
+            is_x,
+        }
+    }
+
     pub fn open_stub() -> Result<GpuDisplay, GpuDisplayError> {
         let display = gpu_display_stub::DisplayStub::new()?;
         let inner = Box::new(display);
         Ok(GpuDisplay { inner, is_x: false })
     }
 
+    /// Opens a display for hosts without any, where surfaces can be created and flipped but
+    /// draw nowhere.
+    pub fn open_headless() -> Result<GpuDisplay, GpuDisplayError> {
+        let display = gpu_display_headless::DisplayHeadless::new()?;
+        let inner = Box::new(display);
+        Ok(GpuDisplay { inner, is_x: false })
+    }
+
+    /// Opens a display writing every flipped frame to a numbered file in `directory`, or only
+    /// the frames whose contents changed if `only_on_damage` is set.
+    pub fn open_dump<P: AsRef<Path>>(
+        directory: P,
+        format: FrameDumpFormat,
+        only_on_damage: bool,
+    ) -> Result<GpuDisplay, GpuDisplayError> {
+        let display = gpu_display_dump::DisplayDump::new(directory, format, only_on_damage)?;
+        let inner = Box::new(display);
+        Ok(GpuDisplay { inner, is_x: false })
+    }
+
+    /// Opens a display that presents nothing and records the CRC32 of every flipped frame in
+    /// `checksums`, which can be read from any thread.
+    pub fn open_crc(checksums: FrameChecksums) -> Result<GpuDisplay, GpuDisplayError> {
+        let display = gpu_display_crc::DisplayCrc::new(checksums)?;
+        let inner = Box::new(display);
+        Ok(GpuDisplay { inner, is_x: false })
+    }
+
     /// Return whether this display is an X display
     pub fn is_x(&self) -> bool {
         self.is_x
@@ -222,6 +423,12 @@ impl GpuDisplay {
         self.inner.dispatch_events()
     }
 
+    /// Returns the file descriptor of the compositor connection, readable when `dispatch_events`
+    /// has something to process.  `None` when there is no compositor to wait on.
+    pub fn event_fd(&self) -> Option<RawFd> {
+        self.inner.event_fd()
+    }
+
     /// Creates a surface on the the compositor as either a top level window, or child of another
     /// surface, returning a handle to the new surface.
     pub fn create_surface(
@@ -276,6 +483,12 @@ impl GpuDisplay {
         self.inner.flip(surface_id)
     }
 
+    /// Like `flip`, when only the `damage` rects of the framebuffer changed since the previous
+    /// flip.  Backends may show the whole framebuffer anyway.
+    pub fn flip_damage(&mut self, surface_id: u32, damage: &[GpuDisplayRect]) {
+        self.inner.flip_damage(surface_id, damage)
+    }
+
     /// Changes the visible contents of the identified surface to that of the identified imported
     /// buffer.
     pub fn flip_to(&mut self, surface_id: u32, import_id: u32) {
@@ -288,6 +501,13 @@ impl GpuDisplay {
         self.inner.close_requested(surface_id)
     }
 
+    /// Returns true while the last frame flipped on the identified surface waits for the vertical
+    /// blank, a frame flipped meanwhile would replace it unseen.  Always false for backends
+    /// without `vsync_events`.
+    pub fn frame_pending(&self, surface_id: u32) -> bool {
+        self.inner.frame_pending(surface_id)
+    }
+
     /// Sets the position of the identified subsurface relative to its parent.
     ///
     /// The change in position will not be visible until `commit` is called for the parent surface.
@@ -295,6 +515,15 @@ impl GpuDisplay {
         self.inner.set_position(surface_id, x, y)
     }
 
+    /// Moves the identified subsurface right away, without waiting for a `commit` of its parent.
+    ///
+    /// This is meant for cursors, which move far more often than the parent contents change.
+    /// Returns `GpuDisplayError::Unsupported` if the backend can only move subsurfaces through
+    /// `set_position`.
+    pub fn move_surface(&mut self, surface_id: u32, x: u32, y: u32) -> Result<(), GpuDisplayError> {
+        self.inner.move_surface(surface_id, x, y)
+    }
+
     pub fn import_event_device(
         &mut self,
         event_device: EventDevice,
@@ -309,5 +538,35 @@ impl GpuDisplay {
     pub fn attach_event_device(&mut self, surface_id: u32, event_device_id: u32) {
         self.inner.attach_event_device(surface_id, event_device_id);
     }
+
+    /// Returns the current list of host outputs if it changed since the last call, for example
+    /// because a monitor was plugged or unplugged.
+    ///
+    /// Changes are picked up while dispatching events, so `dispatch_events` should be called
+    /// first.
+    pub fn take_output_changes(&mut self) -> Option<Vec<GpuDisplayOutput>> {
+        self.inner.take_output_changes()
+    }
+
+    /// Returns what the backend supports.
+    pub fn capabilities(&self) -> GpuDisplayCapabilities {
+        self.inner.capabilities()
+    }
+
+    /// Tells the display how often the identified surface is flipped, so a variable refresh rate
+    /// output can follow it.  `None` goes back to the fixed refresh rate of the output.
+    ///
+    /// This is only a hint, backends without variable refresh rate support ignore it.
+    pub fn set_frame_interval(&mut self, surface_id: u32, interval: Option<Duration>) {
+        self.inner.set_frame_interval(surface_id, interval)
+    }
+
+    /// Returns true once the connection to the compositor is gone, e.g. because it restarted.
+    ///
+    /// Nothing but dropping the display should be done afterwards, the surfaces and imports are
+    /// gone with the connection.
+    pub fn connection_lost(&self) -> bool {
+        self.inner.connection_lost()
+    }
 }
 
@@ -123,11 +123,11 @@ struct GfxstreamContext {
 }
 
 impl RutabagaContext for GfxstreamContext {
-    fn submit_cmd(&mut self, commands: &mut [u8]) -> RutabagaResult<()> {
-        if commands.len() % size_of::<u32>() != 0 {
-            return Err(RutabagaError::InvalidCommandSize(commands.len()));
+    fn submit_cmd(&mut self, commands: VolatileSlice) -> RutabagaResult<()> {
+        if commands.size() % size_of::<u32>() != 0 {
+            return Err(RutabagaError::InvalidCommandSize(commands.size()));
         }
-        let dword_count = (commands.len() / size_of::<u32>()) as i32;
+        let dword_count = (commands.size() / size_of::<u32>()) as i32;
         // Safe because the context and buffer are valid and virglrenderer will have been
         // initialized if there are Context instances.
         let ret = unsafe {
@@ -200,6 +200,7 @@ impl Gfxstream {
 
         let cookie: *mut VirglCookie = Box::into_raw(Box::new(VirglCookie {
             fence_state: Rc::clone(&fence_state),
+            fence_event: None,
         }));
 
         unsafe {
@@ -426,15 +427,18 @@ impl RutabagaComponent for Gfxstream {
         &self,
         ctx_id: u32,
         _context_init: u32,
+        context_name: Option<&str>,
     ) -> RutabagaResult<Box<dyn RutabagaContext + Send>> {
         const CONTEXT_NAME: &[u8] = b"gpu_renderer";
-        // Safe because virglrenderer is initialized by now and the context name is statically
-        // allocated. The return value is checked before returning a new context.
+        // Host tools attribute the context's work by this name, the guest's one when it gave one.
+        let name = context_name.map_or(CONTEXT_NAME, str::as_bytes);
+        // Safe because virglrenderer is initialized by now and the context name outlives the
+        // call. The return value is checked before returning a new context.
         let ret = unsafe {
             pipe_virgl_renderer_context_create(
                 ctx_id,
-                CONTEXT_NAME.len() as u32,
-                CONTEXT_NAME.as_ptr() as *const c_char,
+                name.len() as u32,
+                name.as_ptr() as *const c_char,
             )
         };
         ret_to_res(ret)?;
@@ -9,8 +9,10 @@ mod gfxstream;
 mod renderer_utils;
 mod rutabaga_2d;
 mod rutabaga_core;
+mod rutabaga_formats;
 mod rutabaga_utils;
 mod virgl_renderer;
 
 pub use crate::rutabaga_core::{Rutabaga, RutabagaBuilder};
+pub use crate::rutabaga_formats::{format_layout, RutabagaFormatLayout, RutabagaPlane};
 pub use crate::rutabaga_utils::*;
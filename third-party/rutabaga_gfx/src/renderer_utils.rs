@@ -5,7 +5,9 @@
 //! renderer_utils: Utility functions and structs used by virgl_renderer and gfxstream.
 
 use std::cell::RefCell;
+use std::fs::File;
 use std::os::raw::c_void;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::rc::Rc;
 
 use crate::generated::virgl_renderer_bindings::__va_list_tag;
@@ -63,16 +65,67 @@ impl FenceState {
     }
 }
 
+/// An eventfd signalled when fences are reported from the renderer's own threads, so that the
+/// thread owning the renderer knows to poll them.
+pub struct FenceEvent(File);
+
+impl FenceEvent {
+    pub fn new() -> RutabagaResult<FenceEvent> {
+        // Safe because eventfd takes no pointers and the result is checked.
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return base::errno_result().map_err(|e| RutabagaError::SysError(base::Error::SystemCallFailed(e)));
+        }
+        // Safe because the descriptor was just created and is owned by nothing else.
+        Ok(FenceEvent(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    pub fn signal(&self) {
+        let count = 1u64.to_ne_bytes();
+        // Safe because the buffer holds the 8 bytes eventfd takes.  Only fails when the counter
+        // would overflow, in which case the event is pending anyway.
+        unsafe {
+            libc::write(self.0.as_raw_fd(), count.as_ptr() as *const c_void, count.len());
+        }
+    }
+
+    /// Consumes the pending signals, if any.
+    pub fn clear(&self) {
+        let mut count = [0u8; 8];
+        // Safe because the buffer holds the 8 bytes eventfd returns.
+        unsafe {
+            libc::read(self.0.as_raw_fd(), count.as_mut_ptr() as *mut c_void, count.len());
+        }
+    }
+}
+
+impl AsRawFd for FenceEvent {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
 pub struct VirglCookie {
     pub fence_state: Arc<Mutex<FenceState>>,
+    /// Signalled after every fence when virglrenderer reports them from its sync thread
+    pub fence_event: Option<Arc<FenceEvent>>,
 }
 
+/// Called by virglrenderer on the thread owning it, or on its sync thread with the async fence
+/// callback, hence the lock around the fence state.
 pub extern "C" fn write_fence(cookie: *mut c_void, fence: u32) {
     assert!(!cookie.is_null());
     let cookie = unsafe { &*(cookie as *mut VirglCookie) };
 
     // Track the most recent fence.
-    let mut guard = cookie.fence_state.lock().unwrap();
-    let mut fence_state = guard.deref_mut();
-    fence_state.write(fence);
+    {
+        let mut guard = cookie.fence_state.lock().unwrap();
+        let mut fence_state = guard.deref_mut();
+        fence_state.write(fence);
+    }
+
+    // The owning thread clears the event before reading the state, so it sees this fence.
+    if let Some(ref fence_event) = cookie.fence_event {
+        fence_event.signal();
+    }
 }
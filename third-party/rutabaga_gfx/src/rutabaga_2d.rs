@@ -9,6 +9,7 @@ use std::cmp::{max, min};
 use data_model::*;
 
 use crate::rutabaga_core::{Rutabaga2DInfo, RutabagaComponent, RutabagaResource};
+use crate::rutabaga_formats::format_layout;
 use crate::rutabaga_utils::*;
 
 macro_rules! checked_arithmetic {
@@ -54,6 +55,7 @@ pub fn transfer_2d<'a, S: Iterator<Item = VolatileSlice<'a>>>(
     rect_y: u32,
     rect_w: u32,
     rect_h: u32,
+    bytes_per_pixel: u32,
     dst_stride: u32,
     dst_offset: u64,
     dst: VolatileSlice,
@@ -68,7 +70,7 @@ pub fn transfer_2d<'a, S: Iterator<Item = VolatileSlice<'a>>>(
     checked_range!(checked_arithmetic!(rect_x + rect_w)?; <= resource_w)?;
     checked_range!(checked_arithmetic!(rect_y + rect_h)?; <= resource_h)?;
 
-    let bytes_per_pixel = 4 as u64;
+    let bytes_per_pixel = bytes_per_pixel as u64;
 
     let rect_x = rect_x as u64;
     let rect_y = rect_y as u64;
@@ -172,6 +174,23 @@ pub fn transfer_2d<'a, S: Iterator<Item = VolatileSlice<'a>>>(
     Ok(())
 }
 
+/// Copies the host copy of a 2D resource into `iovecs`, laid out as transfers read the backing, so
+/// the contents survive the guest replacing the backing.
+pub fn restore_backing(resource_2d: &Rutabaga2DInfo, iovecs: &[RutabagaIovec]) {
+    let mut remaining = resource_2d.host_mem.as_slice();
+    for iovec in iovecs {
+        if remaining.is_empty() {
+            break;
+        }
+
+        // Safe because Rutabaga users should have already checked the iovecs.
+        let slice = unsafe { VolatileSlice::from_raw_parts(iovec.base as *mut u8, iovec.len) };
+        let len = min(iovec.len, remaining.len());
+        slice.copy_from(&remaining[..len]);
+        remaining = &remaining[len..];
+    }
+}
+
 pub struct Rutabaga2D {
     latest_created_fence_id: u32,
 }
@@ -199,14 +218,17 @@ impl RutabagaComponent for Rutabaga2D {
         resource_id: u32,
         resource_create_3d: ResourceCreate3D,
     ) -> RutabagaResult<RutabagaResource> {
-        // All virtio formats are 4 bytes per pixel.
-        let resource_bpp = 4;
-        let resource_stride = resource_bpp * resource_create_3d.width;
-        let resource_size = (resource_stride as usize) * (resource_create_3d.height as usize);
+        let layout = format_layout(
+            resource_create_3d.format,
+            resource_create_3d.width,
+            resource_create_3d.height,
+        )
+        .ok_or(RutabagaError::Unsupported)?;
         let resource_2d = Rutabaga2DInfo {
             width: resource_create_3d.width,
             height: resource_create_3d.height,
-            host_mem: vec![0; resource_size],
+            format: resource_create_3d.format,
+            host_mem: vec![0; layout.size as usize],
         };
 
         Ok(RutabagaResource {
@@ -235,8 +257,8 @@ impl RutabagaComponent for Rutabaga2D {
             .take()
             .ok_or(RutabagaError::Unsupported)?;
 
-        // All offical virtio_gpu formats are 4 bytes per pixel.
-        let resource_bpp = 4;
+        let layout = format_layout(resource_2d.format, resource_2d.width, resource_2d.height)
+            .ok_or(RutabagaError::Unsupported)?;
         let mut src_slices = Vec::with_capacity(resource.backing_iovecs.len());
         for iovec in &resource.backing_iovecs {
             // Safe because Rutabaga users should have already checked the iovecs.
@@ -244,10 +266,53 @@ impl RutabagaComponent for Rutabaga2D {
             src_slices.push(slice);
         }
 
-        let src_stride = resource_bpp * resource_2d.width;
-        let src_offset = transfer.offset;
+        // Multi-planar backings share the host layout, every plane gets the subsampled rect.
+        if layout.is_multi_planar() {
+            for plane in &layout.planes {
+                let (x, y, w, h) = plane.rect(transfer.x, transfer.y, transfer.w, transfer.h);
+                transfer_2d(
+                    plane.width,
+                    plane.height,
+                    x,
+                    y,
+                    w,
+                    h,
+                    plane.bytes_per_pixel,
+                    plane.stride,
+                    plane.offset,
+                    VolatileSlice::new(resource_2d.host_mem.as_mut_slice()),
+                    plane.stride,
+                    plane.offset,
+                    src_slices.iter().cloned(),
+                )?;
+            }
+
+            resource.resource_2d = Some(resource_2d);
+            return Ok(());
+        }
 
-        let dst_stride = resource_bpp * resource_2d.width;
+        // The guest backing may be laid out with its own pitch, tightly packed rows otherwise.
+        let plane = layout.planes[0];
+        let resource_bpp = plane.bytes_per_pixel;
+        let packed_stride = plane.stride;
+        let src_stride = match transfer.stride {
+            0 => packed_stride,
+            stride => stride,
+        };
+        checked_range!(packed_stride; <= src_stride)?;
+
+        // A non-zero offset locates the origin of the rect in the backing, transfer_2d wants the
+        // offset of the backing's first pixel.
+        let src_offset = match transfer.offset {
+            0 => 0,
+            rect_offset => {
+                let rect_origin =
+                    transfer.y as u64 * src_stride as u64 + transfer.x as u64 * resource_bpp as u64;
+                checked_arithmetic!(rect_offset - rect_origin)?
+            }
+        };
+
+        let dst_stride = plane.stride;
         let dst_offset = 0;
 
         transfer_2d(
@@ -257,6 +322,7 @@ impl RutabagaComponent for Rutabaga2D {
             transfer.y,
             transfer.w,
             transfer.h,
+            resource_bpp,
             dst_stride,
             dst_offset,
             VolatileSlice::new(resource_2d.host_mem.as_mut_slice()),
@@ -281,14 +347,42 @@ impl RutabagaComponent for Rutabaga2D {
             .take()
             .ok_or(RutabagaError::Unsupported)?;
 
-        // All offical virtio_gpu formats are 4 bytes per pixel.
-        let resource_bpp = 4;
-        let src_stride = resource_bpp * resource_2d.width;
+        let layout = format_layout(resource_2d.format, resource_2d.width, resource_2d.height)
+            .ok_or(RutabagaError::Unsupported)?;
+        let dst_slice = buf.ok_or(RutabagaError::Unsupported)?;
+
+        // Multi-planar resources are read back in the host layout.
+        if layout.is_multi_planar() {
+            for plane in &layout.planes {
+                let (x, y, w, h) = plane.rect(transfer.x, transfer.y, transfer.w, transfer.h);
+                transfer_2d(
+                    plane.width,
+                    plane.height,
+                    x,
+                    y,
+                    w,
+                    h,
+                    plane.bytes_per_pixel,
+                    plane.stride,
+                    plane.offset,
+                    dst_slice,
+                    plane.stride,
+                    plane.offset,
+                    [VolatileSlice::new(resource_2d.host_mem.as_mut_slice())]
+                        .iter()
+                        .cloned(),
+                )?;
+            }
+
+            resource.resource_2d = Some(resource_2d);
+            return Ok(());
+        }
+
+        let plane = layout.planes[0];
+        let src_stride = plane.stride;
         let src_offset = 0;
         let dst_offset = 0;
 
-        let dst_slice = buf.ok_or(RutabagaError::Unsupported)?;
-
         transfer_2d(
             resource_2d.width,
             resource_2d.height,
@@ -296,6 +390,7 @@ impl RutabagaComponent for Rutabaga2D {
             transfer.y,
             transfer.w,
             transfer.h,
+            plane.bytes_per_pixel,
             transfer.stride,
             dst_offset,
             dst_slice,
@@ -5,6 +5,7 @@
 //! rutabaga_core: Cross-platform, Rust-based, Vulkan centric GPU virtualization.
 
 use std::collections::BTreeMap as Map;
+use std::os::unix::io::RawFd;
 use std::sync::Arc;
 
 use base::ExternalMapping;
@@ -13,7 +14,7 @@ use data_model::VolatileSlice;
 #[cfg(feature = "gfxstream")]
 use crate::gfxstream::Gfxstream;
 
-use crate::rutabaga_2d::Rutabaga2D;
+use crate::rutabaga_2d::{restore_backing, Rutabaga2D};
 use crate::rutabaga_utils::*;
 
 #[cfg(feature = "virgl_renderer")]
@@ -23,6 +24,7 @@ use crate::virgl_renderer::VirglRenderer;
 pub struct Rutabaga2DInfo {
     pub width: u32,
     pub height: u32,
+    pub format: u32,
     pub host_mem: Vec<u8>,
 }
 
@@ -71,6 +73,12 @@ pub trait RutabagaComponent {
         0
     }
 
+    /// Implementations may return a descriptor that becomes readable when `poll` should be
+    /// called.  None is returned by default.
+    fn poll_descriptor(&self) -> Option<RawFd> {
+        None
+    }
+
     /// Implementations must create a resource with the given metadata.  For 2D rutabaga components,
     /// this a system memory allocation.  For 3D components, this is typically a GL texture or
     /// buffer.  Vulkan components should use blob resources instead.
@@ -161,18 +169,21 @@ pub trait RutabagaComponent {
     /// Implementations must create a context for submitting commands.  The command stream of the
     /// context is determined by `context_init`.  For virgl contexts, it is a Gallium/TGSI command
     /// stream.  For gfxstream contexts, it's an autogenerated Vulkan or GLES streams.
+    /// `context_name` is the name the guest gave the context, if any.
     fn create_context(
         &self,
         _ctx_id: u32,
         _context_init: u32,
+        _context_name: Option<&str>,
     ) -> RutabagaResult<Box<dyn RutabagaContext>> {
         Err(RutabagaError::Unsupported)
     }
 }
 
 pub trait RutabagaContext {
-    /// Implementations must handle the context-specific command stream.
-    fn submit_cmd(&mut self, _commands: &mut [u8]) -> RutabagaResult<()>;
+    /// Implementations must handle the context-specific command stream.  `commands` may be
+    /// guest memory the guest can write to meanwhile.
+    fn submit_cmd(&mut self, _commands: VolatileSlice) -> RutabagaResult<()>;
 
     /// Implementations may use `resource` in this context's command stream.
     fn attach(&mut self, _resource: &RutabagaResource);
@@ -299,6 +310,14 @@ impl Rutabaga {
         Ok(())
     }
 
+    /// Returns the descriptor of the default component that becomes readable when fences may have
+    /// completed, if it has one.
+    pub fn poll_descriptor(&self) -> Option<RawFd> {
+        self.components
+            .get(&self.default_component)
+            .and_then(|component| component.poll_descriptor())
+    }
+
     /// Polls all rutabaga components and contexts, and returns a vector of RutabagaFenceData
     /// describing which fences have completed.
     pub fn poll(&mut self) -> Vec<RutabagaFenceData> {
@@ -388,6 +407,23 @@ impl Rutabaga {
         Ok(())
     }
 
+    /// Copies the host contents of the 2D resource given by `resource_id` into its attached
+    /// backing, restoring what a previous backing held.
+    pub fn restore_backing(&mut self, resource_id: u32) -> RutabagaResult<()> {
+        let resource = self
+            .resources
+            .get(&resource_id)
+            .ok_or(RutabagaError::InvalidResourceId)?;
+
+        let resource_2d = resource
+            .resource_2d
+            .as_ref()
+            .ok_or(RutabagaError::Unsupported)?;
+
+        restore_backing(resource_2d, &resource.backing_iovecs);
+        Ok(())
+    }
+
     /// Releases guest kernel reference on the resource.
     pub fn unref_resource(&mut self, resource_id: u32) -> RutabagaResult<()> {
         let component = self
@@ -556,8 +592,14 @@ impl Rutabaga {
     }
 
     /// Creates a context with the given `ctx_id` and `context_init` variable.
-    /// `context_init` is used to determine which rutabaga component creates the context.
-    pub fn create_context(&mut self, ctx_id: u32, context_init: u32) -> RutabagaResult<()> {
+    /// `context_init` is used to determine which rutabaga component creates the context, which
+    /// is named `context_name` in the renderer when given.
+    pub fn create_context(
+        &mut self,
+        ctx_id: u32,
+        context_init: u32,
+        context_name: Option<&str>,
+    ) -> RutabagaResult<()> {
         // The default workaround is just until context types are fully supported in all
         // Google kernels.
         let capset_id = context_init & RUTABAGA_CONTEXT_INIT_CAPSET_ID_MASK;
@@ -573,7 +615,7 @@ impl Rutabaga {
             return Err(RutabagaError::InvalidContextId);
         }
 
-        let ctx = component.create_context(ctx_id, context_init)?;
+        let ctx = component.create_context(ctx_id, context_init, context_name)?;
         self.contexts.insert(ctx_id, ctx);
         Ok(())
     }
@@ -619,7 +661,7 @@ impl Rutabaga {
     }
 
     /// Submits `commands` to the context given by `ctx_id`.
-    pub fn submit_command(&mut self, ctx_id: u32, commands: &mut [u8]) -> RutabagaResult<()> {
+    pub fn submit_command(&mut self, ctx_id: u32, commands: VolatileSlice) -> RutabagaResult<()> {
         let ctx = self
             .contexts
             .get_mut(&ctx_id)
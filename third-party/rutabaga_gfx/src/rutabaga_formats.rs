@@ -0,0 +1,118 @@
+// Copyright 2021 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! rutabaga_formats: Memory layout of the resource formats handled by the 2D component.
+
+use std::convert::TryFrom;
+
+// virtio-gpu formats, numbered like the virgl formats they alias.
+const VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM: u32 = 1;
+const VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM: u32 = 2;
+const VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM: u32 = 3;
+const VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM: u32 = 4;
+const VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM: u32 = 67;
+const VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM: u32 = 68;
+const VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM: u32 = 121;
+const VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM: u32 = 134;
+const VIRTIO_GPU_FORMAT_YV12: u32 = 163;
+const VIRTIO_GPU_FORMAT_NV12: u32 = 166;
+
+/// Rows of every plane start on this byte boundary.
+const STRIDE_ALIGNMENT: u64 = 4;
+
+/// (bytes per pixel, horizontal shift, vertical shift) of each plane of a format.
+type PlaneInfo = (u32, u32, u32);
+
+const PACKED_32BPP: &[PlaneInfo] = &[(4, 0, 0)];
+// Y plane followed by an interleaved, 2x2 subsampled CbCr plane.
+const NV12: &[PlaneInfo] = &[(1, 0, 0), (2, 1, 1)];
+// Y plane followed by 2x2 subsampled Cr and Cb planes.
+const YV12: &[PlaneInfo] = &[(1, 0, 0), (1, 1, 1), (1, 1, 1)];
+
+fn format_planes(format: u32) -> Option<&'static [PlaneInfo]> {
+    match format {
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM
+        | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM
+        | VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM
+        | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM
+        | VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM
+        | VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM
+        | VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM
+        | VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM => Some(PACKED_32BPP),
+        VIRTIO_GPU_FORMAT_NV12 => Some(NV12),
+        VIRTIO_GPU_FORMAT_YV12 => Some(YV12),
+        _ => None,
+    }
+}
+
+/// Divides `value` by 2^`shift`, rounding up.
+fn shift_up(value: u64, shift: u32) -> u64 {
+    (value + (1 << shift) - 1) >> shift
+}
+
+/// One plane of a resource.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RutabagaPlane {
+    /// Byte offset of the plane's first row within the resource.
+    pub offset: u64,
+    /// Bytes between the starts of two consecutive rows.
+    pub stride: u32,
+    pub bytes_per_pixel: u32,
+    /// log2 of the subsampling of the plane relative to the resource, per axis.
+    pub h_shift: u32,
+    pub v_shift: u32,
+    /// Dimensions of the plane, in plane pixels.
+    pub width: u32,
+    pub height: u32,
+}
+
+impl RutabagaPlane {
+    /// Maps a rect in resource pixels to the plane's pixels.  Subsampled planes cover every plane
+    /// pixel the rect touches.
+    pub fn rect(&self, x: u32, y: u32, w: u32, h: u32) -> (u32, u32, u32, u32) {
+        let x0 = x >> self.h_shift;
+        let y0 = y >> self.v_shift;
+        let x1 = shift_up(x as u64 + w as u64, self.h_shift) as u32;
+        let y1 = shift_up(y as u64 + h as u64, self.v_shift) as u32;
+        (x0, y0, x1 - x0, y1 - y0)
+    }
+}
+
+/// Planes of a resource packed one after the other, and the memory they span.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RutabagaFormatLayout {
+    pub planes: Vec<RutabagaPlane>,
+    pub size: u64,
+}
+
+impl RutabagaFormatLayout {
+    pub fn is_multi_planar(&self) -> bool {
+        self.planes.len() > 1
+    }
+}
+
+/// Layout of a `width`x`height` resource of `format`, `None` for unknown formats or layouts that
+/// overflow.
+pub fn format_layout(format: u32, width: u32, height: u32) -> Option<RutabagaFormatLayout> {
+    let mut planes = Vec::new();
+    let mut size = 0u64;
+    for &(bytes_per_pixel, h_shift, v_shift) in format_planes(format)? {
+        let plane_width = shift_up(width as u64, h_shift);
+        let plane_height = shift_up(height as u64, v_shift);
+        let row = plane_width.checked_mul(bytes_per_pixel as u64)?;
+        let stride = row.checked_add(STRIDE_ALIGNMENT - 1)? / STRIDE_ALIGNMENT * STRIDE_ALIGNMENT;
+        planes.push(RutabagaPlane {
+            offset: size,
+            stride: u32::try_from(stride).ok()?,
+            bytes_per_pixel,
+            h_shift,
+            v_shift,
+            width: plane_width as u32,
+            height: plane_height as u32,
+        });
+        size = size.checked_add(stride.checked_mul(plane_height)?)?;
+    }
+
+    Some(RutabagaFormatLayout { planes, size })
+}
@@ -123,6 +123,8 @@ pub enum RutabagaError {
     MemCopy(VolatileMemoryError),
     /// An internal Rutabaga component error was returned.
     RutabagaComponentError(i32),
+    /// A system call failed.
+    SysError(base::Error),
     /// The command is unsupported.
     Unsupported,
 }
@@ -158,6 +160,7 @@ impl Display for RutabagaError {
             MappingFailed(s) => write!(f, "The mapping failed for the following reason: {}", s),
             MemCopy(e) => write!(f, "{}", e),
             RutabagaComponentError(ret) => write!(f, "rutabaga failed with error {}", ret),
+            SysError(e) => write!(f, "system call failed: {}", e),
             Unsupported => write!(f, "gpu renderer function unsupported"),
         }
     }
@@ -173,6 +176,7 @@ pub const VIRGLRENDERER_USE_GLX: u32 = 4;
 pub const VIRGLRENDERER_USE_SURFACELESS: u32 = 8;
 pub const VIRGLRENDERER_USE_GLES: u32 = 16;
 pub const VIRGLRENDERER_USE_EXTERNAL_BLOB: u32 = 32;
+pub const VIRGLRENDERER_ASYNC_FENCE_CB: u32 = 1 << 8;
 
 /// virglrenderer flag struct.
 #[derive(Copy, Clone)]
@@ -231,6 +235,22 @@ impl VirglRendererFlags {
     pub fn use_external_blob(self, v: bool) -> VirglRendererFlags {
         self.set_flag(VIRGLRENDERER_USE_EXTERNAL_BLOB, v)
     }
+
+    /// Retire fences on a sync thread instead of polling for them.
+    pub fn use_thread_sync(self, v: bool) -> VirglRendererFlags {
+        self.set_flag(VIRGLRENDERER_THREAD_SYNC, v)
+    }
+
+    /// Report fences from the sync thread as soon as they retire.  Implies `use_thread_sync`.
+    pub fn use_async_fence_cb(self, v: bool) -> VirglRendererFlags {
+        self.set_flag(VIRGLRENDERER_ASYNC_FENCE_CB, v)
+            .use_thread_sync(v || self.0 & VIRGLRENDERER_THREAD_SYNC != 0)
+    }
+
+    /// Whether fences are reported from the renderer's own threads.
+    pub fn uses_async_fence_cb(self) -> bool {
+        self.0 & VIRGLRENDERER_ASYNC_FENCE_CB != 0
+    }
 }
 
 /// Flags for the gfxstream renderer.
@@ -364,3 +384,18 @@ impl RutabagaHandle {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn async_fence_cb_flag() {
+        // VIRGL_RENDERER_ASYNC_FENCE_CB in virglrenderer.h
+        assert_eq!(VIRGLRENDERER_ASYNC_FENCE_CB, 1 << 8);
+
+        let flags = VirglRendererFlags::new().use_async_fence_cb(true);
+        assert!(flags.uses_async_fence_cb());
+        assert_eq!(i32::from(flags), (1 << 8) | VIRGLRENDERER_THREAD_SYNC as i32);
+    }
+}
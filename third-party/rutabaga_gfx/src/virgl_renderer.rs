@@ -11,6 +11,7 @@ use std::ffi::CString;
 use std::fs::File;
 use std::mem::{size_of, transmute};
 use std::os::raw::{c_char, c_void};
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::ptr::null_mut;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -34,6 +35,8 @@ type Query = virgl_renderer_export_query;
 /// The virtio-gpu backend state tracker which supports accelerated rendering.
 pub struct VirglRenderer {
     fence_state: Arc<Mutex<FenceState>>,
+    /// Signalled by the sync thread when fences are reported asynchronously
+    fence_event: Option<Arc<FenceEvent>>,
 }
 
 struct VirglRendererContext {
@@ -41,11 +44,11 @@ struct VirglRendererContext {
 }
 
 impl RutabagaContext for VirglRendererContext {
-    fn submit_cmd(&mut self, commands: &mut [u8]) -> RutabagaResult<()> {
-        if commands.len() % size_of::<u32>() != 0 {
-            return Err(RutabagaError::InvalidCommandSize(commands.len()));
+    fn submit_cmd(&mut self, commands: VolatileSlice) -> RutabagaResult<()> {
+        if commands.size() % size_of::<u32>() != 0 {
+            return Err(RutabagaError::InvalidCommandSize(commands.size()));
         }
-        let dword_count = (commands.len() / size_of::<u32>()) as i32;
+        let dword_count = (commands.size() / size_of::<u32>()) as i32;
         // Safe because the context and buffer are valid and virglrenderer will have been
         // initialized if there are Context instances.
         let ret = unsafe {
@@ -189,9 +192,17 @@ impl VirglRenderer {
         // library.
 
         let fence_state = Arc::new(Mutex::new(FenceState { latest_fence: 0 }));
+        // With the async callback fences are reported from the sync thread, which has no other way
+        // to wake up the thread owning the renderer.
+        let fence_event = if virglrenderer_flags.uses_async_fence_cb() {
+            Some(Arc::new(FenceEvent::new()?))
+        } else {
+            None
+        };
 
         let cookie: *mut VirglCookie = Box::into_raw(Box::new(VirglCookie {
             fence_state: Arc::clone(&fence_state),
+            fence_event: fence_event.clone(),
         }));
 
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -210,7 +221,10 @@ impl VirglRenderer {
         };
 
         ret_to_res(ret)?;
-        Ok(Box::new(VirglRenderer { fence_state }))
+        Ok(Box::new(VirglRenderer {
+            fence_state,
+            fence_event,
+        }))
     }
 }
 
@@ -248,10 +262,29 @@ impl RutabagaComponent for VirglRenderer {
     }
 
     fn poll(&self) -> u32 {
+        // cleared before reading the fence state so that a fence reported meanwhile signals again
+        if let Some(ref fence_event) = self.fence_event {
+            fence_event.clear();
+        }
         unsafe { virgl_renderer_poll() };
         self.fence_state.lock().as_ref().unwrap().latest_fence
     }
 
+    fn poll_descriptor(&self) -> Option<RawFd> {
+        if let Some(ref fence_event) = self.fence_event {
+            return Some(fence_event.as_raw_fd());
+        }
+
+        // Safe because virglrenderer is initialized by now.  The descriptor is only valid when
+        // the renderer was started with its sync thread, -1 is returned otherwise.
+        let fd = unsafe { virgl_renderer_get_poll_fd() };
+        if fd >= 0 {
+            Some(fd)
+        } else {
+            None
+        }
+    }
+
     fn create_3d(
         &self,
         resource_id: u32,
@@ -532,15 +565,18 @@ impl RutabagaComponent for VirglRenderer {
         &self,
         ctx_id: u32,
         _context_init: u32,
+        context_name: Option<&str>,
     ) -> RutabagaResult<Box<dyn RutabagaContext>> {
         const CONTEXT_NAME: &[u8] = b"gpu_renderer";
-        // Safe because virglrenderer is initialized by now and the context name is statically
-        // allocated. The return value is checked before returning a new context.
+        // Host tools attribute the context's work by this name, the guest's one when it gave one.
+        let name = context_name.map_or(CONTEXT_NAME, str::as_bytes);
+        // Safe because virglrenderer is initialized by now and the context name outlives the
+        // call. The return value is checked before returning a new context.
         let ret = unsafe {
             virgl_renderer_context_create(
                 ctx_id,
-                CONTEXT_NAME.len() as u32,
-                CONTEXT_NAME.as_ptr() as *const c_char,
+                name.len() as u32,
+                name.as_ptr() as *const c_char,
             )
         };
         ret_to_res(ret)?;